@@ -0,0 +1,196 @@
+//! Dedicated server, which simulates levels without rendering, input or any assets
+//! besides what the simulation needs, so it can run on machines without a GPU.
+//! Clients connect to it over TCP, see `gaemstone::bloxel::net`.
+//!
+//! ```sh
+//! cargo run --release --bin gaemstone-server --no-default-features --features empty -- [seed]
+//! ```
+//!
+//! The level is saved with its seed, so the seed is only used when there's no saved level yet.
+//! Without one, a random seed is picked.
+
+use {
+  amethyst::{
+    core::{
+      frame_limiter::FrameRateLimitStrategy,
+      transform::{Transform, TransformBundle},
+      Time,
+    },
+    ecs::prelude::*,
+    prelude::*,
+    utils::application_root_dir,
+    Error,
+  },
+  gaemstone::bloxel::{
+    chunk::{ChunkLayers, ChunkLookupSystemDesc, DefaultLayout},
+    create_level, load_level,
+    net::{NetworkServer, ServerNetworkSystemDesc},
+    save_level, ActiveLevel, BlockEntitySystemDesc, BlockTickSystem, ChunkLoader, FluidLevel,
+    FluidSystemDesc, Level, ScriptSystemDesc, Scripts, VoxelBodySystem, WorldGenerator,
+    BLOCK_TICK_SECONDS, FLUID_LEVEL_LAYER, SCRIPTS_DIR,
+  },
+  log::{error, info, warn},
+  std::path::PathBuf,
+};
+
+/// Address clients connect to.
+const LISTEN_ADDRESS: &str = "0.0.0.0:29300";
+
+/// Seconds between saving the level to disk while the server is running.
+const AUTOSAVE_SECONDS: f32 = 60.0;
+
+fn main() -> Result<(), Error> {
+  amethyst::start_logger(Default::default());
+
+  let app_root = application_root_dir()?;
+  let assets_dir = app_root.join("assets");
+  let save_dir = app_root.join("saves").join("level");
+  // The seed of a new level can be passed as the first argument.
+  let seed = match std::env::args().nth(1) {
+    Some(arg) => Some(
+      arg
+        .parse::<u32>()
+        .map_err(|err| Error::from_string(format!("Invalid seed '{}': {}", arg, err)))?,
+    ),
+    None => None,
+  };
+
+  let game_data = GameDataBuilder::default()
+    .with_system_desc(ChunkLookupSystemDesc::default(), "chunk_lookup", &[])
+    .with(
+      WorldGenerator::<DefaultLayout>::default(),
+      "world_gen",
+      &["chunk_lookup"],
+    )
+    .with(
+      VoxelBodySystem::<DefaultLayout>::default(),
+      "voxel_bodies",
+      &["chunk_lookup"],
+    )
+    .with(
+      BlockTickSystem::<DefaultLayout>::default(),
+      "block_ticks",
+      &["chunk_lookup"],
+    )
+    .with_system_desc(
+      FluidSystemDesc::<DefaultLayout>::default(),
+      "fluids",
+      &["chunk_lookup"],
+    )
+    .with_system_desc(
+      BlockEntitySystemDesc::default(),
+      "block_entities",
+      &["chunk_lookup", "block_ticks", "fluids"],
+    )
+    .with_system_desc(
+      ScriptSystemDesc::default(),
+      "scripts",
+      &["world_gen", "block_ticks", "fluids"],
+    )
+    .with_system_desc(
+      ServerNetworkSystemDesc::<DefaultLayout>::default(),
+      "network",
+      &[
+        "chunk_lookup",
+        "world_gen",
+        "block_ticks",
+        "fluids",
+        "block_entities",
+        "scripts",
+      ],
+    )
+    // Chunk loaders are positioned using their global transform.
+    .with_bundle(TransformBundle::new().with_dep(&["voxel_bodies", "network"]))?;
+
+  // The server only advances as often as blocks are ticked, rather than rendering frames.
+  let ticks_per_second = (1.0 / BLOCK_TICK_SECONDS).round() as u32;
+  // Scripts are sent to clients as they join, and again whenever they're reloaded.
+  let scripts = Scripts::new::<DefaultLayout>().watch(assets_dir.join(SCRIPTS_DIR));
+  let mut server = Application::build(assets_dir, ServerState::new(save_dir, seed))?
+    .with_resource(scripts)
+    .with_frame_limit(FrameRateLimitStrategy::Sleep, ticks_per_second)
+    .build(game_data)?;
+  server.run();
+  Ok(())
+}
+
+struct ServerState {
+  save_dir: PathBuf,
+  /// Seed passed on the command line, if any.
+  seed: Option<u32>,
+  level: Option<Entity>,
+  /// Seconds since the level was last saved.
+  since_save: f32,
+}
+
+impl ServerState {
+  fn new(save_dir: PathBuf, seed: Option<u32>) -> Self {
+    ServerState {
+      save_dir,
+      seed,
+      level: None,
+      since_save: 0.0,
+    }
+  }
+
+  fn save(&mut self, world: &World) {
+    if let Some(level) = self.level {
+      match save_level::<DefaultLayout>(world, level, &self.save_dir) {
+        Ok(count) => info!("Saved {} chunks to {:?}", count, self.save_dir),
+        Err(err) => error!("Couldn't save level: {}", err),
+      }
+    }
+    self.since_save = 0.0;
+  }
+}
+
+impl SimpleState for ServerState {
+  fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+    let layers = ChunkLayers::<DefaultLayout>::default().with::<FluidLevel>(FLUID_LEVEL_LAYER);
+    layers.register(data.world);
+    data.world.insert(layers);
+
+    let seed = self.seed.unwrap_or_else(rand::random);
+    let level = create_level(data.world.create_entity(), Level::new(seed));
+    match load_level::<DefaultLayout>(data.world, level, &self.save_dir) {
+      Ok(count) => info!("Loaded {} chunks from {:?}", count, self.save_dir),
+      Err(err) => error!("Couldn't load level: {}", err),
+    }
+    // A saved level keeps the seed it was generated with, so new chunks match the old ones.
+    let seed = data.world.read_storage::<Level>().get(level).unwrap().seed;
+    if let Some(requested) = self.seed.filter(|requested| *requested != seed) {
+      warn!("Ignoring seed {}, the saved level uses {}", requested, seed);
+    }
+    info!("Level seed is {}", seed);
+    data.world.insert(ActiveLevel(Some(level)));
+    self.level = Some(level);
+
+    match NetworkServer::listen(LISTEN_ADDRESS) {
+      Ok(server) => {
+        info!("Listening on {}", LISTEN_ADDRESS);
+        data.world.insert(server);
+      }
+      Err(err) => error!("Couldn't listen on {}: {}", LISTEN_ADDRESS, err),
+    }
+
+    // Keep the area around spawn simulated, even while nobody is around.
+    data
+      .world
+      .create_entity()
+      .with(Transform::default())
+      .with(ChunkLoader::default())
+      .build();
+  }
+
+  fn on_stop(&mut self, data: StateData<'_, GameData<'_, '_>>) {
+    self.save(data.world);
+  }
+
+  fn update(&mut self, data: &mut StateData<'_, GameData<'_, '_>>) -> SimpleTrans {
+    self.since_save += data.world.read_resource::<Time>().delta_seconds();
+    if self.since_save >= AUTOSAVE_SECONDS {
+      self.save(data.world);
+    }
+    Trans::None
+  }
+}
@@ -0,0 +1,527 @@
+use {
+  super::{
+    chunk::{storage::*, *},
+    mesh_generator::mark_mesh_outdated,
+    raycast::{raycast_chunks, RaycastHit},
+    world_generator::chunk_render_components,
+    BlockPos, VoxelBody,
+  },
+  crate::util::{ChunkedOctree, ZOrder},
+  amethyst::{
+    core::{
+      math::{Point3, Vector3},
+      transform::Parent,
+    },
+    ecs::{prelude::*, storage::GenericReadStorage},
+    shrev::{EventChannel, EventIterator},
+  },
+  std::convert::TryFrom,
+};
+
+/// Read-only system data for looking up blocks using world-space `BlockPos` coordinates. Unlike
+/// `BlockAccess`, it only borrows chunk storages immutably, so systems that merely read blocks
+/// can run in parallel with each other.
+///
+/// Every operation takes the level entity containing the blocks. Entities which aren't levels
+/// (lacking a `ChunkLookup`) behave as if they were empty.
+#[derive(SystemData)]
+pub struct BlockView<'a, T: BlockData = u8, L: ChunkLayout = DefaultLayout> {
+  lookups: ReadStorage<'a, ChunkLookup>,
+  octrees: ReadStorage<'a, ChunkedOctree<ChunkState>>,
+  storages: ReadStorage<'a, ChunkStorage<T, L>>,
+}
+
+impl<'a, T: BlockData, L: ChunkLayout> BlockView<'a, T, L> {
+  /// Gets the chunk entity at the specified position, if it exists.
+  pub fn chunk(&self, level: Entity, pos: ChunkPos) -> Option<Entity> {
+    chunk_in(&self.lookups, level, pos)
+  }
+
+  /// Iterates over the positions and entities of all chunks in the specified level.
+  pub fn chunks(&self, level: Entity) -> impl Iterator<Item = (ChunkPos, Entity)> + '_ {
+    self
+      .lookups
+      .get(level)
+      .into_iter()
+      .flat_map(|lookup| lookup.iter())
+  }
+
+  /// Gets the value of the block at the specified position.
+  /// Returns the default value if the chunk containing it doesn't exist.
+  pub fn get(&self, level: Entity, pos: BlockPos) -> T {
+    block_in::<T, L, _, _>(&self.lookups, &self.storages, level, pos)
+  }
+
+  /// Calls `func` for every block inside the box spanned by `from` and `to` (inclusive),
+  /// passing its position and value. Blocks in chunks that don't exist are skipped.
+  pub fn for_each_in<F>(&self, level: Entity, from: BlockPos, to: BlockPos, func: F)
+  where
+    F: FnMut(BlockPos, T),
+  {
+    for_each_block_in::<T, L, _, _, _>(&self.lookups, &self.storages, level, from, to, func);
+  }
+
+  /// Casts a ray through the specified level, returning the first block for which `is_hit`
+  /// returns `true`, if any is found within `max_distance`. See `raycast` for details.
+  pub fn raycast<F>(
+    &self,
+    level: Entity,
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    max_distance: f32,
+    is_hit: F,
+  ) -> Option<RaycastHit>
+  where
+    F: FnMut(T) -> bool,
+  {
+    let lookup = self.lookups.get(level)?;
+    raycast_chunks(
+      lookup,
+      &self.storages,
+      origin,
+      direction,
+      max_distance,
+      is_hit,
+    )
+  }
+
+  /// Gets the storage of the chunk at the specified position, if it exists.
+  pub fn storage(&self, level: Entity, pos: ChunkPos) -> Option<&ChunkStorage<T, L>> {
+    self.storages.get(self.chunk(level, pos)?)
+  }
+
+  /// Gets the octree keeping track of the state of the specified level's chunks.
+  pub fn octree(&self, level: Entity) -> Option<&ChunkedOctree<ChunkState>> {
+    self.octrees.get(level)
+  }
+}
+
+/// System data which allows reading and writing blocks using world-space `BlockPos` coordinates,
+/// taking care of looking up the chunk entity and its storage behind the scenes.
+///
+/// Every operation takes the level entity containing the blocks. Entities which aren't levels
+/// (lacking a `ChunkLookup` and `ChunkedOctree<ChunkState>`) behave as if they were empty.
+///
+/// Writing a non-default value into a chunk that doesn't exist yet creates that chunk entity on
+/// the spot. Any chunk that was written to is marked so `ChunkMeshGenerator` will rebuild its mesh,
+/// and every block that changed is announced through the `EventChannel<BlockChange<T>>` resource.
+/// Blocks written into chunks of a level that haven't been generated yet, including default
+/// values, are recorded in `UngeneratedEdits<T>` so generation doesn't overwrite them.
+///
+/// Systems which only read blocks should use `BlockView` instead, and block entities are
+/// handled by `BlockEntityAccess`.
+///
+/// # Examples
+///
+/// ```
+/// # use amethyst::ecs::prelude::*;
+/// # use gaemstone::bloxel::{create_level, BlockAccess, BlockPos, Level};
+/// # let mut world = World::new();
+/// # <BlockAccess<'_> as SystemData>::setup(&mut world);
+/// # world.register::<Level>();
+/// let level = create_level(world.create_entity(), Level::new(1234));
+/// world.exec(|mut blocks: BlockAccess<'_>| {
+///   let pos = BlockPos::new(-4, 10, 20);
+///   if blocks.get(level, pos) == 0 {
+///     blocks.set(level, pos, 1);
+///   }
+///   blocks.fill(level, pos + (-2, -2, -2), pos + (2, 2, 2), 0);
+/// });
+/// ```
+#[derive(SystemData)]
+pub struct BlockAccess<'a, T: BlockData = u8, L: ChunkLayout = DefaultLayout> {
+  entities: Entities<'a>,
+  lazy: Read<'a, LazyUpdate>,
+  lookups: WriteStorage<'a, ChunkLookup>,
+  octrees: WriteStorage<'a, ChunkedOctree<ChunkState>>,
+  chunks: WriteStorage<'a, Chunk>,
+  storages: WriteStorage<'a, ChunkStorage<T, L>>,
+  edits: WriteStorage<'a, UngeneratedEdits<T, L>>,
+  bodies: WriteStorage<'a, VoxelBody>,
+  events: Write<'a, EventChannel<BlockChange<T>>>,
+}
+
+/// Event sent by `BlockAccess` whenever the value of a block changes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockChange<T: BlockData = u8> {
+  pub level: Entity,
+  pub pos: BlockPos,
+  pub previous: T,
+  pub value: T,
+}
+
+impl<'a, T: BlockData, L: ChunkLayout> BlockAccess<'a, T, L> {
+  /// Gets the chunk entity at the specified position, if it exists.
+  pub fn chunk(&self, level: Entity, pos: ChunkPos) -> Option<Entity> {
+    chunk_in(&self.lookups, level, pos)
+  }
+
+  /// Iterates over the positions and entities of all chunks in the specified level.
+  pub fn chunks(&self, level: Entity) -> impl Iterator<Item = (ChunkPos, Entity)> + '_ {
+    self
+      .lookups
+      .get(level)
+      .into_iter()
+      .flat_map(|lookup| lookup.iter())
+  }
+
+  /// Gets the value of the block at the specified position.
+  /// Returns the default value if the chunk containing it doesn't exist.
+  pub fn get(&self, level: Entity, pos: BlockPos) -> T {
+    block_in::<T, L, _, _>(&self.lookups, &self.storages, level, pos)
+  }
+
+  /// Sets the value of the block at the specified position, creating
+  /// the chunk containing it if necessary. Returns the previous value.
+  pub fn set(&mut self, level: Entity, pos: BlockPos, value: T) -> T {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    let ungenerated = self.is_ungenerated(level, chunk_pos);
+    if ungenerated {
+      self.record_edits(level, chunk_pos, std::iter::once(index));
+    }
+    let storage = match self.storage_mut(level, chunk_pos, ungenerated || value != T::default()) {
+      Some(storage) => storage,
+      None => return Default::default(),
+    };
+
+    let previous = storage.get(index);
+    if previous != value {
+      storage.set(index, value);
+      self.mark_dirty(level, chunk_pos);
+      self.events.single_write(BlockChange {
+        level,
+        pos,
+        previous,
+        value,
+      });
+    }
+    previous
+  }
+
+  /// Sets all blocks inside the box spanned by `from` and `to` (inclusive) to the specified value.
+  pub fn fill(&mut self, level: Entity, from: BlockPos, to: BlockPos, value: T) {
+    for_each_chunk_in::<L, _>(from, to, |chunk_pos, min, max| {
+      let ungenerated = self.is_ungenerated(level, chunk_pos);
+      if ungenerated {
+        let indices = (min.0..=max.0).flat_map(|x| {
+          (min.1..=max.1).flat_map(move |y| {
+            // SAFETY: `for_each_chunk_in` only passes bounds that are inside of the chunk.
+            (min.2..=max.2).map(move |z| unsafe { Index::<L>::new_unchecked(x, y, z) })
+          })
+        });
+        self.record_edits(level, chunk_pos, indices);
+      }
+      let create = ungenerated || value != T::default();
+      let storage = match self.storage_mut(level, chunk_pos, create) {
+        Some(storage) => storage,
+        None => return,
+      };
+
+      let mut changes = Vec::new();
+      for x in min.0..=max.0 {
+        for y in min.1..=max.1 {
+          for z in min.2..=max.2 {
+            // SAFETY: `for_each_chunk_in` only passes bounds that are inside of the chunk.
+            let index = unsafe { Index::<L>::new_unchecked(x, y, z) };
+            let previous = storage.get(index);
+            if previous != value {
+              storage.set(index, value);
+              changes.push(BlockChange {
+                level,
+                pos: (chunk_pos, index).into(),
+                previous,
+                value,
+              });
+            }
+          }
+        }
+      }
+
+      if !changes.is_empty() {
+        self.mark_dirty(level, chunk_pos);
+        self.events.iter_write(changes);
+      }
+    });
+  }
+
+  /// Calls `func` for every block inside the box spanned by `from` and `to` (inclusive),
+  /// passing its position and value. Blocks in chunks that don't exist are skipped.
+  pub fn for_each_in<F>(&self, level: Entity, from: BlockPos, to: BlockPos, func: F)
+  where
+    F: FnMut(BlockPos, T),
+  {
+    for_each_block_in::<T, L, _, _, _>(&self.lookups, &self.storages, level, from, to, func);
+  }
+
+  /// Casts a ray through the specified level, returning the first block for which `is_hit`
+  /// returns `true`, if any is found within `max_distance`. See `raycast` for details.
+  pub fn raycast<F>(
+    &self,
+    level: Entity,
+    origin: Point3<f32>,
+    direction: Vector3<f32>,
+    max_distance: f32,
+    is_hit: F,
+  ) -> Option<RaycastHit>
+  where
+    F: FnMut(T) -> bool,
+  {
+    let lookup = self.lookups.get(level)?;
+    raycast_chunks(
+      lookup,
+      &self.storages,
+      origin,
+      direction,
+      max_distance,
+      is_hit,
+    )
+  }
+
+  /// Reads the block changes announced since `reader` last read them. Systems using `BlockAccess`
+  /// have to read changes this way, as it already borrows the `EventChannel` mutably.
+  pub fn read_changes(
+    &self,
+    reader: &mut ReaderId<BlockChange<T>>,
+  ) -> EventIterator<'_, BlockChange<T>> {
+    self.events.read(reader)
+  }
+
+  /// Gets the storage of the chunk at the specified position, if it exists.
+  pub fn storage(&self, level: Entity, pos: ChunkPos) -> Option<&ChunkStorage<T, L>> {
+    self.storages.get(self.chunk(level, pos)?)
+  }
+
+  /// Gets the octree keeping track of the state of the specified level's chunks.
+  pub fn octree(&self, level: Entity) -> Option<&ChunkedOctree<ChunkState>> {
+    self.octrees.get(level)
+  }
+
+  /// Whether the chunk at the specified position belongs to a level and
+  /// hasn't been generated yet, so blocks set in it have to be recorded.
+  fn is_ungenerated(&self, level: Entity, pos: ChunkPos) -> bool {
+    // Voxel bodies are never generated.
+    if self.bodies.contains(level) {
+      return false;
+    }
+    let (octree, z_pos) = match (self.octrees.get(level), ZOrder::try_from(pos)) {
+      (Some(octree), Ok(z_pos)) => (octree, z_pos),
+      _ => return false,
+    };
+    !octree.get(0, z_pos).contains(ChunkState::GENERATED_ALL)
+  }
+
+  /// Records that the specified blocks of a chunk that hasn't been generated yet were set,
+  /// creating the chunk if necessary.
+  fn record_edits<I>(&mut self, level: Entity, pos: ChunkPos, indices: I)
+  where
+    I: IntoIterator<Item = Index<L>>,
+  {
+    let entity = match self.chunk(level, pos) {
+      Some(entity) => entity,
+      None => match self.create_chunk(level, pos) {
+        Some(entity) => entity,
+        None => return,
+      },
+    };
+    let edits = self
+      .edits
+      .entry(entity)
+      .unwrap()
+      .or_insert_with(Default::default);
+    for index in indices {
+      edits.insert(index);
+    }
+  }
+
+  /// Gets the storage of the chunk at the specified position. If `create` is set and
+  /// the chunk doesn't exist yet, a new, empty chunk entity is created in its place.
+  fn storage_mut(
+    &mut self,
+    level: Entity,
+    pos: ChunkPos,
+    create: bool,
+  ) -> Option<&mut ChunkStorage<T, L>> {
+    let entity = match self.chunk(level, pos) {
+      Some(entity) => entity,
+      None if create => self.create_chunk(level, pos)?,
+      None => return None,
+    };
+    if !self.storages.contains(entity) {
+      if !create {
+        return None;
+      }
+      self
+        .storages
+        .insert(entity, ChunkStorage::new(PaletteStorageImpl::new()))
+        .unwrap();
+    }
+    self.storages.get_mut(entity)
+  }
+
+  /// Creates a new, empty chunk entity in the specified level,
+  /// or returns `None` if the entity isn't a level.
+  pub fn create_chunk(&mut self, level: Entity, pos: ChunkPos) -> Option<Entity> {
+    if !self.lookups.contains(level) || !self.octrees.contains(level) {
+      return None;
+    }
+
+    let entity = self.entities.create();
+    self.chunks.insert(entity, Chunk { level, pos }).unwrap();
+    self.lookups.get_mut(level).unwrap().insert(pos, entity);
+
+    // Rendering related components aren't needed right away, so they can be added lazily.
+    let (transform, bounds) = chunk_render_components::<L>(pos);
+    self.lazy.insert(entity, transform);
+    self.lazy.insert(entity, bounds);
+    // Chunks of voxel bodies are positioned relative to the body they're part of.
+    if self.bodies.contains(level) {
+      self.lazy.insert(entity, Parent::new(level));
+    }
+
+    // Mark the chunk as existing, but not generated, so `WorldGenerator`
+    // will still fill in the terrain around any blocks set here.
+    if let Ok(z_pos) = ZOrder::try_from(pos) {
+      let mask_some = ChunkState::EXISTS_SOME;
+      let mask_all = ChunkState::EXISTS_ALL;
+      self.octrees.get_mut(level).unwrap().update(
+        z_pos,
+        |state| *state = *state | mask_all,
+        |_level, children, parent| {
+          let mask = if children.iter().all(|s| *s & mask_all == mask_all) {
+            mask_all
+          } else {
+            mask_some
+          };
+          if *parent & mask == mask {
+            false
+          } else {
+            *parent = *parent | mask;
+            true
+          }
+        },
+      );
+    }
+
+    Some(entity)
+  }
+
+  /// Clears the mesh state of the chunk at the specified position, causing `ChunkMeshGenerator`
+  /// to pick it up again. Done automatically when blocks change, but other chunk data may also
+  /// affect meshes.
+  pub fn mark_dirty(&mut self, level: Entity, pos: ChunkPos) {
+    if let Some(body) = self.bodies.get_mut(level) {
+      body.mark_dirty();
+    }
+    if let Some(octree) = self.octrees.get_mut(level) {
+      mark_mesh_outdated(octree, pos);
+    }
+  }
+}
+
+/// Gets the chunk entity at the specified position from a level's `ChunkLookup`, if it exists.
+fn chunk_in<S>(lookups: &S, level: Entity, pos: ChunkPos) -> Option<Entity>
+where
+  S: GenericReadStorage<Component = ChunkLookup>,
+{
+  lookups.get(level).and_then(|lookup| lookup.get(pos))
+}
+
+/// Gets the value of a block, shared by `BlockView` and `BlockAccess`.
+fn block_in<T, L, S1, S2>(lookups: &S1, storages: &S2, level: Entity, pos: BlockPos) -> T
+where
+  T: BlockData,
+  L: ChunkLayout,
+  S1: GenericReadStorage<Component = ChunkLookup>,
+  S2: GenericReadStorage<Component = ChunkStorage<T, L>>,
+{
+  let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+  chunk_in(lookups, level, chunk_pos)
+    .and_then(|entity| storages.get(entity))
+    .map(|storage| storage.get(index))
+    .unwrap_or_default()
+}
+
+/// Calls `func` for every existing block inside the box spanned by `from` and `to` (inclusive),
+/// shared by `BlockView` and `BlockAccess`.
+fn for_each_block_in<T, L, S1, S2, F>(
+  lookups: &S1,
+  storages: &S2,
+  level: Entity,
+  from: BlockPos,
+  to: BlockPos,
+  mut func: F,
+) where
+  T: BlockData,
+  L: ChunkLayout,
+  S1: GenericReadStorage<Component = ChunkLookup>,
+  S2: GenericReadStorage<Component = ChunkStorage<T, L>>,
+  F: FnMut(BlockPos, T),
+{
+  for_each_chunk_in::<L, _>(from, to, |chunk_pos, min, max| {
+    let storage = match chunk_in(lookups, level, chunk_pos).and_then(|e| storages.get(e)) {
+      Some(storage) => storage,
+      None => return,
+    };
+
+    for x in min.0..=max.0 {
+      for y in min.1..=max.1 {
+        for z in min.2..=max.2 {
+          // SAFETY: `for_each_chunk_in` only passes bounds that are inside of the chunk.
+          let index = unsafe { Index::<L>::new_unchecked(x, y, z) };
+          func((chunk_pos, index).into(), storage.get(index));
+        }
+      }
+    }
+  });
+}
+
+/// Calls `func` for every chunk intersecting the box spanned by `from` and `to` (inclusive),
+/// passing its position and the minimum and maximum relative coordinates inside of that chunk.
+fn for_each_chunk_in<L, F>(from: BlockPos, to: BlockPos, mut func: F)
+where
+  L: ChunkLayout,
+  F: FnMut(ChunkPos, (i32, i32, i32), (i32, i32, i32)),
+{
+  let min = BlockPos::new(from.x.min(to.x), from.y.min(to.y), from.z.min(to.z));
+  let max = BlockPos::new(from.x.max(to.x), from.y.max(to.y), from.z.max(to.z));
+  let (min_chunk, _): (ChunkPos, Index<L>) = min.into();
+  let (max_chunk, _): (ChunkPos, Index<L>) = max.into();
+
+  for cx in min_chunk.x..=max_chunk.x {
+    let (min_x, max_x) = clamp_to_chunk::<L>(cx, min.x, max.x);
+    for cy in min_chunk.y..=max_chunk.y {
+      let (min_y, max_y) = clamp_to_chunk::<L>(cy, min.y, max.y);
+      for cz in min_chunk.z..=max_chunk.z {
+        let (min_z, max_z) = clamp_to_chunk::<L>(cz, min.z, max.z);
+        func(
+          ChunkPos::new(cx, cy, cz),
+          (min_x, min_y, min_z),
+          (max_x, max_y, max_z),
+        );
+      }
+    }
+  }
+}
+
+/// Clamps a single world-space coordinate range to the chunk at `chunk`, in relative coordinates.
+/// Done in `i64`, since the distance between a coordinate and a chunk's origin can overflow.
+fn clamp_to_chunk<L: ChunkLayout>(chunk: i32, min: i32, max: i32) -> (i32, i32) {
+  let origin = i64::from(chunk) << L::LENGTH_BITS;
+  let relative_min = (i64::from(min) - origin).max(0);
+  let relative_max = (i64::from(max) - origin).min(L::LENGTH as i64 - 1);
+  (relative_min as i32, relative_max as i32)
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::bloxel::chunk::LinearLayout16};
+
+  #[test]
+  fn chunks_at_the_edge_of_the_world_are_clamped() {
+    let clamp = clamp_to_chunk::<LinearLayout16>;
+    assert_eq!(clamp(i32::MIN >> 4, i32::MIN, i32::MAX), (0, 15));
+    assert_eq!(clamp(i32::MAX >> 4, i32::MIN, i32::MAX), (0, 15));
+    assert_eq!(clamp(i32::MAX >> 4, i32::MAX - 3, i32::MAX), (12, 15));
+    assert_eq!(clamp(i32::MIN >> 4, i32::MIN + 2, i32::MIN + 4), (2, 4));
+  }
+}
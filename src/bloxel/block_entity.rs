@@ -0,0 +1,229 @@
+use {
+  super::{
+    chunk::{ChunkLayout, ChunkPos, DefaultLayout, Index},
+    BlockChange, BlockPos, BlockView,
+  },
+  amethyst::{derive::SystemDesc, ecs::prelude::*, shrev::EventChannel},
+  std::collections::HashMap,
+};
+
+/// Component for entities attached to a single block, such as a chest's inventory or a sign's
+/// text. Block entities are created and looked up through `BlockEntityAccess`. Once the block
+/// they are attached to changes, they are considered detached and get deleted by
+/// `BlockEntitySystem`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BlockEntity {
+  pub level: Entity,
+  pub pos: BlockPos,
+  /// The block this entity was attached to.
+  pub block: u8,
+}
+
+impl Component for BlockEntity {
+  type Storage = DenseVecStorage<Self>;
+}
+
+/// Component on chunks which have block entities attached to any of their blocks.
+#[derive(Debug, Default)]
+pub struct ChunkBlockEntities(pub(crate) HashMap<BlockPos, Entity>);
+
+impl Component for ChunkBlockEntities {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl ChunkBlockEntities {
+  pub fn get(&self, pos: BlockPos) -> Option<Entity> {
+    self.0.get(&pos).copied()
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  /// Iterates over all block entities in the chunk and the positions they're attached at.
+  pub fn iter(&self) -> impl Iterator<Item = (BlockPos, Entity)> + '_ {
+    self.0.iter().map(|(pos, entity)| (*pos, *entity))
+  }
+}
+
+/// System data for creating, looking up and removing entities attached to single blocks.
+/// Only reads the blocks themselves, so it doesn't conflict with systems merely reading blocks.
+#[derive(SystemData)]
+pub struct BlockEntityAccess<'a, L: ChunkLayout = DefaultLayout> {
+  entities: Entities<'a>,
+  blocks: BlockView<'a, u8, L>,
+  chunk_block_entities: WriteStorage<'a, ChunkBlockEntities>,
+  block_entities: WriteStorage<'a, BlockEntity>,
+}
+
+impl<'a, L: ChunkLayout> BlockEntityAccess<'a, L> {
+  /// Gets the view of the blocks entities are attached to.
+  pub fn blocks(&self) -> &BlockView<'a, u8, L> {
+    &self.blocks
+  }
+
+  /// Gets the entity attached to the block at the specified position, if any.
+  pub fn block_entity(&self, level: Entity, pos: BlockPos) -> Option<Entity> {
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    let entity = (self.blocks.chunk(level, chunk_pos))
+      .and_then(|chunk| self.chunk_block_entities.get(chunk))
+      .and_then(|block_entities| block_entities.get(pos))?;
+    // The entity only counts as attached while its block stays the same.
+    let attached = self.block_entities.get(entity)?;
+    let is_attached = attached.level == level && attached.pos == pos;
+    if is_attached
+      && self.entities.is_alive(entity)
+      && attached.block == self.blocks.get(level, pos)
+    {
+      Some(entity)
+    } else {
+      None
+    }
+  }
+
+  /// Gets the level and position of the block the specified entity is attached to, if any.
+  pub fn block_entity_pos(&self, entity: Entity) -> Option<(Entity, BlockPos)> {
+    let attached = self.block_entities.get(entity)?;
+    match self.block_entity(attached.level, attached.pos) {
+      Some(current) if current == entity => Some((attached.level, attached.pos)),
+      _ => None,
+    }
+  }
+
+  /// Attaches an existing entity to the block at the specified position, replacing and deleting
+  /// any entity previously attached to it. Returns `false` if the chunk doesn't exist.
+  pub fn attach_block_entity(&mut self, level: Entity, pos: BlockPos, entity: Entity) -> bool {
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    let chunk = match self.blocks.chunk(level, chunk_pos) {
+      Some(chunk) => chunk,
+      None => return false,
+    };
+    self.remove_block_entity(level, pos);
+
+    let block = self.blocks.get(level, pos);
+    let attached = BlockEntity { level, pos, block };
+    if self.block_entities.insert(entity, attached).is_err() {
+      return false;
+    }
+    let chunk_block_entities = match self.chunk_block_entities.entry(chunk) {
+      Ok(entry) => entry.or_insert_with(Default::default),
+      Err(_) => return false,
+    };
+    chunk_block_entities.0.insert(pos, entity);
+    true
+  }
+
+  /// Creates a new entity attached to the block at the specified position,
+  /// to which further components can then be added. See `attach_block_entity`.
+  pub fn create_block_entity(&mut self, level: Entity, pos: BlockPos) -> Option<Entity> {
+    let entity = self.entities.create();
+    if self.attach_block_entity(level, pos, entity) {
+      Some(entity)
+    } else {
+      self.entities.delete(entity).unwrap();
+      None
+    }
+  }
+
+  /// Detaches and deletes the entity attached to the block at the specified position.
+  /// Returns whether there was an entity attached, even if it's no longer valid.
+  pub fn remove_block_entity(&mut self, level: Entity, pos: BlockPos) -> bool {
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    let chunk = match self.blocks.chunk(level, chunk_pos) {
+      Some(chunk) => chunk,
+      None => return false,
+    };
+    let entity = match self.chunk_block_entities.get_mut(chunk) {
+      Some(chunk_block_entities) => match chunk_block_entities.0.remove(&pos) {
+        Some(entity) => entity,
+        None => return false,
+      },
+      None => return false,
+    };
+    if self
+      .chunk_block_entities
+      .get(chunk)
+      .map_or(false, |c| c.is_empty())
+    {
+      self.chunk_block_entities.remove(chunk);
+    }
+    // Deleting an entity that's already gone is fine.
+    let _ = self.entities.delete(entity);
+    true
+  }
+}
+
+/// Deletes block entities whose block was changed.
+#[derive(SystemDesc)]
+#[system_desc(name(BlockEntitySystemDesc))]
+pub struct BlockEntitySystem {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<BlockChange>,
+}
+
+impl BlockEntitySystem {
+  pub fn new(reader: ReaderId<BlockChange>) -> Self {
+    Self { reader }
+  }
+}
+
+impl<'a> System<'a> for BlockEntitySystem {
+  type SystemData = (Read<'a, EventChannel<BlockChange>>, BlockEntityAccess<'a>);
+
+  fn run(&mut self, (changes, mut blocks): Self::SystemData) {
+    for change in changes.read(&mut self.reader) {
+      // The block may have been changed back since, in which case its entity is still valid.
+      if blocks.block_entity(change.level, change.pos).is_none() {
+        blocks.remove_block_entity(change.level, change.pos);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{create_level, BlockAccess, Level},
+    amethyst::{core::transform::Transform, renderer::visibility::BoundingSphere},
+  };
+
+  #[test]
+  fn block_entity_detaches_when_block_changes() {
+    let mut world = World::new();
+    <BlockAccess<'_> as SystemData>::setup(&mut world);
+    <BlockEntityAccess<'_> as SystemData>::setup(&mut world);
+    world.register::<Level>();
+    // Created chunks get these lazily.
+    world.register::<Transform>();
+    world.register::<BoundingSphere>();
+    let level = create_level(world.create_entity(), Level::new(0));
+    let reader = (world.write_resource::<EventChannel<BlockChange>>()).register_reader();
+    let mut system = BlockEntitySystem::new(reader);
+
+    let pos = BlockPos::new(-1, 0, 0);
+    world.exec(|mut blocks: BlockAccess<'_>| blocks.set(level, pos, 1));
+    let entity = world.exec(|mut access: BlockEntityAccess<'_>| {
+      let entity = access.create_block_entity(level, pos).unwrap();
+      assert_eq!(access.block_entity(level, pos), Some(entity));
+      assert_eq!(access.block_entity_pos(entity), Some((level, pos)));
+      entity
+    });
+    world.exec(|mut blocks: BlockAccess<'_>| blocks.set(level, pos, 2));
+    world.exec(|access: BlockEntityAccess<'_>| {
+      assert_eq!(access.block_entity(level, pos), None);
+      assert_eq!(access.block_entity_pos(entity), None);
+    });
+
+    system.run_now(&world);
+    world.maintain();
+    assert!(!world.is_alive(entity));
+    world.exec(|mut access: BlockEntityAccess<'_>| {
+      assert!(!access.remove_block_entity(level, pos));
+    });
+  }
+}
@@ -0,0 +1,142 @@
+use {
+  super::{is_solid, net::NetworkClient, ActiveLevel, BlockAccess, Fluid},
+  amethyst::{
+    controls::{FlyControlTag, HideCursor},
+    core::{
+      math::{Point3, Vector3},
+      transform::Transform,
+    },
+    derive::SystemDesc,
+    ecs::prelude::*,
+    input::{InputEvent, StringBindings},
+    renderer::{debug_drawing::DebugLines, palette::Srgba},
+    shrev::EventChannel,
+  },
+};
+
+/// Maximum distance in blocks at which blocks can be broken or placed.
+pub const REACH_DISTANCE: f32 = 8.0;
+
+/// Input action which breaks the targeted block.
+pub const ACTION_BREAK_BLOCK: &str = "break_block";
+/// Input action which places a block against the targeted face.
+pub const ACTION_PLACE_BLOCK: &str = "place_block";
+/// Input action which places water against the targeted face.
+pub const ACTION_PLACE_FLUID: &str = "place_fluid";
+
+/// Lets the player break and place blocks in the `ActiveLevel` by casting a ray from the camera
+/// marked with `FlyControlTag`. The targeted block is outlined using `DebugLines`. Edits go through
+/// `BlockAccess`, which marks affected chunks so `ChunkMeshGenerator` rebuilds their meshes.
+/// While connected to a server, edits are predicted and sent to it using the `NetworkClient`.
+#[derive(SystemDesc)]
+#[system_desc(name(BlockInteractionSystemDesc))]
+pub struct BlockInteractionSystem {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<InputEvent<StringBindings>>,
+  /// Whether the cursor was hidden during the previous run.
+  #[system_desc(skip)]
+  cursor_hidden: bool,
+}
+
+impl BlockInteractionSystem {
+  pub fn new(reader: ReaderId<InputEvent<StringBindings>>) -> Self {
+    Self {
+      reader,
+      cursor_hidden: false,
+    }
+  }
+}
+
+impl<'a> System<'a> for BlockInteractionSystem {
+  type SystemData = (
+    Read<'a, EventChannel<InputEvent<StringBindings>>>,
+    Read<'a, ActiveLevel>,
+    Read<'a, HideCursor>,
+    ReadStorage<'a, FlyControlTag>,
+    ReadStorage<'a, Transform>,
+    Write<'a, DebugLines>,
+    Option<Write<'a, NetworkClient>>,
+    BlockAccess<'a>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      events,
+      active_level,
+      hide_cursor,
+      tags,
+      transforms,
+      mut debug_lines,
+      mut client,
+      mut blocks,
+    ): Self::SystemData,
+  ) {
+    // Always read events, so they don't pile up while there's nothing to interact with.
+    let (mut break_block, mut place) = (false, None);
+    for event in events.read(&mut self.reader) {
+      if let InputEvent::ActionPressed(action) = event {
+        match action.as_str() {
+          ACTION_BREAK_BLOCK => break_block = true,
+          ACTION_PLACE_BLOCK => place = Some(1),
+          ACTION_PLACE_FLUID => place = Some(Fluid::Water.block()),
+          _ => {}
+        }
+      }
+    }
+
+    // Clicking while the cursor is visible only serves to grab it, which `MainState` handles
+    // before this system runs. Ignore actions until the cursor was already hidden last frame.
+    let cursor_was_hidden = std::mem::replace(&mut self.cursor_hidden, hide_cursor.hide);
+
+    let level = match active_level.0 {
+      Some(level) => level,
+      None => return,
+    };
+    let hit = match (&tags, &transforms).join().next() {
+      Some((_, transform)) => {
+        let (origin, direction) = camera_ray(transform);
+        blocks.raycast(level, origin, direction, REACH_DISTANCE, is_solid)
+      }
+      None => return,
+    };
+    let hit = match hit {
+      Some(hit) => hit,
+      None => return,
+    };
+
+    let min = Point3::new(hit.pos.x as f32, hit.pos.y as f32, hit.pos.z as f32);
+    let max = min + Vector3::repeat(1.0);
+    // Slightly enlarge the outline so it isn't hidden by the faces of the block itself.
+    let margin = Vector3::repeat(0.005);
+    debug_lines.draw_box(min - margin, max + margin, Srgba::new(0.0, 0.0, 0.0, 1.0));
+
+    if !(cursor_was_hidden && hide_cursor.hide) {
+      return;
+    }
+
+    let edit = if break_block {
+      Some((hit.pos, 0))
+    } else {
+      // The face is only unknown when the camera is inside of the block itself.
+      place.and_then(|value| Some((hit.pos + hit.facing?, value)))
+    };
+    match (edit, client.as_deref_mut()) {
+      (Some((pos, value)), Some(client)) => client.edit_block(&mut blocks, level, pos, value),
+      (Some((pos, value)), None) => {
+        blocks.set(level, pos, value);
+      }
+      (None, _) => {}
+    }
+  }
+}
+
+/// Gets the origin and direction of the ray used to find the block
+/// the specified camera transform is looking at.
+pub(crate) fn camera_ray(transform: &Transform) -> (Point3<f32>, Vector3<f32>) {
+  let matrix = transform.global_matrix();
+  let origin = Point3::from(matrix.column(3).xyz());
+  // Cameras look towards their local `-Z` axis.
+  let direction = -matrix.column(2).xyz();
+  (origin, direction)
+}
@@ -0,0 +1,348 @@
+use {
+  super::{Facing, Fluid},
+  serde::{Deserialize, Serialize},
+};
+
+/// Shape of a block, which decides the geometry it's meshed with and which
+/// of its faces are full, hiding the faces of neighbouring blocks behind them.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum BlockShape {
+  /// Not rendered at all, like air. Fluids are meshed separately, so they use this as well.
+  Empty,
+  Cube,
+  /// The lower half of a block.
+  Slab,
+  /// Stairs ascending towards the specified horizontal direction.
+  Stairs(Facing),
+  /// Two diagonal planes crossing each other, visible from both sides, such as for plants.
+  Cross,
+  /// A model loaded from a `.obj`, `.glb` or `.gltf` file, by path relative to the assets
+  /// directory. Rather than being part of the chunk's mesh, each of these blocks is
+  /// rendered by an entity of its own.
+  Model(String),
+}
+
+/// How a block's mesh is blended with whatever is behind it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub enum BlockTransparency {
+  Opaque,
+  /// Either fully opaque or fully transparent, decided per pixel using
+  /// an alpha test, such as for leaves. Doesn't require sorting.
+  Cutout,
+  /// Partially transparent, such as glass. Meshed separately so it can be
+  /// sorted and drawn after opaque geometry.
+  Translucent,
+}
+
+/// A quad making up part of a block's mesh, relative to the block's origin.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quad {
+  /// Corners of the quad, ordered counter-clockwise as seen from the front.
+  pub corners: [[f32; 3]; 4],
+  pub normal: [f32; 3],
+  /// Side of the block this quad lies on. The quad isn't
+  /// drawn if a neighbouring block's full face covers it.
+  pub cull_face: Option<Facing>,
+}
+
+static OFFSETS_PER_FACING: [[[usize; 3]; 4]; 6] = [
+  [[1, 1, 1], [1, 0, 1], [1, 0, 0], [1, 1, 0]], // +X
+  [[0, 1, 0], [0, 0, 0], [0, 0, 1], [0, 1, 1]], // -X
+  [[1, 1, 0], [0, 1, 0], [0, 1, 1], [1, 1, 1]], // +Y
+  [[1, 0, 1], [0, 0, 1], [0, 0, 0], [1, 0, 0]], // -Y
+  [[0, 1, 1], [0, 0, 1], [1, 0, 1], [1, 1, 1]], // +Z
+  [[1, 1, 0], [1, 0, 0], [0, 0, 0], [0, 1, 0]], // -Z
+];
+
+/// Returns the specified face of the box spanning from `min` to `max`.
+pub fn box_face(min: [f32; 3], max: [f32; 3], face: Facing) -> Quad {
+  let offsets = &OFFSETS_PER_FACING[match face {
+    Facing::East => 0,
+    Facing::West => 1,
+    Facing::Up => 2,
+    Facing::Down => 3,
+    Facing::South => 4,
+    Facing::North => 5,
+  }];
+  let mut corners = [[0.0; 3]; 4];
+  for (corner, offset) in corners.iter_mut().zip(offsets) {
+    for axis in 0..3 {
+      corner[axis] = if offset[axis] > 0 {
+        max[axis]
+      } else {
+        min[axis]
+      };
+    }
+  }
+  let (fx, fy, fz) = face.into();
+  let (axis, positive) = match (fx, fy, fz) {
+    (x, 0, 0) => (0, x > 0),
+    (0, y, 0) => (1, y > 0),
+    (_, _, z) => (2, z > 0),
+  };
+  let on_border = if positive {
+    max[axis] >= 1.0
+  } else {
+    min[axis] <= 0.0
+  };
+  Quad {
+    corners,
+    normal: [fx as f32, fy as f32, fz as f32],
+    cull_face: if on_border { Some(face) } else { None },
+  }
+}
+
+/// Adds the faces of the box spanning from `min` to `max`, except for the ones in `hidden`.
+fn push_box(quads: &mut Vec<Quad>, min: [f32; 3], max: [f32; 3], hidden: &[Facing]) {
+  for face in Facing::iter_all().filter(|face| !hidden.contains(face)) {
+    quads.push(box_face(min, max, face));
+  }
+}
+
+/// Returns the box filling the half of a block towards the specified horizontal direction,
+/// spanning vertically from `bottom` to `top`.
+fn half_towards(facing: Facing, (bottom, top): (f32, f32)) -> ([f32; 3], [f32; 3]) {
+  let (fx, _, fz) = facing.into();
+  let (mut min, mut max) = ([0.0, bottom, 0.0], [1.0, top, 1.0]);
+  let axis = if fx != 0 { 0 } else { 2 };
+  if fx + fz > 0 {
+    min[axis] = 0.5;
+  } else {
+    max[axis] = 0.5;
+  }
+  (min, max)
+}
+
+/// Adds a quad along with a copy facing the opposite way, so it's visible from both sides.
+fn push_double_sided(quads: &mut Vec<Quad>, corners: [[f32; 3]; 4], normal: [f32; 3]) {
+  let [a, b, c, d] = corners;
+  let [x, y, z] = normal;
+  quads.push(Quad {
+    corners,
+    normal,
+    cull_face: None,
+  });
+  quads.push(Quad {
+    corners: [d, c, b, a],
+    normal: [-x, -y, -z],
+    cull_face: None,
+  });
+}
+
+impl BlockShape {
+  /// Returns the quads this shape is meshed with. Models aren't part of
+  /// the chunk's mesh, so they return no quads, just like empty blocks.
+  pub fn quads(&self) -> Vec<Quad> {
+    let mut quads = Vec::new();
+    match self {
+      BlockShape::Empty | BlockShape::Model(_) => {}
+      BlockShape::Cube => push_box(&mut quads, [0.0; 3], [1.0; 3], &[]),
+      BlockShape::Slab => push_box(&mut quads, [0.0; 3], [1.0, 0.5, 1.0], &[]),
+      BlockShape::Stairs(facing) => {
+        let back = *facing;
+        let front = facing.opposite();
+        let (min, max) = half_towards(back, (0.0, 1.0));
+        push_box(&mut quads, min, max, &[front]);
+        // Only the upper half of the back's front face is exposed, right above the step.
+        let (min, max) = half_towards(back, (0.5, 1.0));
+        quads.push(box_face(min, max, front));
+        let (min, max) = half_towards(front, (0.0, 0.5));
+        push_box(&mut quads, min, max, &[back]);
+      }
+      BlockShape::Cross => {
+        let n = std::f32::consts::FRAC_1_SQRT_2;
+        let corners = [
+          [0.0, 1.0, 0.0],
+          [0.0, 0.0, 0.0],
+          [1.0, 0.0, 1.0],
+          [1.0, 1.0, 1.0],
+        ];
+        push_double_sided(&mut quads, corners, [-n, 0.0, n]);
+        let corners = [
+          [0.0, 1.0, 1.0],
+          [0.0, 0.0, 1.0],
+          [1.0, 0.0, 0.0],
+          [1.0, 1.0, 0.0],
+        ];
+        push_double_sided(&mut quads, corners, [n, 0.0, n]);
+      }
+    }
+    quads
+  }
+
+  /// Returns whether the specified face of this shape covers the whole side of the block.
+  pub fn is_full(&self, face: Facing) -> bool {
+    match self {
+      BlockShape::Cube => true,
+      BlockShape::Slab => face == Facing::Down,
+      BlockShape::Stairs(facing) => face == Facing::Down || face == *facing,
+      BlockShape::Empty | BlockShape::Cross | BlockShape::Model(_) => false,
+    }
+  }
+}
+
+/// Resource describing how each type of block is rendered.
+#[derive(Clone)]
+pub struct BlockShapes {
+  shapes: Vec<BlockShape>,
+  transparency: Vec<BlockTransparency>,
+  quads: Vec<Vec<Quad>>,
+}
+
+impl Default for BlockShapes {
+  /// By default, every block besides air and fluids is an opaque cube.
+  fn default() -> Self {
+    let mut shapes = BlockShapes {
+      shapes: vec![BlockShape::Cube; 256],
+      transparency: vec![BlockTransparency::Opaque; 256],
+      quads: vec![BlockShape::Cube.quads(); 256],
+    }
+    .with_shape(0, BlockShape::Empty, BlockTransparency::Opaque);
+    for fluid in Fluid::ALL.iter() {
+      shapes = shapes.with_shape(fluid.block(), BlockShape::Empty, BlockTransparency::Opaque);
+    }
+    shapes
+  }
+}
+
+impl BlockShapes {
+  pub fn with_shape(
+    mut self,
+    block: u8,
+    shape: BlockShape,
+    transparency: BlockTransparency,
+  ) -> Self {
+    self.quads[block as usize] = shape.quads();
+    self.shapes[block as usize] = shape;
+    self.transparency[block as usize] = transparency;
+    self
+  }
+
+  pub fn shape(&self, block: u8) -> &BlockShape {
+    &self.shapes[block as usize]
+  }
+
+  pub fn transparency(&self, block: u8) -> BlockTransparency {
+    self.transparency[block as usize]
+  }
+
+  /// Returns the quads the specified block is meshed with.
+  pub fn quads(&self, block: u8) -> &[Quad] {
+    &self.quads[block as usize]
+  }
+
+  /// Returns whether the specified face of a block is full and opaque,
+  /// hiding anything behind it from view.
+  pub fn is_opaque_face(&self, block: u8, face: Facing) -> bool {
+    self.transparency(block) == BlockTransparency::Opaque && self.shape(block).is_full(face)
+  }
+
+  /// Returns whether a quad of `block` on the specified face is hidden by `neighbour`.
+  /// Faces between two blocks of the same type, such as glass panes, are hidden as well.
+  pub fn is_hidden(&self, block: u8, neighbour: u8, face: Facing) -> bool {
+    let opposite = face.opposite();
+    self.is_opaque_face(neighbour, opposite)
+      || (block == neighbour && self.shape(neighbour).is_full(opposite))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+  }
+
+  fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+      a[1] * b[2] - a[2] * b[1],
+      a[2] * b[0] - a[0] * b[2],
+      a[0] * b[1] - a[1] * b[0],
+    ]
+  }
+
+  fn all_shapes() -> Vec<BlockShape> {
+    let mut shapes = vec![BlockShape::Cube, BlockShape::Slab, BlockShape::Cross];
+    shapes.extend(Facing::iter_horizontal().map(BlockShape::Stairs));
+    shapes
+  }
+
+  #[test]
+  fn quads_wind_towards_their_normal() {
+    for shape in all_shapes() {
+      for quad in shape.quads() {
+        let [a, b, _, d] = quad.corners;
+        let winding = cross(sub(b, a), sub(d, a));
+        let dot: f32 = (0..3).map(|i| winding[i] * quad.normal[i]).sum();
+        assert!(
+          dot > 0.0,
+          "{:?} has a quad facing away from its normal",
+          shape
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn quads_stay_inside_block() {
+    for shape in all_shapes() {
+      for quad in shape.quads() {
+        for corner in quad.corners.iter() {
+          assert!(corner.iter().all(|v| *v >= 0.0 && *v <= 1.0), "{:?}", shape);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn only_full_faces_hide_neighbours() {
+    let shapes = BlockShapes::default()
+      .with_shape(4, BlockShape::Cube, BlockTransparency::Translucent)
+      .with_shape(5, BlockShape::Slab, BlockTransparency::Opaque);
+    // A cube next to a solid cube is hidden, but not next to air.
+    assert!(shapes.is_hidden(1, 1, Facing::East));
+    assert!(!shapes.is_hidden(1, 0, Facing::East));
+    // Glass doesn't hide what's behind it, unless it's more glass.
+    assert!(!shapes.is_hidden(1, 4, Facing::East));
+    assert!(shapes.is_hidden(4, 4, Facing::East));
+    // Only a slab's bottom face is full.
+    assert!(shapes.is_hidden(1, 5, Facing::Up));
+    assert!(!shapes.is_hidden(1, 5, Facing::Down));
+    assert!(!shapes.is_hidden(1, 5, Facing::East));
+  }
+
+  #[test]
+  fn slab_top_is_never_culled() {
+    let quads = BlockShape::Slab.quads();
+    let top = quads
+      .iter()
+      .find(|quad| quad.normal == [0.0, 1.0, 0.0])
+      .unwrap();
+    assert_eq!(top.cull_face, None);
+    let bottom = quads
+      .iter()
+      .find(|quad| quad.normal == [0.0, -1.0, 0.0])
+      .unwrap();
+    assert_eq!(bottom.cull_face, Some(Facing::Down));
+  }
+
+  #[test]
+  fn stairs_have_no_hidden_inner_faces() {
+    let quads = BlockShape::Stairs(Facing::East).quads();
+    // Both halves have five faces each, plus the part of the back half rising above the step.
+    assert_eq!(quads.len(), 11);
+    let facing_west = quads.iter().filter(|quad| quad.normal == [-1.0, 0.0, 0.0]);
+    assert_eq!(facing_west.count(), 2);
+  }
+
+  #[test]
+  fn default_shapes() {
+    let shapes = BlockShapes::default();
+    assert_eq!(shapes.shape(0), &BlockShape::Empty);
+    assert_eq!(shapes.shape(1), &BlockShape::Cube);
+    assert_eq!(shapes.shape(Fluid::Water.block()), &BlockShape::Empty);
+    assert_eq!(shapes.quads(1).len(), 6);
+    assert!(shapes.quads(0).is_empty());
+  }
+}
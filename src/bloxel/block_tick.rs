@@ -0,0 +1,338 @@
+use {
+  super::{
+    chunk::{ChunkLayout, ChunkLookup, ChunkPos, DefaultLayout, Index},
+    chunk_loader_positions,
+    net::NetworkClient,
+    BlockAccess, BlockPos, ChunkLoader, Level,
+  },
+  amethyst::{
+    core::{transform::Transform, Time},
+    ecs::prelude::*,
+  },
+  rand::Rng,
+  serde::{Deserialize, Serialize},
+  std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+  },
+};
+
+/// Time in seconds between block ticks.
+pub const BLOCK_TICK_SECONDS: f32 = 0.05;
+/// Number of blocks in each active chunk which receive a random tick every block tick.
+pub const RANDOM_TICKS_PER_CHUNK: usize = 3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockTickKind {
+  /// The tick was requested using `BlockTickScheduler` or `BlockTickContext::schedule`.
+  Scheduled,
+  /// The block was picked at random, such as for plants growing over time.
+  Random,
+}
+
+/// Component holding the ticks scheduled for blocks in a chunk, keyed by the level's tick they are
+/// due at. Since it's part of the chunk, it can be saved and loaded along with the chunk's blocks.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ScheduledTicks(BTreeMap<u64, Vec<BlockPos>>);
+
+impl Component for ScheduledTicks {
+  type Storage = DenseVecStorage<Self>;
+}
+
+impl ScheduledTicks {
+  /// Schedules a tick for the block at the specified position. Returns
+  /// `false` if that same tick had already been scheduled previously.
+  pub fn schedule(&mut self, pos: BlockPos, tick: u64) -> bool {
+    let positions = self.0.entry(tick).or_default();
+    if positions.contains(&pos) {
+      false
+    } else {
+      positions.push(pos);
+      true
+    }
+  }
+
+  /// Returns whether any tick is scheduled for the block at the specified position.
+  pub fn is_scheduled(&self, pos: BlockPos) -> bool {
+    self.0.values().any(|positions| positions.contains(&pos))
+  }
+
+  /// Removes and returns all scheduled ticks due at or before the specified tick, in order.
+  pub fn take_due(&mut self, tick: u64) -> Vec<(u64, BlockPos)> {
+    let later = self.0.split_off(&(tick + 1));
+    let due = std::mem::replace(&mut self.0, later);
+    due
+      .into_iter()
+      .flat_map(|(tick, positions)| positions.into_iter().map(move |pos| (tick, pos)))
+      .collect()
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.values().map(Vec::len).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+/// Adds a scheduled tick to the `ScheduledTicks` of the specified chunk, creating it if necessary.
+fn schedule_in(
+  ticks: &mut WriteStorage<'_, ScheduledTicks>,
+  chunk: Entity,
+  pos: BlockPos,
+  tick: u64,
+) -> bool {
+  match ticks.entry(chunk) {
+    Ok(entry) => entry.or_insert_with(Default::default).schedule(pos, tick),
+    Err(_) => false,
+  }
+}
+
+/// System data which allows scheduling ticks for blocks, which `BlockTickSystem` passes to
+/// the `BlockTickHandlers` registered for the block's type once the delay is up.
+#[derive(SystemData)]
+pub struct BlockTickScheduler<'a, L: ChunkLayout = DefaultLayout> {
+  levels: ReadStorage<'a, Level>,
+  lookups: ReadStorage<'a, ChunkLookup>,
+  ticks: WriteStorage<'a, ScheduledTicks>,
+  layout: PhantomData<L>,
+}
+
+impl<'a, L: ChunkLayout> BlockTickScheduler<'a, L> {
+  /// Schedules a tick for the block at the specified position in `delay` block ticks. Returns
+  /// `false` if the chunk containing the block doesn't exist or the tick was already scheduled.
+  pub fn schedule(&mut self, level: Entity, pos: BlockPos, delay: u64) -> bool {
+    let tick = match self.levels.get(level) {
+      Some(level) => level.tick + delay.max(1),
+      None => return false,
+    };
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    match self
+      .lookups
+      .get(level)
+      .and_then(|lookup| lookup.get(chunk_pos))
+    {
+      Some(chunk) => schedule_in(&mut self.ticks, chunk, pos, tick),
+      None => false,
+    }
+  }
+
+  /// Returns whether any tick is scheduled for the block at the specified position.
+  pub fn is_scheduled(&self, level: Entity, pos: BlockPos) -> bool {
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    self
+      .lookups
+      .get(level)
+      .and_then(|lookup| lookup.get(chunk_pos))
+      .and_then(|chunk| self.ticks.get(chunk))
+      .map_or(false, |ticks| ticks.is_scheduled(pos))
+  }
+}
+
+/// Passed to block tick handlers, allowing them to modify the level they're in.
+pub struct BlockTickContext<'s, 'a, L: ChunkLayout = DefaultLayout> {
+  pub level: Entity,
+  /// The level's current block tick.
+  pub tick: u64,
+  pub blocks: &'s mut BlockAccess<'a, u8, L>,
+  ticks: &'s mut WriteStorage<'a, ScheduledTicks>,
+}
+
+impl<'s, 'a, L: ChunkLayout> BlockTickContext<'s, 'a, L> {
+  /// Schedules a tick for the block at the specified position in `delay` block ticks.
+  pub fn schedule(&mut self, pos: BlockPos, delay: u64) -> bool {
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    match self.blocks.chunk(self.level, chunk_pos) {
+      Some(chunk) => schedule_in(self.ticks, chunk, pos, self.tick + delay.max(1)),
+      None => false,
+    }
+  }
+}
+
+pub type BlockTickHandler<L = DefaultLayout> =
+  Box<dyn Fn(&mut BlockTickContext<'_, '_, L>, BlockPos, BlockTickKind) + Send + Sync>;
+
+/// Resource holding the handlers called by `BlockTickSystem` when blocks are ticked, by block type.
+///
+/// # Examples
+///
+/// ```
+/// # use amethyst::ecs::prelude::*;
+/// # use gaemstone::bloxel::{BlockTickHandlers, Facing};
+/// # const GRASS: u8 = 2;
+/// # const DIRT: u8 = 3;
+/// # let mut world = World::new();
+/// // Grass slowly turns into dirt when there's something on top of it.
+/// let handlers: BlockTickHandlers =
+///   BlockTickHandlers::default().with_handler(GRASS, |context, pos, _| {
+///     if context.blocks.get(context.level, pos + Facing::Up) != 0 {
+///       context.blocks.set(context.level, pos, DIRT);
+///     }
+///   });
+/// world.insert(handlers);
+/// ```
+pub struct BlockTickHandlers<L: ChunkLayout = DefaultLayout>(HashMap<u8, BlockTickHandler<L>>);
+
+impl<L: ChunkLayout> Default for BlockTickHandlers<L> {
+  fn default() -> Self {
+    BlockTickHandlers(HashMap::new())
+  }
+}
+
+impl<L: ChunkLayout> BlockTickHandlers<L> {
+  pub fn with_handler<F>(mut self, block: u8, handler: F) -> Self
+  where
+    F: Fn(&mut BlockTickContext<'_, '_, L>, BlockPos, BlockTickKind) + Send + Sync + 'static,
+  {
+    self.0.insert(block, Box::new(handler));
+    self
+  }
+
+  pub fn get(&self, block: u8) -> Option<&BlockTickHandler<L>> {
+    self.0.get(&block)
+  }
+}
+
+/// Advances the tick of every `Level` at a fixed rate, calling the `BlockTickHandlers` of blocks
+/// whose scheduled ticks are due, as well as of a few random blocks in each chunk. Only chunks
+/// near a `ChunkLoader` are ticked. Scheduled ticks of other chunks are delayed until they are.
+/// Blocks aren't ticked while a `NetworkClient` exists, as the server ticks them instead.
+pub struct BlockTickSystem<L: ChunkLayout = DefaultLayout> {
+  /// Time accumulated towards the next block tick, in seconds.
+  elapsed: f32,
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> Default for BlockTickSystem<L> {
+  fn default() -> Self {
+    Self {
+      elapsed: 0.0,
+      layout: PhantomData,
+    }
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for BlockTickSystem<L> {
+  type SystemData = (
+    Entities<'a>,
+    Read<'a, Time>,
+    Read<'a, BlockTickHandlers<L>>,
+    ReadStorage<'a, ChunkLoader>,
+    ReadStorage<'a, Transform>,
+    WriteStorage<'a, Level>,
+    WriteStorage<'a, ScheduledTicks>,
+    BlockAccess<'a, u8, L>,
+    Option<Read<'a, NetworkClient>>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      entities,
+      time,
+      handlers,
+      loaders,
+      transforms,
+      mut levels,
+      mut ticks,
+      mut blocks,
+      client,
+    ): Self::SystemData,
+  ) {
+    if client.is_some() {
+      return;
+    }
+    self.elapsed += time.delta_seconds();
+    if self.elapsed < BLOCK_TICK_SECONDS {
+      return;
+    }
+    // Skip ticks rather than catching up, which could make slow frames even slower.
+    self.elapsed = self.elapsed.min(BLOCK_TICK_SECONDS * 2.0) - BLOCK_TICK_SECONDS;
+
+    let loaders = chunk_loader_positions(&loaders, &transforms);
+    let mut rng = rand::thread_rng();
+
+    for (level, level_data) in (&entities, &mut levels).join() {
+      level_data.tick += 1;
+      let tick = level_data.tick;
+
+      let mut due = Vec::new();
+      for (chunk_pos, chunk) in blocks.chunks(level) {
+        let is_active = loaders
+          .iter()
+          .any(|(loader, center)| loader.contains::<L>(*center, chunk_pos));
+        if !is_active {
+          continue;
+        }
+        if let Some(scheduled) = ticks.get_mut(chunk) {
+          due.extend(
+            scheduled
+              .take_due(tick)
+              .into_iter()
+              .map(|(_, pos)| (pos, BlockTickKind::Scheduled)),
+          );
+        }
+        for _ in 0..RANDOM_TICKS_PER_CHUNK {
+          let index = Index::<L>::from_raw(rng.gen_range(0, L::SIZE as u32)).unwrap();
+          due.push(((chunk_pos, index).into(), BlockTickKind::Random));
+        }
+      }
+
+      let mut context = BlockTickContext {
+        level,
+        tick,
+        blocks: &mut blocks,
+        ticks: &mut ticks,
+      };
+      for (pos, kind) in due {
+        let block = context.blocks.get(level, pos);
+        if let Some(handler) = handlers.get(block) {
+          handler(&mut context, pos, kind);
+        }
+      }
+    }
+    // Chunks without any scheduled ticks left don't need to hold onto the component.
+    let empty = (&entities, &ticks)
+      .join()
+      .filter(|(_, scheduled)| scheduled.is_empty())
+      .map(|(entity, _)| entity)
+      .collect::<Vec<_>>();
+    for entity in empty {
+      ticks.remove(entity);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scheduling_same_tick_twice_is_ignored() {
+    let mut ticks = ScheduledTicks::default();
+    assert!(ticks.schedule(BlockPos::new(1, 2, 3), 10));
+    assert!(!ticks.schedule(BlockPos::new(1, 2, 3), 10));
+    assert!(ticks.schedule(BlockPos::new(1, 2, 3), 11));
+    assert_eq!(ticks.len(), 2);
+    assert!(ticks.is_scheduled(BlockPos::new(1, 2, 3)));
+    assert!(!ticks.is_scheduled(BlockPos::new(0, 0, 0)));
+  }
+
+  #[test]
+  fn take_due_returns_ticks_in_order() {
+    let mut ticks = ScheduledTicks::default();
+    ticks.schedule(BlockPos::new(0, 0, 3), 30);
+    ticks.schedule(BlockPos::new(0, 0, 1), 10);
+    ticks.schedule(BlockPos::new(0, 0, 2), 20);
+
+    assert_eq!(ticks.take_due(5), vec![]);
+    assert_eq!(
+      ticks.take_due(20),
+      vec![(10, BlockPos::new(0, 0, 1)), (20, BlockPos::new(0, 0, 2))]
+    );
+    assert_eq!(ticks.len(), 1);
+    assert_eq!(ticks.take_due(100), vec![(30, BlockPos::new(0, 0, 3))]);
+    assert!(ticks.is_empty());
+  }
+}
@@ -0,0 +1,502 @@
+use {
+  super::{
+    super::BlockPos,
+    storage::{BlockData, ChunkStorage, PaletteStorageImpl, StorageImpl},
+    ChunkLayout, ChunkPos, DefaultLayout, Index,
+  },
+  amethyst::ecs::prelude::*,
+  serde::{de::DeserializeOwned, Deserialize, Serialize},
+  std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    hash::Hash,
+    marker::PhantomData,
+  },
+};
+
+/// A per-chunk data layer in serialized form, as used when saving chunks or sending them over the
+/// network. Each distinct value is only stored once in the palette, which blocks refer to by index.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct LayerData {
+  /// Distinct values of the layer, each serialized as RON.
+  pub palette: Vec<String>,
+  /// Index into `palette` for every block, by raw index. Empty if the palette has only one entry.
+  pub indices: Vec<u16>,
+}
+
+/// Number of distinct values up to which `LayerData::encode` searches its palette linearly.
+const LINEAR_SEARCH_LIMIT: usize = 16;
+
+impl LayerData {
+  /// Fails if the storage holds more distinct values than `u16` palette indices can refer to.
+  pub fn encode<T, L>(storage: &ChunkStorage<T, L>) -> Result<Self, LayerError>
+  where
+    T: BlockData + Hash + Serialize,
+    L: ChunkLayout,
+  {
+    let mut values = Vec::<T>::new();
+    let mut lookup = HashMap::<T, u16>::new();
+    let mut indices = Vec::with_capacity(L::SIZE);
+    for raw in 0..L::SIZE as u32 {
+      let value = storage.get(Index::from_raw(raw).unwrap());
+      // Palettes tend to be small, so a linear search is faster than hashing until they grow.
+      let found = if values.len() <= LINEAR_SEARCH_LIMIT {
+        values.iter().position(|v| *v == value).map(|i| i as u16)
+      } else {
+        lookup.get(&value).copied()
+      };
+      let index = match found {
+        Some(index) => index,
+        None if values.len() >= u16::MAX as usize => {
+          return Err(LayerError::PaletteTooLarge);
+        }
+        None => {
+          let index = values.len() as u16;
+          values.push(value);
+          lookup.insert(value, index);
+          index
+        }
+      };
+      indices.push(index);
+    }
+    if values.len() == 1 {
+      indices.clear();
+    }
+    let palette = values
+      .iter()
+      .map(ron::ser::to_string)
+      .collect::<Result<_, _>>()
+      .map_err(LayerError::Serialize)?;
+    Ok(LayerData { palette, indices })
+  }
+
+  pub fn decode<T, L>(&self) -> Result<ChunkStorage<T, L>, LayerError>
+  where
+    T: BlockData + DeserializeOwned,
+    L: ChunkLayout,
+  {
+    let values = (self.palette.iter())
+      .map(|value| ron::de::from_str(value))
+      .collect::<Result<Vec<T>, _>>()
+      .map_err(LayerError::Deserialize)?;
+    let mut storage = PaletteStorageImpl::<T, L>::new();
+    if self.indices.is_empty() && values.len() == 1 {
+      if values[0] != T::default() {
+        for raw in 0..L::SIZE as u32 {
+          storage.set(Index::from_raw(raw).unwrap(), values[0]);
+        }
+      }
+    } else if self.indices.len() == L::SIZE {
+      for (raw, index) in self.indices.iter().enumerate() {
+        let value = values
+          .get(*index as usize)
+          .ok_or(LayerError::InvalidIndex(*index))?;
+        storage.set(Index::from_raw(raw as u32).unwrap(), *value);
+      }
+    } else {
+      return Err(LayerError::WrongSize(self.indices.len()));
+    }
+    Ok(ChunkStorage::new(storage))
+  }
+}
+
+#[derive(Debug)]
+pub enum LayerError {
+  /// The layer doesn't have the number of entries the chunk layout requires.
+  WrongSize(usize),
+  /// A block refers to an index outside of the palette.
+  InvalidIndex(u16),
+  /// The layer has more distinct values than fit into a palette, which is `u16::MAX`.
+  PaletteTooLarge,
+  Serialize(ron::ser::Error),
+  Deserialize(ron::de::Error),
+}
+
+impl Error for LayerError {}
+
+impl fmt::Display for LayerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LayerError::WrongSize(size) => write!(f, "Layer has wrong size {}", size),
+      LayerError::InvalidIndex(index) => write!(f, "Palette index {} is out of bounds", index),
+      LayerError::PaletteTooLarge => write!(f, "Layer has too many distinct values"),
+      LayerError::Serialize(err) => write!(f, "Couldn't serialize value: {}", err),
+      LayerError::Deserialize(err) => write!(f, "Couldn't deserialize value: {}", err),
+    }
+  }
+}
+
+/// A type-erased per-chunk data layer, stored as `ChunkStorage<T>` on chunk entities.
+/// Layers are registered through the `ChunkLayers` resource.
+pub trait ChunkLayer<L: ChunkLayout = DefaultLayout>: Send + Sync {
+  /// Unique name of the layer, used to identify it in saved and sent chunks.
+  fn name(&self) -> &'static str;
+
+  /// Registers the layer's storage with the world.
+  fn register(&self, world: &mut World);
+
+  /// Lazily fills in the layer of a freshly generated chunk, based on its terrain. Values which
+  /// were already set, such as through `BlockAccess`, take precedence over generated ones.
+  fn generate(
+    &self,
+    lazy: &LazyUpdate,
+    chunk: Entity,
+    seed: u32,
+    pos: ChunkPos,
+    blocks: &dyn StorageImpl<u8, L>,
+  );
+
+  /// Serializes the layer of the specified chunk, or returns `None` if the chunk doesn't have it,
+  /// in which case every block has the layer's default value.
+  fn save(&self, world: &World, chunk: Entity) -> Result<Option<LayerData>, LayerError>;
+
+  /// Deserializes a saved layer without changing anything yet. The returned function replaces
+  /// the layer of the chunk it's called with, removing it if `data` is `None`.
+  fn load(&self, data: Option<&LayerData>) -> Result<LoadLayer, LayerError>;
+}
+
+/// Applies a layer deserialized by `ChunkLayer::load` to a chunk.
+pub type LoadLayer = Box<dyn FnOnce(&World, Entity)>;
+
+pub type LayerGenerator<T> = Box<dyn Fn(u32, BlockPos, u8) -> T + Send + Sync>;
+
+/// Component for chunks which haven't been generated yet, holding the positions at which their
+/// layer of type `T` was set explicitly, such as through `BlockAccess`. Generation leaves these
+/// positions alone, even where they were set to the default value, then removes the component.
+pub struct UngeneratedEdits<T, L: ChunkLayout = DefaultLayout>(HashSet<u32>, PhantomData<(T, L)>);
+
+impl<T, L: ChunkLayout> Default for UngeneratedEdits<T, L> {
+  fn default() -> Self {
+    UngeneratedEdits(HashSet::new(), PhantomData)
+  }
+}
+
+impl<T, L: ChunkLayout> UngeneratedEdits<T, L> {
+  pub fn insert(&mut self, index: Index<L>) {
+    self.0.insert(index.raw_index());
+  }
+
+  pub fn contains(&self, index: Index<L>) -> bool {
+    self.0.contains(&index.raw_index())
+  }
+}
+
+impl<T: BlockData, L: ChunkLayout> Component for UngeneratedEdits<T, L> {
+  type Storage = HashMapStorage<Self>;
+}
+
+struct TypedLayer<T: BlockData, L: ChunkLayout> {
+  name: &'static str,
+  generator: Option<LayerGenerator<T>>,
+  layout: PhantomData<L>,
+}
+
+impl<T, L> ChunkLayer<L> for TypedLayer<T, L>
+where
+  T: BlockData + Hash + Serialize + DeserializeOwned,
+  L: ChunkLayout,
+{
+  fn name(&self) -> &'static str {
+    self.name
+  }
+
+  fn register(&self, world: &mut World) {
+    world.register::<ChunkStorage<T, L>>();
+    world.register::<UngeneratedEdits<T, L>>();
+  }
+
+  fn generate(
+    &self,
+    lazy: &LazyUpdate,
+    chunk: Entity,
+    seed: u32,
+    pos: ChunkPos,
+    blocks: &dyn StorageImpl<u8, L>,
+  ) {
+    let generator = match &self.generator {
+      Some(generator) => generator,
+      None => {
+        lazy.remove::<UngeneratedEdits<T, L>>(chunk);
+        return;
+      }
+    };
+    let mut storage = PaletteStorageImpl::<T, L>::new();
+    for raw in 0..L::SIZE as u32 {
+      let index = Index::<L>::from_raw(raw).unwrap();
+      let value = generator(seed, (pos, index).into(), blocks.get(index));
+      if value != T::default() {
+        storage.set(index, value);
+      }
+    }
+
+    let generated = ChunkStorage::new(storage);
+    lazy.exec_mut(move |world| {
+      let mut storages = world.write_storage::<ChunkStorage<T, L>>();
+      let edits = (world
+        .write_storage::<UngeneratedEdits<T, L>>()
+        .remove(chunk))
+      .unwrap_or_default();
+      match storages.get_mut(chunk) {
+        Some(existing) => {
+          for raw in 0..L::SIZE as u32 {
+            let index = Index::<L>::from_raw(raw).unwrap();
+            if !edits.contains(index) {
+              existing.set(index, generated.get(index));
+            }
+          }
+        }
+        None => {
+          // The chunk may have been deleted in the meantime.
+          let _ = storages.insert(chunk, generated);
+        }
+      }
+    });
+  }
+
+  fn save(&self, world: &World, chunk: Entity) -> Result<Option<LayerData>, LayerError> {
+    let storages = world.read_storage::<ChunkStorage<T, L>>();
+    storages.get(chunk).map(LayerData::encode).transpose()
+  }
+
+  fn load(&self, data: Option<&LayerData>) -> Result<LoadLayer, LayerError> {
+    let storage = data.map(LayerData::decode::<T, L>).transpose()?;
+    Ok(Box::new(move |world, chunk| {
+      let mut storages = world.write_storage::<ChunkStorage<T, L>>();
+      match storage {
+        Some(storage) => {
+          let _ = storages.insert(chunk, storage);
+        }
+        None => {
+          storages.remove(chunk);
+        }
+      }
+    }))
+  }
+}
+
+/// Resource listing the data layers stored on chunks, which generation, persistence and
+/// networking take care of. Blocks that are missing a layer have its `Default` value.
+/// The blocks themselves are always registered, as `"blocks"`.
+///
+/// # Examples
+///
+/// ```
+/// # use amethyst::ecs::prelude::*;
+/// # use gaemstone::bloxel::{chunk::{ChunkLayers, DefaultLayout}, FluidLevel};
+/// # use serde::{Deserialize, Serialize};
+/// # const GRASS: u8 = 2;
+/// # let mut world = World::new();
+/// #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Deserialize, Serialize)]
+/// struct Tint(u8);
+///
+/// let layers = ChunkLayers::<DefaultLayout>::default()
+///   .with::<FluidLevel>("fluid_levels")
+///   .with_generator("tint", |_seed, pos, block| {
+///     if block == GRASS { Tint(pos.y as u8) } else { Tint::default() }
+///   });
+/// layers.register(&mut world);
+/// world.insert(layers);
+/// ```
+pub struct ChunkLayers<L: ChunkLayout = DefaultLayout>(Vec<Box<dyn ChunkLayer<L>>>);
+
+impl<L: ChunkLayout> Default for ChunkLayers<L> {
+  fn default() -> Self {
+    ChunkLayers(Vec::new()).with::<u8>("blocks")
+  }
+}
+
+impl<L: ChunkLayout> ChunkLayers<L> {
+  /// Adds a layer which is left empty when chunks are generated.
+  pub fn with<T>(self, name: &'static str) -> Self
+  where
+    T: BlockData + Hash + Serialize + DeserializeOwned,
+  {
+    self.with_layer(TypedLayer::<T, L> {
+      name,
+      generator: None,
+      layout: PhantomData,
+    })
+  }
+
+  /// Adds a layer whose values are generated along with the terrain, from the level's seed,
+  /// the position of each block and the block itself.
+  pub fn with_generator<T, F>(self, name: &'static str, generator: F) -> Self
+  where
+    T: BlockData + Hash + Serialize + DeserializeOwned,
+    F: Fn(u32, BlockPos, u8) -> T + Send + Sync + 'static,
+  {
+    self.with_layer(TypedLayer::<T, L> {
+      name,
+      generator: Some(Box::new(generator)),
+      layout: PhantomData,
+    })
+  }
+
+  pub fn with_layer<C: ChunkLayer<L> + 'static>(mut self, layer: C) -> Self {
+    assert!(
+      self.get(layer.name()).is_none(),
+      "Chunk layer '{}' is already registered",
+      layer.name()
+    );
+    self.0.push(Box::new(layer));
+    self
+  }
+
+  pub fn get(&self, name: &str) -> Option<&dyn ChunkLayer<L>> {
+    self.iter().find(|layer| layer.name() == name)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &dyn ChunkLayer<L>> + '_ {
+    self.0.iter().map(|layer| layer.as_ref())
+  }
+
+  /// Registers the storages of all layers with the world.
+  pub fn register(&self, world: &mut World) {
+    for layer in self.iter() {
+      layer.register(world);
+    }
+  }
+}
+
+/// Event sent through `EventChannel<LayerChange>` by systems changing a layer other than the
+/// blocks themselves, which are covered by `BlockChange`. Sent at most once per block changed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerChange {
+  pub level: Entity,
+  pub pos: ChunkPos,
+  /// Name of the layer, as registered with `ChunkLayers`.
+  pub layer: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{chunk::LinearLayout64, create_level, BlockAccess, BlockView, Level},
+    amethyst::{core::transform::Transform, renderer::visibility::BoundingSphere},
+  };
+
+  #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Deserialize, Serialize)]
+  struct Tint(u8, u8, u8);
+
+  #[test]
+  fn layer_data_round_trip() {
+    let mut storage = ChunkStorage::<Tint>::new(PaletteStorageImpl::new());
+    storage.set(Index::new(1, 2, 3).unwrap(), Tint(255, 0, 0));
+    storage.set(Index::new(15, 15, 15).unwrap(), Tint(0, 0, 255));
+
+    let data = LayerData::encode(&storage).unwrap();
+    assert_eq!(data.palette.len(), 3);
+    assert_eq!(data.indices.len(), DefaultLayout::SIZE);
+
+    let decoded = data.decode::<Tint, DefaultLayout>().unwrap();
+    for raw in 0..DefaultLayout::SIZE as u32 {
+      let index = Index::from_raw(raw).unwrap();
+      assert_eq!(decoded.get(index), storage.get(index));
+    }
+  }
+
+  #[test]
+  fn uniform_layer_has_no_indices() {
+    let mut storage = ChunkStorage::<u8>::new(PaletteStorageImpl::new());
+    for raw in 0..DefaultLayout::SIZE as u32 {
+      storage.set(Index::from_raw(raw).unwrap(), 7);
+    }
+    let data = LayerData::encode(&storage).unwrap();
+    assert_eq!(data.palette, vec!["7".to_string()]);
+    assert!(data.indices.is_empty());
+    let decoded = data.decode::<u8, DefaultLayout>().unwrap();
+    assert_eq!(decoded.get(Index::new(4, 5, 6).unwrap()), 7);
+  }
+
+  #[test]
+  fn invalid_layer_data_is_rejected() {
+    let data = LayerData {
+      palette: vec!["0".to_string(), "1".to_string()],
+      indices: vec![0; 10],
+    };
+    assert!(matches!(
+      data.decode::<u8, DefaultLayout>(),
+      Err(LayerError::WrongSize(10))
+    ));
+    let data = LayerData {
+      palette: vec!["0".to_string()],
+      indices: vec![2; DefaultLayout::SIZE],
+    };
+    assert!(matches!(
+      data.decode::<u8, DefaultLayout>(),
+      Err(LayerError::InvalidIndex(2))
+    ));
+  }
+
+  /// Storage holding a distinct value for every block, its raw index.
+  struct DistinctStorage;
+
+  impl StorageImpl<u32, LinearLayout64> for DistinctStorage {
+    fn get(&self, index: Index<LinearLayout64>) -> u32 {
+      index.raw_index()
+    }
+
+    fn set(&mut self, _index: Index<LinearLayout64>, _value: u32) {}
+
+    fn heap_size(&self) -> usize {
+      0
+    }
+  }
+
+  #[test]
+  fn layers_with_too_many_values_are_rejected() {
+    let storage = ChunkStorage::new(DistinctStorage);
+    assert!(matches!(
+      LayerData::encode(&storage),
+      Err(LayerError::PaletteTooLarge)
+    ));
+  }
+
+  #[test]
+  #[should_panic]
+  fn duplicate_layer_names_panic() {
+    ChunkLayers::<DefaultLayout>::default().with::<u8>("blocks");
+  }
+
+  #[test]
+  fn generation_keeps_explicitly_set_values() {
+    let mut world = World::new();
+    BlockAccess::<Tint>::setup(&mut world);
+    world.register::<Level>();
+    // Created chunks get these lazily.
+    world.register::<Transform>();
+    world.register::<BoundingSphere>();
+    let layers =
+      ChunkLayers::<DefaultLayout>::default().with_generator("tint", |_, _, _| Tint(1, 1, 1));
+    layers.register(&mut world);
+    let level = create_level(world.create_entity(), Level::new(0));
+
+    let (cleared, tinted) = (BlockPos::new(1, 2, 3), BlockPos::new(4, 5, 6));
+    let chunk = world.exec(|mut tints: BlockAccess<'_, Tint>| {
+      tints.set(level, cleared, Tint::default());
+      tints.set(level, tinted, Tint(0, 9, 0));
+      tints.chunk(level, ChunkPos::new(0, 0, 0)).unwrap()
+    });
+    let blocks = PaletteStorageImpl::<u8, DefaultLayout>::new();
+    let layer = layers.get("tint").unwrap();
+    layer.generate(
+      &world.read_resource(),
+      chunk,
+      0,
+      ChunkPos::new(0, 0, 0),
+      &blocks,
+    );
+    world.maintain();
+
+    world.exec(|tints: BlockView<'_, Tint>| {
+      assert_eq!(tints.get(level, cleared), Tint::default());
+      assert_eq!(tints.get(level, tinted), Tint(0, 9, 0));
+      assert_eq!(tints.get(level, BlockPos::new(0, 0, 0)), Tint(1, 1, 1));
+    });
+    assert!(!world
+      .read_storage::<UngeneratedEdits<Tint>>()
+      .contains(chunk));
+  }
+}
@@ -0,0 +1,166 @@
+use crate::util::ZOrder;
+
+/// The layout used by chunks unless specified otherwise: 16×16×16 blocks, stored linearly.
+pub type DefaultLayout = LinearLayout16;
+
+/// Describes the size of chunks and how blocks relative to a chunk are laid out in its storage.
+/// Chunk related types such as `Index` and `ChunkStorage` are generic over their layout, which
+/// usually defaults to `DefaultLayout`.
+///
+/// Chunks are cubes with a length of `8`, `16`, `32` or `64` blocks. Blocks can either be laid
+/// out linearly (`x`, then `y`, then `z`) or following a [Z-order curve][ZOrder], which keeps
+/// blocks that are close to each other in space close together in memory as well.
+pub trait ChunkLayout: Send + Sync + 'static {
+  /// Number of bits required to represent a relative coordinate inside of a chunk.
+  const LENGTH_BITS: usize;
+  /// Number of blocks along each axis of a chunk.
+  const LENGTH: usize = 1 << Self::LENGTH_BITS;
+  /// Number of blocks in a chunk.
+  const SIZE: usize = 1 << (Self::LENGTH_BITS * 3);
+
+  /// Encodes relative coordinates into an index into chunk storage.
+  /// All of the coordinates must be in range `0..LENGTH`.
+  fn encode(x: i32, y: i32, z: i32) -> u32;
+
+  /// Decodes an index into chunk storage back into relative coordinates.
+  fn decode(index: u32) -> (i32, i32, i32);
+}
+
+macro_rules! linear_layout {
+  ($(#[$meta:meta])* $name:ident, $bits:expr) => {
+    $(#[$meta])*
+    #[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+    pub struct $name;
+
+    impl ChunkLayout for $name {
+      const LENGTH_BITS: usize = $bits;
+
+      #[inline]
+      fn encode(x: i32, y: i32, z: i32) -> u32 {
+        (x | (y << $bits) | (z << ($bits * 2))) as u32
+      }
+
+      #[inline]
+      fn decode(index: u32) -> (i32, i32, i32) {
+        const MASK: i32 = !(!0 << $bits);
+        let index = index as i32;
+        (index & MASK, (index >> $bits) & MASK, (index >> ($bits * 2)) & MASK)
+      }
+    }
+  };
+}
+
+macro_rules! z_order_layout {
+  ($(#[$meta:meta])* $name:ident, $bits:expr, $store:ty, $element:ty) => {
+    $(#[$meta])*
+    #[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+    pub struct $name;
+
+    impl ChunkLayout for $name {
+      const LENGTH_BITS: usize = $bits;
+
+      #[inline]
+      fn encode(x: i32, y: i32, z: i32) -> u32 {
+        // SAFETY: Relative coordinates always fit into the element type.
+        let order =
+          unsafe { ZOrder::<$store>::new_unchecked(x as $element, y as $element, z as $element) };
+        order.raw() as u32
+      }
+
+      #[inline]
+      fn decode(index: u32) -> (i32, i32, i32) {
+        let (x, y, z) = ZOrder::<$store>::from_raw(index as $store).into();
+        (x as i32, y as i32, z as i32)
+      }
+    }
+  };
+}
+
+linear_layout!(
+  /// Chunk layout with a length of 8 blocks, stored linearly.
+  LinearLayout8,
+  3
+);
+linear_layout!(
+  /// Chunk layout with a length of 16 blocks, stored linearly.
+  LinearLayout16,
+  4
+);
+linear_layout!(
+  /// Chunk layout with a length of 32 blocks, stored linearly.
+  LinearLayout32,
+  5
+);
+linear_layout!(
+  /// Chunk layout with a length of 64 blocks, stored linearly.
+  LinearLayout64,
+  6
+);
+
+z_order_layout!(
+  /// Chunk layout with a length of 8 blocks, stored in Z-order.
+  ZOrderLayout8,
+  3,
+  u16, u8
+);
+z_order_layout!(
+  /// Chunk layout with a length of 16 blocks, stored in Z-order.
+  ZOrderLayout16,
+  4,
+  u16, u8
+);
+z_order_layout!(
+  /// Chunk layout with a length of 32 blocks, stored in Z-order.
+  ZOrderLayout32,
+  5,
+  u16, u8
+);
+z_order_layout!(
+  /// Chunk layout with a length of 64 blocks, stored in Z-order.
+  ZOrderLayout64,
+  6,
+  u32, u16
+);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_bijective<L: ChunkLayout>() {
+    let mut seen = vec![false; L::SIZE];
+    for x in 0..L::LENGTH as i32 {
+      for y in 0..L::LENGTH as i32 {
+        for z in 0..L::LENGTH as i32 {
+          let index = L::encode(x, y, z);
+          assert!((index as usize) < L::SIZE);
+          assert!(!seen[index as usize], "{:?} encoded twice", (x, y, z));
+          seen[index as usize] = true;
+          assert_eq!(L::decode(index), (x, y, z));
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn layouts_are_bijective() {
+    assert_bijective::<LinearLayout8>();
+    assert_bijective::<LinearLayout16>();
+    assert_bijective::<LinearLayout32>();
+    assert_bijective::<LinearLayout64>();
+
+    assert_bijective::<ZOrderLayout8>();
+    assert_bijective::<ZOrderLayout16>();
+    assert_bijective::<ZOrderLayout32>();
+    assert_bijective::<ZOrderLayout64>();
+  }
+
+  #[test]
+  fn z_order_neighbours() {
+    // In Z-order, each 2×2×2 cube of blocks is stored contiguously.
+    assert_eq!(ZOrderLayout16::encode(0, 0, 0), 0);
+    assert_eq!(ZOrderLayout16::encode(1, 0, 0), 1);
+    assert_eq!(ZOrderLayout16::encode(0, 1, 0), 2);
+    assert_eq!(ZOrderLayout16::encode(1, 1, 1), 7);
+    assert_eq!(ZOrderLayout16::encode(2, 0, 0), 8);
+  }
+}
@@ -4,16 +4,47 @@ use {
   std::collections::HashMap,
 };
 
+/// Component on level entities (or anything else that contains chunks)
+/// which allows looking up chunk entities by their position.
 #[derive(Default)]
 pub struct ChunkLookup {
   entity_lookup: HashMap<ChunkPos, Entity>,
-  pos_lookup: HashMap<Index, ChunkPos>,
+}
+
+impl Component for ChunkLookup {
+  type Storage = HashMapStorage<Self>;
 }
 
 impl ChunkLookup {
   pub fn get(&self, pos: ChunkPos) -> Option<Entity> {
     self.entity_lookup.get(&pos).cloned()
   }
+
+  /// Registers a chunk entity at the specified position. Normally this is handled by
+  /// `ChunkLookupSystem`, but chunks created mid-frame need to be visible right away.
+  pub fn insert(&mut self, pos: ChunkPos, entity: Entity) {
+    self.entity_lookup.insert(pos, entity);
+  }
+
+  fn remove(&mut self, pos: ChunkPos, entity: Entity) {
+    // Only remove the entry if it still refers to the same chunk entity.
+    if self.entity_lookup.get(&pos) == Some(&entity) {
+      self.entity_lookup.remove(&pos);
+    }
+  }
+
+  /// Gets the number of chunks in this lookup.
+  pub fn len(&self) -> usize {
+    self.entity_lookup.len()
+  }
+
+  /// Iterates over all chunks in this lookup, and their positions.
+  pub fn iter(&self) -> impl Iterator<Item = (ChunkPos, Entity)> + '_ {
+    self
+      .entity_lookup
+      .iter()
+      .map(|(pos, entity)| (*pos, *entity))
+  }
 }
 
 #[derive(SystemDesc)]
@@ -21,47 +52,51 @@ impl ChunkLookup {
 pub struct ChunkLookupSystem {
   #[system_desc(flagged_storage_reader(Chunk))]
   reader: ReaderId<ComponentEvent>,
+  /// Chunk entities and their level and position by their entity index, so they
+  /// can be removed from their level's `ChunkLookup` once `Chunk` is gone.
+  #[system_desc(skip)]
+  chunks: HashMap<Index, (Entity, Chunk)>,
 }
 
 impl ChunkLookupSystem {
   pub fn new(reader: ReaderId<ComponentEvent>) -> Self {
-    Self { reader }
+    Self {
+      reader,
+      chunks: HashMap::new(),
+    }
   }
 }
 
 impl<'a> System<'a> for ChunkLookupSystem {
-  type SystemData = (Entities<'a>, Write<'a, ChunkLookup>, ReadStorage<'a, Chunk>);
+  type SystemData = (
+    Entities<'a>,
+    WriteStorage<'a, ChunkLookup>,
+    ReadStorage<'a, Chunk>,
+  );
 
-  fn run(&mut self, (entities, mut lookup, chunks): Self::SystemData) {
+  fn run(&mut self, (entities, mut lookups, chunks): Self::SystemData) {
     use ComponentEvent::*;
     for event in chunks.channel().read(&mut self.reader) {
-      match event {
-        Inserted(index) => {
-          let entity = entities.entity(*index);
-          if let Some(chunk) = chunks.get(entity) {
-            lookup.entity_lookup.insert(chunk.pos, entity);
-            lookup.pos_lookup.insert(*index, chunk.pos);
-          }
-        }
-        Modified(index) => {
-          // NOTE: Chunk should not be modified once added, but just in case..
-
-          if let Some(pos) = lookup.pos_lookup.remove(index) {
-            lookup.entity_lookup.remove(&pos);
-          }
+      let index = match event {
+        Inserted(index) | Modified(index) | Removed(index) => *index,
+      };
 
-          let entity = entities.entity(*index);
-          if let Some(chunk) = chunks.get(entity) {
-            lookup.entity_lookup.insert(chunk.pos, entity);
-            lookup.pos_lookup.insert(*index, chunk.pos);
-          }
+      // NOTE: Chunk should not be modified once added, but just in case..
+      if let Some((entity, previous)) = self.chunks.remove(&index) {
+        if let Some(lookup) = lookups.get_mut(previous.level) {
+          lookup.remove(previous.pos, entity);
         }
-        Removed(index) => {
-          if let Some(pos) = lookup.pos_lookup.remove(index) {
-            lookup.entity_lookup.remove(&pos);
+      }
+
+      if let Inserted(_) | Modified(_) = event {
+        let entity = entities.entity(index);
+        if let Some(chunk) = chunks.get(entity) {
+          if let Some(lookup) = lookups.get_mut(chunk.level) {
+            lookup.insert(chunk.pos, entity);
           }
+          self.chunks.insert(index, (entity, *chunk));
         }
-      };
+      }
     }
   }
 }
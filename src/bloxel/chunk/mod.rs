@@ -1,21 +1,23 @@
 use {
-  super::Facing,
+  super::{BlockPos, Facing},
+  crate::util::ZOrder,
   amethyst::ecs::prelude::*,
-  std::{convert::TryFrom, error::Error, fmt, ops},
+  serde::{Deserialize, Serialize},
+  std::{convert::TryFrom, error::Error, fmt, marker::PhantomData, ops},
 };
 
 pub mod storage;
 
+mod layer;
+mod layout;
 mod lookup;
-pub use lookup::*;
-
-pub const CHUNK_LENGTH_BITS: usize = 4;
-pub const CHUNK_LENGTH: usize = 1 << CHUNK_LENGTH_BITS;
-pub const CHUNK_SIZE: usize = 1 << (CHUNK_LENGTH_BITS * 3);
+pub use {layer::*, layout::*, lookup::*};
 
 #[derive(Copy, Clone)]
 pub struct Chunk {
-  // pub level: Entity,
+  /// The level entity (or other entity made up of chunks, such as a `VoxelBody`)
+  /// this chunk is part of.
+  pub level: Entity,
   pub pos: ChunkPos,
 }
 
@@ -35,7 +37,7 @@ bitflags! {
   }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub struct ChunkPos {
   pub x: i32,
   pub y: i32,
@@ -86,16 +88,58 @@ impl ops::Sub<Facing> for ChunkPos {
   }
 }
 
-const BIT_MASK: i32 = !(!0 << CHUNK_LENGTH_BITS);
+impl TryFrom<ChunkPos> for ZOrder {
+  type Error = ZOrderRangeError;
+  fn try_from(pos: ChunkPos) -> Result<Self, Self::Error> {
+    ZOrder::new(pos.x, pos.y, pos.z).ok_or(ZOrderRangeError(pos))
+  }
+}
+
+impl From<ZOrder> for ChunkPos {
+  fn from(order: ZOrder) -> Self {
+    let (x, y, z) = order.into();
+    ChunkPos { x, y, z }
+  }
+}
 
-// TODO: With `u16` being the base type, `Index` can only support `CHUNK_LENGTH` up to 32 (5 bits).
-//       Consider encoding this using "Z-order curve"? Not sure what the benefits are.
-#[derive(Copy, Clone, PartialEq, Eq)]
-pub struct Index(u16);
+/// Splits a world-space block position into the position of the chunk
+/// containing it, and its index relative to that chunk.
+impl<L: ChunkLayout> From<BlockPos> for (ChunkPos, Index<L>) {
+  fn from(pos: BlockPos) -> Self {
+    // Arithmetic right shift rounds towards negative infinity, so
+    // for example block `-1` ends up in chunk `-1`, not chunk `0`.
+    let chunk_pos = ChunkPos {
+      x: pos.x >> L::LENGTH_BITS,
+      y: pos.y >> L::LENGTH_BITS,
+      z: pos.z >> L::LENGTH_BITS,
+    };
+    let mask = L::LENGTH as i32 - 1;
+    // SAFETY: Masking the coordinates always results in values inside of the chunk bounds.
+    let index = unsafe { Index::new_unchecked(pos.x & mask, pos.y & mask, pos.z & mask) };
+    (chunk_pos, index)
+  }
+}
+
+/// Combines the position of a chunk and an index relative to it into a world-space block position.
+/// Chunk positions too far out to contain any `BlockPos` don't have a meaningful result.
+impl<L: ChunkLayout> From<(ChunkPos, Index<L>)> for BlockPos {
+  fn from((chunk_pos, index): (ChunkPos, Index<L>)) -> Self {
+    let (x, y, z) = index.into();
+    BlockPos {
+      x: (chunk_pos.x << L::LENGTH_BITS) | x,
+      y: (chunk_pos.y << L::LENGTH_BITS) | y,
+      z: (chunk_pos.z << L::LENGTH_BITS) | z,
+    }
+  }
+}
 
-impl Index {
+/// Index of a block relative to its chunk, encoded according to the chunk's `ChunkLayout`.
+pub struct Index<L: ChunkLayout = DefaultLayout>(u32, PhantomData<L>);
+
+impl<L: ChunkLayout> Index<L> {
   pub fn new(x: i32, y: i32, z: i32) -> Result<Self, BoundsError> {
-    if (x & !BIT_MASK == 0) && (y & !BIT_MASK == 0) && (z & !BIT_MASK == 0) {
+    let mask = L::LENGTH as i32 - 1;
+    if (x & !mask == 0) && (y & !mask == 0) && (z & !mask == 0) {
       // SAFETY: Bounds already checked.
       unsafe { Ok(Self::new_unchecked(x, y, z)) }
     } else {
@@ -104,41 +148,66 @@ impl Index {
   }
 
   pub unsafe fn new_unchecked(x: i32, y: i32, z: i32) -> Self {
-    Index((x | (y << CHUNK_LENGTH_BITS) | (z << (CHUNK_LENGTH_BITS * 2))) as u16)
+    Index(L::encode(x, y, z), PhantomData)
+  }
+
+  /// Creates an index from its raw representation, as returned by `raw_index`.
+  /// Returns `None` if the value is outside of the bounds of the chunk.
+  pub fn from_raw(raw: u32) -> Option<Self> {
+    if (raw as usize) < L::SIZE {
+      Some(Index(raw, PhantomData))
+    } else {
+      None
+    }
   }
 
   pub fn x(&self) -> i32 {
-    (self.0 as i32) & BIT_MASK
+    L::decode(self.0).0
   }
 
   pub fn y(&self) -> i32 {
-    (self.0 as i32 >> CHUNK_LENGTH_BITS) & BIT_MASK
+    L::decode(self.0).1
   }
 
   pub fn z(&self) -> i32 {
-    (self.0 as i32 >> (CHUNK_LENGTH_BITS * 2)) & BIT_MASK
+    L::decode(self.0).2
   }
 
   #[inline]
-  pub fn raw_index(&self) -> u16 {
+  pub fn raw_index(&self) -> u32 {
     self.0
   }
 }
 
-impl TryFrom<(i32, i32, i32)> for Index {
+// Implemented manually because deriving would require the layout itself to implement these traits.
+impl<L: ChunkLayout> Copy for Index<L> {}
+impl<L: ChunkLayout> Clone for Index<L> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<L: ChunkLayout> Eq for Index<L> {}
+impl<L: ChunkLayout> PartialEq for Index<L> {
+  fn eq(&self, rhs: &Self) -> bool {
+    self.0 == rhs.0
+  }
+}
+
+impl<L: ChunkLayout> TryFrom<(i32, i32, i32)> for Index<L> {
   type Error = BoundsError;
   fn try_from((x, y, z): (i32, i32, i32)) -> Result<Self, Self::Error> {
     Self::new(x, y, z)
   }
 }
 
-impl Into<(i32, i32, i32)> for Index {
+impl<L: ChunkLayout> Into<(i32, i32, i32)> for Index<L> {
   fn into(self) -> (i32, i32, i32) {
-    (self.x(), self.y(), self.z())
+    L::decode(self.0)
   }
 }
 
-impl fmt::Debug for Index {
+impl<L: ChunkLayout> fmt::Debug for Index<L> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
@@ -151,7 +220,7 @@ impl fmt::Debug for Index {
   }
 }
 
-impl fmt::Display for Index {
+impl<L: ChunkLayout> fmt::Display for Index<L> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(f, "({}, {}, {})", self.x(), self.y(), self.z())
   }
@@ -171,3 +240,90 @@ impl fmt::Display for BoundsError {
     )
   }
 }
+
+#[derive(Debug)]
+pub struct ZOrderRangeError(ChunkPos);
+
+impl Error for ZOrderRangeError {}
+
+impl fmt::Display for ZOrderRangeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "Chunk ({}, {}, {}) outside of ZOrder range",
+      self.0.x, self.0.y, self.0.z
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, proptest::prelude::*};
+
+  #[test]
+  fn block_pos_split_negative() {
+    let (chunk_pos, index): (ChunkPos, Index) = BlockPos::new(-1, -16, -17).into();
+    assert_eq!(chunk_pos, ChunkPos::new(-1, -1, -2));
+    assert_eq!(index, Index::new(15, 0, 15).unwrap());
+
+    let (chunk_pos, index): (ChunkPos, Index) = BlockPos::new(i32::MIN, i32::MAX, 0).into();
+    assert_eq!(chunk_pos, ChunkPos::new(i32::MIN >> 4, i32::MAX >> 4, 0));
+    assert_eq!(index, Index::new(0, 15, 0).unwrap());
+  }
+
+  #[test]
+  fn chunk_pos_z_order_out_of_range() {
+    const MIN: i32 = -(1 << 20);
+    const MAX: i32 = (1 << 20) - 1;
+    assert!(ZOrder::try_from(ChunkPos::new(MIN, MAX, 0)).is_ok());
+    assert!(ZOrder::try_from(ChunkPos::new(MIN - 1, 0, 0)).is_err());
+    assert!(ZOrder::try_from(ChunkPos::new(0, MAX + 1, 0)).is_err());
+    assert!(ZOrder::try_from(ChunkPos::new(0, 0, i32::MIN)).is_err());
+  }
+
+  #[test]
+  fn block_pos_split_z_order() {
+    let (chunk_pos, index): (ChunkPos, Index<ZOrderLayout32>) = BlockPos::new(-1, 33, -64).into();
+    assert_eq!(chunk_pos, ChunkPos::new(-1, 1, -2));
+    assert_eq!(index, Index::new(31, 1, 0).unwrap());
+    assert_eq!(
+      BlockPos::from((chunk_pos, index)),
+      BlockPos::new(-1, 33, -64)
+    );
+  }
+
+  proptest! {
+    #[test]
+    fn block_pos_round_trip(x in any::<i32>(), y in any::<i32>(), z in any::<i32>()) {
+      let pos = BlockPos::new(x, y, z);
+      let (chunk_pos, index): (ChunkPos, Index) = pos.into();
+      prop_assert_eq!(BlockPos::from((chunk_pos, index)), pos);
+    }
+
+    #[test]
+    fn block_pos_split_floors(x in -1000..1000, y in -1000..1000, z in -1000..1000) {
+      const LENGTH: i32 = DefaultLayout::LENGTH as i32;
+      let (chunk_pos, index): (ChunkPos, Index) = BlockPos::new(x, y, z).into();
+      let relative: (i32, i32, i32) = index.into();
+      prop_assert_eq!(
+        chunk_pos,
+        ChunkPos::new(x.div_euclid(LENGTH), y.div_euclid(LENGTH), z.div_euclid(LENGTH))
+      );
+      prop_assert_eq!(
+        relative,
+        (x.rem_euclid(LENGTH), y.rem_euclid(LENGTH), z.rem_euclid(LENGTH))
+      );
+    }
+
+    #[test]
+    fn chunk_pos_z_order_round_trip(
+      x in -(1 << 20)..(1 << 20),
+      y in -(1 << 20)..(1 << 20),
+      z in -(1 << 20)..(1 << 20),
+    ) {
+      let pos = ChunkPos::new(x, y, z);
+      let order = ZOrder::try_from(pos).unwrap();
+      prop_assert_eq!(ChunkPos::from(order), pos);
+    }
+  }
+}
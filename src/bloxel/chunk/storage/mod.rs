@@ -1,5 +1,5 @@
 use {
-  super::Index,
+  super::{ChunkLayout, DefaultLayout, Index},
   amethyst::ecs::{Component, DenseVecStorage},
   std::sync::RwLock,
 };
@@ -8,19 +8,19 @@ pub use palette::*;
 
 mod palette;
 
-pub trait BlockData: Default + Copy + Eq + 'static {}
-impl<T: Default + Copy + Eq + 'static> BlockData for T {}
+pub trait BlockData: Default + Copy + Eq + Send + Sync + 'static {}
+impl<T: Default + Copy + Eq + Send + Sync + 'static> BlockData for T {}
 
 #[derive(Component)]
-pub struct ChunkStorage<T: BlockData> {
-  storage: RwLock<Box<dyn StorageImpl<T>>>,
+pub struct ChunkStorage<T: BlockData, L: ChunkLayout = DefaultLayout> {
+  storage: RwLock<Box<dyn StorageImpl<T, L>>>,
 }
 
-unsafe impl<T: BlockData> Send for ChunkStorage<T> {}
-unsafe impl<T: BlockData> Sync for ChunkStorage<T> {}
+unsafe impl<T: BlockData, L: ChunkLayout> Send for ChunkStorage<T, L> {}
+unsafe impl<T: BlockData, L: ChunkLayout> Sync for ChunkStorage<T, L> {}
 
-impl<T: BlockData> ChunkStorage<T> {
-  pub fn new<S: StorageImpl<T> + 'static>(storage: S) -> Self {
+impl<T: BlockData, L: ChunkLayout> ChunkStorage<T, L> {
+  pub fn new<S: StorageImpl<T, L> + 'static>(storage: S) -> Self {
     ChunkStorage {
       storage: RwLock::new(Box::new(storage)),
     }
@@ -28,23 +28,43 @@ impl<T: BlockData> ChunkStorage<T> {
 
   /// Attempts to get a value from this storage from the specified relative coordinates.
   /// Returns `Err(BoundsError)` if the coordinates are outside the bounds of the storage.
-  pub fn get(&self, index: Index) -> T {
+  pub fn get(&self, index: Index<L>) -> T {
     self.storage.read().unwrap().get(index)
   }
 
   /// Attempts to set a value from this storage at the specified relative coordinates.
   /// Returns `Err(BoundsError)` if the coordinates are outside the bounds of the storage.
-  pub fn set(&mut self, index: Index, value: T) {
+  pub fn set(&mut self, index: Index<L>, value: T) {
     self.storage.write().unwrap().set(index, value)
   }
+
+  /// Returns the number of bytes this storage has allocated on the heap.
+  pub fn heap_size(&self) -> usize {
+    self.storage.read().unwrap().heap_size()
+  }
+
+  /// Returns the index of the palette entry holding the value at the
+  /// specified coordinates, if this storage makes use of a palette.
+  pub fn palette_index(&self, index: Index<L>) -> Option<usize> {
+    self.storage.read().unwrap().palette_index(index)
+  }
 }
 
-pub trait StorageImpl<T: BlockData> {
+pub trait StorageImpl<T: BlockData, L: ChunkLayout = DefaultLayout> {
   /// Attempts to get a value from this storage from the specified relative coordinates.
   /// Returns `Err(BoundsError)` if the coordinates are outside the bounds of the storage.
-  fn get(&self, index: Index) -> T;
+  fn get(&self, index: Index<L>) -> T;
 
   /// Attempts to set a value from this storage at the specified relative coordinates.
   /// Returns `Err(BoundsError)` if the coordinates are outside the bounds of the storage.
-  fn set(&mut self, index: Index, value: T);
+  fn set(&mut self, index: Index<L>, value: T);
+
+  /// Returns the number of bytes this storage has allocated on the heap.
+  fn heap_size(&self) -> usize;
+
+  /// Returns the index of the palette entry holding the value at the
+  /// specified coordinates, if this storage makes use of a palette.
+  fn palette_index(&self, _index: Index<L>) -> Option<usize> {
+    None
+  }
 }
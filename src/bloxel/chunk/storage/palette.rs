@@ -1,19 +1,22 @@
 use {
   super::{
-    super::{Index, CHUNK_SIZE},
+    super::{ChunkLayout, DefaultLayout, Index},
     BlockData, StorageImpl,
   },
   crate::util::PaletteStore,
+  std::marker::PhantomData,
 };
 
-pub struct PaletteStorageImpl<T: BlockData> {
+pub struct PaletteStorageImpl<T: BlockData, L: ChunkLayout = DefaultLayout> {
   pub data: PaletteStore<T>,
+  layout: PhantomData<L>,
 }
 
-impl<T: BlockData> PaletteStorageImpl<T> {
+impl<T: BlockData, L: ChunkLayout> PaletteStorageImpl<T, L> {
   pub fn new() -> Self {
     PaletteStorageImpl {
-      data: PaletteStore::new(CHUNK_SIZE),
+      data: PaletteStore::new(L::SIZE),
+      layout: PhantomData,
     }
   }
 
@@ -24,14 +27,22 @@ impl<T: BlockData> PaletteStorageImpl<T> {
   }
 }
 
-impl<T: BlockData> StorageImpl<T> for PaletteStorageImpl<T> {
-  fn get(&self, index: Index) -> T {
+impl<T: BlockData, L: ChunkLayout> StorageImpl<T, L> for PaletteStorageImpl<T, L> {
+  fn get(&self, index: Index<L>) -> T {
     // SAFETY: Bounds already satisfied by chunk size.
     unsafe { self.data.get_unchecked(index.raw_index() as usize) }
   }
 
-  fn set(&mut self, index: Index, value: T) {
+  fn set(&mut self, index: Index<L>, value: T) {
     // SAFETY: Bounds already satisfied by chunk size.
     unsafe { self.data.set_unchecked(index.raw_index() as usize, value) }
   }
+
+  fn heap_size(&self) -> usize {
+    self.data.heap_size()
+  }
+
+  fn palette_index(&self, index: Index<L>) -> Option<usize> {
+    self.data.palette_index(index.raw_index() as usize)
+  }
 }
@@ -0,0 +1,171 @@
+use {
+  super::BlockPos,
+  amethyst::core::math::{Point3, Vector3},
+};
+
+/// Small distance used to keep boxes from getting stuck in blocks they're merely touching.
+const EPSILON: f32 = 1.0e-4;
+
+/// Axis-aligned bounding box in world space.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+  pub min: Point3<f32>,
+  pub max: Point3<f32>,
+}
+
+impl Aabb {
+  pub fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+    Aabb { min, max }
+  }
+
+  /// Returns this box moved by the specified offset.
+  pub fn translate(&self, offset: Vector3<f32>) -> Self {
+    Aabb {
+      min: self.min + offset,
+      max: self.max + offset,
+    }
+  }
+
+  /// Returns the range of blocks (inclusive) on the specified axis that this box overlaps.
+  /// Blocks only touched by the box's faces don't count as overlapping.
+  fn block_range(&self, axis: usize) -> (i32, i32) {
+    (
+      (self.min[axis] + EPSILON).floor() as i32,
+      (self.max[axis] - EPSILON).ceil() as i32 - 1,
+    )
+  }
+}
+
+/// Result of moving an `Aabb` through the block grid using `move_aabb`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Movement {
+  /// How far the box actually moved.
+  pub offset: Vector3<f32>,
+  /// Whether movement was blocked on each of the axes.
+  pub collided: [bool; 3],
+}
+
+/// Moves a box along a single axis by up to `delta`, stopping at the first block for which
+/// `is_solid` returns `true`. Returns the distance the box was able to move.
+pub fn sweep_axis<F>(aabb: &Aabb, axis: usize, delta: f32, mut is_solid: F) -> f32
+where
+  F: FnMut(BlockPos) -> bool,
+{
+  let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+  let (min_a, max_a) = aabb.block_range(a);
+  let (min_b, max_b) = aabb.block_range(b);
+  let mut layer_is_solid = |layer: i32| {
+    for i in min_a..=max_a {
+      for j in min_b..=max_b {
+        let mut pos = [0; 3];
+        pos[axis] = layer;
+        pos[a] = i;
+        pos[b] = j;
+        if is_solid(BlockPos::new(pos[0], pos[1], pos[2])) {
+          return true;
+        }
+      }
+    }
+    false
+  };
+
+  if delta > 0.0 {
+    let edge = aabb.max[axis];
+    let mut layer = (edge - EPSILON).ceil() as i32;
+    while (layer as f32) < edge + delta {
+      if layer_is_solid(layer) {
+        return (layer as f32 - edge).max(0.0);
+      }
+      layer += 1;
+    }
+  } else if delta < 0.0 {
+    let edge = aabb.min[axis];
+    let mut layer = (edge + EPSILON).floor() as i32 - 1;
+    while (layer + 1) as f32 > edge + delta {
+      if layer_is_solid(layer) {
+        return ((layer + 1) as f32 - edge).min(0.0);
+      }
+      layer -= 1;
+    }
+  }
+  delta
+}
+
+/// Moves a box by `delta` through the block grid, one axis after the other (`Y` first, then
+/// `X` and `Z`), so it slides along any solid blocks it runs into instead of stopping entirely.
+pub fn move_aabb<F>(aabb: &Aabb, delta: Vector3<f32>, mut is_solid: F) -> Movement
+where
+  F: FnMut(BlockPos) -> bool,
+{
+  let mut current = *aabb;
+  let mut offset = Vector3::zeros();
+  let mut collided = [false; 3];
+  for &axis in &[1, 0, 2] {
+    let moved = sweep_axis(&current, axis, delta[axis], &mut is_solid);
+    collided[axis] = moved != delta[axis];
+    offset[axis] = moved;
+    let mut step = Vector3::zeros();
+    step[axis] = moved;
+    current = current.translate(step);
+  }
+  Movement { offset, collided }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn unit_box(x: f32, y: f32, z: f32) -> Aabb {
+    Aabb::new(Point3::new(x, y, z), Point3::new(x + 1.0, y + 1.0, z + 1.0))
+  }
+
+  fn floor(pos: BlockPos) -> bool {
+    pos.y < 0
+  }
+
+  #[test]
+  fn falls_onto_floor() {
+    let movement = move_aabb(
+      &unit_box(0.5, 2.0, 0.5),
+      Vector3::new(0.0, -5.0, 0.0),
+      floor,
+    );
+    assert_eq!(movement.offset, Vector3::new(0.0, -2.0, 0.0));
+    assert_eq!(movement.collided, [false, true, false]);
+  }
+
+  #[test]
+  fn slides_along_floor() {
+    // Resting exactly on top of the floor must not count as being stuck in it.
+    let movement = move_aabb(
+      &unit_box(0.5, 0.0, 0.5),
+      Vector3::new(3.0, 0.0, -2.0),
+      floor,
+    );
+    assert_eq!(movement.offset, Vector3::new(3.0, 0.0, -2.0));
+    assert_eq!(movement.collided, [false, false, false]);
+  }
+
+  #[test]
+  fn stops_at_wall_in_both_directions() {
+    let wall = |pos: BlockPos| pos.x == 3 || pos.x == -3;
+    let aabb = unit_box(0.5, 0.0, 0.0);
+    assert_eq!(sweep_axis(&aabb, 0, 10.0, wall), 1.5);
+    assert_eq!(sweep_axis(&aabb, 0, -10.0, wall), -2.5);
+    assert_eq!(sweep_axis(&aabb, 0, 1.0, wall), 1.0);
+  }
+
+  #[test]
+  fn does_not_tunnel_through_thin_walls() {
+    let wall = |pos: BlockPos| pos == BlockPos::new(5, 0, 0);
+    let aabb = Aabb::new(Point3::new(0.2, 0.2, 0.2), Point3::new(0.8, 0.8, 0.8));
+    assert_eq!(sweep_axis(&aabb, 0, 100.0, wall), 4.2);
+  }
+
+  #[test]
+  fn ignores_blocks_only_touched_on_the_side() {
+    let column = |pos: BlockPos| pos.x == 1 && pos.z == 0;
+    let aabb = unit_box(0.0, 0.0, 0.0);
+    assert_eq!(sweep_axis(&aabb, 1, 5.0, column), 5.0);
+  }
+}
@@ -0,0 +1,743 @@
+use {
+  super::{
+    chunk::{storage::*, ChunkLayout, ChunkPos, Index},
+    net::NetworkClient,
+    ActiveLevel, BlockAccess, BlockPos, BlockView, DebugStats, Level, PlayerController,
+  },
+  amethyst::{
+    assets::{AssetStorage, Loader},
+    controls::HideCursor,
+    core::{math::Vector3, transform::Transform},
+    derive::SystemDesc,
+    ecs::prelude::*,
+    input::{InputEvent, StringBindings},
+    shrev::EventChannel,
+    ui::{get_default_font, Anchor, FontAsset, LineMode, UiText, UiTransform},
+    winit::{Event, WindowEvent},
+  },
+  std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+  },
+};
+
+/// Input action which opens and closes the console.
+pub const ACTION_TOGGLE_CONSOLE: &str = "toggle_console";
+
+/// Number of lines of output the console keeps around.
+pub const MAX_CONSOLE_LINES: usize = 16;
+/// Maximum number of blocks the `fill` command changes at once.
+pub const MAX_FILL_VOLUME: usize = 1 << 16;
+
+/// Function run when a console command is entered, which returns the text to print.
+pub type CommandHandler =
+  dyn Fn(&mut World, &CommandArgs<'_>) -> Result<String, CommandError> + Send + Sync;
+
+struct Command {
+  usage: String,
+  help: String,
+  handler: Arc<CommandHandler>,
+}
+
+/// Resource holding the commands which can be entered into the console, by name.
+/// Other modules add their own commands using `with` or `register`.
+#[derive(Default)]
+pub struct ConsoleCommands {
+  commands: BTreeMap<String, Command>,
+}
+
+impl ConsoleCommands {
+  /// Creates the registry with the built-in commands, which act on the `ActiveLevel`:
+  /// `help`, `tp`, `setblock`, `fill`, `regen`, `seed` and `stats`.
+  pub fn builtin<L: ChunkLayout>() -> Self {
+    ConsoleCommands::default()
+      .with("help", "help", "Lists all commands", help)
+      .with("tp", "tp <x> <y> <z>", "Teleports the player", teleport)
+      .with(
+        "setblock",
+        "setblock <x> <y> <z> <block>",
+        "Sets a single block",
+        set_block::<L>,
+      )
+      .with(
+        "fill",
+        "fill <x1> <y1> <z1> <x2> <y2> <z2> <block>",
+        "Sets all blocks in a box",
+        fill::<L>,
+      )
+      .with(
+        "regen",
+        "regen [<chunk x> <chunk y> <chunk z>]",
+        "Regenerates the terrain of a chunk, by default the one the player is in",
+        regenerate::<L>,
+      )
+      .with("seed", "seed", "Shows the seed of the level", seed)
+      .with(
+        "stats",
+        "stats",
+        "Shows statistics about the level",
+        stats::<L>,
+      )
+  }
+
+  pub fn with<F>(mut self, name: &str, usage: &str, help: &str, handler: F) -> Self
+  where
+    F: Fn(&mut World, &CommandArgs<'_>) -> Result<String, CommandError> + Send + Sync + 'static,
+  {
+    self.register(name, usage, help, handler);
+    self
+  }
+
+  /// Adds a command, replacing any existing command with the same name. The `usage`
+  /// string is shown when the command is entered with the wrong arguments.
+  pub fn register<F>(&mut self, name: &str, usage: &str, help: &str, handler: F)
+  where
+    F: Fn(&mut World, &CommandArgs<'_>) -> Result<String, CommandError> + Send + Sync + 'static,
+  {
+    let command = Command {
+      usage: usage.to_string(),
+      help: help.to_string(),
+      handler: Arc::new(handler),
+    };
+    self.commands.insert(name.to_string(), command);
+  }
+
+  /// Iterates over the usage and help text of all commands, ordered by name.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    (self.commands.values()).map(|command| (command.usage.as_str(), command.help.as_str()))
+  }
+}
+
+/// The arguments a command was entered with.
+#[derive(Debug)]
+pub struct CommandArgs<'a> {
+  usage: &'a str,
+  args: &'a [String],
+}
+
+impl<'a> CommandArgs<'a> {
+  pub fn len(&self) -> usize {
+    self.args.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.args.is_empty()
+  }
+
+  /// Fails with the command's usage unless there are exactly the specified number of arguments.
+  pub fn expect(&self, count: usize) -> Result<(), CommandError> {
+    if self.args.len() == count {
+      Ok(())
+    } else {
+      Err(self.usage_error())
+    }
+  }
+
+  /// Parses the argument at the specified index.
+  pub fn get<T: FromStr>(&self, index: usize) -> Result<T, CommandError> {
+    let arg = self.args.get(index).ok_or_else(|| self.usage_error())?;
+    arg
+      .parse()
+      .map_err(|_| CommandError::InvalidArgument(arg.clone()))
+  }
+
+  /// Parses the three arguments starting at the specified index as a block position.
+  pub fn block_pos(&self, index: usize) -> Result<BlockPos, CommandError> {
+    Ok(BlockPos::new(
+      self.get(index)?,
+      self.get(index + 1)?,
+      self.get(index + 2)?,
+    ))
+  }
+
+  pub fn usage_error(&self) -> CommandError {
+    CommandError::Usage(self.usage.to_string())
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+  /// A quoted argument is missing its closing quote.
+  UnclosedQuote,
+  UnknownCommand(String),
+  /// The command was entered with the wrong number of arguments. Contains its usage.
+  Usage(String),
+  InvalidArgument(String),
+  /// The command couldn't be run, for the specified reason.
+  Failed(String),
+}
+
+impl Error for CommandError {}
+
+impl fmt::Display for CommandError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CommandError::UnclosedQuote => write!(f, "Missing closing quote"),
+      CommandError::UnknownCommand(name) => {
+        write!(f, "Unknown command '{}', try 'help'", name)
+      }
+      CommandError::Usage(usage) => write!(f, "Usage: {}", usage),
+      CommandError::InvalidArgument(arg) => write!(f, "Invalid argument '{}'", arg),
+      CommandError::Failed(reason) => write!(f, "{}", reason),
+    }
+  }
+}
+
+/// Splits a line entered into the console into words, the first one being the command's name.
+/// Words are separated by whitespace, unless it's inside of double quotes.
+pub fn parse_command(line: &str) -> Result<Vec<String>, CommandError> {
+  let mut words = Vec::new();
+  let mut word = None::<String>;
+  let mut quoted = false;
+  for c in line.chars() {
+    match c {
+      '"' => {
+        quoted = !quoted;
+        // Quotes start a word even if there's nothing between them.
+        word.get_or_insert_with(String::new);
+      }
+      c if c.is_whitespace() && !quoted => words.extend(word.take()),
+      c => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+  if quoted {
+    return Err(CommandError::UnclosedQuote);
+  }
+  words.extend(word);
+  Ok(words)
+}
+
+/// Parses and runs a line entered into the console, returning the text to print.
+/// Empty lines do nothing.
+pub fn run_command(world: &mut World, line: &str) -> Result<String, CommandError> {
+  let words = parse_command(line)?;
+  let (name, args) = match words.split_first() {
+    Some(split) => split,
+    None => return Ok(String::new()),
+  };
+  // The handler is taken out of the registry, so it may access any resource, including that one.
+  let (usage, handler) = {
+    let commands = world.try_fetch::<ConsoleCommands>();
+    let command = (commands.as_ref())
+      .and_then(|commands| commands.commands.get(name))
+      .ok_or_else(|| CommandError::UnknownCommand(name.clone()))?;
+    (command.usage.clone(), command.handler.clone())
+  };
+  handler(
+    world,
+    &CommandArgs {
+      usage: &usage,
+      args,
+    },
+  )
+}
+
+/// Runs a line entered into the console, printing it along with the result to the `Console`.
+pub fn execute_command(world: &mut World, line: &str) {
+  let result = run_command(world, line);
+  let mut console = world.entry::<Console>().or_insert_with(Console::default);
+  console.print(format!("> {}", line));
+  match result {
+    Ok(output) => output.lines().for_each(|line| console.print(line)),
+    Err(err) => console.print(err.to_string()),
+  }
+}
+
+/// Resource holding the state of the in-game console, which `ConsoleSystem` displays.
+#[derive(Debug, Default)]
+pub struct Console {
+  open: bool,
+  /// Line currently being typed.
+  pub input: String,
+  output: VecDeque<String>,
+}
+
+impl Console {
+  pub fn is_open(&self) -> bool {
+    self.open
+  }
+
+  /// Output of previous commands, oldest first.
+  pub fn output(&self) -> impl Iterator<Item = &str> {
+    self.output.iter().map(String::as_str)
+  }
+
+  pub fn print(&mut self, line: impl Into<String>) {
+    if self.output.len() == MAX_CONSOLE_LINES {
+      self.output.pop_front();
+    }
+    self.output.push_back(line.into());
+  }
+
+  /// Handles a character typed while the console is open. Returns the
+  /// entered line, which should be run as a command, once return is pressed.
+  pub fn type_char(&mut self, c: char) -> Option<String> {
+    match c {
+      '\r' | '\n' if !self.input.trim().is_empty() => Some(std::mem::take(&mut self.input)),
+      // Backspace
+      '\u{8}' => {
+        self.input.pop();
+        None
+      }
+      c if !c.is_control() => {
+        self.input.push(c);
+        None
+      }
+      _ => None,
+    }
+  }
+}
+
+/// Resource holding the entities which display the `Console`, see `create_console_ui`.
+pub struct ConsoleUi {
+  pub output: Entity,
+  pub input: Entity,
+}
+
+/// Creates the text elements the console is displayed with, inserting the `ConsoleUi` resource.
+pub fn create_console_ui(world: &mut World) {
+  let font = world.exec(
+    |(loader, fonts): (ReadExpect<Loader>, Read<AssetStorage<FontAsset>>)| {
+      get_default_font(&loader, &fonts)
+    },
+  );
+  let mut create_text = |id: &str, y: f32, height: f32, line_mode| {
+    let transform = UiTransform::new(
+      id.to_string(),
+      Anchor::TopLeft,
+      Anchor::TopLeft,
+      8.0,
+      y,
+      1.0,
+      1000.0,
+      height,
+    );
+    let color = [1.0, 1.0, 1.0, 1.0];
+    let text = UiText::new(
+      font.clone(),
+      String::new(),
+      color,
+      16.0,
+      line_mode,
+      Anchor::BottomLeft,
+    );
+    world.create_entity().with(transform).with(text).build()
+  };
+  let output = create_text(
+    "console_output",
+    -8.0,
+    MAX_CONSOLE_LINES as f32 * 18.0,
+    LineMode::Wrap,
+  );
+  let input = create_text(
+    "console_input",
+    -8.0 - MAX_CONSOLE_LINES as f32 * 18.0,
+    18.0,
+    LineMode::Single,
+  );
+  world.insert(ConsoleUi { output, input });
+}
+
+/// Opens and closes the `Console` using the "toggle_console" action, passing typed text to it while
+/// it's open and running entered commands at the end of the frame. The cursor is shown while the
+/// console is open, so other systems ignore the mouse, and `PlayerMovementSystem` ignores the keys.
+#[derive(SystemDesc)]
+#[system_desc(name(ConsoleSystemDesc))]
+pub struct ConsoleSystem {
+  #[system_desc(event_channel_reader)]
+  input_reader: ReaderId<InputEvent<StringBindings>>,
+  #[system_desc(event_channel_reader)]
+  window_reader: ReaderId<Event>,
+}
+
+impl ConsoleSystem {
+  pub fn new(
+    input_reader: ReaderId<InputEvent<StringBindings>>,
+    window_reader: ReaderId<Event>,
+  ) -> Self {
+    Self {
+      input_reader,
+      window_reader,
+    }
+  }
+}
+
+impl<'a> System<'a> for ConsoleSystem {
+  type SystemData = (
+    Read<'a, EventChannel<InputEvent<StringBindings>>>,
+    Read<'a, EventChannel<Event>>,
+    Read<'a, LazyUpdate>,
+    Write<'a, Console>,
+    Write<'a, HideCursor>,
+    Option<Read<'a, ConsoleUi>>,
+    WriteStorage<'a, UiText>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      input_events,
+      window_events,
+      lazy,
+      mut console,
+      mut hide_cursor,
+      ui,
+      mut texts,
+    ): Self::SystemData,
+  ) {
+    let toggle = input_events
+      .read(&mut self.input_reader)
+      .filter(|event| match event {
+        InputEvent::ActionPressed(action) => action == ACTION_TOGGLE_CONSOLE,
+        _ => false,
+      })
+      .count()
+      % 2
+      == 1;
+
+    for event in window_events.read(&mut self.window_reader) {
+      // The key toggling the console also types a character, which shouldn't end up in it.
+      if !console.open || toggle {
+        continue;
+      }
+      if let Event::WindowEvent {
+        event: WindowEvent::ReceivedCharacter(c),
+        ..
+      } = event
+      {
+        if let Some(line) = console.type_char(*c) {
+          lazy.exec_mut(move |world| execute_command(world, &line));
+        }
+      }
+    }
+
+    if toggle {
+      console.open = !console.open;
+      hide_cursor.hide = !console.open;
+    }
+
+    if let Some(ui) = ui {
+      if let Some(text) = texts.get_mut(ui.output) {
+        text.text = if console.open {
+          console.output().collect::<Vec<_>>().join("\n")
+        } else {
+          String::new()
+        };
+      }
+      if let Some(text) = texts.get_mut(ui.input) {
+        text.text = if console.open {
+          format!("> {}_", console.input)
+        } else {
+          String::new()
+        };
+      }
+    }
+  }
+}
+
+/// Returns the `ActiveLevel`, failing if there is none or if blocks can't be changed
+/// locally because they're controlled by the server the client is connected to.
+fn local_level(world: &World) -> Result<Entity, CommandError> {
+  if world.try_fetch::<NetworkClient>().is_some() {
+    return Err(CommandError::Failed(
+      "Not available while connected to a server".to_string(),
+    ));
+  }
+  active_level(world)
+}
+
+fn active_level(world: &World) -> Result<Entity, CommandError> {
+  (world.try_fetch::<ActiveLevel>())
+    .and_then(|active_level| active_level.0)
+    .ok_or_else(|| CommandError::Failed("There is no active level".to_string()))
+}
+
+/// Returns the position of the block the player is in.
+fn player_pos(world: &World) -> Result<BlockPos, CommandError> {
+  let (controllers, transforms) =
+    world.system_data::<(ReadStorage<PlayerController>, ReadStorage<Transform>)>();
+  let (_, transform) = (&controllers, &transforms)
+    .join()
+    .next()
+    .ok_or_else(|| CommandError::Failed("There is no player".to_string()))?;
+  let translation = transform.translation();
+  Ok(BlockPos::new(
+    translation.x.floor() as i32,
+    translation.y.floor() as i32,
+    translation.z.floor() as i32,
+  ))
+}
+
+fn help(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  args.expect(0)?;
+  let commands = world.read_resource::<ConsoleCommands>();
+  let lines = commands
+    .iter()
+    .map(|(usage, help)| format!("{} - {}", usage, help))
+    .collect::<Vec<_>>();
+  Ok(lines.join("\n"))
+}
+
+fn teleport(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  args.expect(3)?;
+  let target = Vector3::new(args.get(0)?, args.get(1)?, args.get(2)?);
+  let (mut controllers, mut transforms) =
+    world.system_data::<(WriteStorage<PlayerController>, WriteStorage<Transform>)>();
+  let mut teleported = false;
+  for (controller, transform) in (&mut controllers, &mut transforms).join() {
+    transform.set_translation(target);
+    controller.velocity = Vector3::zeros();
+    teleported = true;
+  }
+  if teleported {
+    Ok(format!(
+      "Teleported to {} {} {}",
+      target.x, target.y, target.z
+    ))
+  } else {
+    Err(CommandError::Failed("There is no player".to_string()))
+  }
+}
+
+fn set_block<L: ChunkLayout>(
+  world: &mut World,
+  args: &CommandArgs<'_>,
+) -> Result<String, CommandError> {
+  args.expect(4)?;
+  let (pos, value) = (args.block_pos(0)?, args.get::<u8>(3)?);
+  let level = local_level(world)?;
+  world.exec(|mut blocks: BlockAccess<'_, u8, L>| blocks.set(level, pos, value));
+  Ok(format!("Set block at {:?} to {}", pos, value))
+}
+
+fn fill<L: ChunkLayout>(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  args.expect(7)?;
+  let (a, b, value) = (args.block_pos(0)?, args.block_pos(3)?, args.get::<u8>(6)?);
+  let from = BlockPos::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+  let to = BlockPos::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+  // Coordinates can span the whole `i32` range, so lengths are computed in `i64`.
+  let volume = [(from.x, to.x), (from.y, to.y), (from.z, to.z)]
+    .iter()
+    .map(|&(min, max)| (i64::from(max) - i64::from(min) + 1) as u64)
+    .fold(1u64, u64::saturating_mul);
+  if volume > MAX_FILL_VOLUME as u64 {
+    return Err(CommandError::Failed(format!(
+      "Can't fill {} blocks at once, the maximum is {}",
+      volume, MAX_FILL_VOLUME
+    )));
+  }
+  let level = local_level(world)?;
+  world.exec(|mut blocks: BlockAccess<'_, u8, L>| blocks.fill(level, from, to, value));
+  Ok(format!("Filled {} blocks with {}", volume, value))
+}
+
+fn regenerate<L: ChunkLayout>(
+  world: &mut World,
+  args: &CommandArgs<'_>,
+) -> Result<String, CommandError> {
+  let pos = if args.is_empty() {
+    let (pos, _): (ChunkPos, Index<L>) = player_pos(world)?.into();
+    pos
+  } else {
+    args.expect(3)?;
+    ChunkPos::new(args.get(0)?, args.get(1)?, args.get(2)?)
+  };
+  let level = local_level(world)?;
+  let storage = {
+    let levels = world.read_storage::<Level>();
+    let level = (levels.get(level)).ok_or_else(|| CommandError::Failed("Not a level".into()))?;
+    level.generator.generate::<L>(level.seed, pos)
+  };
+  // Blocks are set one by one, so everything depending on them is updated as usual.
+  world.exec(|mut blocks: BlockAccess<'_, u8, L>| {
+    for raw in 0..L::SIZE as u32 {
+      let index = Index::<L>::from_raw(raw).unwrap();
+      blocks.set(level, (pos, index).into(), storage.get(index));
+    }
+  });
+  Ok(format!("Regenerated chunk {:?}", pos))
+}
+
+fn seed(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  args.expect(0)?;
+  let level = active_level(world)?;
+  let levels = world.read_storage::<Level>();
+  let level = (levels.get(level)).ok_or_else(|| CommandError::Failed("Not a level".into()))?;
+  Ok(format!("Seed: {}", level.seed))
+}
+
+fn stats<L: ChunkLayout>(
+  world: &mut World,
+  args: &CommandArgs<'_>,
+) -> Result<String, CommandError> {
+  args.expect(0)?;
+  let level = active_level(world)?;
+  let (chunks, entities) = world.exec(|(blocks, entities): (BlockView<'_, u8, L>, Entities)| {
+    (blocks.chunks(level).count(), entities.join().count())
+  });
+  let mut output = format!("Loaded chunks: {}\nEntities: {}", chunks, entities);
+  if let Some(stats) = world.try_fetch::<DebugStats>() {
+    output += &format!("\n{}", *stats);
+  }
+  Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{chunk::LinearLayout16, create_level},
+  };
+
+  type Blocks<'a> = BlockAccess<'a, u8, LinearLayout16>;
+
+  fn world() -> (World, Entity) {
+    let mut world = World::new();
+    Blocks::setup(&mut world);
+    world.register::<Level>();
+    world.register::<PlayerController>();
+    world.register::<Transform>();
+    let level = create_level(world.create_entity(), Level::new(1234));
+    world.insert(ActiveLevel(Some(level)));
+    world.insert(ConsoleCommands::builtin::<LinearLayout16>());
+    (world, level)
+  }
+
+  #[test]
+  fn parse_splits_words_and_quotes() {
+    assert_eq!(
+      parse_command("  tp 1  -2.5 3 ").unwrap(),
+      vec!["tp", "1", "-2.5", "3"]
+    );
+    assert_eq!(
+      parse_command(r#"say "hello world" "" x"#).unwrap(),
+      vec!["say", "hello world", "", "x"]
+    );
+    assert_eq!(parse_command("   ").unwrap(), Vec::<String>::new());
+    assert_eq!(
+      parse_command(r#"say "oops"#),
+      Err(CommandError::UnclosedQuote)
+    );
+  }
+
+  #[test]
+  fn wrong_commands_are_reported() {
+    let (mut world, _) = world();
+    assert_eq!(
+      run_command(&mut world, "teleport 1 2 3"),
+      Err(CommandError::UnknownCommand("teleport".to_string()))
+    );
+    assert_eq!(
+      run_command(&mut world, "tp 1 2"),
+      Err(CommandError::Usage("tp <x> <y> <z>".to_string()))
+    );
+    assert_eq!(
+      run_command(&mut world, "setblock 1 2 3 stone"),
+      Err(CommandError::InvalidArgument("stone".to_string()))
+    );
+    assert!(run_command(&mut world, "fill 0 0 0 1000 1000 1000 1").is_err());
+    assert_eq!(run_command(&mut world, "").unwrap(), "");
+  }
+
+  #[test]
+  fn builtin_commands_edit_level() {
+    let (mut world, level) = world();
+    run_command(&mut world, "setblock 1 -2 3 5").unwrap();
+    run_command(&mut world, "fill 4 4 4 0 0 0 2").unwrap();
+    world.exec(|blocks: Blocks<'_>| {
+      assert_eq!(blocks.get(level, BlockPos::new(1, -2, 3)), 5);
+      assert_eq!(blocks.get(level, BlockPos::new(0, 0, 0)), 2);
+      assert_eq!(blocks.get(level, BlockPos::new(4, 4, 4)), 2);
+      assert_eq!(blocks.get(level, BlockPos::new(5, 4, 4)), 0);
+    });
+    assert_eq!(run_command(&mut world, "seed").unwrap(), "Seed: 1234");
+
+    // Regenerating the chunk brings back the terrain it was generated with.
+    let expected = {
+      let levels = world.read_storage::<Level>();
+      let level = levels.get(level).unwrap();
+      (level.generator).generate::<LinearLayout16>(level.seed, ChunkPos::new(0, 0, 0))
+    };
+    run_command(&mut world, "regen 0 0 0").unwrap();
+    world.exec(|blocks: Blocks<'_>| {
+      for raw in 0..LinearLayout16::SIZE as u32 {
+        let index = Index::<LinearLayout16>::from_raw(raw).unwrap();
+        let pos = (ChunkPos::new(0, 0, 0), index).into();
+        assert_eq!(blocks.get(level, pos), expected.get(index));
+      }
+    });
+  }
+
+  #[test]
+  fn fill_rejects_huge_volumes() {
+    let (mut world, level) = world();
+    let max = i32::MAX;
+    for command in &[
+      format!("fill {} 0 0 {} 0 0 1", i32::MIN, max),
+      format!("fill 0 0 0 {} {} {} 1", max, max, max),
+      "fill 0 0 0 256 255 0 1".to_string(),
+    ] {
+      assert!(run_command(&mut world, command).is_err());
+    }
+    run_command(&mut world, "fill 0 0 0 255 255 0 1").unwrap();
+    world.exec(|blocks: Blocks<'_>| {
+      assert_eq!(blocks.get(level, BlockPos::new(255, 255, 0)), 1);
+      assert_eq!(blocks.get(level, BlockPos::new(256, 0, 0)), 0);
+    });
+  }
+
+  #[test]
+  fn teleport_moves_player() {
+    let (mut world, _) = world();
+    assert!(run_command(&mut world, "regen").is_err());
+    let player = (world.create_entity())
+      .with(PlayerController::default())
+      .with(Transform::default())
+      .build();
+    run_command(&mut world, "tp 10 -20.5 30").unwrap();
+    let transforms = world.read_storage::<Transform>();
+    let translation = transforms.get(player).unwrap().translation();
+    assert_eq!(*translation, Vector3::new(10.0, -20.5, 30.0));
+  }
+
+  #[test]
+  fn commands_can_be_registered() {
+    let (mut world, _) = world();
+    world.write_resource::<ConsoleCommands>().register(
+      "echo",
+      "echo <text>",
+      "Prints text",
+      |_, args| {
+        args.expect(1)?;
+        args.get::<String>(0)
+      },
+    );
+    execute_command(&mut world, r#"echo "hi there""#);
+    execute_command(&mut world, "echo");
+    let console = world.read_resource::<Console>();
+    assert_eq!(
+      console.output().collect::<Vec<_>>(),
+      vec![
+        r#"> echo "hi there""#,
+        "hi there",
+        "> echo",
+        "Usage: echo <text>"
+      ]
+    );
+  }
+
+  #[test]
+  fn typing_into_console() {
+    let mut console = Console::default();
+    for c in "tpx\u{8} 1 2 3".chars() {
+      assert_eq!(console.type_char(c), None);
+    }
+    assert_eq!(console.type_char('\r'), Some("tp 1 2 3".to_string()));
+    assert_eq!(console.input, "");
+    // Empty lines aren't entered.
+    assert_eq!(console.type_char('\r'), None);
+  }
+}
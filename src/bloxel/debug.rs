@@ -0,0 +1,615 @@
+use {
+  super::{
+    chunk::{
+      storage::{BlockData, ChunkStorage},
+      ChunkLayout, ChunkPos, ChunkState, DefaultLayout, Index,
+    },
+    ActiveLevel, BlockLight, BlockPos, CommandArgs, CommandError, MeshVertexCount, SkyLight,
+    OCTREE_DEPTH,
+  },
+  crate::util::{ChunkedOctree, ZOrder},
+  amethyst::{
+    assets::{AssetStorage, Loader},
+    controls::FlyControlTag,
+    core::{
+      math::{Point3, Vector3},
+      transform::Transform,
+      Time,
+    },
+    derive::SystemDesc,
+    ecs::prelude::*,
+    input::{InputEvent, StringBindings},
+    renderer::{debug_drawing::DebugLines, palette::Srgba},
+    shrev::EventChannel,
+    ui::{get_default_font, Anchor, FontAsset, LineMode, UiText, UiTransform},
+  },
+  std::{
+    collections::BTreeMap,
+    fmt,
+    marker::PhantomData,
+    time::{Duration, Instant},
+  },
+};
+
+/// Input action which shows and hides the debug overlay.
+pub const ACTION_TOGGLE_DEBUG: &str = "toggle_debug";
+/// Input action which shows and hides the boxes drawn by `ChunkBordersSystem`.
+pub const ACTION_TOGGLE_CHUNK_BORDERS: &str = "toggle_chunk_borders";
+
+/// How much a new sample contributes to the averaged FPS and system timings.
+const SMOOTHING: f32 = 0.05;
+
+/// Number of chunks in a level for each of the stages they go through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkStateCounts {
+  pub exists: usize,
+  pub generated: usize,
+  pub meshed: usize,
+}
+
+impl ChunkStateCounts {
+  pub fn count(octree: &ChunkedOctree<ChunkState>) -> Self {
+    let mut counts = ChunkStateCounts::default();
+    for (_, state) in octree.leaves() {
+      counts.exists += state.contains(ChunkState::EXISTS_ALL) as usize;
+      counts.generated += state.contains(ChunkState::GENERATED_ALL) as usize;
+      counts.meshed += state.contains(ChunkState::MESH_UPDATED_ALL) as usize;
+    }
+    counts
+  }
+}
+
+/// Resource holding the average time systems wrapped in `Timed` take to run, by name.
+#[derive(Debug, Default)]
+pub struct SystemTimings(BTreeMap<&'static str, Duration>);
+
+impl SystemTimings {
+  pub fn get(&self, name: &str) -> Option<Duration> {
+    self.0.get(name).copied()
+  }
+
+  pub fn record(&mut self, name: &'static str, duration: Duration) {
+    let average = self.0.entry(name).or_insert(duration);
+    *average = average.mul_f32(1.0 - SMOOTHING) + duration.mul_f32(SMOOTHING);
+  }
+}
+
+/// Wraps a system, recording how long it takes to run in the `SystemTimings` resource.
+pub struct Timed<S> {
+  name: &'static str,
+  system: S,
+}
+
+impl<S> Timed<S> {
+  pub fn new(name: &'static str, system: S) -> Self {
+    Timed { name, system }
+  }
+}
+
+impl<'a, S> System<'a> for Timed<S>
+where
+  S: System<'a>,
+  S::SystemData: SystemData<'a>,
+{
+  type SystemData = (S::SystemData, Write<'a, SystemTimings>);
+
+  fn run(&mut self, (data, mut timings): Self::SystemData) {
+    let start = Instant::now();
+    self.system.run(data);
+    timings.record(self.name, start.elapsed());
+  }
+
+  fn setup(&mut self, world: &mut World) {
+    <Write<'a, SystemTimings> as SystemData>::setup(world);
+    self.system.setup(world);
+  }
+}
+
+/// Resource holding statistics about the `ActiveLevel` and
+/// how it's being processed, updated by `DebugStatsSystem`.
+#[derive(Clone, Debug, Default)]
+pub struct DebugStats {
+  pub fps: f32,
+  /// Position of the camera marked with `FlyControlTag`.
+  pub camera: Option<(BlockPos, ChunkPos)>,
+  pub chunks: ChunkStateCounts,
+  /// Average time each system wrapped in `Timed` takes to run.
+  pub timings: BTreeMap<&'static str, Duration>,
+  /// Number of vertices of all chunk meshes.
+  pub vertices: usize,
+  /// Number of bytes used by the palette storages of blocks and light.
+  pub palette_memory: usize,
+}
+
+impl fmt::Display for DebugStats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "FPS: {:.0}", self.fps)?;
+    if let Some((block, chunk)) = self.camera {
+      writeln!(f, "Block: {} {} {}", block.x, block.y, block.z)?;
+      writeln!(f, "Chunk: {} {} {}", chunk.x, chunk.y, chunk.z)?;
+    }
+    let chunks = self.chunks;
+    writeln!(
+      f,
+      "Chunks: {} exist, {} generated, {} meshed",
+      chunks.exists, chunks.generated, chunks.meshed
+    )?;
+    for (name, duration) in &self.timings {
+      writeln!(f, "{}: {:.2} ms", name, duration.as_secs_f64() * 1000.0)?;
+    }
+    writeln!(f, "Vertices: {}", self.vertices)?;
+    write!(
+      f,
+      "Palette memory: {:.1} KiB",
+      self.palette_memory as f64 / 1024.0
+    )
+  }
+}
+
+/// Collects the `DebugStats` each frame.
+pub struct DebugStatsSystem<L: ChunkLayout = DefaultLayout>(PhantomData<L>);
+
+impl<L: ChunkLayout> Default for DebugStatsSystem<L> {
+  fn default() -> Self {
+    DebugStatsSystem(PhantomData)
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for DebugStatsSystem<L> {
+  type SystemData = (
+    Read<'a, Time>,
+    Read<'a, ActiveLevel>,
+    Read<'a, SystemTimings>,
+    Write<'a, DebugStats>,
+    ReadStorage<'a, FlyControlTag>,
+    ReadStorage<'a, Transform>,
+    ReadStorage<'a, ChunkedOctree<ChunkState>>,
+    ReadStorage<'a, MeshVertexCount>,
+    ReadStorage<'a, ChunkStorage<u8, L>>,
+    ReadStorage<'a, ChunkStorage<BlockLight, L>>,
+    ReadStorage<'a, ChunkStorage<SkyLight, L>>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      time,
+      active_level,
+      timings,
+      mut stats,
+      tags,
+      transforms,
+      octrees,
+      vertex_counts,
+      blocks,
+      block_lights,
+      sky_lights,
+    ): Self::SystemData,
+  ) {
+    let delta = time.delta_real_seconds();
+    if delta > 0.0 {
+      stats.fps = match stats.fps {
+        fps if fps > 0.0 => fps * (1.0 - SMOOTHING) + SMOOTHING / delta,
+        _ => 1.0 / delta,
+      };
+    }
+
+    stats.camera = (&tags, &transforms).join().next().map(|(_, transform)| {
+      let translation = transform.translation();
+      let pos = BlockPos::new(
+        translation.x.floor() as i32,
+        translation.y.floor() as i32,
+        translation.z.floor() as i32,
+      );
+      let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+      (pos, chunk_pos)
+    });
+
+    stats.chunks = (active_level.0)
+      .and_then(|level| octrees.get(level))
+      .map(ChunkStateCounts::count)
+      .unwrap_or_default();
+    stats.timings = timings.0.clone();
+    stats.vertices = vertex_counts.join().map(|count| count.0).sum();
+    stats.palette_memory =
+      total_heap_size(&blocks) + total_heap_size(&block_lights) + total_heap_size(&sky_lights);
+  }
+}
+
+fn total_heap_size<T: BlockData, L: ChunkLayout>(
+  storages: &ReadStorage<'_, ChunkStorage<T, L>>,
+) -> usize {
+  storages.join().map(ChunkStorage::heap_size).sum()
+}
+
+/// Resource holding the entity which displays the `DebugStats`, see `create_debug_overlay`.
+pub struct DebugOverlay {
+  pub text: Entity,
+  pub visible: bool,
+}
+
+/// Creates the text element the debug overlay is displayed with, inserting the `DebugOverlay`.
+pub fn create_debug_overlay(world: &mut World) {
+  let font = world.exec(
+    |(loader, fonts): (ReadExpect<Loader>, Read<AssetStorage<FontAsset>>)| {
+      get_default_font(&loader, &fonts)
+    },
+  );
+  let transform = UiTransform::new(
+    "debug_overlay".to_string(),
+    Anchor::TopRight,
+    Anchor::TopRight,
+    -8.0,
+    -8.0,
+    1.0,
+    400.0,
+    300.0,
+  );
+  let text = UiText::new(
+    font,
+    String::new(),
+    [1.0, 1.0, 1.0, 1.0],
+    16.0,
+    LineMode::Wrap,
+    Anchor::TopRight,
+  );
+  let text = world.create_entity().with(transform).with(text).build();
+  world.insert(DebugOverlay {
+    text,
+    visible: false,
+  });
+}
+
+/// Shows the `DebugStats` while the `DebugOverlay` is visible,
+/// toggled by the "toggle_debug" action.
+#[derive(SystemDesc)]
+#[system_desc(name(DebugOverlaySystemDesc))]
+pub struct DebugOverlaySystem {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<InputEvent<StringBindings>>,
+}
+
+impl DebugOverlaySystem {
+  pub fn new(reader: ReaderId<InputEvent<StringBindings>>) -> Self {
+    Self { reader }
+  }
+}
+
+impl<'a> System<'a> for DebugOverlaySystem {
+  type SystemData = (
+    Read<'a, EventChannel<InputEvent<StringBindings>>>,
+    Read<'a, DebugStats>,
+    Option<Write<'a, DebugOverlay>>,
+    WriteStorage<'a, UiText>,
+  );
+
+  fn run(&mut self, (events, stats, overlay, mut texts): Self::SystemData) {
+    let toggle = events
+      .read(&mut self.reader)
+      .filter(|event| match event {
+        InputEvent::ActionPressed(action) => action == ACTION_TOGGLE_DEBUG,
+        _ => false,
+      })
+      .count()
+      % 2
+      == 1;
+
+    let mut overlay = match overlay {
+      Some(overlay) => overlay,
+      None => return,
+    };
+    if toggle {
+      overlay.visible = !overlay.visible;
+    }
+    if let Some(text) = texts.get_mut(overlay.text) {
+      text.text = if overlay.visible {
+        stats.to_string()
+      } else {
+        String::new()
+      };
+    }
+  }
+}
+
+/// A wireframe box to be drawn using `DebugLines`, in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DebugBox {
+  pub min: Point3<f32>,
+  pub max: Point3<f32>,
+  pub color: Srgba,
+}
+
+/// Returns the color octree nodes with the specified state are drawn with, from red for nodes
+/// that merely exist through yellow for generated ones to green once they've been meshed.
+/// Nodes which are only partially generated are orange, and those that don't exist are grey.
+pub fn chunk_state_color(state: ChunkState) -> Srgba {
+  if state.contains(ChunkState::MESH_UPDATED_ALL) {
+    Srgba::new(0.0, 1.0, 0.0, 1.0)
+  } else if state.contains(ChunkState::GENERATED_ALL) {
+    Srgba::new(1.0, 1.0, 0.0, 1.0)
+  } else if state.intersects(ChunkState::GENERATED_SOME) {
+    Srgba::new(1.0, 0.5, 0.0, 1.0)
+  } else if state.intersects(ChunkState::EXISTS_SOME) {
+    Srgba::new(1.0, 0.0, 0.0, 1.0)
+  } else {
+    Srgba::new(0.5, 0.5, 0.5, 0.5)
+  }
+}
+
+/// Creates boxes outlining the nodes of the octree at the specified level, where level `0` is
+/// single chunks, colored by their state. Boxes are created for all nodes within `radius` nodes
+/// in each direction of the one containing the chunk at `center`.
+pub fn octree_node_boxes<L: ChunkLayout>(
+  octree: &ChunkedOctree<ChunkState>,
+  level: u8,
+  center: ChunkPos,
+  radius: i32,
+) -> Vec<DebugBox> {
+  let size = (L::LENGTH << level) as f32;
+  let (cx, cy, cz) = (center.x >> level, center.y >> level, center.z >> level);
+  let mut boxes = Vec::new();
+  for x in cx - radius..=cx + radius {
+    for y in cy - radius..=cy + radius {
+      for z in cz - radius..=cz + radius {
+        let node_pos = match ZOrder::new(x, y, z) {
+          Some(node_pos) => node_pos,
+          None => continue,
+        };
+        let min = Point3::new(x as f32 * size, y as f32 * size, z as f32 * size);
+        boxes.push(DebugBox {
+          min,
+          max: min + Vector3::repeat(size),
+          color: chunk_state_color(octree.get(level, node_pos)),
+        });
+      }
+    }
+  }
+  boxes
+}
+
+/// Resource controlling the boxes `ChunkBordersSystem` draws around the camera.
+#[derive(Clone, Debug)]
+pub struct ChunkBorders {
+  pub visible: bool,
+  /// Level of the octree nodes which are outlined, where level `0` is single chunks.
+  pub level: u8,
+  /// Number of nodes outlined in each direction from the one containing the camera.
+  pub radius: i32,
+}
+
+impl Default for ChunkBorders {
+  fn default() -> Self {
+    ChunkBorders {
+      visible: false,
+      level: 0,
+      radius: 1,
+    }
+  }
+}
+
+/// Console command which toggles the `ChunkBorders`, or shows the nodes at the specified level.
+pub fn chunk_borders_command(
+  world: &mut World,
+  args: &CommandArgs<'_>,
+) -> Result<String, CommandError> {
+  let mut borders = world
+    .entry::<ChunkBorders>()
+    .or_insert_with(Default::default);
+  if args.is_empty() {
+    borders.visible = !borders.visible;
+  } else {
+    args.expect(1)?;
+    let level = args.get::<u8>(0)?;
+    if level > OCTREE_DEPTH {
+      return Err(CommandError::Failed(format!(
+        "Octree levels only go up to {}",
+        OCTREE_DEPTH
+      )));
+    }
+    borders.level = level;
+    borders.visible = true;
+  }
+  if borders.visible {
+    Ok(format!("Showing octree nodes at level {}", borders.level))
+  } else {
+    Ok("Hiding octree nodes".to_string())
+  }
+}
+
+/// Outlines the chunks, or larger octree nodes, around the camera marked with `FlyControlTag`
+/// in the `ActiveLevel` while `ChunkBorders` are visible, toggled by "toggle_chunk_borders".
+#[derive(SystemDesc)]
+#[system_desc(name(ChunkBordersSystemDesc))]
+pub struct ChunkBordersSystem<L: ChunkLayout = DefaultLayout> {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<InputEvent<StringBindings>>,
+  #[system_desc(skip)]
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> ChunkBordersSystem<L> {
+  pub fn new(reader: ReaderId<InputEvent<StringBindings>>) -> Self {
+    Self {
+      reader,
+      layout: PhantomData,
+    }
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for ChunkBordersSystem<L> {
+  type SystemData = (
+    Read<'a, EventChannel<InputEvent<StringBindings>>>,
+    Read<'a, ActiveLevel>,
+    Write<'a, ChunkBorders>,
+    Write<'a, DebugLines>,
+    ReadStorage<'a, FlyControlTag>,
+    ReadStorage<'a, Transform>,
+    ReadStorage<'a, ChunkedOctree<ChunkState>>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      events,
+      active_level,
+      mut borders,
+      mut debug_lines,
+      tags,
+      transforms,
+      octrees,
+    ): Self::SystemData,
+  ) {
+    let toggle = events
+      .read(&mut self.reader)
+      .filter(|event| match event {
+        InputEvent::ActionPressed(action) => action == ACTION_TOGGLE_CHUNK_BORDERS,
+        _ => false,
+      })
+      .count()
+      % 2
+      == 1;
+    if toggle {
+      borders.visible = !borders.visible;
+    }
+    if !borders.visible {
+      return;
+    }
+
+    let octree = match active_level.0.and_then(|level| octrees.get(level)) {
+      Some(octree) => octree,
+      None => return,
+    };
+    let center = match (&tags, &transforms).join().next() {
+      Some((_, transform)) => {
+        let translation = transform.translation();
+        let pos = BlockPos::new(
+          translation.x.floor() as i32,
+          translation.y.floor() as i32,
+          translation.z.floor() as i32,
+        );
+        let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+        chunk_pos
+      }
+      None => return,
+    };
+    let level = borders.level.min(octree.depth());
+    for node in octree_node_boxes::<L>(octree, level, center, borders.radius) {
+      debug_lines.draw_box(node.min, node.max, node.color);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{
+      chunk::LinearLayout16, create_level, mark_chunk_generated, BlockAccess, BlockView, Level,
+    },
+  };
+
+  #[test]
+  fn node_boxes_are_colored_by_state() {
+    let mut octree = ChunkedOctree::<ChunkState>::new(OCTREE_DEPTH);
+    mark_chunk_generated(&mut octree, ZOrder::new(0, 0, 0).unwrap());
+
+    let boxes = octree_node_boxes::<LinearLayout16>(&octree, 0, ChunkPos::new(0, 0, 0), 1);
+    assert_eq!(boxes.len(), 27);
+    let generated = (boxes.iter())
+      .find(|node| node.min == Point3::new(0.0, 0.0, 0.0))
+      .unwrap();
+    assert_eq!(generated.max, Point3::new(16.0, 16.0, 16.0));
+    assert_eq!(
+      generated.color,
+      chunk_state_color(ChunkState::GENERATED_ALL)
+    );
+    let empty = (boxes.iter())
+      .find(|node| node.min == Point3::new(-16.0, 0.0, 16.0))
+      .unwrap();
+    assert_eq!(empty.color, chunk_state_color(ChunkState::empty()));
+
+    // The node at level 1 containing the chunk is only partially generated.
+    let boxes = octree_node_boxes::<LinearLayout16>(&octree, 1, ChunkPos::new(1, 1, 0), 0);
+    assert_eq!(
+      boxes,
+      vec![DebugBox {
+        min: Point3::new(0.0, 0.0, 0.0),
+        max: Point3::new(32.0, 32.0, 32.0),
+        color: chunk_state_color(ChunkState::GENERATED_SOME),
+      }]
+    );
+    let boxes = octree_node_boxes::<LinearLayout16>(&octree, 1, ChunkPos::new(-1, 0, 0), 0);
+    assert_eq!(boxes[0].min, Point3::new(-32.0, 0.0, 0.0));
+    assert_eq!(boxes[0].color, chunk_state_color(ChunkState::empty()));
+  }
+
+  #[test]
+  fn counts_chunk_states() {
+    let mut octree = ChunkedOctree::<ChunkState>::new(2);
+    let states = [
+      ChunkState::EXISTS_ALL,
+      ChunkState::EXISTS_ALL | ChunkState::GENERATED_ALL,
+      ChunkState::EXISTS_ALL | ChunkState::GENERATED_ALL | ChunkState::MESH_UPDATED_ALL,
+    ];
+    for (i, state) in states.iter().enumerate() {
+      let pos = ZOrder::new(i as i32 * 10, 0, 0).unwrap();
+      octree.update(pos, |value| *value = *state, |_, _, _| false);
+    }
+    assert_eq!(
+      ChunkStateCounts::count(&octree),
+      ChunkStateCounts {
+        exists: 3,
+        generated: 2,
+        meshed: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn timed_systems_are_recorded() {
+    struct Sleep;
+    impl<'a> System<'a> for Sleep {
+      type SystemData = ();
+      fn run(&mut self, _: ()) {
+        std::thread::sleep(Duration::from_millis(2));
+      }
+    }
+
+    let mut world = World::new();
+    let mut system = Timed::new("sleep", Sleep);
+    System::setup(&mut system, &mut world);
+    system.run_now(&world);
+    let timing = world.read_resource::<SystemTimings>().get("sleep");
+    assert!(timing.unwrap() >= Duration::from_millis(2));
+    assert_eq!(world.read_resource::<SystemTimings>().get("other"), None);
+  }
+
+  #[test]
+  fn stats_are_collected_headlessly() {
+    let mut world = World::new();
+    BlockAccess::<u8, LinearLayout16>::setup(&mut world);
+    world.register::<Level>();
+    let mut system = DebugStatsSystem::<LinearLayout16>::default();
+    System::setup(&mut system, &mut world);
+    let level = create_level(world.create_entity(), Level::new(0));
+    world.insert(ActiveLevel(Some(level)));
+
+    world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.set(level, BlockPos::new(0, 0, 0), 1);
+      blocks.set(level, BlockPos::new(100, 0, 0), 2);
+    });
+    let chunk = world.exec(|blocks: BlockView<'_, u8, LinearLayout16>| {
+      blocks.chunk(level, ChunkPos::new(0, 0, 0)).unwrap()
+    });
+    (world.write_storage::<MeshVertexCount>())
+      .insert(chunk, MeshVertexCount(24))
+      .unwrap();
+
+    system.run_now(&world);
+    let stats = world.read_resource::<DebugStats>();
+    assert_eq!(stats.chunks.exists, 2);
+    assert_eq!(stats.vertices, 24);
+    assert!(stats.palette_memory > 0);
+    assert!(stats.to_string().contains("Chunks: 2 exist"));
+  }
+}
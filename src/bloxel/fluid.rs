@@ -0,0 +1,625 @@
+use {
+  super::{
+    chunk::{storage::*, *},
+    chunk_loader_positions,
+    net::NetworkClient,
+    BlockAccess, BlockChange, BlockPos, ChunkLoader, Facing,
+  },
+  amethyst::{
+    core::{transform::Transform, Time},
+    derive::SystemDesc,
+    ecs::prelude::*,
+    shrev::EventChannel,
+  },
+  serde::{Deserialize, Serialize},
+  std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+  },
+};
+
+/// Name `FluidLevel` is registered under with `ChunkLayers`, for the `LayerChange`s sent.
+pub const FLUID_LEVEL_LAYER: &str = "fluid_levels";
+
+/// Fill level of a block completely filled with fluid.
+pub const MAX_FLUID_LEVEL: u8 = 8;
+
+/// Time in seconds between ticks of `FluidSystem`.
+const TICK_SECONDS: f32 = 0.1;
+
+/// Types of fluid. Each fluid is a block type, with how much of the block
+/// is filled stored separately in a `ChunkStorage<FluidLevel>` layer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Fluid {
+  Water,
+  Lava,
+}
+
+impl Fluid {
+  pub const ALL: [Fluid; 2] = [Fluid::Water, Fluid::Lava];
+
+  pub fn from_block(block: u8) -> Option<Self> {
+    match block {
+      2 => Some(Fluid::Water),
+      3 => Some(Fluid::Lava),
+      _ => None,
+    }
+  }
+
+  pub fn block(self) -> u8 {
+    match self {
+      Fluid::Water => 2,
+      Fluid::Lava => 3,
+    }
+  }
+
+  /// Number of `FluidSystem` ticks between updates of this fluid.
+  pub fn ticks_per_update(self) -> u64 {
+    match self {
+      Fluid::Water => 2,
+      Fluid::Lava => 6,
+    }
+  }
+
+  /// Fill level below which this fluid stops spreading sideways.
+  pub fn min_spread_level(self) -> u8 {
+    match self {
+      Fluid::Water => 2,
+      Fluid::Lava => 3,
+    }
+  }
+
+  /// Color and opacity this fluid is rendered with.
+  pub fn color(self) -> [f32; 4] {
+    match self {
+      Fluid::Water => [0.2, 0.4, 0.9, 0.6],
+      Fluid::Lava => [0.9, 0.35, 0.05, 0.9],
+    }
+  }
+}
+
+/// Returns whether the specified block is solid, which is every block except air and fluids.
+pub fn is_solid(block: u8) -> bool {
+  block != 0 && Fluid::from_block(block).is_none()
+}
+
+/// How much of a fluid block is filled, from `1` to `MAX_FLUID_LEVEL`, stored per chunk as
+/// `ChunkStorage<FluidLevel>`. Fluid blocks without a level, such as ones that were just placed,
+/// are completely filled. The level of blocks that aren't fluids is always zero, which
+/// `FluidSystem` ensures by clearing the level of fluid blocks replaced by another block.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct FluidLevel(pub u8);
+
+/// Access to the blocks and fluid levels of a level, as required for simulating fluids.
+pub trait FluidAccess {
+  /// Returns whether fluid may flow into the specified position.
+  fn is_loaded(&self, pos: BlockPos) -> bool;
+
+  fn block(&self, pos: BlockPos) -> u8;
+
+  fn level(&self, pos: BlockPos) -> FluidLevel;
+
+  fn set(&mut self, pos: BlockPos, block: u8, level: FluidLevel);
+}
+
+/// Gets the fluid at the specified position and its fill level, if there is any.
+pub fn fluid_at<A: FluidAccess>(access: &A, pos: BlockPos) -> Option<(Fluid, u8)> {
+  Fluid::from_block(access.block(pos)).map(|fluid| match access.level(pos).0 {
+    0 => (fluid, MAX_FLUID_LEVEL),
+    level => (fluid, level),
+  })
+}
+
+/// What happens when a fluid tries to flow into a block.
+enum FlowTarget {
+  /// The block is air or the same fluid with the specified level.
+  Fill(u8),
+  /// The block is a different fluid, which turns solid on contact.
+  Solidify,
+  Blocked,
+}
+
+fn flow_target<A: FluidAccess>(access: &A, pos: BlockPos, fluid: Fluid) -> FlowTarget {
+  if !access.is_loaded(pos) {
+    return FlowTarget::Blocked;
+  }
+  match access.block(pos) {
+    0 => FlowTarget::Fill(0),
+    _ => match fluid_at(access, pos) {
+      Some((other, level)) if other == fluid => FlowTarget::Fill(level),
+      Some(_) => FlowTarget::Solidify,
+      None => FlowTarget::Blocked,
+    },
+  }
+}
+
+/// Moves up to `max_flow` of the fluid at `level` into `target`, returning whether it changed.
+fn flow<A: FluidAccess>(
+  access: &mut A,
+  fluid: Fluid,
+  level: &mut u8,
+  target: BlockPos,
+  max_flow: u8,
+) -> bool {
+  match flow_target(access, target, fluid) {
+    FlowTarget::Fill(existing) => {
+      let amount = max_flow.min(*level).min(MAX_FLUID_LEVEL - existing);
+      if amount > 0 {
+        access.set(target, fluid.block(), FluidLevel(existing + amount));
+        *level -= amount;
+      }
+      amount > 0
+    }
+    FlowTarget::Solidify => {
+      access.set(target, 1, FluidLevel(0));
+      true
+    }
+    FlowTarget::Blocked => false,
+  }
+}
+
+/// Runs a single tick of the fluid simulation for each of the specified positions, a cellular
+/// automaton that moves fluid downwards first, then evens out its level with horizontal
+/// neighbours. Returns the positions which need to be updated during the next tick.
+pub fn update_fluids<A: FluidAccess>(
+  access: &mut A,
+  positions: &[BlockPos],
+  tick: u64,
+) -> HashSet<BlockPos> {
+  let mut next = HashSet::new();
+  for &pos in positions {
+    let (fluid, mut level) = match fluid_at(access, pos) {
+      Some(fluid) => fluid,
+      None => continue,
+    };
+    if tick % fluid.ticks_per_update() != 0 {
+      next.insert(pos);
+      continue;
+    }
+
+    let mut changed = Vec::new();
+    let original = level;
+    let below = pos + Facing::Down;
+    if flow(access, fluid, &mut level, below, MAX_FLUID_LEVEL) {
+      changed.push(below);
+    }
+    for facing in Facing::iter_horizontal() {
+      if level < fluid.min_spread_level() {
+        break;
+      }
+      let target = pos + facing;
+      // Only flow towards blocks with at least two levels less, so fluid settles.
+      let lower = match flow_target(access, target, fluid) {
+        FlowTarget::Fill(existing) => existing + 1 < level,
+        _ => true,
+      };
+      if lower && flow(access, fluid, &mut level, target, 1) {
+        changed.push(target);
+      }
+    }
+
+    if level != original {
+      match level {
+        0 => access.set(pos, 0, FluidLevel(0)),
+        _ => access.set(pos, fluid.block(), FluidLevel(level)),
+      }
+      changed.push(pos);
+    }
+    for pos in changed {
+      next.insert(pos);
+      next.extend(Facing::iter_all().map(|facing| pos + facing));
+    }
+  }
+  next
+}
+
+/// Implements `FluidAccess` on top of the chunks of a level.
+struct ChunkFluidAccess<'s, 'a, L: ChunkLayout> {
+  level: Entity,
+  blocks: &'s mut BlockAccess<'a, u8, L>,
+  levels: &'s mut WriteStorage<'a, ChunkStorage<FluidLevel, L>>,
+  layer_changes: &'s mut EventChannel<LayerChange>,
+}
+
+impl<'s, 'a, L: ChunkLayout> FluidAccess for ChunkFluidAccess<'s, 'a, L> {
+  fn is_loaded(&self, pos: BlockPos) -> bool {
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    self.blocks.chunk(self.level, chunk_pos).is_some()
+  }
+
+  fn block(&self, pos: BlockPos) -> u8 {
+    self.blocks.get(self.level, pos)
+  }
+
+  fn level(&self, pos: BlockPos) -> FluidLevel {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    self
+      .blocks
+      .chunk(self.level, chunk_pos)
+      .and_then(|entity| self.levels.get(entity))
+      .map_or(FluidLevel(0), |storage| storage.get(index))
+  }
+
+  fn set(&mut self, pos: BlockPos, block: u8, level: FluidLevel) {
+    self.blocks.set(self.level, pos, block);
+    self.set_level(pos, level);
+  }
+}
+
+impl<'s, 'a, L: ChunkLayout> ChunkFluidAccess<'s, 'a, L> {
+  /// Sets the fill level at the specified position without changing its block.
+  fn set_level(&mut self, pos: BlockPos, level: FluidLevel) {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    let entity = match self.blocks.chunk(self.level, chunk_pos) {
+      Some(entity) => entity,
+      None => return,
+    };
+    if self.levels.get(entity).is_none() {
+      if level == FluidLevel(0) {
+        return;
+      }
+      let storage = ChunkStorage::new(PaletteStorageImpl::new());
+      self.levels.insert(entity, storage).unwrap();
+    }
+    let storage = self.levels.get_mut(entity).unwrap();
+    if storage.get(index) != level {
+      storage.set(index, level);
+      // The block itself may not have changed, but its mesh did.
+      self.blocks.mark_dirty(self.level, chunk_pos);
+      self.layer_changes.single_write(LayerChange {
+        level: self.level,
+        pos: chunk_pos,
+        layer: FLUID_LEVEL_LAYER,
+      });
+    }
+  }
+}
+
+/// Simulates fluids in chunks near any `ChunkLoader` at a fixed tick rate. Only blocks which
+/// changed recently, or are next to ones that did, are updated. Updates in chunks away from
+/// loaders are postponed until a loader comes close enough. While a `NetworkClient` exists,
+/// fluids are left to the server, which sends the changes they cause.
+#[derive(SystemDesc)]
+#[system_desc(name(FluidSystemDesc))]
+pub struct FluidSystem<L: ChunkLayout = DefaultLayout> {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<BlockChange>,
+  /// Positions to update during the next tick, for each level.
+  #[system_desc(skip)]
+  pending: HashMap<Entity, HashSet<BlockPos>>,
+  #[system_desc(skip)]
+  tick: u64,
+  /// Time accumulated towards the next tick, in seconds.
+  #[system_desc(skip)]
+  elapsed: f32,
+  #[system_desc(skip)]
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> FluidSystem<L> {
+  pub fn new(reader: ReaderId<BlockChange>) -> Self {
+    Self {
+      reader,
+      pending: HashMap::new(),
+      tick: 0,
+      elapsed: 0.0,
+      layout: PhantomData,
+    }
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for FluidSystem<L> {
+  type SystemData = (
+    Read<'a, Time>,
+    ReadStorage<'a, ChunkLoader>,
+    ReadStorage<'a, Transform>,
+    WriteStorage<'a, ChunkStorage<FluidLevel, L>>,
+    BlockAccess<'a, u8, L>,
+    Write<'a, EventChannel<LayerChange>>,
+    Option<Read<'a, NetworkClient>>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      time,
+      loaders,
+      transforms,
+      mut levels,
+      mut blocks,
+      mut layer_changes,
+      client,
+    ): Self::SystemData,
+  ) {
+    let changes = (blocks.read_changes(&mut self.reader).copied()).collect::<Vec<_>>();
+    if client.is_some() {
+      self.pending.clear();
+      return;
+    }
+    // Changing a block may allow fluids around it to flow, or itself be a fluid.
+    for change in changes {
+      // A fluid's level doesn't apply to whatever replaced it.
+      if Fluid::from_block(change.previous).is_some() && change.value != change.previous {
+        let mut access = ChunkFluidAccess {
+          level: change.level,
+          blocks: &mut blocks,
+          levels: &mut levels,
+          layer_changes: &mut layer_changes,
+        };
+        access.set_level(change.pos, FluidLevel(0));
+      }
+      let pending = self.pending.entry(change.level).or_default();
+      pending.insert(change.pos);
+      pending.extend(Facing::iter_all().map(|facing| change.pos + facing));
+    }
+
+    self.elapsed += time.delta_seconds();
+    if self.elapsed < TICK_SECONDS {
+      return;
+    }
+    // Skip ticks rather than catching up, which could make slow frames even slower.
+    self.elapsed = self.elapsed.min(TICK_SECONDS * 2.0) - TICK_SECONDS;
+    self.tick += 1;
+
+    let loaders = chunk_loader_positions(&loaders, &transforms);
+    let is_active = |pos: BlockPos| {
+      let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+      loaders
+        .iter()
+        .any(|(loader, center)| loader.contains::<L>(*center, chunk_pos))
+    };
+
+    for (&level, pending) in self.pending.iter_mut() {
+      let (mut active, inactive): (Vec<_>, Vec<_>) =
+        pending.drain().partition(|&pos| is_active(pos));
+      pending.extend(inactive);
+      // Update from the bottom up, so falling fluid moves as one.
+      active.sort_by_key(|pos| (pos.y, pos.x, pos.z));
+
+      let mut access = ChunkFluidAccess {
+        level,
+        blocks: &mut blocks,
+        levels: &mut levels,
+        layer_changes: &mut layer_changes,
+      };
+      pending.extend(update_fluids(&mut access, &active, self.tick));
+    }
+    self.pending.retain(|_, pending| !pending.is_empty());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{create_level, Level},
+  };
+
+  /// Fluid access over a region in which positions below `y = 0` are solid.
+  #[derive(Default)]
+  struct TestAccess {
+    blocks: HashMap<BlockPos, (u8, u8)>,
+  }
+
+  impl FluidAccess for TestAccess {
+    fn is_loaded(&self, _pos: BlockPos) -> bool {
+      true
+    }
+
+    fn block(&self, pos: BlockPos) -> u8 {
+      match pos.y {
+        y if y < 0 => 1,
+        _ => self.blocks.get(&pos).map_or(0, |(block, _)| *block),
+      }
+    }
+
+    fn level(&self, pos: BlockPos) -> FluidLevel {
+      FluidLevel(self.blocks.get(&pos).map_or(0, |(_, level)| *level))
+    }
+
+    fn set(&mut self, pos: BlockPos, block: u8, level: FluidLevel) {
+      self.blocks.insert(pos, (block, level.0));
+    }
+  }
+
+  impl TestAccess {
+    /// Runs ticks until nothing changes anymore, returning how many it took.
+    fn settle(&mut self, start: BlockPos) -> u64 {
+      let mut pending = vec![start];
+      for tick in 0..1000 {
+        if pending.is_empty() {
+          return tick;
+        }
+        pending.sort_by_key(|pos| (pos.y, pos.x, pos.z));
+        pending = update_fluids(self, &pending, tick).into_iter().collect();
+      }
+      panic!("fluid didn't settle");
+    }
+
+    fn total(&self, fluid: Fluid) -> u32 {
+      let positions = self.blocks.keys().copied().collect::<Vec<_>>();
+      positions
+        .into_iter()
+        .filter_map(|pos| fluid_at(self, pos))
+        .filter(|(f, _)| *f == fluid)
+        .map(|(_, level)| level as u32)
+        .sum()
+    }
+  }
+
+  #[test]
+  fn placed_fluid_is_full() {
+    let mut access = TestAccess::default();
+    access.set(BlockPos::new(0, 0, 0), Fluid::Water.block(), FluidLevel(0));
+    let fluid = fluid_at(&access, BlockPos::new(0, 0, 0));
+    assert_eq!(fluid, Some((Fluid::Water, MAX_FLUID_LEVEL)));
+    assert_eq!(fluid_at(&access, BlockPos::new(0, 1, 0)), None);
+  }
+
+  #[test]
+  fn fluid_falls_down() {
+    let mut access = TestAccess::default();
+    access.set(BlockPos::new(0, 5, 0), Fluid::Water.block(), FluidLevel(0));
+    let next = update_fluids(&mut access, &[BlockPos::new(0, 5, 0)], 0);
+    assert_eq!(fluid_at(&access, BlockPos::new(0, 5, 0)), None);
+    assert_eq!(
+      fluid_at(&access, BlockPos::new(0, 4, 0)),
+      Some((Fluid::Water, MAX_FLUID_LEVEL))
+    );
+    assert!(next.contains(&BlockPos::new(0, 4, 0)));
+  }
+
+  #[test]
+  fn fluid_spreads_and_settles_without_losing_volume() {
+    let mut access = TestAccess::default();
+    access.set(BlockPos::new(0, 3, 0), Fluid::Water.block(), FluidLevel(0));
+    access.settle(BlockPos::new(0, 3, 0));
+
+    assert_eq!(access.total(Fluid::Water), MAX_FLUID_LEVEL as u32);
+    let levels = access
+      .blocks
+      .iter()
+      .filter(|(_, (block, _))| *block == Fluid::Water.block())
+      .map(|(pos, (_, level))| (*pos, *level))
+      .collect::<Vec<_>>();
+    assert!(levels.len() > 1);
+    for (pos, level) in levels {
+      assert_eq!(pos.y, 0);
+      assert!(level < Fluid::Water.min_spread_level() + 1);
+    }
+  }
+
+  #[test]
+  fn lava_updates_less_often() {
+    let mut access = TestAccess::default();
+    access.set(BlockPos::new(0, 5, 0), Fluid::Lava.block(), FluidLevel(0));
+    let next = update_fluids(&mut access, &[BlockPos::new(0, 5, 0)], 1);
+    assert_eq!(
+      next.into_iter().collect::<Vec<_>>(),
+      vec![BlockPos::new(0, 5, 0)]
+    );
+    assert!(fluid_at(&access, BlockPos::new(0, 5, 0)).is_some());
+  }
+
+  #[test]
+  fn different_fluids_solidify() {
+    let mut access = TestAccess::default();
+    access.set(BlockPos::new(0, 1, 0), Fluid::Water.block(), FluidLevel(0));
+    access.set(BlockPos::new(0, 0, 0), Fluid::Lava.block(), FluidLevel(0));
+    update_fluids(&mut access, &[BlockPos::new(0, 1, 0)], 0);
+    assert_eq!(access.block(BlockPos::new(0, 0, 0)), 1);
+    assert_eq!(access.block(BlockPos::new(0, 1, 0)), Fluid::Water.block());
+  }
+
+  #[test]
+  fn level_changes_are_announced() {
+    let mut world = World::new();
+    BlockAccess::<u8, LinearLayout16>::setup(&mut world);
+    world.register::<ChunkStorage<FluidLevel, LinearLayout16>>();
+    world.register::<Level>();
+    let level = create_level(world.create_entity(), Level::new(0));
+    let mut reader = world
+      .entry::<EventChannel<LayerChange>>()
+      .or_insert_with(Default::default)
+      .register_reader();
+
+    let pos = BlockPos::new(1, 2, -3);
+    {
+      let (mut blocks, mut levels, mut layer_changes) = world.system_data::<(
+        BlockAccess<'_, u8, LinearLayout16>,
+        WriteStorage<'_, ChunkStorage<FluidLevel, LinearLayout16>>,
+        Write<'_, EventChannel<LayerChange>>,
+      )>();
+      let mut access = ChunkFluidAccess {
+        level,
+        blocks: &mut blocks,
+        levels: &mut levels,
+        layer_changes: &mut layer_changes,
+      };
+      access.set(pos, Fluid::Water.block(), FluidLevel(3));
+      // Setting the same level again doesn't change anything.
+      access.set(pos, Fluid::Water.block(), FluidLevel(3));
+    }
+    let changes = world
+      .read_resource::<EventChannel<LayerChange>>()
+      .read(&mut reader)
+      .copied()
+      .collect::<Vec<_>>();
+    assert_eq!(
+      changes,
+      vec![LayerChange {
+        level,
+        pos: ChunkPos::new(0, 0, -1),
+        layer: FLUID_LEVEL_LAYER,
+      }]
+    );
+  }
+
+  #[test]
+  fn replaced_fluid_loses_its_level() {
+    let mut world = World::new();
+    BlockAccess::<u8, LinearLayout16>::setup(&mut world);
+    world.register::<Level>();
+    let mut system = FluidSystem::<LinearLayout16>::new(
+      world
+        .fetch_mut::<EventChannel<BlockChange>>()
+        .register_reader(),
+    );
+    System::setup(&mut system, &mut world);
+    let level = create_level(world.create_entity(), Level::new(0));
+    let mut reader = world
+      .fetch_mut::<EventChannel<LayerChange>>()
+      .register_reader();
+
+    let pos = BlockPos::new(4, 0, 4);
+    world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.set(level, pos, Fluid::Water.block());
+    });
+    system.run_now(&world);
+    let (chunk, index): (ChunkPos, Index<LinearLayout16>) = pos.into();
+    {
+      let (mut blocks, mut levels, mut layer_changes) = world.system_data::<(
+        BlockAccess<'_, u8, LinearLayout16>,
+        WriteStorage<'_, ChunkStorage<FluidLevel, LinearLayout16>>,
+        Write<'_, EventChannel<LayerChange>>,
+      )>();
+      let mut access = ChunkFluidAccess {
+        level,
+        blocks: &mut blocks,
+        levels: &mut levels,
+        layer_changes: &mut layer_changes,
+      };
+      access.set_level(pos, FluidLevel(3));
+      blocks.set(level, pos, 1);
+    }
+    // Only look at the change caused by replacing the fluid.
+    world
+      .read_resource::<EventChannel<LayerChange>>()
+      .read(&mut reader)
+      .for_each(drop);
+
+    system.run_now(&world);
+    world.exec(
+      |(blocks, levels): (
+        BlockAccess<'_, u8, LinearLayout16>,
+        ReadStorage<'_, ChunkStorage<FluidLevel, LinearLayout16>>,
+      )| {
+        let storage = levels.get(blocks.chunk(level, chunk).unwrap()).unwrap();
+        assert_eq!(storage.get(index), FluidLevel(0));
+      },
+    );
+    let changes = world
+      .read_resource::<EventChannel<LayerChange>>()
+      .read(&mut reader)
+      .copied()
+      .collect::<Vec<_>>();
+    assert_eq!(
+      changes,
+      vec![LayerChange {
+        level,
+        pos: chunk,
+        layer: FLUID_LEVEL_LAYER,
+      }]
+    );
+  }
+}
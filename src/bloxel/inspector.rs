@@ -0,0 +1,614 @@
+use {
+  super::{
+    camera_ray,
+    chunk::{Chunk, ChunkLayout, ChunkPos, DefaultLayout, Index},
+    is_solid, ActiveLevel, BlockEntityAccess, BlockPos, ChunkLoader, CommandArgs, CommandError,
+    Level, MeshVertexCount, MovementMode, PlayerController, REACH_DISTANCE,
+  },
+  amethyst::{
+    assets::{AssetStorage, Loader},
+    controls::FlyControlTag,
+    core::transform::Transform,
+    derive::SystemDesc,
+    ecs::{prelude::*, storage::MaskedStorage},
+    input::{InputEvent, StringBindings},
+    shrev::EventChannel,
+    ui::{get_default_font, Anchor, FontAsset, LineMode, UiText, UiTransform},
+  },
+  std::{error::Error, fmt, str::FromStr},
+};
+
+/// Input action which shows and hides the inspector panel.
+pub const ACTION_TOGGLE_INSPECTOR: &str = "toggle_inspector";
+
+/// Components which can be shown and edited by the inspector, field by field.
+pub trait Inspect {
+  /// Lists the fields of this component by name, along with their values formatted as text.
+  fn fields(&self) -> Vec<(&'static str, String)>;
+
+  /// Parses the specified text and sets the field with the specified name to it.
+  fn set_field(&mut self, field: &str, value: &str) -> Result<(), InspectError>;
+}
+
+/// Parses the value of a field for `Inspect::set_field`.
+pub fn parse_field<T: FromStr>(field: &str, value: &str) -> Result<T, InspectError> {
+  (value.trim().parse())
+    .map_err(|_| InspectError::InvalidValue(field.to_string(), value.to_string()))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InspectError {
+  /// There's no component with the specified name in `InspectableComponents`.
+  UnknownComponent(String),
+  /// The entity doesn't have the component with the specified name.
+  MissingComponent(String),
+  UnknownField(String),
+  ReadOnly(String),
+  /// The value can't be parsed for the field, both specified by name.
+  InvalidValue(String, String),
+}
+
+impl Error for InspectError {}
+
+impl fmt::Display for InspectError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      InspectError::UnknownComponent(name) => write!(f, "Unknown component '{}'", name),
+      InspectError::MissingComponent(name) => write!(f, "Entity has no component '{}'", name),
+      InspectError::UnknownField(name) => write!(f, "Unknown field '{}'", name),
+      InspectError::ReadOnly(name) => write!(f, "Field '{}' can't be edited", name),
+      InspectError::InvalidValue(field, value) => {
+        write!(f, "Invalid value '{}' for field '{}'", value, field)
+      }
+    }
+  }
+}
+
+type InspectFn = fn(&World, Entity) -> Option<Vec<(&'static str, String)>>;
+type EditFn = fn(&World, Entity, &str, &str) -> Option<Result<(), InspectError>>;
+
+/// Resource listing the components which are shown by the inspector and can be edited through it.
+#[derive(Default)]
+pub struct InspectableComponents(Vec<(&'static str, InspectFn, EditFn)>);
+
+impl InspectableComponents {
+  pub fn with<C>(mut self, name: &'static str) -> Self
+  where
+    C: Component + Inspect,
+  {
+    self
+      .0
+      .push((name, inspect_component::<C>, edit_component_field::<C>));
+    self
+  }
+}
+
+fn inspect_component<C>(world: &World, entity: Entity) -> Option<Vec<(&'static str, String)>>
+where
+  C: Component + Inspect,
+{
+  // Components which were never registered can't be attached to anything.
+  if !world.has_value::<MaskedStorage<C>>() {
+    return None;
+  }
+  world.read_storage::<C>().get(entity).map(C::fields)
+}
+
+fn edit_component_field<C>(
+  world: &World,
+  entity: Entity,
+  field: &str,
+  value: &str,
+) -> Option<Result<(), InspectError>>
+where
+  C: Component + Inspect,
+{
+  if !world.has_value::<MaskedStorage<C>>() {
+    return None;
+  }
+  (world.write_storage::<C>().get_mut(entity)).map(|component| component.set_field(field, value))
+}
+
+/// A component of an inspected entity, with its fields formatted as text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InspectedComponent {
+  pub name: &'static str,
+  pub fields: Vec<(&'static str, String)>,
+}
+
+/// Lists the components of the specified entity which are registered in `InspectableComponents`.
+pub fn inspect_entity(world: &World, entity: Entity) -> Vec<InspectedComponent> {
+  let registered = world.try_fetch::<InspectableComponents>();
+  (registered.iter())
+    .flat_map(|registered| registered.0.iter())
+    .filter_map(|(name, inspect, _)| {
+      let fields = inspect(world, entity)?;
+      Some(InspectedComponent { name, fields })
+    })
+    .collect()
+}
+
+/// Sets a field of a component, registered in `InspectableComponents`, of the specified entity.
+pub fn edit_component(
+  world: &World,
+  entity: Entity,
+  component: &str,
+  field: &str,
+  value: &str,
+) -> Result<(), InspectError> {
+  let edit = world
+    .try_fetch::<InspectableComponents>()
+    .and_then(|registered| {
+      (registered.0.iter())
+        .find(|(name, _, _)| *name == component)
+        .map(|(_, _, edit)| *edit)
+    });
+  let edit = edit.ok_or_else(|| InspectError::UnknownComponent(component.to_string()))?;
+  edit(world, entity, field, value)
+    .unwrap_or_else(|| Err(InspectError::MissingComponent(component.to_string())))
+}
+
+/// Everything there is to know about a single block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockInfo<L: ChunkLayout = DefaultLayout> {
+  pub pos: BlockPos,
+  pub value: u8,
+  /// Chunk entity containing the block, if it exists.
+  pub chunk: Option<Entity>,
+  pub index: Index<L>,
+  /// Index of the entry in the chunk's palette which holds the block's value, if any.
+  pub palette_index: Option<usize>,
+  pub block_entity: Option<Entity>,
+}
+
+pub fn inspect_block<L: ChunkLayout>(
+  access: &BlockEntityAccess<'_, L>,
+  level: Entity,
+  pos: BlockPos,
+) -> BlockInfo<L> {
+  let blocks = access.blocks();
+  let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+  BlockInfo {
+    pos,
+    value: blocks.get(level, pos),
+    chunk: blocks.chunk(level, chunk_pos),
+    index,
+    palette_index: (blocks.storage(level, chunk_pos))
+      .and_then(|storage| storage.palette_index(index)),
+    block_entity: access.block_entity(level, pos),
+  }
+}
+
+impl<L: ChunkLayout> fmt::Display for BlockInfo<L> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let pos = self.pos;
+    writeln!(f, "Block {} {} {}: {}", pos.x, pos.y, pos.z, self.value)?;
+    match self.chunk {
+      Some(chunk) => writeln!(f, "Chunk: #{}, index {}", chunk.id(), self.index)?,
+      None => writeln!(f, "Chunk: none, index {}", self.index)?,
+    }
+    match self.palette_index {
+      Some(palette_index) => writeln!(f, "Palette entry: {}", palette_index)?,
+      None => writeln!(f, "Palette entry: none")?,
+    }
+    match self.block_entity {
+      Some(entity) => write!(f, "Block entity: #{}", entity.id()),
+      None => write!(f, "Block entity: none"),
+    }
+  }
+}
+
+/// Formats the inspected components of an entity, one field per line.
+pub fn format_entity(world: &World, entity: Entity) -> String {
+  let mut text = format!("Entity #{}", entity.id());
+  for component in inspect_entity(world, entity) {
+    text += &format!("\n[{}]", component.name);
+    for (field, value) in component.fields {
+      text += &format!("\n  {}: {}", field, value);
+    }
+  }
+  text
+}
+
+/// Returns the text the inspector panel shows for the block targeted by the camera marked with
+/// `FlyControlTag`, followed by the components of its block entity or otherwise its chunk.
+pub fn inspector_text(world: &World) -> String {
+  let info = {
+    let (active_level, tags, transforms, access) = world.system_data::<(
+      Read<ActiveLevel>,
+      ReadStorage<FlyControlTag>,
+      ReadStorage<Transform>,
+      BlockEntityAccess<'_>,
+    )>();
+    let level = match active_level.0 {
+      Some(level) => level,
+      None => return "No active level".to_string(),
+    };
+    let hit = (&tags, &transforms)
+      .join()
+      .next()
+      .and_then(|(_, transform)| {
+        let (origin, direction) = camera_ray(transform);
+        (access.blocks()).raycast(level, origin, direction, REACH_DISTANCE, is_solid)
+      });
+    match hit {
+      Some(hit) => inspect_block(&access, level, hit.pos),
+      None => return "No block targeted".to_string(),
+    }
+  };
+  match info.block_entity.or(info.chunk) {
+    Some(entity) => format!("{}\n{}", info, format_entity(world, entity)),
+    None => info.to_string(),
+  }
+}
+
+/// Resource holding the entity which displays the inspector, see `create_inspector_panel`.
+pub struct InspectorPanel {
+  pub text: Entity,
+  pub visible: bool,
+}
+
+/// Creates the text element the inspector is displayed with, inserting the `InspectorPanel`.
+pub fn create_inspector_panel(world: &mut World) {
+  let font = world.exec(
+    |(loader, fonts): (ReadExpect<Loader>, Read<AssetStorage<FontAsset>>)| {
+      get_default_font(&loader, &fonts)
+    },
+  );
+  let transform = UiTransform::new(
+    "inspector".to_string(),
+    Anchor::BottomRight,
+    Anchor::BottomRight,
+    -8.0,
+    8.0,
+    1.0,
+    400.0,
+    400.0,
+  );
+  let text = UiText::new(
+    font,
+    String::new(),
+    [1.0, 1.0, 1.0, 1.0],
+    16.0,
+    LineMode::Wrap,
+    Anchor::BottomRight,
+  );
+  let text = world.create_entity().with(transform).with(text).build();
+  world.insert(InspectorPanel {
+    text,
+    visible: false,
+  });
+}
+
+/// Shows information about the targeted block in the `InspectorPanel` while it's visible, toggled
+/// by the "toggle_inspector" action. Components are edited using the `edit` console command.
+#[derive(SystemDesc)]
+#[system_desc(name(InspectorSystemDesc))]
+pub struct InspectorSystem {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<InputEvent<StringBindings>>,
+}
+
+impl InspectorSystem {
+  pub fn new(reader: ReaderId<InputEvent<StringBindings>>) -> Self {
+    Self { reader }
+  }
+}
+
+impl<'a> System<'a> for InspectorSystem {
+  type SystemData = (
+    Read<'a, EventChannel<InputEvent<StringBindings>>>,
+    Read<'a, LazyUpdate>,
+    Option<Write<'a, InspectorPanel>>,
+    WriteStorage<'a, UiText>,
+  );
+
+  fn run(&mut self, (events, lazy, panel, mut texts): Self::SystemData) {
+    let toggle = events
+      .read(&mut self.reader)
+      .filter(|event| match event {
+        InputEvent::ActionPressed(action) => action == ACTION_TOGGLE_INSPECTOR,
+        _ => false,
+      })
+      .count()
+      % 2
+      == 1;
+
+    let mut panel = match panel {
+      Some(panel) => panel,
+      None => return,
+    };
+    if toggle {
+      panel.visible = !panel.visible;
+    }
+    let entity = panel.text;
+    if panel.visible {
+      // Inspecting components requires access to the whole `World`.
+      lazy.exec(move |world| {
+        let text = inspector_text(world);
+        if let Some(ui_text) = world.write_storage::<UiText>().get_mut(entity) {
+          ui_text.text = text;
+        }
+      });
+    } else if let Some(text) = texts.get_mut(entity) {
+      text.text.clear();
+    }
+  }
+}
+
+fn find_entity(world: &World, args: &CommandArgs<'_>) -> Result<Entity, CommandError> {
+  let entity = world.entities().entity(args.get(0)?);
+  if world.is_alive(entity) {
+    Ok(entity)
+  } else {
+    Err(CommandError::Failed(format!(
+      "There's no entity #{}",
+      entity.id()
+    )))
+  }
+}
+
+/// Console command which shows the components of the entity with the specified id,
+/// or information about the targeted block, like the inspector panel.
+pub fn inspect_command(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  if args.is_empty() {
+    return Ok(inspector_text(world));
+  }
+  args.expect(1)?;
+  let entity = find_entity(world, args)?;
+  Ok(format_entity(world, entity))
+}
+
+/// Console command which sets a field of a component of the entity with the specified id.
+pub fn edit_command(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  args.expect(4)?;
+  let entity = find_entity(world, args)?;
+  let (component, field) = (args.get::<String>(1)?, args.get::<String>(2)?);
+  let value = args.get::<String>(3)?;
+  edit_component(world, entity, &component, &field, &value)
+    .map_err(|err| CommandError::Failed(err.to_string()))?;
+  Ok(format!("Set {}.{} to {}", component, field, value))
+}
+
+impl Inspect for Transform {
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    let translation = self.translation();
+    vec![
+      ("x", translation.x.to_string()),
+      ("y", translation.y.to_string()),
+      ("z", translation.z.to_string()),
+    ]
+  }
+
+  fn set_field(&mut self, field: &str, value: &str) -> Result<(), InspectError> {
+    let translation = self.translation_mut();
+    match field {
+      "x" => translation.x = parse_field(field, value)?,
+      "y" => translation.y = parse_field(field, value)?,
+      "z" => translation.z = parse_field(field, value)?,
+      _ => return Err(InspectError::UnknownField(field.to_string())),
+    }
+    Ok(())
+  }
+}
+
+impl Inspect for PlayerController {
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    vec![
+      ("mode", format!("{:?}", self.mode)),
+      ("fly_speed", self.fly_speed.to_string()),
+      ("walk_speed", self.walk_speed.to_string()),
+      ("jump_speed", self.jump_speed.to_string()),
+      ("step_height", self.step_height.to_string()),
+      ("on_ground", self.on_ground.to_string()),
+    ]
+  }
+
+  fn set_field(&mut self, field: &str, value: &str) -> Result<(), InspectError> {
+    match field {
+      "mode" => {
+        self.mode = match value {
+          "Fly" => MovementMode::Fly,
+          "Walk" => MovementMode::Walk,
+          _ => return Err(InspectError::InvalidValue(field.into(), value.into())),
+        }
+      }
+      "fly_speed" => self.fly_speed = parse_field(field, value)?,
+      "walk_speed" => self.walk_speed = parse_field(field, value)?,
+      "jump_speed" => self.jump_speed = parse_field(field, value)?,
+      "step_height" => self.step_height = parse_field(field, value)?,
+      "on_ground" => return Err(InspectError::ReadOnly(field.to_string())),
+      _ => return Err(InspectError::UnknownField(field.to_string())),
+    }
+    Ok(())
+  }
+}
+
+impl Inspect for ChunkLoader {
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    vec![("radius", self.radius.to_string())]
+  }
+
+  fn set_field(&mut self, field: &str, value: &str) -> Result<(), InspectError> {
+    match field {
+      "radius" => self.radius = parse_field(field, value)?,
+      _ => return Err(InspectError::UnknownField(field.to_string())),
+    }
+    Ok(())
+  }
+}
+
+impl Inspect for Level {
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    vec![
+      ("seed", self.seed.to_string()),
+      ("tick", self.tick.to_string()),
+    ]
+  }
+
+  fn set_field(&mut self, field: &str, value: &str) -> Result<(), InspectError> {
+    match field {
+      // Changing the seed would only affect chunks generated from now on.
+      "seed" => Err(InspectError::ReadOnly(field.to_string())),
+      "tick" => {
+        self.tick = parse_field(field, value)?;
+        Ok(())
+      }
+      _ => Err(InspectError::UnknownField(field.to_string())),
+    }
+  }
+}
+
+impl Inspect for Chunk {
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    let pos = self.pos;
+    vec![
+      ("level", format!("#{}", self.level.id())),
+      ("pos", format!("{} {} {}", pos.x, pos.y, pos.z)),
+    ]
+  }
+
+  fn set_field(&mut self, field: &str, _value: &str) -> Result<(), InspectError> {
+    match field {
+      "level" | "pos" => Err(InspectError::ReadOnly(field.to_string())),
+      _ => Err(InspectError::UnknownField(field.to_string())),
+    }
+  }
+}
+
+impl Inspect for MeshVertexCount {
+  fn fields(&self) -> Vec<(&'static str, String)> {
+    vec![("vertices", self.0.to_string())]
+  }
+
+  fn set_field(&mut self, field: &str, _value: &str) -> Result<(), InspectError> {
+    match field {
+      "vertices" => Err(InspectError::ReadOnly(field.to_string())),
+      _ => Err(InspectError::UnknownField(field.to_string())),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{
+      chunk::LinearLayout16, create_level, run_command, BlockAccess, ConsoleCommands,
+    },
+    amethyst::core::math::Vector3,
+  };
+
+  type Blocks<'a> = BlockAccess<'a, u8, LinearLayout16>;
+
+  fn world() -> (World, Entity) {
+    let mut world = World::new();
+    Blocks::setup(&mut world);
+    BlockEntityAccess::<LinearLayout16>::setup(&mut world);
+    world.register::<Level>();
+    world.register::<Transform>();
+    world.insert(
+      InspectableComponents::default()
+        .with::<Level>("level")
+        .with::<Transform>("transform")
+        // Never registered with the world, so no entity has it.
+        .with::<PlayerController>("player"),
+    );
+    let level = create_level(world.create_entity(), Level::new(42));
+    (world, level)
+  }
+
+  #[test]
+  fn block_info_includes_chunk_and_palette() {
+    let (mut world, level) = world();
+    let pos = BlockPos::new(17, -1, 3);
+    world.exec(|mut blocks: Blocks<'_>| {
+      blocks.set(level, BlockPos::new(16, -1, 3), 2);
+      blocks.set(level, pos, 5);
+    });
+    let (info, block_entity) = world.exec(|mut access: BlockEntityAccess<'_, LinearLayout16>| {
+      let block_entity = access.create_block_entity(level, pos);
+      (inspect_block(&access, level, pos), block_entity)
+    });
+    assert_eq!(info.value, 5);
+    assert!(info.chunk.is_some());
+    assert_eq!(info.index, Index::new(1, 15, 3).unwrap());
+    // Entry `0` is air, followed by the blocks in the order they were set.
+    assert_eq!(info.palette_index, Some(2));
+    assert_eq!(info.block_entity, block_entity);
+
+    let empty = world.exec(|access: BlockEntityAccess<'_, LinearLayout16>| {
+      inspect_block(&access, level, BlockPos::new(0, 100, 0))
+    });
+    assert_eq!(
+      (empty.value, empty.chunk, empty.palette_index),
+      (0, None, None)
+    );
+  }
+
+  #[test]
+  fn components_are_listed_and_edited() {
+    let (world, level) = world();
+    assert_eq!(
+      inspect_entity(&world, level),
+      vec![InspectedComponent {
+        name: "level",
+        fields: vec![("seed", "42".to_string()), ("tick", "0".to_string())],
+      }]
+    );
+
+    edit_component(&world, level, "level", "tick", "100").unwrap();
+    assert_eq!(world.read_storage::<Level>().get(level).unwrap().tick, 100);
+    assert_eq!(
+      edit_component(&world, level, "level", "seed", "1"),
+      Err(InspectError::ReadOnly("seed".to_string()))
+    );
+    assert_eq!(
+      edit_component(&world, level, "level", "tick", "soon"),
+      Err(InspectError::InvalidValue(
+        "tick".to_string(),
+        "soon".to_string()
+      ))
+    );
+    assert_eq!(
+      edit_component(&world, level, "transform", "x", "1"),
+      Err(InspectError::MissingComponent("transform".to_string()))
+    );
+    assert_eq!(
+      edit_component(&world, level, "player", "fly_speed", "1"),
+      Err(InspectError::MissingComponent("player".to_string()))
+    );
+    assert_eq!(
+      edit_component(&world, level, "sign", "text", "hi"),
+      Err(InspectError::UnknownComponent("sign".to_string()))
+    );
+  }
+
+  #[test]
+  fn console_commands_edit_components() {
+    let (mut world, _) = world();
+    world.insert(
+      ConsoleCommands::default()
+        .with("inspect", "inspect [<entity>]", "", inspect_command)
+        .with(
+          "edit",
+          "edit <entity> <component> <field> <value>",
+          "",
+          edit_command,
+        ),
+    );
+    let entity = world.create_entity().with(Transform::default()).build();
+    let line = format!("edit {} transform y -2.5", entity.id());
+    run_command(&mut world, &line).unwrap();
+    let translation = *world
+      .read_storage::<Transform>()
+      .get(entity)
+      .unwrap()
+      .translation();
+    assert_eq!(translation, Vector3::new(0.0, -2.5, 0.0));
+
+    let output = run_command(&mut world, &format!("inspect {}", entity.id())).unwrap();
+    assert!(output.contains("[transform]\n  x: 0\n  y: -2.5\n  z: 0"));
+    assert!(run_command(&mut world, "inspect 1000").is_err());
+  }
+}
@@ -0,0 +1,136 @@
+use {
+  super::{
+    chunk::{ChunkLayout, ChunkLookup, ChunkPos, ChunkState, Index},
+    BlockPos, GeneratorConfig,
+  },
+  crate::util::ChunkedOctree,
+  amethyst::{
+    assets::PrefabData,
+    core::{
+      math::{Point3, Vector3},
+      transform::Transform,
+    },
+    derive::PrefabData,
+    ecs::prelude::*,
+    Error,
+  },
+  serde::{Deserialize, Serialize},
+};
+
+/// Depth of the `ChunkedOctree<ChunkState>` regions created for each level.
+pub const OCTREE_DEPTH: u8 = 5;
+
+/// Component which marks an entity as a level, an independent world made up of chunks.
+/// Each `Chunk` refers to the level it's part of, and level entities also hold the
+/// `ChunkLookup` and `ChunkedOctree<ChunkState>` components for their chunks.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Level {
+  /// Seed used to generate the terrain of this level.
+  pub seed: u32,
+  pub generator: GeneratorConfig,
+  /// Number of block ticks that have passed in this level, see `BlockTickSystem`.
+  pub tick: u64,
+}
+
+impl Component for Level {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl Component for ChunkedOctree<ChunkState> {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl Level {
+  pub fn new(seed: u32) -> Self {
+    Level {
+      seed,
+      generator: Default::default(),
+      tick: 0,
+    }
+  }
+}
+
+/// Resource holding the level the local player is in, which
+/// input handling and other player-facing systems act upon.
+#[derive(Default)]
+pub struct ActiveLevel(pub Option<Entity>);
+
+/// Component for entities which keep the chunks around them active, such as the player's
+/// camera. Systems simulating the world, like `FluidSystem`, only update chunks near a loader.
+/// Loaders are positioned using their global `Transform`, as levels are assumed to be unmoved.
+#[derive(Clone, Debug, Deserialize, Serialize, PrefabData)]
+#[prefab(Component)]
+#[serde(default)]
+pub struct ChunkLoader {
+  /// Distance in blocks from the loader to the center of chunks kept active.
+  pub radius: f32,
+}
+
+impl Default for ChunkLoader {
+  fn default() -> Self {
+    ChunkLoader { radius: 64.0 }
+  }
+}
+
+impl Component for ChunkLoader {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl ChunkLoader {
+  /// Returns whether the chunk at the specified position is kept
+  /// active by this loader, if the loader is positioned at `center`.
+  pub fn contains<L: ChunkLayout>(&self, center: Point3<f32>, pos: ChunkPos) -> bool {
+    let half_length = (L::LENGTH / 2) as f32;
+    let chunk_center = Point3::new(
+      (pos.x << L::LENGTH_BITS) as f32,
+      (pos.y << L::LENGTH_BITS) as f32,
+      (pos.z << L::LENGTH_BITS) as f32,
+    ) + Vector3::repeat(half_length);
+    (chunk_center - center).norm_squared() <= self.radius * self.radius
+  }
+}
+
+/// Collects every `ChunkLoader` along with its position, for use with `ChunkLoader::contains`.
+pub fn chunk_loader_positions(
+  loaders: &ReadStorage<'_, ChunkLoader>,
+  transforms: &ReadStorage<'_, Transform>,
+) -> Vec<(ChunkLoader, Point3<f32>)> {
+  (loaders, transforms)
+    .join()
+    .map(|(loader, transform)| {
+      let matrix = transform.global_matrix();
+      (loader.clone(), Point3::from(matrix.column(3).xyz()))
+    })
+    .collect()
+}
+
+/// Returns the position of the chunk each `ChunkLoader` is in, without duplicates,
+/// around which chunks are generated and meshed.
+pub fn chunk_loader_chunks<L: ChunkLayout>(
+  loaders: &ReadStorage<'_, ChunkLoader>,
+  transforms: &ReadStorage<'_, Transform>,
+) -> Vec<ChunkPos> {
+  let mut chunks = Vec::new();
+  for (_, pos) in chunk_loader_positions(loaders, transforms) {
+    let pos = BlockPos::new(
+      pos.x.floor() as i32,
+      pos.y.floor() as i32,
+      pos.z.floor() as i32,
+    );
+    let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+    if !chunks.contains(&chunk_pos) {
+      chunks.push(chunk_pos);
+    }
+  }
+  chunks
+}
+
+/// Finishes building a level entity, adding the
+/// specified `Level` and any other components it requires.
+pub fn create_level<B: Builder>(builder: B, level: Level) -> Entity {
+  builder
+    .with(level)
+    .with(ChunkLookup::default())
+    .with(ChunkedOctree::<ChunkState>::new(OCTREE_DEPTH))
+    .build()
+}
@@ -0,0 +1,561 @@
+use {
+  super::{
+    chunk::{storage::*, *},
+    is_solid, mark_mesh_outdated, BlockChange, BlockPos, Facing, Fluid,
+  },
+  crate::util::ChunkedOctree,
+  amethyst::{derive::SystemDesc, ecs::prelude::*, shrev::EventChannel},
+  std::{
+    collections::{HashMap, HashSet, VecDeque},
+    marker::PhantomData,
+  },
+};
+
+/// Highest light level. Light decreases by one for every block it travels.
+pub const MAX_LIGHT: u8 = 15;
+
+/// Number of chunks lit for the first time per run of `LightingSystem`.
+const CHUNKS_PER_RUN: usize = 4;
+
+/// Light emitted by blocks, stored per chunk as `ChunkStorage<BlockLight>`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct BlockLight(pub u8);
+
+/// Light coming from the sky, stored per chunk as `ChunkStorage<SkyLight>`. Sky light travels
+/// downwards through transparent blocks without losing strength. Chunks that don't exist are
+/// considered to be open air, fully lit by the sky.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SkyLight(pub u8);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LightChannel {
+  Block,
+  Sky,
+}
+
+/// Resource describing how each type of block interacts with light.
+#[derive(Clone)]
+pub struct BlockLighting {
+  emission: [u8; 256],
+  opaque: [bool; 256],
+}
+
+impl Default for BlockLighting {
+  /// By default, every solid block is opaque and only lava emits light.
+  fn default() -> Self {
+    let mut opaque = [true; 256];
+    for (block, opaque) in opaque.iter_mut().enumerate() {
+      *opaque = is_solid(block as u8);
+    }
+    BlockLighting {
+      emission: [0; 256],
+      opaque,
+    }
+    .with_emission(Fluid::Lava.block(), MAX_LIGHT)
+  }
+}
+
+impl BlockLighting {
+  pub fn with_emission(mut self, block: u8, light: u8) -> Self {
+    self.emission[block as usize] = light.min(MAX_LIGHT);
+    self
+  }
+
+  pub fn with_opaque(mut self, block: u8, opaque: bool) -> Self {
+    self.opaque[block as usize] = opaque;
+    self
+  }
+
+  pub fn emission(&self, block: u8) -> u8 {
+    self.emission[block as usize]
+  }
+
+  pub fn is_opaque(&self, block: u8) -> bool {
+    self.opaque[block as usize]
+  }
+}
+
+/// Access to the blocks and light values of a level, as required for propagating light.
+pub trait LightAccess {
+  fn block(&self, pos: BlockPos) -> u8;
+
+  fn light(&self, channel: LightChannel, pos: BlockPos) -> u8;
+
+  /// Sets the light at the specified position, returning `false`
+  /// if there is no light storage there that could be written to.
+  fn set_light(&mut self, channel: LightChannel, pos: BlockPos, value: u8) -> bool;
+}
+
+/// Returns the light a block receives from a neighbour lit with `light` in the specified direction.
+fn spread(channel: LightChannel, light: u8, direction: Facing) -> u8 {
+  match (channel, direction) {
+    (LightChannel::Sky, Facing::Down) if light == MAX_LIGHT => MAX_LIGHT,
+    _ => light.saturating_sub(1),
+  }
+}
+
+/// Spreads light outwards from each of the positions in `queue` using breadth-first search.
+pub fn propagate_light<A: LightAccess>(
+  access: &mut A,
+  lighting: &BlockLighting,
+  channel: LightChannel,
+  mut queue: VecDeque<BlockPos>,
+) {
+  while let Some(pos) = queue.pop_front() {
+    let light = access.light(channel, pos);
+    for facing in Facing::iter_all() {
+      let neighbour = pos + facing;
+      let spread = spread(channel, light, facing);
+      if spread > access.light(channel, neighbour)
+        && !lighting.is_opaque(access.block(neighbour))
+        && access.set_light(channel, neighbour, spread)
+      {
+        queue.push_back(neighbour);
+      }
+    }
+  }
+}
+
+/// Removes light that originated from the positions in `queue`, which have already been set to
+/// zero and are paired with the light they had before. Light from other sources bordering on
+/// the darkened area is then spread back into it.
+pub fn remove_light<A: LightAccess>(
+  access: &mut A,
+  lighting: &BlockLighting,
+  channel: LightChannel,
+  mut queue: VecDeque<(BlockPos, u8)>,
+) {
+  let mut relight = VecDeque::new();
+  while let Some((pos, light)) = queue.pop_front() {
+    for facing in Facing::iter_all() {
+      let neighbour = pos + facing;
+      let neighbour_light = access.light(channel, neighbour);
+      if neighbour_light == 0 {
+        continue;
+      }
+      // Light at full strength traveling down is the only sky light that doesn't decrease.
+      let from_here = neighbour_light < light
+        || (spread(channel, light, facing) == light && neighbour_light == light);
+      if from_here && access.set_light(channel, neighbour, 0) {
+        queue.push_back((neighbour, neighbour_light));
+        if channel == LightChannel::Block {
+          let emission = lighting.emission(access.block(neighbour));
+          if emission > 0 && access.set_light(channel, neighbour, emission) {
+            relight.push_back(neighbour);
+          }
+        }
+      } else if !from_here {
+        relight.push_back(neighbour);
+      }
+    }
+  }
+  propagate_light(access, lighting, channel, relight);
+}
+
+/// Updates the light around a block which was changed to `value`.
+pub fn relight_block<A: LightAccess>(
+  access: &mut A,
+  lighting: &BlockLighting,
+  pos: BlockPos,
+  value: u8,
+) {
+  for &channel in &[LightChannel::Block, LightChannel::Sky] {
+    let old = access.light(channel, pos);
+    if old > 0 && access.set_light(channel, pos, 0) {
+      remove_light(access, lighting, channel, vec![(pos, old)].into());
+    }
+
+    let mut queue = VecDeque::new();
+    if channel == LightChannel::Block {
+      let emission = lighting.emission(value);
+      if emission > 0 && access.set_light(channel, pos, emission) {
+        queue.push_back(pos);
+      }
+    }
+    if !lighting.is_opaque(value) {
+      // Let light from surrounding blocks flow back into this one.
+      queue.extend(Facing::iter_all().map(|facing| pos + facing));
+    }
+    propagate_light(access, lighting, channel, queue);
+  }
+}
+
+/// Lights a chunk for the first time, pulling in light from its surroundings. Light that
+/// surrounding chunks previously received through this chunk's position is recalculated.
+pub fn light_chunk<A: LightAccess, L: ChunkLayout>(
+  access: &mut A,
+  lighting: &BlockLighting,
+  chunk_pos: ChunkPos,
+) {
+  let min = BlockPos::from((chunk_pos, Index::<L>::new(0, 0, 0).unwrap()));
+  let length = L::LENGTH as i32;
+  let contains = |pos: BlockPos| {
+    let (x, y, z) = (pos.x - min.x, pos.y - min.y, pos.z - min.z);
+    x >= 0 && x < length && y >= 0 && y < length && z >= 0 && z < length
+  };
+
+  // Every position directly outside of the chunk's faces.
+  let mut outside = Vec::new();
+  for a in 0..length {
+    for b in 0..length {
+      outside.push(min + (-1, a, b));
+      outside.push(min + (length, a, b));
+      outside.push(min + (a, -1, b));
+      outside.push(min + (a, length, b));
+      outside.push(min + (a, b, -1));
+      outside.push(min + (a, b, length));
+    }
+  }
+
+  for &channel in &[LightChannel::Block, LightChannel::Sky] {
+    let mut removal = VecDeque::new();
+    for &pos in &outside {
+      let light = access.light(channel, pos);
+      if light > 0 && access.set_light(channel, pos, 0) {
+        removal.push_back((pos, light));
+      }
+    }
+    remove_light(access, lighting, channel, removal);
+
+    let mut queue = outside
+      .iter()
+      .copied()
+      .filter(|pos| !contains(*pos))
+      .collect::<VecDeque<_>>();
+    if channel == LightChannel::Block {
+      for x in 0..length {
+        for y in 0..length {
+          for z in 0..length {
+            let pos = min + (x, y, z);
+            let emission = lighting.emission(access.block(pos));
+            if emission > 0 && access.set_light(channel, pos, emission) {
+              queue.push_back(pos);
+            }
+          }
+        }
+      }
+    }
+    propagate_light(access, lighting, channel, queue);
+  }
+}
+
+/// Implements `LightAccess` on top of the chunks of a level, keeping
+/// track of which chunks' meshes need to be rebuilt due to changed light.
+struct ChunkLightAccess<'s, 'a, L: ChunkLayout> {
+  lookup: &'s ChunkLookup,
+  blocks: &'s ReadStorage<'a, ChunkStorage<u8, L>>,
+  block_light: &'s mut WriteStorage<'a, ChunkStorage<BlockLight, L>>,
+  sky_light: &'s mut WriteStorage<'a, ChunkStorage<SkyLight, L>>,
+  changed: HashSet<ChunkPos>,
+}
+
+impl<'s, 'a, L: ChunkLayout> LightAccess for ChunkLightAccess<'s, 'a, L> {
+  fn block(&self, pos: BlockPos) -> u8 {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    self
+      .lookup
+      .get(chunk_pos)
+      .and_then(|entity| self.blocks.get(entity))
+      .map_or(0, |storage| storage.get(index))
+  }
+
+  fn light(&self, channel: LightChannel, pos: BlockPos) -> u8 {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    let entity = match self.lookup.get(chunk_pos) {
+      Some(entity) => entity,
+      None if channel == LightChannel::Sky => return MAX_LIGHT,
+      None => return 0,
+    };
+    match channel {
+      LightChannel::Block => self.block_light.get(entity).map_or(0, |s| s.get(index).0),
+      LightChannel::Sky => self.sky_light.get(entity).map_or(0, |s| s.get(index).0),
+    }
+  }
+
+  fn set_light(&mut self, channel: LightChannel, pos: BlockPos, value: u8) -> bool {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    let entity = match self.lookup.get(chunk_pos) {
+      Some(entity) => entity,
+      None => return false,
+    };
+    let written = match channel {
+      LightChannel::Block => self
+        .block_light
+        .get_mut(entity)
+        .map(|s| s.set(index, BlockLight(value))),
+      LightChannel::Sky => self
+        .sky_light
+        .get_mut(entity)
+        .map(|s| s.set(index, SkyLight(value))),
+    };
+    if written.is_none() {
+      return false;
+    }
+
+    // Faces of neighbouring chunks' blocks are lit by this block, so they need updating too.
+    self.changed.insert(chunk_pos);
+    let (x, y, z) = index.into();
+    let last = L::LENGTH as i32 - 1;
+    for (relative, facing) in [(x, Facing::West), (y, Facing::Down), (z, Facing::North)].iter() {
+      if *relative == 0 {
+        self.changed.insert(chunk_pos + *facing);
+      } else if *relative == last {
+        self.changed.insert(chunk_pos - *facing);
+      }
+    }
+    true
+  }
+}
+
+/// Lights chunks that don't have light storages yet and updates light around changed blocks.
+/// Chunks whose light changed have their mesh rebuilt, which bakes light into vertex colors.
+#[derive(SystemDesc)]
+#[system_desc(name(LightingSystemDesc))]
+pub struct LightingSystem<L: ChunkLayout = DefaultLayout> {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<BlockChange>,
+  #[system_desc(skip)]
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> LightingSystem<L> {
+  pub fn new(reader: ReaderId<BlockChange>) -> Self {
+    Self {
+      reader,
+      layout: PhantomData,
+    }
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for LightingSystem<L> {
+  type SystemData = (
+    Entities<'a>,
+    Read<'a, EventChannel<BlockChange>>,
+    Read<'a, BlockLighting>,
+    ReadStorage<'a, Chunk>,
+    ReadStorage<'a, ChunkLookup>,
+    ReadStorage<'a, ChunkStorage<u8, L>>,
+    WriteStorage<'a, ChunkStorage<BlockLight, L>>,
+    WriteStorage<'a, ChunkStorage<SkyLight, L>>,
+    WriteStorage<'a, ChunkedOctree<ChunkState>>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      entities,
+      events,
+      lighting,
+      chunks,
+      lookups,
+      blocks,
+      mut block_light,
+      mut sky_light,
+      mut octrees,
+    ): Self::SystemData,
+  ) {
+    // Group changes by level, so each level's chunks are only accessed once.
+    let mut changes = HashMap::<Entity, Vec<BlockChange>>::new();
+    for change in events.read(&mut self.reader) {
+      changes.entry(change.level).or_default().push(*change);
+    }
+
+    let unlit = (&entities, &chunks, &blocks, !&block_light)
+      .join()
+      .map(|(entity, chunk, _, _)| (entity, *chunk))
+      .take(CHUNKS_PER_RUN)
+      .collect::<Vec<_>>();
+    for (entity, chunk) in &unlit {
+      block_light
+        .insert(*entity, ChunkStorage::new(PaletteStorageImpl::new()))
+        .unwrap();
+      sky_light
+        .insert(*entity, ChunkStorage::new(PaletteStorageImpl::new()))
+        .unwrap();
+      changes.entry(chunk.level).or_default();
+    }
+
+    for (level, changes) in changes {
+      let lookup = match lookups.get(level) {
+        Some(lookup) => lookup,
+        None => continue,
+      };
+      let mut access = ChunkLightAccess {
+        lookup,
+        blocks: &blocks,
+        block_light: &mut block_light,
+        sky_light: &mut sky_light,
+        changed: HashSet::new(),
+      };
+
+      for (_, chunk) in unlit.iter().filter(|(_, chunk)| chunk.level == level) {
+        light_chunk::<_, L>(&mut access, &lighting, chunk.pos);
+        // Meshes built before the chunk was lit assumed full brightness.
+        access.changed.insert(chunk.pos);
+        access
+          .changed
+          .extend(Facing::iter_all().map(|facing| chunk.pos + facing));
+      }
+      for change in changes {
+        relight_block(&mut access, &lighting, change.pos, change.value);
+      }
+
+      if let Some(octree) = octrees.get_mut(level) {
+        for chunk_pos in access.changed {
+          if lookup.get(chunk_pos).is_some() {
+            mark_mesh_outdated(octree, chunk_pos);
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Gets the brightness of a block lit by the specified light levels, from `0.0` to `1.0`.
+pub fn light_brightness(block_light: u8, sky_light: u8) -> f32 {
+  const MIN_BRIGHTNESS: f32 = 0.05;
+  let light = block_light.max(sky_light).min(MAX_LIGHT);
+  // Each level of light is perceived to be a constant factor brighter than the previous one.
+  let brightness = 0.8f32.powi((MAX_LIGHT - light) as i32);
+  MIN_BRIGHTNESS + brightness * (1.0 - MIN_BRIGHTNESS)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Light access over a single 16×16×16 region of blocks, which is all there is.
+  /// Above the region, there's open sky. Everywhere else, there's nothing.
+  #[derive(Default)]
+  struct TestAccess {
+    blocks: HashMap<BlockPos, u8>,
+    block_light: HashMap<BlockPos, u8>,
+    sky_light: HashMap<BlockPos, u8>,
+  }
+
+  fn inside(pos: BlockPos) -> bool {
+    (0..16).contains(&pos.x) && (0..16).contains(&pos.y) && (0..16).contains(&pos.z)
+  }
+
+  impl LightAccess for TestAccess {
+    fn block(&self, pos: BlockPos) -> u8 {
+      self.blocks.get(&pos).copied().unwrap_or(0)
+    }
+
+    fn light(&self, channel: LightChannel, pos: BlockPos) -> u8 {
+      match channel {
+        LightChannel::Block => self.block_light.get(&pos).copied().unwrap_or(0),
+        LightChannel::Sky if !inside(pos) => {
+          if pos.y >= 16 {
+            MAX_LIGHT
+          } else {
+            0
+          }
+        }
+        LightChannel::Sky => self.sky_light.get(&pos).copied().unwrap_or(0),
+      }
+    }
+
+    fn set_light(&mut self, channel: LightChannel, pos: BlockPos, value: u8) -> bool {
+      if !inside(pos) {
+        return false;
+      }
+      match channel {
+        LightChannel::Block => self.block_light.insert(pos, value),
+        LightChannel::Sky => self.sky_light.insert(pos, value),
+      };
+      true
+    }
+  }
+
+  /// Changes a block and updates light around it, like `LightingSystem` does.
+  fn set_block(access: &mut TestAccess, lighting: &BlockLighting, pos: BlockPos, value: u8) {
+    access.blocks.insert(pos, value);
+    relight_block(access, lighting, pos, value);
+  }
+
+  fn lit_region(blocks: &[(BlockPos, u8)], lighting: &BlockLighting) -> TestAccess {
+    let mut access = TestAccess::default();
+    access.blocks.extend(blocks.iter().copied());
+    light_chunk::<_, LinearLayout16>(&mut access, lighting, ChunkPos::new(0, 0, 0));
+    access
+  }
+
+  #[test]
+  fn block_light_falls_off_with_distance() {
+    let lighting = BlockLighting::default().with_emission(2, 15);
+    let access = lit_region(&[(BlockPos::new(8, 8, 8), 2)], &lighting);
+    assert_eq!(
+      access.light(LightChannel::Block, BlockPos::new(8, 8, 8)),
+      15
+    );
+    assert_eq!(
+      access.light(LightChannel::Block, BlockPos::new(9, 8, 8)),
+      14
+    );
+    assert_eq!(
+      access.light(LightChannel::Block, BlockPos::new(10, 9, 7)),
+      11
+    );
+    assert_eq!(access.light(LightChannel::Block, BlockPos::new(0, 0, 0)), 0);
+  }
+
+  #[test]
+  fn sky_light_travels_down_without_falloff() {
+    let lighting = BlockLighting::default();
+    let roof = (0..16)
+      .flat_map(|x| (0..16).map(move |z| (BlockPos::new(x, 10, z), 1)))
+      .filter(|(pos, _)| (pos.x, pos.z) != (4, 4))
+      .collect::<Vec<_>>();
+    let access = lit_region(&roof, &lighting);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(0, 11, 0)), 15);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(4, 0, 4)), 15);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(5, 9, 4)), 14);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(5, 0, 6)), 12);
+  }
+
+  #[test]
+  fn placing_block_casts_shadow() {
+    let lighting = BlockLighting::default();
+    let mut access = lit_region(&[], &lighting);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(3, 0, 3)), 15);
+
+    set_block(&mut access, &lighting, BlockPos::new(3, 5, 3), 1);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(3, 5, 3)), 0);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(3, 6, 3)), 15);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(3, 0, 3)), 14);
+
+    set_block(&mut access, &lighting, BlockPos::new(3, 5, 3), 0);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(3, 5, 3)), 15);
+    assert_eq!(access.light(LightChannel::Sky, BlockPos::new(3, 0, 3)), 15);
+  }
+
+  #[test]
+  fn removing_light_source_darkens_surroundings() {
+    let lighting = BlockLighting::default().with_emission(2, 15);
+    let mut access = lit_region(&[], &lighting);
+    let lamp = BlockPos::new(8, 8, 8);
+    let other = BlockPos::new(2, 8, 8);
+    set_block(&mut access, &lighting, lamp, 2);
+    set_block(&mut access, &lighting, other, 2);
+    assert_eq!(
+      access.light(LightChannel::Block, BlockPos::new(12, 8, 8)),
+      11
+    );
+
+    set_block(&mut access, &lighting, lamp, 0);
+    assert_eq!(access.light(LightChannel::Block, lamp), 9);
+    assert_eq!(
+      access.light(LightChannel::Block, BlockPos::new(12, 8, 8)),
+      5
+    );
+    assert_eq!(access.light(LightChannel::Block, other), 15);
+  }
+
+  #[test]
+  fn brightness_range() {
+    assert_eq!(light_brightness(15, 0), 1.0);
+    assert!(light_brightness(0, 0) > 0.0);
+    assert!(light_brightness(0, 7) < light_brightness(8, 0));
+  }
+}
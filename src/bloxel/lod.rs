@@ -0,0 +1,562 @@
+use {
+  crate::{
+    bloxel::{
+      box_face,
+      chunk::{storage::*, *},
+      chunk_loader_chunks, light_brightness, BlockChange, BlockMaterials, BlockPos, BlockShape,
+      BlockShapes, BlockTransparency, ChunkLoader, Facing, Level, MeshVertices, MAX_LIGHT,
+      VIEW_DISTANCE,
+    },
+    render::VertexLitMaterial,
+    util::{ChunkedOctree, ZOrder},
+  },
+  amethyst::{
+    assets::{AssetStorage, Handle, Loader},
+    core::{math::Vector3, transform::Transform},
+    derive::SystemDesc,
+    ecs::prelude::*,
+    renderer::{types::Mesh, visibility::BoundingSphere},
+    shrev::EventChannel,
+  },
+  std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    marker::PhantomData,
+  },
+};
+
+/// Highest octree level terrain is shown at. Each level doubles the distance to which terrain is
+/// shown, so with `VIEW_DISTANCE` at 8.5 chunks, level 3 nodes reach out to 68 chunks.
+pub const MAX_LOD_LEVEL: u8 = 3;
+
+/// Number of LOD nodes `LodMeshGenerator` builds per level and run.
+const NODES_PER_RUN: usize = 2;
+
+/// Returns the squared distance from the lowest corner of the chunk at `center` to the nearest
+/// point of the octree node at the specified position, in units of chunks.
+pub fn node_distance_squared(center: ChunkPos, level: u8, pos: ZOrder) -> f32 {
+  let (x, y, z) = (pos << level as usize).into();
+  let size = 1 << level;
+  let distance = |min: i32, center: i32| (min - center).max(center - (min + size)).max(0) as f32;
+  let (x, y, z) = (
+    distance(x, center.x),
+    distance(y, center.y),
+    distance(z, center.z),
+  );
+  x * x + y * y + z * z
+}
+
+/// Weight function for `ChunkedOctree::find` which only accepts nodes containing chunks shown at
+/// full detail around the chunk at `center`, nearest first. Chunks are shown at full detail
+/// whenever their level `1` parent node is within `max_distance` chunks, so that `lod_nodes` can
+/// cover the rest of the terrain without ever overlapping them.
+pub fn full_detail_weight(
+  center: ChunkPos,
+  level: u8,
+  pos: ZOrder,
+  max_distance: f32,
+) -> Option<f32> {
+  let parent_distance = if level == 0 {
+    node_distance_squared(center, 1, pos >> 1)
+  } else {
+    node_distance_squared(center, level, pos)
+  };
+  if parent_distance <= max_distance * max_distance {
+    Some(node_distance_squared(center, level, pos))
+  } else {
+    None
+  }
+}
+
+/// Returns up to `limit` chunks shown at full detail around each of the chunks in `centers`,
+/// nearest first for each of them, whose state is accepted by `filter`.
+pub fn nearest_full_detail<F>(
+  octree: &ChunkedOctree<ChunkState>,
+  centers: &[ChunkPos],
+  max_distance: f32,
+  limit: usize,
+  filter: F,
+) -> Vec<ZOrder>
+where
+  F: Fn(&ChunkState) -> bool,
+{
+  let mut chunks = Vec::new();
+  for &center in centers {
+    let z_center = match ZOrder::try_from(center) {
+      Ok(z_center) => z_center,
+      Err(_) => continue,
+    };
+    let nearest = octree
+      .find(
+        |level, pos| full_detail_weight(center, level, pos, max_distance),
+        &filter,
+      )
+      .search(z_center)
+      .map(|(pos, _)| pos)
+      .filter(|pos| !chunks.contains(pos))
+      .take(limit)
+      .collect::<Vec<_>>();
+    chunks.extend(nearest);
+  }
+  chunks
+}
+
+/// Returns the octree nodes which show terrain at a lower level of detail around the chunk at
+/// `center`, nearest first, as `(level, pos)` pairs. Together with the chunks accepted by
+/// `full_detail_weight` they cover all terrain out to `max_distance * 2^max_level` chunks, with
+/// each chunk covered exactly once.
+///
+/// Each level of detail takes over where the previous one ends, at twice the distance: a node at
+/// `level` is shown if it's farther away than `max_distance * 2^(level - 1)` chunks, meaning it
+/// isn't split up into more detailed nodes, but its parent node is closer than that distance
+/// times two. Nodes at `max_level` don't have a parent, and are shown up to that distance.
+pub fn lod_nodes(center: ChunkPos, max_distance: f32, max_level: u8) -> Vec<(u8, ZOrder)> {
+  let mut nodes = Vec::new();
+  for level in 1..=max_level {
+    let split_distance = max_distance * (1 << (level - 1)) as f32;
+    let shown_distance = split_distance * 2.0;
+    // Distances measured in nodes of this level are the same for every level.
+    let range = max_distance.ceil() as i32 + 2;
+    let (cx, cy, cz) = (center.x >> level, center.y >> level, center.z >> level);
+    for x in cx - range..=cx + range {
+      for y in cy - range..=cy + range {
+        for z in cz - range..=cz + range {
+          let pos = match ZOrder::new(x, y, z) {
+            Some(pos) => pos,
+            None => continue,
+          };
+          let distance = node_distance_squared(center, level, pos);
+          let parent_distance = if level < max_level {
+            node_distance_squared(center, level + 1, pos >> 1)
+          } else {
+            distance
+          };
+          if distance > split_distance * split_distance
+            && parent_distance <= shown_distance * shown_distance
+          {
+            nodes.push((level, pos, distance));
+          }
+        }
+      }
+    }
+  }
+  nodes.sort_by(|(_, _, a), (_, _, b)| a.partial_cmp(b).unwrap());
+  nodes
+    .into_iter()
+    .map(|(level, pos, _)| (level, pos))
+    .collect()
+}
+
+/// Returns the position of the block with the lowest coordinates in the specified octree node.
+pub fn node_origin<L: ChunkLayout>(level: u8, pos: ZOrder) -> BlockPos {
+  let chunk_pos = ChunkPos::from(pos << level as usize);
+  BlockPos::from((chunk_pos, Index::<L>::new(0, 0, 0).unwrap()))
+}
+
+/// Builds the storage of the octree node at level `lod` starting at `origin`, with each entry
+/// standing for a cube of `2^lod` blocks on each side. Entries are solid if at least half of their
+/// blocks are, according to `is_solid`, in which case they take on the most common solid block.
+pub fn downsample<L, B, S>(
+  lod: u8,
+  origin: BlockPos,
+  block_at: B,
+  is_solid: S,
+) -> PaletteStorageImpl<u8, L>
+where
+  L: ChunkLayout,
+  B: Fn(BlockPos) -> u8,
+  S: Fn(u8) -> bool,
+{
+  let cell_size = 1 << lod;
+  let mut storage = PaletteStorageImpl::<u8, L>::new();
+  let mut counts = HashMap::<u8, usize>::new();
+  for x in 0..L::LENGTH as i32 {
+    for y in 0..L::LENGTH as i32 {
+      for z in 0..L::LENGTH as i32 {
+        counts.clear();
+        let cell_origin = origin + (x * cell_size, y * cell_size, z * cell_size);
+        for dx in 0..cell_size {
+          for dy in 0..cell_size {
+            for dz in 0..cell_size {
+              let block = block_at(cell_origin + (dx, dy, dz));
+              if is_solid(block) {
+                *counts.entry(block).or_default() += 1;
+              }
+            }
+          }
+        }
+        let solid = counts.values().sum::<usize>();
+        if solid * 2 >= (cell_size * cell_size * cell_size) as usize {
+          // Ties are broken by block type, so the result doesn't depend on iteration order.
+          let (block, _) = counts
+            .iter()
+            .max_by_key(|(block, count)| (**count, **block))
+            .unwrap();
+          // SAFETY: Bounds should be safe due to loop only going over valid values.
+          let index = unsafe { Index::new_unchecked(x, y, z) };
+          storage.set(index, *block);
+        }
+      }
+    }
+  }
+  storage
+}
+
+/// Returns the faces of solid entries in a LOD node's storage which are visible, along with the
+/// position of their entry. Faces on the border of the node are always visible, which closes
+/// off the surface of every LOD node as well as every chunk (as `ChunkMeshGenerator` treats
+/// blocks outside of a chunk as air). This way, no cracks show up where nodes with a different
+/// level of detail meet, even though their surfaces don't match up.
+pub fn lod_faces<L, S>(
+  storage: &dyn StorageImpl<u8, L>,
+  is_solid: S,
+) -> Vec<((i32, i32, i32), Facing)>
+where
+  L: ChunkLayout,
+  S: Fn(u8) -> bool,
+{
+  let solid_at = |x: i32, y: i32, z: i32| {
+    Index::<L>::new(x, y, z)
+      .map(|index| is_solid(storage.get(index)))
+      .unwrap_or(false)
+  };
+  let mut faces = Vec::new();
+  for x in 0..L::LENGTH as i32 {
+    for y in 0..L::LENGTH as i32 {
+      for z in 0..L::LENGTH as i32 {
+        if !solid_at(x, y, z) {
+          continue;
+        }
+        for face in Facing::iter_all() {
+          let (fx, fy, fz) = face.into();
+          if !solid_at(x + fx, y + fy, z + fz) {
+            faces.push(((x, y, z), face));
+          }
+        }
+      }
+    }
+  }
+  faces
+}
+
+/// Whether a block counts as solid when terrain is shown at a lower level of detail.
+fn is_lod_solid(shapes: &BlockShapes, block: u8) -> bool {
+  !matches!(shapes.shape(block), BlockShape::Empty | BlockShape::Cross)
+}
+
+/// Component for entities rendering an octree node of a level at a lower level of detail.
+/// They also hold the node's downsampled `ChunkStorage<u8>`, whose entries each stand for a cube
+/// of `2^lod` blocks on each side.
+pub struct LodNode {
+  pub level: Entity,
+  /// Octree level of the node.
+  pub lod: u8,
+  /// Position of the node, in units of the node size at its octree level.
+  pub pos: ZOrder,
+}
+
+impl Component for LodNode {
+  type Storage = DenseVecStorage<Self>;
+}
+
+/// Component for levels keeping track of which LOD nodes have been built.
+/// Nodes without any solid terrain don't get an entity.
+#[derive(Default)]
+pub struct LodNodes(pub HashMap<(u8, ZOrder), Option<Entity>>);
+
+impl Component for LodNodes {
+  type Storage = HashMapStorage<Self>;
+}
+
+/// Builds meshes for the octree nodes returned by `lod_nodes`, nearest first, showing terrain
+/// beyond `VIEW_DISTANCE` up to octree level `MAX_LOD_LEVEL` around the first `ChunkLoader`.
+///
+/// Nodes whose chunks have all been generated are downsampled from the chunks' storages, while
+/// the terrain of the others is sampled from the level's generator at a lower resolution, see
+/// `GeneratorConfig::generate_lod`. Whenever the loader moves into another chunk the set of nodes
+/// is recomputed, and the entities of nodes which aren't part of it anymore are deleted. Nodes
+/// containing changed blocks are built again, replacing their entity once the new mesh is loaded.
+#[derive(SystemDesc)]
+#[system_desc(name(LodMeshGeneratorDesc))]
+pub struct LodMeshGenerator<L: ChunkLayout = DefaultLayout> {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<BlockChange>,
+  /// Chunk the current `nodes` were computed around.
+  #[system_desc(skip)]
+  center: Option<ChunkPos>,
+  #[system_desc(skip)]
+  nodes: Vec<(u8, ZOrder)>,
+  /// Built nodes containing blocks which changed since, for each level.
+  #[system_desc(skip)]
+  outdated: HashMap<Entity, HashSet<(u8, ZOrder)>>,
+  /// Entities of rebuilt nodes, kept around until the mesh replacing them is loaded.
+  #[system_desc(skip)]
+  replaced: Vec<(Handle<Mesh>, Entity)>,
+  #[system_desc(skip)]
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> LodMeshGenerator<L> {
+  pub fn new(reader: ReaderId<BlockChange>) -> Self {
+    Self {
+      reader,
+      center: None,
+      nodes: Vec::new(),
+      outdated: HashMap::new(),
+      replaced: Vec::new(),
+      layout: PhantomData,
+    }
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for LodMeshGenerator<L> {
+  type SystemData = (
+    Entities<'a>,
+    Read<'a, LazyUpdate>,
+    ReadExpect<'a, Loader>,
+    ReadExpect<'a, AssetStorage<Mesh>>,
+    Read<'a, EventChannel<BlockChange>>,
+    Read<'a, BlockShapes>,
+    Read<'a, Option<BlockMaterials>>,
+    ReadStorage<'a, ChunkLoader>,
+    ReadStorage<'a, Transform>,
+    ReadStorage<'a, Level>,
+    ReadStorage<'a, ChunkLookup>,
+    ReadStorage<'a, ChunkedOctree<ChunkState>>,
+    ReadStorage<'a, ChunkStorage<u8, L>>,
+    WriteStorage<'a, LodNodes>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      entities,
+      lazy,
+      loader,
+      mesh_storage,
+      changes,
+      shapes,
+      block_materials,
+      loaders,
+      transforms,
+      levels,
+      lookups,
+      octrees,
+      storages,
+      mut built_nodes,
+    ): Self::SystemData,
+  ) {
+    for change in changes.read(&mut self.reader) {
+      let (chunk_pos, _): (ChunkPos, Index<L>) = change.pos.into();
+      if let Ok(z_pos) = ZOrder::try_from(chunk_pos) {
+        let outdated = self.outdated.entry(change.level).or_default();
+        outdated.extend((1..=MAX_LOD_LEVEL).map(|lod| (lod, z_pos >> lod as usize)));
+      }
+    }
+
+    let center = (chunk_loader_chunks::<L>(&loaders, &transforms)
+      .first()
+      .copied())
+    .unwrap_or_else(|| ChunkPos::new(0, 0, 0));
+    if self.center != Some(center) {
+      self.nodes = lod_nodes(center, VIEW_DISTANCE / L::LENGTH as f32, MAX_LOD_LEVEL);
+      self.center = Some(center);
+      let shown = self.nodes.iter().copied().collect::<HashSet<_>>();
+      for built in (&mut built_nodes).join() {
+        built.0.retain(|node, entity| {
+          let keep = shown.contains(node);
+          if let (false, Some(entity)) = (keep, entity) {
+            let _ = entities.delete(*entity);
+          }
+          keep
+        });
+      }
+    }
+
+    self.replaced.retain(|(mesh, previous)| {
+      let loaded = mesh_storage.get(mesh).is_some();
+      if loaded {
+        let _ = entities.delete(*previous);
+      }
+      !loaded
+    });
+
+    // Materials are created by `ChunkMeshGenerator`.
+    let material = match &*block_materials {
+      Some(materials) => materials.get(BlockTransparency::Opaque).clone(),
+      None => return,
+    };
+    let is_solid = |block| is_lod_solid(&shapes, block);
+
+    for (level_entity, level, lookup, octree) in (&entities, &levels, &lookups, &octrees).join() {
+      let built = built_nodes
+        .entry(level_entity)
+        .unwrap()
+        .or_insert_with(LodNodes::default);
+      let outdated = self.outdated.entry(level_entity).or_default();
+      // Nodes which aren't built yet don't need to be marked as outdated.
+      outdated.retain(|node| built.0.contains_key(node));
+      let missing = (self.nodes.iter())
+        .filter(|node| !built.0.contains_key(node) || outdated.contains(node))
+        .take(NODES_PER_RUN)
+        .copied()
+        .collect::<Vec<_>>();
+
+      for (lod, pos) in missing {
+        let origin = node_origin::<L>(lod, pos);
+        let generated = octree.get(lod, pos) & ChunkState::GENERATED_ALL;
+        let storage = if generated == ChunkState::GENERATED_ALL {
+          let block_at = |pos: BlockPos| {
+            let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+            (lookup.get(chunk_pos))
+              .and_then(|chunk| storages.get(chunk))
+              .map_or(0, |storage| storage.get(index))
+          };
+          downsample::<L, _, _>(lod, origin, block_at, is_solid)
+        } else {
+          level.generator.generate_lod::<L>(level.seed, lod, origin)
+        };
+
+        outdated.remove(&(lod, pos));
+        let previous = built.0.remove(&(lod, pos)).flatten();
+
+        let faces = lod_faces(&storage, is_solid);
+        if faces.is_empty() {
+          if let Some(previous) = previous {
+            let _ = entities.delete(previous);
+          }
+          built.0.insert((lod, pos), None);
+          continue;
+        }
+        let cell_size = 1 << lod;
+        // There's no light information for LOD nodes, so they're shown as if lit by the sky.
+        let brightness = light_brightness(0, MAX_LIGHT);
+        let mut vertices = MeshVertices::default();
+        for ((x, y, z), face) in faces {
+          let mut quad = box_face([0.0; 3], [1.0; 3], face);
+          for corner in &mut quad.corners {
+            for value in corner.iter_mut() {
+              *value *= cell_size as f32;
+            }
+          }
+          let cell_pos = (x * cell_size, y * cell_size, z * cell_size);
+          vertices.push_quad(cell_pos, &quad, brightness);
+        }
+        let mesh: Handle<Mesh> = loader.load_from_data(vertices.build().into(), (), &mesh_storage);
+
+        let half_length = (L::LENGTH << lod) as f32 / 2.0;
+        let bounds = BoundingSphere::new([half_length; 3].into(), half_length * 3f32.sqrt());
+        let transform = Transform::from(Vector3::new(
+          origin.x as f32,
+          origin.y as f32,
+          origin.z as f32,
+        ));
+        let entity = lazy
+          .create_entity(&entities)
+          .with(LodNode {
+            level: level_entity,
+            lod,
+            pos,
+          })
+          .with(ChunkStorage::new(storage))
+          .with(transform)
+          .with(bounds)
+          .with(mesh.clone())
+          .with(VertexLitMaterial(material.clone()))
+          .build();
+        built.0.insert((lod, pos), Some(entity));
+        if let Some(previous) = previous {
+          self.replaced.push((mesh, previous));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::collections::HashMap};
+
+  #[test]
+  fn lod_nodes_cover_each_chunk_once() {
+    let (max_distance, max_level) = (2.5, 2);
+    let shown_distance = max_distance * (1 << max_level) as f32;
+    for &center in &[ChunkPos::new(0, 0, 0), ChunkPos::new(21, -7, 3)] {
+      let nodes = lod_nodes(center, max_distance, max_level);
+      for x in -12..=12 {
+        for y in -12..=12 {
+          for z in -12..=12 {
+            let chunk = ZOrder::new(center.x + x, center.y + y, center.z + z).unwrap();
+            let full_detail = full_detail_weight(center, 0, chunk, max_distance).is_some();
+            let covering = (nodes.iter())
+              .filter(|(level, pos)| chunk >> *level as usize == *pos)
+              .count();
+            let expected =
+              if node_distance_squared(center, 0, chunk) <= shown_distance * shown_distance {
+                1
+              } else {
+                covering.min(1)
+              };
+            assert_eq!(covering + full_detail as usize, expected, "{:?}", chunk);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn full_detail_chunks_are_found_around_each_center() {
+    let octree = ChunkedOctree::<ChunkState>::new(5);
+    let centers = [ChunkPos::new(0, 0, 0), ChunkPos::new(40, -3, 7)];
+    let chunks = nearest_full_detail(&octree, &centers, 2.5, 3, |_| true);
+    assert_eq!(chunks.len(), 6);
+    for (i, center) in centers.iter().enumerate() {
+      for &chunk in &chunks[i * 3..i * 3 + 3] {
+        assert_eq!(node_distance_squared(*center, 0, chunk), 0.0);
+      }
+    }
+  }
+
+  #[test]
+  fn downsample_takes_most_common_solid_block() {
+    let block_at = |pos: BlockPos| match (pos.x, pos.y, pos.z) {
+      (0, 0, _) | (0, 1, 0) => 3,
+      (_, 0, _) => 2,
+      _ => 0,
+    };
+    let storage =
+      downsample::<DefaultLayout, _, _>(1, BlockPos::new(0, 0, 0), block_at, |b| b != 0);
+    assert_eq!(storage.get(Index::new(0, 0, 0).unwrap()), 3);
+    assert_eq!(storage.get(Index::new(1, 0, 0).unwrap()), 2);
+    assert_eq!(storage.get(Index::new(0, 1, 0).unwrap()), 0);
+  }
+
+  #[test]
+  fn lod_faces_are_closed() {
+    let mut storage = PaletteStorageImpl::<u8, DefaultLayout>::new();
+    for &(x, y, z) in &[
+      (0, 0, 0),
+      (1, 0, 0),
+      (1, 1, 0),
+      (5, 5, 5),
+      (15, 3, 15),
+      (15, 4, 15),
+    ] {
+      storage.set(Index::new(x, y, z).unwrap(), 1);
+    }
+    let faces = lod_faces(&storage, |block| block != 0);
+    assert_eq!(faces.len(), 6 * 6 - 2 * 3);
+
+    // Every edge of a closed surface is shared by an even number of faces.
+    let mut edges = HashMap::<([i32; 3], [i32; 3]), usize>::new();
+    for ((x, y, z), face) in faces {
+      let corners = box_face([0.0; 3], [1.0; 3], face).corners;
+      for i in 0..4 {
+        let corner = |c: [f32; 3]| [x + c[0] as i32, y + c[1] as i32, z + c[2] as i32];
+        let (a, b) = (corner(corners[i]), corner(corners[(i + 1) % 4]));
+        *edges.entry((a.min(b), a.max(b))).or_default() += 1;
+      }
+    }
+    assert!(edges.values().all(|count| count % 2 == 0));
+  }
+}
@@ -1,31 +1,172 @@
 use {
   crate::{
     bloxel::{
+      box_face,
       chunk::{storage::*, *},
-      Facing,
+      chunk_loader_chunks, light_brightness, nearest_full_detail,
+      world_generator::chunk_render_components,
+      BlockLight, BlockPos, BlockShape, BlockShapes, BlockTransparency, ChunkLoader, Facing, Fluid,
+      FluidLevel, Level, Quad, SkyLight, MAX_FLUID_LEVEL, MAX_LIGHT, VIEW_DISTANCE,
     },
+    render::VertexLitMaterial,
     util::{ChunkedOctree, ZOrder},
   },
   amethyst::{
     assets::*,
+    core::{
+      math::Vector3,
+      transform::{Parent, Transform},
+    },
     ecs::prelude::*,
+    gltf::{GltfSceneAsset, GltfSceneFormat},
     renderer::{
-      loaders::load_from_srgb,
-      palette::rgb::Srgb,
-      rendy::mesh::{MeshBuilder, Normal, Position, TexCoord},
+      formats::mesh::ObjFormat,
+      loaders::{load_from_srgb, load_from_srgba},
+      palette::{rgb::Srgb, Srgba},
+      rendy::mesh::{Color, MeshBuilder, Normal, Position, TexCoord},
+      resources::Tint,
+      transparent::Transparent,
       types::{Mesh, TextureData},
       Material, MaterialDefaults, Texture,
     },
   },
+  log::warn,
+  std::{collections::HashMap, convert::TryFrom, marker::PhantomData},
 };
 
+/// Builds meshes for chunks whose mesh is outdated, nearest first. Light from `LightingSystem`
+/// is baked into the meshes' vertex colors, which `RenderVertexLit3D` multiplies their material
+/// by. Blocks using a model are darkened through a `Tint` instead.
+///
+/// Blocks are meshed according to their shape in the `BlockShapes` resource. Faces are only
+/// skipped when a neighbouring block's full, opaque face covers them. Opaque blocks make up the
+/// chunk's own mesh, while everything else is rendered by child entities of the chunk, listed in
+/// its `ChunkMeshes` component: cutout blocks are alpha-tested, translucent blocks and fluids are
+/// marked `Transparent` to be sorted and drawn after opaque geometry, and blocks using a model
+/// get an entity of their own.
+///
+/// Fluids are meshed with the height of their surface depending on their `FluidLevel`.
+pub struct ChunkMeshGenerator<L: ChunkLayout = DefaultLayout>(PhantomData<L>);
+
+impl<L: ChunkLayout> Default for ChunkMeshGenerator<L> {
+  fn default() -> Self {
+    ChunkMeshGenerator(PhantomData)
+  }
+}
+
+/// Materials blocks are rendered with, by their transparency.
+pub struct BlockMaterials(HashMap<BlockTransparency, Handle<Material>>);
+
+impl BlockMaterials {
+  pub fn get(&self, transparency: BlockTransparency) -> &Handle<Material> {
+    &self.0[&transparency]
+  }
+}
+
+/// Materials fluids are rendered with, colored according to `Fluid::color`.
+pub struct FluidMaterials(HashMap<Fluid, Handle<Material>>);
+
+/// Handle of a model used by blocks with a `BlockShape::Model` shape.
+#[derive(Clone)]
+pub enum BlockModel {
+  /// A single mesh loaded from an `.obj` file, rendered with the block's material.
+  Mesh(Handle<Mesh>),
+  /// A scene loaded from a `.glb` or `.gltf` file, which brings its own materials
+  /// and is therefore not affected by light.
+  Scene(Handle<GltfSceneAsset>),
+}
+
+/// Resource caching the models of `BlockShape::Model` blocks by path, loaded as they're needed.
+/// Paths with an unsupported file extension map to `None`.
+#[derive(Default)]
+pub struct BlockModels(HashMap<String, Option<BlockModel>>);
+
+/// Parts of a chunk which are rendered by a child entity of their own.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ChunkMeshKind {
+  /// All blocks with the specified transparency besides `Opaque`,
+  /// which are rendered by the chunk entity itself.
+  Blocks(BlockTransparency),
+  Fluid(Fluid),
+  /// A single block using a model, by its position in the level and block type.
+  Model(BlockPos, u8),
+}
+
+/// Component for chunks listing the child entities rendering
+/// the parts of the chunk which aren't opaque cubes.
+#[derive(Default)]
+pub struct ChunkMeshes(pub HashMap<ChunkMeshKind, Entity>);
+
+impl Component for ChunkMeshes {
+  type Storage = HashMapStorage<Self>;
+}
+
+/// Component for chunks holding the number of vertices of all of their meshes combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshVertexCount(pub usize);
+
+impl Component for MeshVertexCount {
+  type Storage = DenseVecStorage<Self>;
+}
+
+static TRIANGLE_INDICES: [u32; 6] = [0, 1, 3, 1, 2, 3];
+
+/// Vertices of a mesh that's being built.
 #[derive(Default)]
-pub struct ChunkMeshGenerator;
+pub(crate) struct MeshVertices {
+  indices: Vec<u32>,
+  pos: Vec<Position>,
+  norm: Vec<Normal>,
+  tex: Vec<TexCoord>,
+  color: Vec<Color>,
+}
+
+impl MeshVertices {
+  /// Adds the specified quad of the block at `(x, y, z)`.
+  pub(crate) fn push_quad(&mut self, (x, y, z): (i32, i32, i32), quad: &Quad, brightness: f32) {
+    for i in &TRIANGLE_INDICES {
+      self.indices.push(self.pos.len() as u32 + i);
+    }
+    for (i, corner) in quad.corners.iter().enumerate() {
+      self.pos.push(Position([
+        x as f32 + corner[0],
+        y as f32 + corner[1],
+        z as f32 + corner[2],
+      ]));
+      self.norm.push(Normal(quad.normal));
+      self
+        .color
+        .push(Color([brightness, brightness, brightness, 1.0]));
+      self.tex.push(TexCoord(match i {
+        0 => [0.0, 0.0],
+        1 => [0.0, 1.0],
+        2 => [1.0, 1.0],
+        3 => [1.0, 0.0],
+        _ => panic!(),
+      }));
+    }
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.indices.is_empty()
+  }
+
+  pub(crate) fn len(&self) -> usize {
+    self.pos.len()
+  }
 
-// TODO: Use lazy static for the material?
-pub struct WhiteMaterial(Handle<Material>);
+  pub(crate) fn build(self) -> MeshBuilder<'static> {
+    MeshBuilder::new()
+      .with_indices(self.indices)
+      .with_vertices(self.pos)
+      .with_vertices(self.norm)
+      .with_vertices(self.tex)
+      .with_vertices(self.color)
+      .into_owned()
+  }
+}
 
-impl<'a> System<'a> for ChunkMeshGenerator {
+impl<'a, L: ChunkLayout> System<'a> for ChunkMeshGenerator<L> {
   type SystemData = (
     Entities<'a>,
     Read<'a, LazyUpdate>,
@@ -34,10 +175,22 @@ impl<'a> System<'a> for ChunkMeshGenerator {
     ReadExpect<'a, AssetStorage<Texture>>,
     ReadExpect<'a, AssetStorage<Material>>,
     ReadExpect<'a, AssetStorage<Mesh>>,
-    Read<'a, ChunkLookup>,
-    ReadStorage<'a, ChunkStorage<u8>>,
-    Write<'a, Option<WhiteMaterial>>,
-    WriteExpect<'a, ChunkedOctree<ChunkState>>,
+    ReadExpect<'a, AssetStorage<GltfSceneAsset>>,
+    Read<'a, BlockShapes>,
+    ReadStorage<'a, ChunkLookup>,
+    ReadStorage<'a, ChunkStorage<u8, L>>,
+    ReadStorage<'a, ChunkStorage<BlockLight, L>>,
+    ReadStorage<'a, ChunkStorage<SkyLight, L>>,
+    ReadStorage<'a, ChunkStorage<FluidLevel, L>>,
+    ReadStorage<'a, ChunkMeshes>,
+    WriteStorage<'a, MeshVertexCount>,
+    Write<'a, Option<BlockMaterials>>,
+    Write<'a, Option<FluidMaterials>>,
+    Write<'a, BlockModels>,
+    WriteStorage<'a, ChunkedOctree<ChunkState>>,
+    ReadStorage<'a, Level>,
+    ReadStorage<'a, ChunkLoader>,
+    ReadStorage<'a, Transform>,
   );
 
   fn run(
@@ -50,172 +203,372 @@ impl<'a> System<'a> for ChunkMeshGenerator {
       texture_storage,
       material_storage,
       mesh_storage,
-      chunk_lookup,
+      scene_storage,
+      shapes,
+      chunk_lookups,
       chunk_storages,
-      mut gen_resources,
-      mut octree,
+      block_lights,
+      sky_lights,
+      fluid_levels,
+      chunk_meshes,
+      mut vertex_counts,
+      mut block_materials,
+      mut fluid_materials,
+      mut models,
+      mut octrees,
+      levels,
+      loaders,
+      transforms,
     ): Self::SystemData,
   ) {
-    let res = gen_resources.get_or_insert_with(|| {
-      let white_texture = loader.load_from_data(
-        TextureData(load_from_srgb(Srgb::new(1., 1., 1.))),
+    let load_material = |[r, g, b, a]: [f32; 4], alpha_cutoff: Option<f32>| {
+      let texture = loader.load_from_data(
+        TextureData(if a < 1.0 {
+          load_from_srgba(Srgba::new(r, g, b, a))
+        } else {
+          load_from_srgb(Srgb::new(r, g, b))
+        }),
         (),
         &texture_storage,
       );
-      let white_material = loader.load_from_data(
+      let defaults = material_defaults.0.clone();
+      loader.load_from_data(
         Material {
-          albedo: white_texture.clone(),
-          ..material_defaults.0.clone()
+          albedo: texture,
+          alpha_cutoff: alpha_cutoff.unwrap_or(defaults.alpha_cutoff),
+          ..defaults
         },
         (),
         &material_storage,
-      );
-      WhiteMaterial(white_material)
+      )
+    };
+    let block_materials = block_materials.get_or_insert_with(|| {
+      let mut materials = HashMap::new();
+      let opaque = load_material([1.0, 1.0, 1.0, 1.0], None);
+      materials.insert(BlockTransparency::Opaque, opaque);
+      let cutout = load_material([1.0, 1.0, 1.0, 1.0], Some(0.5));
+      materials.insert(BlockTransparency::Cutout, cutout);
+      let translucent = load_material([1.0, 1.0, 1.0, 0.5], None);
+      materials.insert(BlockTransparency::Translucent, translucent);
+      BlockMaterials(materials)
+    });
+    let fluid_materials = fluid_materials.get_or_insert_with(|| {
+      let materials = Fluid::ALL
+        .iter()
+        .map(|&fluid| (fluid, load_material(fluid.color(), None)));
+      FluidMaterials(materials.collect())
     });
 
-    const MAX_DISTANCE_SQUARED: f32 = 8.5 * 8.5;
-    let nearest = octree
-      .find(
-        |level, pos| {
-          let (mut x, mut y, mut z) = (pos << level as usize).into();
-          if x < 0 {
-            x += 1 << level;
-          }
-          if y < 0 {
-            y += 1 << level;
-          }
-          if z < 0 {
-            z += 1 << level;
-          }
-
-          let (x, y, z) = (x as f32, y as f32, z as f32);
-          let distance = x * x + y * y + z * z;
-          if distance <= MAX_DISTANCE_SQUARED {
-            Some(distance)
-          } else {
-            None
-          }
-        },
-        |state| (*state & ChunkState::MESH_UPDATED_ALL) != ChunkState::MESH_UPDATED_ALL,
-      )
-      .search(ZOrder::new(0, 0, 0).unwrap())
-      .take(4)
-      .filter_map(|(z_pos, _)| {
-        let (x, y, z) = z_pos.into();
-        let chunk_pos = ChunkPos::new(x, y, z);
-        let entity = chunk_lookup.get(chunk_pos);
-        entity.map(|e| (chunk_pos, z_pos, e))
+    // Levels, as well as anything else made up of chunks, are meshed side by side. Levels are
+    // meshed around each `ChunkLoader`, while others, such as voxel bodies, are meshed around
+    // their own origin.
+    let loader_chunks = chunk_loader_chunks::<L>(&loaders, &transforms);
+    let origin = [ChunkPos::new(0, 0, 0)];
+    for (entity, chunk_lookup, octree) in (&entities, &chunk_lookups, &mut octrees).join() {
+      let centers = match levels.get(entity) {
+        Some(_) => &loader_chunks[..],
+        None => &origin[..],
+      };
+      let max_distance = VIEW_DISTANCE / L::LENGTH as f32;
+      let nearest = nearest_full_detail(octree, centers, max_distance, 4, |state| {
+        (*state & ChunkState::MESH_UPDATED_ALL) != ChunkState::MESH_UPDATED_ALL
       })
+      .into_iter()
+      .filter_map(|z_pos| chunk_lookup.get(ChunkPos::from(z_pos)).map(|e| (z_pos, e)))
       .collect::<Vec<_>>();
 
-    // TODO: ChunkPos should use ZOrder and thus make it unnecessary to keep them both or convert between them.
-    for (_chunk_pos, z_pos, entity) in nearest {
-      if let Some(storage) = chunk_storages.get(entity) {
-        let mut indices = vec![];
-        let mut pos = vec![];
-        let mut norm = vec![];
-        let mut tex = vec![];
-
-        static TRIANGLE_INDICES: [u16; 6] = [0, 1, 3, 1, 2, 3];
-        static OFFSETS_PER_FACING: [[[i32; 3]; 4]; 6] = [
-          [[1, 1, 1], [1, 0, 1], [1, 0, 0], [1, 1, 0]], // +X
-          [[0, 1, 0], [0, 0, 0], [0, 0, 1], [0, 1, 1]], // -X
-          [[1, 1, 0], [0, 1, 0], [0, 1, 1], [1, 1, 1]], // +Y
-          [[1, 0, 1], [0, 0, 1], [0, 0, 0], [1, 0, 0]], // -Y
-          [[0, 1, 1], [0, 0, 1], [1, 0, 1], [1, 1, 1]], // +Z
-          [[1, 1, 0], [1, 0, 0], [0, 0, 0], [0, 1, 0]], // +Z
-        ];
-
-        for x in 0..CHUNK_LENGTH as i32 {
-          for y in 0..CHUNK_LENGTH as i32 {
-            for z in 0..CHUNK_LENGTH as i32 {
-              // SAFETY: Bounds should be safe due to loop only going over valid values.
-              let index = unsafe { Index::new_unchecked(x, y, z) };
-              if storage.get(index) == 0 {
-                continue;
-              }
-              for face in Facing::iter_all() {
-                let (fx, fy, fz) = face.into();
-
-                // Skip drawing this face if there's another block in that direction.
-                // `get` returns `ChunkBoundsError` if coords are outside of the bounds of
-                // the storage, so we can make use of that to avoid checking this ourselves.
-                if Index::new(x + fx, y + fy, z + fz)
-                  .map(|index| storage.get(index))
-                  .unwrap_or_default()
-                  > 0
-                {
+      for (z_pos, entity) in nearest {
+        if let Some(storage) = chunk_storages.get(entity) {
+          let mut block_vertices = HashMap::<BlockTransparency, MeshVertices>::new();
+          let mut fluid_vertices = HashMap::<Fluid, MeshVertices>::new();
+          let mut model_blocks = Vec::new();
+
+          // Faces are lit by the light of the block in front of them, which may be
+          // part of a neighbouring chunk. Missing chunks are considered open sky.
+          let chunk_pos = ChunkPos::from(z_pos);
+          let origin = BlockPos::from((chunk_pos, Index::<L>::new(0, 0, 0).unwrap()));
+          let light_at = |x: i32, y: i32, z: i32| {
+            let (chunk_pos, index): (ChunkPos, Index<L>) = (origin + (x, y, z)).into();
+            let (block_light, sky_light) = match chunk_lookup.get(chunk_pos) {
+              Some(entity) => (
+                block_lights.get(entity).map_or(0, |s| s.get(index).0),
+                sky_lights.get(entity).map_or(MAX_LIGHT, |s| s.get(index).0),
+              ),
+              None => (0, MAX_LIGHT),
+            };
+            light_brightness(block_light, sky_light)
+          };
+
+          // `get` returns `ChunkBoundsError` if coords are outside of the bounds of the storage,
+          // so we can make use of that to treat blocks outside of this chunk as air.
+          let block_at = |x: i32, y: i32, z: i32| {
+            Index::<L>::new(x, y, z)
+              .map(|index| storage.get(index))
+              .unwrap_or_default()
+          };
+          // Gets the fluid at the specified position along with the height of its surface.
+          let fluid_at = |x: i32, y: i32, z: i32| {
+            let index = Index::<L>::new(x, y, z).ok()?;
+            let fluid = Fluid::from_block(storage.get(index))?;
+            if Fluid::from_block(block_at(x, y + 1, z)) == Some(fluid) {
+              return Some((fluid, 1.0));
+            }
+            let level = match fluid_levels.get(entity).map(|s| s.get(index)) {
+              Some(FluidLevel(level)) if level > 0 => level,
+              _ => MAX_FLUID_LEVEL,
+            };
+            Some((fluid, level as f32 / MAX_FLUID_LEVEL as f32))
+          };
+
+          for x in 0..L::LENGTH as i32 {
+            for y in 0..L::LENGTH as i32 {
+              for z in 0..L::LENGTH as i32 {
+                let value = block_at(x, y, z);
+                if let Some((fluid, height)) = fluid_at(x, y, z) {
+                  let fluid_vertices = fluid_vertices.entry(fluid).or_default();
+                  let brightness = light_at(x, y, z);
+                  for face in Facing::iter_all() {
+                    let (fx, fy, fz) = face.into();
+                    let (nx, ny, nz) = (x + fx, y + fy, z + fz);
+                    // Fluid is only visible where it isn't covered by more of itself.
+                    // Sides bordering on lower fluid of the same type show the difference.
+                    let same = fluid_at(nx, ny, nz).filter(|(other, _)| *other == fluid);
+                    let bottom = match (face, same) {
+                      (Facing::Up, Some(_)) | (Facing::Down, Some(_)) => continue,
+                      (_, Some((_, other_height))) => other_height,
+                      // Surfaces below a solid block are still visible from the sides.
+                      (Facing::Up, None) if height < 1.0 => 0.0,
+                      _ if shapes.is_opaque_face(block_at(nx, ny, nz), face.opposite()) => continue,
+                      _ => 0.0,
+                    };
+                    if bottom < height {
+                      let quad = box_face([0.0, bottom, 0.0], [1.0, height, 1.0], face);
+                      fluid_vertices.push_quad((x, y, z), &quad, brightness);
+                    }
+                  }
                   continue;
                 }
-
-                for i in &TRIANGLE_INDICES {
-                  indices.push(pos.len() as u16 + i);
+                if let BlockShape::Model(_) = shapes.shape(value) {
+                  model_blocks.push(((x, y, z), value));
+                  continue;
+                }
+                let quads = shapes.quads(value);
+                if quads.is_empty() {
+                  continue;
                 }
-                let offsets = OFFSETS_PER_FACING[match face {
-                  Facing::East => 0,
-                  Facing::West => 1,
-                  Facing::Up => 2,
-                  Facing::Down => 3,
-                  Facing::South => 4,
-                  Facing::North => 5,
-                }];
-                for i in 0..4 {
-                  let offset = offsets[i];
-                  pos.push(Position([
-                    (x + offset[0]) as f32,
-                    (y + offset[1]) as f32,
-                    (z + offset[2]) as f32,
-                  ]));
-                  norm.push(Normal([fx as f32, fy as f32, fz as f32]));
-                  tex.push(TexCoord(match i {
-                    0 => [0.0, 0.0],
-                    1 => [0.0, 1.0],
-                    2 => [1.0, 1.0],
-                    3 => [1.0, 0.0],
-                    _ => panic!(),
-                  }));
+                let vertices = block_vertices
+                  .entry(shapes.transparency(value))
+                  .or_default();
+                for quad in quads {
+                  let brightness = match quad.cull_face {
+                    Some(face) => {
+                      let (fx, fy, fz) = face.into();
+                      // Skip drawing this quad if it's covered by the neighbouring block.
+                      if shapes.is_hidden(value, block_at(x + fx, y + fy, z + fz), face) {
+                        continue;
+                      }
+                      light_at(x + fx, y + fy, z + fz)
+                    }
+                    // Quads inside of the block, such as the top of a slab, are lit by
+                    // whichever is brighter, the block itself or the one above it.
+                    None => light_at(x, y, z).max(light_at(x, y + 1, z)),
+                  };
+                  vertices.push_quad((x, y, z), quad, brightness);
                 }
               }
             }
           }
-        }
 
-        if indices.is_empty() {
-          // FIXME: This is a temporary solution.
-          entities.delete(entity).unwrap();
-        } else {
-          let mesh_builder = MeshBuilder::new()
-            .with_indices(indices)
-            .with_vertices(pos)
-            .with_vertices(norm)
-            .with_vertices(tex)
-            .into_owned();
-          let mesh = loader.load_from_data(mesh_builder.into(), (), &mesh_storage);
-
-          lazy.insert(entity, mesh);
-          lazy.insert(entity, res.0.clone());
-        }
+          // Everything besides opaque blocks is rendered by child entities of the
+          // chunk, which are kept around as long as they're still needed.
+          let mut previous = chunk_meshes
+            .get(entity)
+            .map(|meshes| meshes.0.clone())
+            .unwrap_or_default();
+          let mut meshes = ChunkMeshes::default();
+          let opaque_vertices = block_vertices
+            .remove(&BlockTransparency::Opaque)
+            .unwrap_or_default();
+          let vertices = (block_vertices.into_iter())
+            .map(|(transparency, vertices)| (ChunkMeshKind::Blocks(transparency), vertices))
+            .chain(
+              (fluid_vertices.into_iter())
+                .map(|(fluid, vertices)| (ChunkMeshKind::Fluid(fluid), vertices)),
+            )
+            .filter(|(_, vertices)| !vertices.is_empty())
+            .collect::<Vec<_>>();
+          let vertex_count =
+            opaque_vertices.len() + vertices.iter().map(|(_, v)| v.len()).sum::<usize>();
+          vertex_counts
+            .insert(entity, MeshVertexCount(vertex_count))
+            .unwrap();
+          for (kind, vertices) in vertices {
+            let mesh = loader.load_from_data(vertices.build().into(), (), &mesh_storage);
+            let mesh_entity = previous.remove(&kind).unwrap_or_else(|| {
+              let (_, bounds) = chunk_render_components::<L>(chunk_pos);
+              let (material, transparent) = match kind {
+                ChunkMeshKind::Blocks(transparency) => (
+                  block_materials.get(transparency).clone(),
+                  transparency == BlockTransparency::Translucent,
+                ),
+                ChunkMeshKind::Fluid(fluid) => (fluid_materials.0[&fluid].clone(), true),
+                ChunkMeshKind::Model(..) => unreachable!(),
+              };
+              let builder = lazy
+                .create_entity(&entities)
+                .with(Parent::new(entity))
+                .with(Transform::default())
+                .with(bounds)
+                .with(VertexLitMaterial(material));
+              if transparent {
+                builder.with(Transparent).build()
+              } else {
+                builder.build()
+              }
+            });
+            lazy.insert(mesh_entity, mesh);
+            meshes.0.insert(kind, mesh_entity);
+          }
 
-        const MASK_SOME: ChunkState = ChunkState::MESH_UPDATED_SOME;
-        const MASK_ALL: ChunkState = ChunkState::MESH_UPDATED_ALL;
-        octree.update(
-          z_pos,
-          |state| *state = *state | MASK_ALL,
-          |_level, children, parent| {
-            let mask = if children.iter().all(|s| *s & MASK_ALL == MASK_ALL) {
-              MASK_ALL
-            } else {
-              MASK_SOME
-            };
-            if *parent & mask == mask {
-              false
-            } else {
-              *parent = *parent | mask;
-              true
+          for ((x, y, z), block) in model_blocks {
+            let kind = ChunkMeshKind::Model(origin + (x, y, z), block);
+            let brightness = light_at(x, y, z);
+            let tint = Tint(Srgba::new(brightness, brightness, brightness, 1.0));
+            if let Some(model_entity) = previous.remove(&kind) {
+              lazy.insert(model_entity, tint);
+              meshes.0.insert(kind, model_entity);
+              continue;
             }
-          },
-        );
+            let path = match shapes.shape(block) {
+              BlockShape::Model(path) => path,
+              _ => unreachable!(),
+            };
+            let model = models
+              .0
+              .entry(path.clone())
+              .or_insert_with(|| load_model(path, &loader, &mesh_storage, &scene_storage));
+            let model = match model {
+              Some(model) => model.clone(),
+              None => continue,
+            };
+            // Models are placed on the center of the bottom of their block.
+            let transform = Transform::from(Vector3::new(x as f32 + 0.5, y as f32, z as f32 + 0.5));
+            let builder = lazy
+              .create_entity(&entities)
+              .with(Parent::new(entity))
+              .with(transform);
+            let model_entity = match model {
+              BlockModel::Mesh(mesh) => {
+                let transparency = shapes.transparency(block);
+                let builder = builder
+                  .with(mesh)
+                  .with(block_materials.get(transparency).clone())
+                  .with(tint);
+                if transparency == BlockTransparency::Translucent {
+                  builder.with(Transparent).build()
+                } else {
+                  builder.build()
+                }
+              }
+              BlockModel::Scene(scene) => builder.with(scene).build(),
+            };
+            meshes.0.insert(kind, model_entity);
+          }
+
+          // Whatever is left over is no longer part of the chunk.
+          for (_, mesh_entity) in previous {
+            let _ = entities.delete(mesh_entity);
+          }
+
+          lazy.insert(entity, meshes);
+          // Chunks without anything to show keep their entity, which also holds their blocks.
+          if opaque_vertices.is_empty() {
+            lazy.remove::<Handle<Mesh>>(entity);
+          } else {
+            let mesh = loader.load_from_data(opaque_vertices.build().into(), (), &mesh_storage);
+            lazy.insert(entity, mesh);
+            let material = block_materials.get(BlockTransparency::Opaque).clone();
+            lazy.insert(entity, VertexLitMaterial(material));
+          }
+
+          const MASK_SOME: ChunkState = ChunkState::MESH_UPDATED_SOME;
+          const MASK_ALL: ChunkState = ChunkState::MESH_UPDATED_ALL;
+          octree.update(
+            z_pos,
+            |state| *state = *state | MASK_ALL,
+            |_level, children, parent| {
+              let mask = if children.iter().all(|s| *s & MASK_ALL == MASK_ALL) {
+                MASK_ALL
+              } else {
+                MASK_SOME
+              };
+              if *parent & mask == mask {
+                false
+              } else {
+                *parent = *parent | mask;
+                true
+              }
+            },
+          );
+        }
       }
     }
   }
 }
+
+/// Starts loading the model at the specified path, based on its file extension.
+fn load_model(
+  path: &str,
+  loader: &Loader,
+  mesh_storage: &AssetStorage<Mesh>,
+  scene_storage: &AssetStorage<GltfSceneAsset>,
+) -> Option<BlockModel> {
+  let extension = path.rsplit('.').next().unwrap_or_default();
+  match extension.to_lowercase().as_str() {
+    "obj" => Some(BlockModel::Mesh(loader.load(
+      path,
+      ObjFormat,
+      (),
+      mesh_storage,
+    ))),
+    "glb" | "gltf" => Some(BlockModel::Scene(loader.load(
+      path,
+      GltfSceneFormat::default(),
+      (),
+      scene_storage,
+    ))),
+    _ => {
+      warn!("Unsupported block model format: {}", path);
+      None
+    }
+  }
+}
+
+/// Clears the mesh state of the chunk at the specified position in
+/// the specified octree, causing `ChunkMeshGenerator` to pick it up again.
+pub fn mark_mesh_outdated(octree: &mut ChunkedOctree<ChunkState>, pos: ChunkPos) {
+  let z_pos = match ZOrder::try_from(pos) {
+    Ok(z_pos) => z_pos,
+    Err(_) => return,
+  };
+  const MASK_SOME: ChunkState = ChunkState::MESH_UPDATED_SOME;
+  const MASK_ALL: ChunkState = ChunkState::MESH_UPDATED_ALL;
+  octree.update(
+    z_pos,
+    |state| *state = *state - MASK_ALL,
+    |_level, children, parent| {
+      let mask = if children.iter().any(|s| s.intersects(MASK_ALL)) {
+        MASK_SOME
+      } else {
+        ChunkState::empty()
+      };
+      let updated = (*parent - MASK_ALL) | mask;
+      if *parent == updated {
+        false
+      } else {
+        *parent = updated;
+        true
+      }
+    },
+  );
+}
@@ -1,15 +1,40 @@
 use {
   self::Facing::*,
+  serde::{Deserialize, Serialize},
   std::{convert::TryFrom, ops},
 };
 
-pub use self::{chunk::ChunkPos, mesh_generator::*, world_generator::*};
+pub use self::{
+  block_access::*, block_entity::*, block_interaction::*, block_shape::*, block_tick::*,
+  chunk::ChunkPos, collision::*, console::*, debug::*, fluid::*, inspector::*, level::*,
+  lighting::*, lod::*, mesh_generator::*, persistence::*, player_controller::*, raycast::*,
+  scripting::*, voxel_body::*, world_generator::*,
+};
 
+mod block_access;
+mod block_entity;
+mod block_interaction;
+mod block_shape;
+mod block_tick;
 pub mod chunk;
+mod collision;
+mod console;
+mod debug;
+mod fluid;
+mod inspector;
+mod level;
+mod lighting;
+mod lod;
 mod mesh_generator;
+pub mod net;
+mod persistence;
+mod player_controller;
+mod raycast;
+mod scripting;
+mod voxel_body;
 mod world_generator;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub struct BlockPos {
   pub x: i32,
   pub y: i32,
@@ -60,7 +85,7 @@ impl ops::Sub<Facing> for BlockPos {
   }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum Facing {
   /// Towards `+X`.
   East,
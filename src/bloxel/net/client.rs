@@ -0,0 +1,253 @@
+use {
+  super::{
+    apply_block_changes, apply_chunk_data, apply_chunk_delta, Message, PredictedEdits,
+    ProtocolError, TcpTransport, Transport,
+  },
+  crate::bloxel::{
+    chunk::{Chunk, ChunkLayout, ChunkPos, DefaultLayout, Index},
+    load_script, remove_script, ActiveLevel, BlockAccess, BlockPos, CommandArgs, CommandError,
+    Level,
+  },
+  amethyst::{controls::FlyControlTag, core::transform::Transform, ecs::prelude::*},
+  log::{debug, info, warn},
+  std::{collections::HashSet, marker::PhantomData},
+};
+
+/// Resource connecting a client to a server. While it exists, `ClientNetworkSystem` applies what
+/// the server sends to the `ActiveLevel`, and block edits made through `edit_block` are predicted.
+pub struct NetworkClient {
+  transport: Box<dyn Transport>,
+  /// Player id assigned by the server, once it has welcomed the client.
+  pub player: Option<u32>,
+  pub predictions: PredictedEdits,
+  /// Set once the connection has been closed, for whatever reason.
+  pub disconnected: Option<String>,
+  /// Chunks that fell out of sync and have been requested again,
+  /// whose deltas are ignored until their `ChunkData` arrives.
+  requested: HashSet<ChunkPos>,
+}
+
+impl NetworkClient {
+  /// Starts the handshake with the server over an established connection.
+  pub fn connect<T: Transport + 'static>(
+    mut transport: T,
+    name: impl Into<String>,
+  ) -> Result<Self, ProtocolError> {
+    transport.send(&Message::hello(name))?;
+    Ok(NetworkClient {
+      transport: Box::new(transport),
+      player: None,
+      predictions: PredictedEdits::default(),
+      disconnected: None,
+      requested: HashSet::new(),
+    })
+  }
+
+  pub fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+    self.transport.send(message)
+  }
+
+  /// Sets a block right away and asks the server to do the same,
+  /// reverting it later if the server doesn't agree.
+  pub fn edit_block<L: ChunkLayout>(
+    &mut self,
+    blocks: &mut BlockAccess<'_, u8, L>,
+    level: Entity,
+    pos: BlockPos,
+    value: u8,
+  ) {
+    let message = self.predictions.predict(blocks, level, pos, value);
+    if let Err(err) = self.transport.send(&message) {
+      self.disconnect(err.to_string());
+    }
+  }
+
+  fn disconnect(&mut self, reason: String) {
+    if self.disconnected.is_none() {
+      info!("Disconnected from server: {}", reason);
+      self.disconnected = Some(reason);
+    }
+  }
+}
+
+/// Name players connecting through `connect_command` are known by, unless they specify one.
+pub const DEFAULT_PLAYER_NAME: &str = "player";
+
+/// Console command connecting to the server at the specified address. The chunks of the
+/// `ActiveLevel` are discarded, to be replaced by the ones the server sends.
+pub fn connect_command(world: &mut World, args: &CommandArgs<'_>) -> Result<String, CommandError> {
+  if args.is_empty() || args.len() > 2 {
+    return Err(args.usage_error());
+  }
+  let addr = args.get::<String>(0)?;
+  let name = match args.len() {
+    2 => args.get::<String>(1)?,
+    _ => DEFAULT_PLAYER_NAME.to_string(),
+  };
+  if let Some(client) = world.try_fetch::<NetworkClient>() {
+    if client.disconnected.is_none() {
+      return Err(CommandError::Failed(
+        "Already connected to a server".to_string(),
+      ));
+    }
+  }
+  let level = (world.try_fetch::<ActiveLevel>())
+    .and_then(|active_level| active_level.0)
+    .ok_or_else(|| CommandError::Failed("There is no active level".to_string()))?;
+
+  let failed = |err: ProtocolError| CommandError::Failed(format!("Couldn't connect: {}", err));
+  let transport = TcpTransport::connect(addr.as_str()).map_err(failed)?;
+  let client = NetworkClient::connect(transport, name).map_err(failed)?;
+
+  {
+    let (entities, chunks) = world.system_data::<(Entities<'_>, ReadStorage<'_, Chunk>)>();
+    for (entity, chunk) in (&entities, &chunks).join() {
+      if chunk.level == level {
+        let _ = entities.delete(entity);
+      }
+    }
+  }
+  world.insert(client);
+  Ok(format!("Connecting to {}", addr))
+}
+
+/// Applies messages from the server to the `ActiveLevel` while a `NetworkClient` resource exists,
+/// and keeps the server updated about the position of the camera marked with `FlyControlTag`.
+/// Updates to blocks go through the client's `PredictedEdits`.
+#[derive(Default)]
+pub struct ClientNetworkSystem<L: ChunkLayout = DefaultLayout> {
+  layout: PhantomData<L>,
+}
+
+impl<'a, L: ChunkLayout> System<'a> for ClientNetworkSystem<L> {
+  type SystemData = (
+    Option<Write<'a, NetworkClient>>,
+    Read<'a, ActiveLevel>,
+    Read<'a, LazyUpdate>,
+    ReadStorage<'a, FlyControlTag>,
+    ReadStorage<'a, Transform>,
+    WriteStorage<'a, Level>,
+    BlockAccess<'a, u8, L>,
+  );
+
+  fn run(
+    &mut self,
+    (client, active_level, lazy, tags, transforms, mut levels, mut blocks): Self::SystemData,
+  ) {
+    let (mut client, level) = match (client, active_level.0) {
+      (Some(client), Some(level)) if client.disconnected.is_none() => (client, level),
+      _ => return,
+    };
+    let client = &mut *client;
+
+    loop {
+      let message = match client.transport.receive() {
+        Ok(Some(message)) => message,
+        Ok(None) => break,
+        Err(err) => {
+          client.disconnect(err.to_string());
+          return;
+        }
+      };
+      match message {
+        Message::Welcome { player, seed } => {
+          info!("Joined server as player {}", player);
+          client.player = Some(player);
+          // Chunks beyond the ones the server sends, such as LOD meshes, are generated locally.
+          if let Some(level) = levels.get_mut(level) {
+            level.seed = seed;
+          }
+        }
+        Message::Disconnect { reason } => {
+          client.disconnect(reason);
+          return;
+        }
+        Message::ChunkData { pos, layers } => {
+          client.requested.remove(&pos);
+          // Chunks are loaded through the `World`, so any further messages, which may refer
+          // to this chunk, have to wait until it's been loaded at the end of this frame.
+          // Edits that are still pending are lost with the old chunk, so they're redone.
+          lazy.exec_mut(move |world| {
+            if let Err(err) = apply_chunk_data::<L>(world, level, pos, layers) {
+              warn!("Couldn't load chunk {:?} from server: {}", pos, err);
+              return;
+            }
+            let (mut client, mut blocks) =
+              world.system_data::<(WriteExpect<'_, NetworkClient>, BlockAccess<'_, u8, L>)>();
+            client.predictions.reapply_chunk(&mut blocks, level, pos);
+          });
+          break;
+        }
+        Message::UnloadChunk { pos } => {
+          client.requested.remove(&pos);
+          if let Some(chunk) = blocks.chunk(level, pos) {
+            lazy.exec_mut(move |world| {
+              let _ = world.entities().delete(chunk);
+            });
+          }
+        }
+        Message::ChunkDelta { pos, .. } if client.requested.contains(&pos) => {}
+        Message::ChunkDelta { pos, delta } => {
+          let result =
+            (client.predictions).apply_authoritative(&mut blocks, level, &[pos], |blocks| {
+              apply_chunk_delta(blocks, level, pos, &delta)
+            });
+          if let Err(err) = result {
+            warn!("Couldn't apply changes to chunk {:?}: {}", pos, err);
+            client.requested.insert(pos);
+            if let Err(err) = client.send(&Message::RequestChunk { pos }) {
+              client.disconnect(err.to_string());
+              return;
+            }
+          }
+        }
+        Message::BlockChanges { changes } => {
+          let chunks = (changes.iter())
+            .map(|(pos, _)| {
+              let (chunk_pos, _): (ChunkPos, Index<L>) = (*pos).into();
+              chunk_pos
+            })
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+          (client.predictions).apply_authoritative(&mut blocks, level, &chunks, |blocks| {
+            apply_block_changes(blocks, level, &changes)
+          });
+        }
+        Message::EditsProcessed { sequence } => {
+          for edit in client.predictions.confirm(&mut blocks, sequence) {
+            debug!("Edit of block {:?} was reverted by the server", edit.pos);
+          }
+        }
+        // Scripts from the server replace any local script of the same name.
+        Message::Script { name, source } => lazy.exec_mut(move |world| {
+          if let Err(err) = load_script(world, &name, source) {
+            warn!("Couldn't load script '{}' from server: {}", name, err);
+          }
+        }),
+        Message::RemoveScript { name } => lazy.exec_mut(move |world| {
+          remove_script(world, &name);
+        }),
+        // Other players aren't displayed yet.
+        Message::PlayerPosition { .. } => {}
+        message => warn!("Unexpected message from server: {:?}", message),
+      }
+    }
+
+    if let (Some(player), Some((_, transform))) =
+      (client.player, (&tags, &transforms).join().next())
+    {
+      let translation = transform.translation();
+      let (pitch, yaw, _) = transform.rotation().euler_angles();
+      let message = Message::PlayerPosition {
+        player,
+        pos: [translation.x, translation.y, translation.z],
+        yaw,
+        pitch,
+      };
+      if let Err(err) = client.send(&message) {
+        client.disconnect(err.to_string());
+      }
+    }
+  }
+}
@@ -0,0 +1,358 @@
+use {
+  crate::bloxel::chunk::{storage::ChunkStorage, ChunkLayout, Index},
+  std::{error::Error, fmt},
+};
+
+/// Largest number of unchanged blocks between two changed ones for them to still be sent as part
+/// of the same range. Including a few unchanged blocks is cheaper than the header of a new range.
+const MAX_RANGE_GAP: usize = 8;
+
+/// Changes to the blocks of a chunk, between contents both client and server know about and new
+/// contents of the server, encoded in whichever way is smallest. See `ChunkDelta::between`.
+/// Raw indices are stored as `u32`, as chunks of larger layouts have more than `u16::MAX` blocks.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkDelta {
+  /// Blocks that changed, each by raw index along with its new value. Used for small edits.
+  Blocks(Vec<(u32, u8)>),
+  /// Runs of blocks that changed, each by the raw index it starts at, along with indices into
+  /// the palette of the old contents, extended by `added`. Used for large edits, such as filling
+  /// an area, where packing palette indices into a few bits per block wins over listing them.
+  Ranges {
+    /// Number of distinct values in the old contents, to tell whether the client's palette
+    /// matches the server's and to know the number of bits per index when decoding.
+    base_palette: u16,
+    added: Vec<u8>,
+    ranges: Vec<(u32, Vec<u16>)>,
+  },
+  /// Complete new contents, as a palette and an index into it for every block, which is empty
+  /// if there's only a single value. Used when neither delta would be any smaller.
+  Full { palette: Vec<u8>, indices: Vec<u16> },
+}
+
+impl ChunkDelta {
+  /// Creates the smallest delta turning `old` into `new`, which have to be of the same length.
+  pub fn between(old: &[u8], new: &[u8]) -> Self {
+    assert_eq!(old.len(), new.len());
+    let changed = (0..new.len())
+      .filter(|&i| old[i] != new[i])
+      .collect::<Vec<_>>();
+    let blocks = ChunkDelta::Blocks(changed.iter().map(|&i| (i as u32, new[i])).collect());
+    let full = {
+      let palette = palette(new);
+      let indices = if palette.len() > 1 {
+        new.iter().map(|value| index_of(&palette, *value)).collect()
+      } else {
+        Vec::new()
+      };
+      ChunkDelta::Full { palette, indices }
+    };
+    if changed.is_empty() {
+      return blocks;
+    }
+
+    let mut palette = palette(old);
+    let base_palette = palette.len() as u16;
+    for &i in &changed {
+      if !palette.contains(&new[i]) {
+        palette.push(new[i]);
+      }
+    }
+    let added = palette.split_off(base_palette as usize);
+    palette.extend(&added);
+    let mut ranges = Vec::<(u32, Vec<u16>)>::new();
+    let mut end = 0;
+    for &i in &changed {
+      match ranges.last_mut() {
+        Some((_, indices)) if i - end <= MAX_RANGE_GAP => {
+          indices.extend(new[end + 1..=i].iter().map(|v| index_of(&palette, *v)));
+        }
+        _ => ranges.push((i as u32, vec![index_of(&palette, new[i])])),
+      }
+      end = i;
+    }
+    let ranges = ChunkDelta::Ranges {
+      base_palette,
+      added,
+      ranges,
+    };
+
+    let delta = if ranges.encoded_size() < blocks.encoded_size() {
+      ranges
+    } else {
+      blocks
+    };
+    if delta.encoded_size() < full.encoded_size() {
+      delta
+    } else {
+      full
+    }
+  }
+
+  /// Number of bytes this delta takes up once encoded as part of a message.
+  pub fn encoded_size(&self) -> usize {
+    // Each variant starts with a byte telling which one it is.
+    1 + match self {
+      ChunkDelta::Blocks(blocks) => 4 + blocks.len() * 5,
+      ChunkDelta::Ranges {
+        base_palette,
+        added,
+        ranges,
+      } => {
+        let bits = index_bits(*base_palette as usize + added.len()) as usize;
+        let ranges = ranges
+          .iter()
+          .map(|(_, indices)| 8 + (indices.len() * bits + 7) / 8)
+          .sum::<usize>();
+        2 + 2 + added.len() + 4 + ranges
+      }
+      ChunkDelta::Full { palette, indices } => {
+        2 + palette.len() + 4 + (indices.len() * index_bits(palette.len()) as usize + 7) / 8
+      }
+    }
+  }
+
+  /// Returns the raw index and new value of every block this delta changes in `old`,
+  /// which has to be the contents the delta was created from.
+  pub fn changes(&self, old: &[u8]) -> Result<Vec<(usize, u8)>, DeltaError> {
+    match self {
+      ChunkDelta::Blocks(blocks) => blocks
+        .iter()
+        .map(|&(index, value)| match index as usize {
+          index if index < old.len() => Ok((index, value)),
+          index => Err(DeltaError::OutOfBounds(index)),
+        })
+        .collect(),
+      ChunkDelta::Ranges {
+        base_palette,
+        added,
+        ranges,
+      } => {
+        let mut palette = palette(old);
+        if palette.len() != *base_palette as usize {
+          return Err(DeltaError::BaseMismatch);
+        }
+        palette.extend(added);
+        let mut changes = Vec::new();
+        for (start, indices) in ranges {
+          for (offset, index) in indices.iter().enumerate() {
+            let raw = *start as usize + offset;
+            if raw >= old.len() {
+              return Err(DeltaError::OutOfBounds(raw));
+            }
+            let value = *(palette.get(*index as usize)).ok_or(DeltaError::InvalidIndex(*index))?;
+            if old[raw] != value {
+              changes.push((raw, value));
+            }
+          }
+        }
+        Ok(changes)
+      }
+      ChunkDelta::Full { palette, indices } => {
+        let value_at = |raw: usize| match (indices.get(raw), palette.len()) {
+          (Some(index), _) => palette
+            .get(*index as usize)
+            .copied()
+            .ok_or(DeltaError::InvalidIndex(*index)),
+          (None, 1) if indices.is_empty() => Ok(palette[0]),
+          _ => Err(DeltaError::WrongSize(indices.len())),
+        };
+        if !indices.is_empty() && indices.len() != old.len() {
+          return Err(DeltaError::WrongSize(indices.len()));
+        }
+        let mut changes = Vec::new();
+        for (raw, old) in old.iter().enumerate() {
+          let value = value_at(raw)?;
+          if *old != value {
+            changes.push((raw, value));
+          }
+        }
+        Ok(changes)
+      }
+    }
+  }
+}
+
+/// Returns the contents of a chunk's blocks, ordered by raw index, to create deltas from.
+pub fn chunk_contents<L: ChunkLayout>(storage: &ChunkStorage<u8, L>) -> Vec<u8> {
+  (0..L::SIZE as u32)
+    .map(|raw| storage.get(Index::from_raw(raw).unwrap()))
+    .collect()
+}
+
+/// Returns the distinct values in `contents`, in the order they first appear.
+fn palette(contents: &[u8]) -> Vec<u8> {
+  let mut seen = [false; 256];
+  let mut palette = Vec::new();
+  for value in contents {
+    if !seen[*value as usize] {
+      seen[*value as usize] = true;
+      palette.push(*value);
+    }
+  }
+  palette
+}
+
+fn index_of(palette: &[u8], value: u8) -> u16 {
+  palette.iter().position(|v| *v == value).unwrap() as u16
+}
+
+/// Returns the number of bits needed to store indices into a palette of the specified length.
+pub(super) fn index_bits(palette_length: usize) -> u32 {
+  let max_index = palette_length.max(2) - 1;
+  32 - (max_index as u32).leading_zeros()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeltaError {
+  /// A block past the end of the chunk was changed.
+  OutOfBounds(usize),
+  /// A palette index doesn't refer to any value.
+  InvalidIndex(u16),
+  /// The contents the delta was applied to aren't the ones it was created from.
+  BaseMismatch,
+  /// Full contents didn't have one index for every block.
+  WrongSize(usize),
+}
+
+impl Error for DeltaError {}
+
+impl fmt::Display for DeltaError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DeltaError::OutOfBounds(index) => write!(f, "Block {} is outside of the chunk", index),
+      DeltaError::InvalidIndex(index) => write!(f, "Palette index {} is invalid", index),
+      DeltaError::BaseMismatch => write!(f, "Delta doesn't apply to the chunk's contents"),
+      DeltaError::WrongSize(size) => write!(f, "Chunk contents of size {} don't fit", size),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::util::PaletteStore};
+
+  const SIZE: usize = 4096;
+
+  fn store_with<F: Fn(usize) -> u8>(value: F) -> PaletteStore<u8> {
+    let mut store = PaletteStore::new(SIZE);
+    for i in 0..SIZE {
+      store.set(i, value(i)).unwrap();
+    }
+    store
+  }
+
+  fn contents(store: &PaletteStore<u8>) -> Vec<u8> {
+    (0..SIZE).map(|i| store.get(i).unwrap()).collect()
+  }
+
+  /// Creates the delta between both stores, applies it to the old one and checks that
+  /// it ends up with the new contents, returning the delta for further inspection.
+  fn round_trip(mut old: PaletteStore<u8>, new: &PaletteStore<u8>) -> ChunkDelta {
+    let delta = ChunkDelta::between(&contents(&old), &contents(new));
+    for (index, value) in delta.changes(&contents(&old)).unwrap() {
+      old.set(index, value).unwrap();
+    }
+    assert_eq!(contents(&old), contents(new));
+    delta
+  }
+
+  /// Terrain-like contents: stone below, air above, with a layer of dirt in between.
+  fn terrain(i: usize) -> u8 {
+    match i / 256 {
+      0..=5 => 1,
+      6..=7 => 2,
+      _ => 0,
+    }
+  }
+
+  #[test]
+  fn small_edits_list_blocks() {
+    let old = store_with(terrain);
+    let new = store_with(|i| match i {
+      100 | 2000 | 3000 => 7,
+      _ => terrain(i),
+    });
+    let delta = round_trip(old, &new);
+    assert_eq!(
+      delta,
+      ChunkDelta::Blocks(vec![(100, 7), (2000, 7), (3000, 7)])
+    );
+  }
+
+  #[test]
+  fn large_edits_pack_ranges() {
+    let old = store_with(terrain);
+    // Replace a whole layer of the chunk, along with a few blocks scattered through the one above.
+    let new = store_with(|i| match i {
+      768..=1023 => 3,
+      1024..=1279 if i % 5 == 0 => 0,
+      _ => terrain(i),
+    });
+    let delta = round_trip(old, &new);
+    match &delta {
+      ChunkDelta::Ranges {
+        base_palette,
+        added,
+        ranges,
+      } => {
+        assert_eq!((*base_palette, added.as_slice()), (3, &[3][..]));
+        assert_eq!(ranges.len(), 1);
+      }
+      other => panic!("Expected ranges, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn rewriting_everything_falls_back_to_full_contents() {
+    let old = store_with(terrain);
+    let new = store_with(|i| (i * 7 % 13) as u8);
+    match round_trip(old, &new) {
+      ChunkDelta::Full { palette, indices } => {
+        assert_eq!(palette.len(), 13);
+        assert_eq!(indices.len(), SIZE);
+      }
+      other => panic!("Expected full contents, got {:?}", other),
+    }
+
+    // Uniform contents don't need any indices.
+    let old = store_with(terrain);
+    let new = store_with(|_| 0);
+    assert_eq!(
+      round_trip(old, &new),
+      ChunkDelta::Full {
+        palette: vec![0],
+        indices: Vec::new()
+      }
+    );
+  }
+
+  #[test]
+  fn unchanged_contents_have_empty_delta() {
+    let delta = round_trip(store_with(terrain), &store_with(terrain));
+    assert_eq!(delta, ChunkDelta::Blocks(Vec::new()));
+  }
+
+  #[test]
+  fn indices_past_u16_are_kept() {
+    // As many blocks as a chunk of `LinearLayout64` has.
+    let old = vec![0; 64 * 64 * 64];
+    let mut new = old.clone();
+    new[70_000] = 1;
+    let delta = ChunkDelta::between(&old, &new);
+    assert_eq!(delta, ChunkDelta::Blocks(vec![(70_000, 1)]));
+    assert_eq!(delta.changes(&old), Ok(vec![(70_000, 1)]));
+  }
+
+  #[test]
+  fn ranges_require_matching_base() {
+    let old = contents(&store_with(terrain));
+    let new = old
+      .iter()
+      .map(|v| if *v == 1 { 4 } else { *v })
+      .collect::<Vec<_>>();
+    let delta = ChunkDelta::between(&old, &new);
+    assert!(matches!(delta, ChunkDelta::Ranges { .. }));
+    let other = vec![5; SIZE];
+    assert_eq!(delta.changes(&other), Err(DeltaError::BaseMismatch));
+  }
+}
@@ -0,0 +1,433 @@
+//! Networking between a server simulating levels and the clients connected to it.
+//!
+//! A connection starts with the client sending `Message::Hello`, which the server answers with
+//! `Message::Welcome`, or `Message::Disconnect` if it can't accept the client. Afterwards, the
+//! server sends the chunks around the player, followed by a `ChunkDelta` whenever blocks change
+//! within them, or the whole chunk again when another of its layers changes, while client and
+//! server keep each other updated about player positions. Clients apply their own block edits
+//! right away, see `PredictedEdits`, and send them to the server as `Message::EditBlock`, which
+//! it confirms with `Message::EditsProcessed`.
+
+use {
+  super::{
+    chunk::{ChunkLayout, ChunkPos, Index, LayerData},
+    load_chunk, save_chunk, BlockAccess, BlockPos, PersistenceError, SavedChunk,
+  },
+  amethyst::ecs::prelude::*,
+};
+
+pub use {client::*, delta::*, prediction::*, protocol::*, server::*, transport::*};
+
+mod client;
+mod delta;
+mod prediction;
+mod protocol;
+mod server;
+mod transport;
+
+/// Waits for the `Hello` of a freshly connected client, answering it with a `Welcome` that
+/// assigns the client the specified player id. Returns the client's name once the handshake
+/// is complete, or `None` if it hasn't sent anything yet. Clients speaking a different version
+/// of the protocol are sent a `Disconnect` explaining why, before the error is returned.
+pub fn accept_client(
+  transport: &mut dyn Transport,
+  player: u32,
+  seed: u32,
+) -> Result<Option<String>, ProtocolError> {
+  match transport.receive() {
+    Ok(Some(Message::Hello { name, .. })) => {
+      transport.send(&Message::Welcome { player, seed })?;
+      Ok(Some(name))
+    }
+    Ok(Some(_)) => Err(ProtocolError::UnexpectedMessage),
+    Ok(None) => Ok(None),
+    Err(err @ ProtocolError::VersionMismatch(_)) => {
+      let reason = err.to_string();
+      // The client is disconnected either way, so failing to tell it why doesn't matter.
+      let _ = transport.send(&Message::Disconnect { reason });
+      Err(err)
+    }
+    Err(err) => Err(err),
+  }
+}
+
+/// Creates the `ChunkData` message for the specified chunk entity.
+pub fn chunk_data<L: ChunkLayout>(
+  world: &World,
+  chunk: Entity,
+) -> Result<Message, PersistenceError> {
+  let saved = save_chunk::<L>(world, chunk)?;
+  Ok(Message::ChunkData {
+    pos: saved.pos,
+    layers: saved.layers,
+  })
+}
+
+/// Loads a chunk received through a `ChunkData` message into the specified level on the client,
+/// replacing the chunk at its position if there is one already.
+pub fn apply_chunk_data<L: ChunkLayout>(
+  world: &mut World,
+  level: Entity,
+  pos: ChunkPos,
+  layers: Vec<(String, LayerData)>,
+) -> Result<Entity, PersistenceError> {
+  let saved = SavedChunk {
+    pos,
+    layers,
+    // Only the server ticks blocks, and block entities aren't synchronized.
+    scheduled_ticks: None,
+    block_entities: Vec::new(),
+  };
+  load_chunk::<L>(world, level, saved)
+}
+
+/// Applies the changes of a `BlockChanges` message to the specified level on the client.
+pub fn apply_block_changes<L: ChunkLayout>(
+  blocks: &mut BlockAccess<'_, u8, L>,
+  level: Entity,
+  changes: &[(BlockPos, u8)],
+) {
+  for (pos, value) in changes {
+    blocks.set(level, *pos, *value);
+  }
+}
+
+/// Applies a `ChunkDelta` message to the chunk at the specified position on the client. Fails if
+/// the delta doesn't fit the chunk, in which case the chunk is out of sync and has to be requested
+/// again through `Message::RequestChunk`. Only `ChunkDelta::Ranges` can tell whether the chunk's
+/// contents are the ones the server created it from, so other deltas may apply to a chunk that's
+/// already out of sync without failing.
+pub fn apply_chunk_delta<L: ChunkLayout>(
+  blocks: &mut BlockAccess<'_, u8, L>,
+  level: Entity,
+  pos: ChunkPos,
+  delta: &ChunkDelta,
+) -> Result<(), DeltaError> {
+  let old = (0..L::SIZE as u32)
+    .map(|raw| blocks.get(level, (pos, Index::<L>::from_raw(raw).unwrap()).into()))
+    .collect::<Vec<_>>();
+  for (raw, value) in delta.changes(&old)? {
+    let index = Index::<L>::from_raw(raw as u32).unwrap();
+    blocks.set(level, (pos, index).into(), value);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{
+      chunk::{storage::ChunkStorage, ChunkLayers, LinearLayout16},
+      create_level, run_command, ActiveLevel, BlockChange, BlockEntityAccess, BlockLight,
+      BlockTickScheduler, BlockView, ConsoleCommands, FluidLevel, Level, SkyLight,
+    },
+    amethyst::shrev::EventChannel,
+  };
+
+  fn world() -> (World, Entity) {
+    let mut world = World::new();
+    BlockAccess::<u8, LinearLayout16>::setup(&mut world);
+    BlockEntityAccess::<LinearLayout16>::setup(&mut world);
+    BlockTickScheduler::<LinearLayout16>::setup(&mut world);
+    let layers = ChunkLayers::<LinearLayout16>::default().with::<FluidLevel>("fluid_levels");
+    layers.register(&mut world);
+    world.insert(layers);
+    world.register::<ChunkStorage<BlockLight, LinearLayout16>>();
+    world.register::<ChunkStorage<SkyLight, LinearLayout16>>();
+    let level = create_level(world.create_entity(), Level::new(1234));
+    (world, level)
+  }
+
+  #[test]
+  fn client_joins_and_receives_chunk() {
+    let (mut server_world, server_level) = world();
+    let (mut client_world, client_level) = world();
+    let (mut client, mut server) = LoopbackTransport::pair();
+
+    assert_eq!(accept_client(&mut server, 7, 1234).unwrap(), None);
+    client.send(&Message::hello("player")).unwrap();
+    assert_eq!(
+      accept_client(&mut server, 7, 1234).unwrap(),
+      Some("player".to_string())
+    );
+    assert_eq!(
+      client.receive().unwrap(),
+      Some(Message::Welcome {
+        player: 7,
+        seed: 1234
+      })
+    );
+
+    let pos = BlockPos::new(-3, 2, 17);
+    let chunk = server_world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.set(server_level, pos, 5);
+      blocks.chunk(server_level, ChunkPos::new(-1, 0, 1)).unwrap()
+    });
+    server
+      .send(&chunk_data::<LinearLayout16>(&server_world, chunk).unwrap())
+      .unwrap();
+    let changed = BlockPos::new(-4, 2, 17);
+    server
+      .send(&Message::BlockChanges {
+        changes: vec![(changed, 6)],
+      })
+      .unwrap();
+
+    match client.receive().unwrap() {
+      Some(Message::ChunkData { pos, layers }) => {
+        apply_chunk_data::<LinearLayout16>(&mut client_world, client_level, pos, layers).unwrap();
+      }
+      other => panic!("Expected chunk data, got {:?}", other),
+    }
+    match client.receive().unwrap() {
+      Some(Message::BlockChanges { changes }) => {
+        client_world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+          apply_block_changes(&mut blocks, client_level, &changes);
+        });
+      }
+      other => panic!("Expected block changes, got {:?}", other),
+    }
+    client_world.exec(|blocks: BlockView<'_, u8, LinearLayout16>| {
+      assert_eq!(blocks.get(client_level, pos), 5);
+      assert_eq!(blocks.get(client_level, changed), 6);
+    });
+  }
+
+  #[test]
+  fn chunk_deltas_keep_client_in_sync() {
+    let (mut server_world, server_level) = world();
+    let (mut client_world, client_level) = world();
+    let (mut client, mut server) = LoopbackTransport::pair();
+
+    let mut reader = server_world
+      .write_resource::<EventChannel<BlockChange>>()
+      .register_reader();
+    let pos = ChunkPos::new(0, 0, 0);
+    let chunk = server_world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.fill(
+        server_level,
+        BlockPos::new(0, 0, 0),
+        BlockPos::new(15, 3, 15),
+        1,
+      );
+      blocks.chunk(server_level, pos).unwrap()
+    });
+    match chunk_data::<LinearLayout16>(&server_world, chunk).unwrap() {
+      Message::ChunkData { pos, layers } => {
+        apply_chunk_data::<LinearLayout16>(&mut client_world, client_level, pos, layers).unwrap();
+      }
+      other => panic!("Expected chunk data, got {:?}", other),
+    }
+
+    // Changes from before the chunk was sent are already part of it.
+    server_world
+      .read_resource::<EventChannel<BlockChange>>()
+      .read(&mut reader)
+      .for_each(drop);
+    server_world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.set(server_level, BlockPos::new(3, 8, 3), 2);
+      blocks.fill(
+        server_level,
+        BlockPos::new(0, 4, 0),
+        BlockPos::new(15, 5, 15),
+        3,
+      );
+      // Changed back, so it doesn't need to be sent at all.
+      blocks.set(server_level, BlockPos::new(0, 20, 0), 4);
+      blocks.set(server_level, BlockPos::new(0, 20, 0), 0);
+    });
+    let changes = server_world
+      .read_resource::<EventChannel<BlockChange>>()
+      .read(&mut reader)
+      .copied()
+      .collect::<Vec<_>>();
+    let deltas = server_world.exec(|blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      chunk_deltas(&changes, &blocks, |_, _| true)
+    });
+    assert_eq!(deltas.len(), 1);
+    for ((_, pos), delta) in deltas {
+      server.send(&Message::ChunkDelta { pos, delta }).unwrap();
+    }
+
+    match client.receive().unwrap() {
+      Some(Message::ChunkDelta { pos, delta }) => {
+        client_world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+          apply_chunk_delta(&mut blocks, client_level, pos, &delta).unwrap();
+        });
+      }
+      other => panic!("Expected chunk delta, got {:?}", other),
+    }
+    let contents = |world: &mut World, level| {
+      world.exec(|blocks: BlockView<'_, u8, LinearLayout16>| {
+        chunk_contents(blocks.storage(level, pos).unwrap())
+      })
+    };
+    assert_eq!(
+      contents(&mut client_world, client_level),
+      contents(&mut server_world, server_level)
+    );
+  }
+
+  #[test]
+  fn chunks_out_of_sync_are_requested_again() {
+    let (mut world, level) = world();
+    world.insert(ActiveLevel(Some(level)));
+    let mut system = ClientNetworkSystem::<LinearLayout16>::default();
+    System::setup(&mut system, &mut world);
+    let (client, mut server) = LoopbackTransport::pair();
+    world.insert(NetworkClient::connect(client, "player").unwrap());
+    assert!(matches!(server.receive(), Ok(Some(Message::Hello { .. }))));
+
+    // Full contents with the wrong number of indices can't be applied to any chunk.
+    let pos = ChunkPos::new(1, 0, 0);
+    let broken = ChunkDelta::Full {
+      palette: vec![0, 1],
+      indices: vec![1; 10],
+    };
+    for _ in 0..2 {
+      let delta = broken.clone();
+      server.send(&Message::ChunkDelta { pos, delta }).unwrap();
+    }
+    system.run_now(&world);
+    // Only the first delta is answered, as the chunk has already been requested by then.
+    assert_eq!(
+      server.receive().unwrap(),
+      Some(Message::RequestChunk { pos })
+    );
+    assert_eq!(server.receive().unwrap(), None);
+    assert!(world
+      .read_resource::<NetworkClient>()
+      .disconnected
+      .is_none());
+  }
+
+  #[test]
+  fn mismatched_version_is_disconnected() {
+    let (mut client, mut server) = LoopbackTransport::pair();
+    client
+      .send(&Message::Hello {
+        version: PROTOCOL_VERSION + 1,
+        name: "player".to_string(),
+      })
+      .unwrap();
+    assert!(matches!(
+      accept_client(&mut server, 0, 0),
+      Err(ProtocolError::VersionMismatch(_))
+    ));
+    assert!(matches!(
+      client.receive(),
+      Ok(Some(Message::Disconnect { .. }))
+    ));
+    drop(server);
+    assert!(matches!(client.receive(), Err(ProtocolError::Closed)));
+  }
+
+  #[test]
+  fn connect_command_joins_server() {
+    let (mut world, level) = world();
+    world.insert(ActiveLevel(Some(level)));
+    world.insert(ConsoleCommands::default().with("connect", "", "", connect_command));
+    let mut system = ClientNetworkSystem::<LinearLayout16>::default();
+    System::setup(&mut system, &mut world);
+
+    let listener = TcpServer::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    run_command(&mut world, &format!("connect {} tester", addr)).unwrap();
+    assert!(run_command(&mut world, &format!("connect {}", addr)).is_err());
+
+    let mut server = loop {
+      if let Some(server) = listener.accept().unwrap() {
+        break server;
+      }
+    };
+    let name = loop {
+      if let Some(name) = accept_client(&mut server, 3, 999).unwrap() {
+        break name;
+      }
+    };
+    assert_eq!(name, "tester");
+    while world.read_resource::<NetworkClient>().player.is_none() {
+      system.run_now(&world);
+    }
+    assert_eq!(world.read_resource::<NetworkClient>().player, Some(3));
+    assert_eq!(world.read_storage::<Level>().get(level).unwrap().seed, 999);
+  }
+
+  #[test]
+  fn mismatched_version_is_disconnected_over_tcp() {
+    let listener = TcpServer::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpTransport::connect(listener.local_addr().unwrap()).unwrap();
+    let mut server = loop {
+      if let Some(server) = listener.accept().unwrap() {
+        break server;
+      }
+    };
+    client
+      .send(&Message::Hello {
+        version: PROTOCOL_VERSION + 1,
+        name: "player".to_string(),
+      })
+      .unwrap();
+    loop {
+      match accept_client(&mut server, 0, 0) {
+        Ok(None) => continue,
+        Err(ProtocolError::VersionMismatch(_)) => break,
+        other => panic!("Expected version mismatch, got {:?}", other),
+      }
+    }
+    // The server closes the connection right after sending the reason,
+    // which must still be received even if both arrive at once.
+    drop(server);
+    let mut receive = || loop {
+      match client.receive() {
+        Ok(None) => continue,
+        result => break result,
+      }
+    };
+    assert!(matches!(receive(), Ok(Some(Message::Disconnect { .. }))));
+    assert!(matches!(receive(), Err(ProtocolError::Closed)));
+  }
+
+  #[test]
+  fn loopback_transport_rejects_large_messages() {
+    let (mut client, mut server) = LoopbackTransport::pair();
+    let message = Message::Script {
+      name: "large".to_string(),
+      source: "x".repeat(MAX_MESSAGE_SIZE),
+    };
+    assert!(matches!(
+      client.send(&message),
+      Err(ProtocolError::TooLarge(_))
+    ));
+    assert!(server.receive().unwrap().is_none());
+  }
+
+  #[test]
+  fn tcp_transport_frames_messages() {
+    let listener = TcpServer::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpTransport::connect(listener.local_addr().unwrap()).unwrap();
+    let mut server = loop {
+      if let Some(server) = listener.accept().unwrap() {
+        break server;
+      }
+    };
+    let messages = vec![
+      Message::hello("player"),
+      Message::BlockChanges {
+        changes: (0..1000).map(|i| (BlockPos::new(i, 0, 0), 1)).collect(),
+      },
+    ];
+    for message in &messages {
+      client.send(message).unwrap();
+    }
+    let mut received = Vec::new();
+    while received.len() < messages.len() {
+      client.receive().unwrap();
+      if let Some(message) = server.receive().unwrap() {
+        received.push(message);
+      }
+    }
+    assert_eq!(received, messages);
+  }
+}
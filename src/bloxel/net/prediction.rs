@@ -0,0 +1,287 @@
+use {
+  super::Message,
+  crate::bloxel::{
+    chunk::{ChunkLayout, ChunkPos, Index},
+    BlockAccess, BlockPos,
+  },
+  amethyst::ecs::prelude::*,
+  std::collections::HashSet,
+};
+
+/// A block edit the client has made locally, which the server hasn't confirmed yet.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PredictedEdit {
+  pub sequence: u32,
+  pub level: Entity,
+  pub pos: BlockPos,
+  /// Value the block had before the edit, as far as the server is concerned.
+  pub previous: u8,
+  pub value: u8,
+}
+
+/// Block edits a client has applied right away, so they feel instant, while waiting for the
+/// server to process them. Since the server may reject edits or other players may have changed
+/// the same blocks first, the server's updates have to be applied through `apply_authoritative`,
+/// and edits are confirmed once the server reports they've been processed using `confirm`.
+///
+/// All changes go through `BlockAccess`, so chunks whose blocks are reverted get remeshed. To
+/// keep that to a minimum, only the edits affected by an update are undone and redone.
+#[derive(Debug, Default)]
+pub struct PredictedEdits {
+  pending: Vec<PredictedEdit>,
+  next_sequence: u32,
+}
+
+impl PredictedEdits {
+  /// Edits that haven't been confirmed yet, oldest first.
+  pub fn pending(&self) -> &[PredictedEdit] {
+    &self.pending
+  }
+
+  /// Sets the block at the specified position locally, returning the `EditBlock`
+  /// message which asks the server to do the same.
+  pub fn predict<L: ChunkLayout>(
+    &mut self,
+    blocks: &mut BlockAccess<'_, u8, L>,
+    level: Entity,
+    pos: BlockPos,
+    value: u8,
+  ) -> Message {
+    let sequence = self.next_sequence;
+    self.next_sequence += 1;
+    let previous = blocks.set(level, pos, value);
+    self.pending.push(PredictedEdit {
+      sequence,
+      level,
+      pos,
+      previous,
+      value,
+    });
+    Message::EditBlock {
+      sequence,
+      pos,
+      value,
+    }
+  }
+
+  /// Applies changes sent by the server to the specified chunks of a level. Pending edits in those
+  /// chunks are undone first, so `update` sees their blocks the way the server knew them, and
+  /// then redone on top of whatever it changed. Edits anywhere else are left alone.
+  pub fn apply_authoritative<L, F, R>(
+    &mut self,
+    blocks: &mut BlockAccess<'_, u8, L>,
+    level: Entity,
+    chunks: &[ChunkPos],
+    update: F,
+  ) -> R
+  where
+    L: ChunkLayout,
+    F: FnOnce(&mut BlockAccess<'_, u8, L>) -> R,
+  {
+    let affected =
+      |edit: &PredictedEdit| edit.level == level && chunks.contains(&chunk_pos_of::<L>(edit.pos));
+    self.rollback(blocks, affected);
+    let result = update(blocks);
+    self.reapply(blocks, affected);
+    result
+  }
+
+  /// Redoes the pending edits in the specified chunk of a level, after the chunk has been
+  /// replaced by the contents the server sent, such as through `apply_chunk_data`.
+  pub fn reapply_chunk<L: ChunkLayout>(
+    &mut self,
+    blocks: &mut BlockAccess<'_, u8, L>,
+    level: Entity,
+    pos: ChunkPos,
+  ) {
+    self.reapply(blocks, |edit| {
+      edit.level == level && chunk_pos_of::<L>(edit.pos) == pos
+    });
+  }
+
+  /// Confirms all edits up to the specified sequence number, which the server has processed.
+  /// Returns the edits that were mispredicted: The server either rejected them or something else
+  /// changed the same block, so they've been reverted to the value the server sent.
+  pub fn confirm<L: ChunkLayout>(
+    &mut self,
+    blocks: &mut BlockAccess<'_, u8, L>,
+    sequence: u32,
+  ) -> Vec<PredictedEdit> {
+    // Only blocks edited by one of the confirmed edits have to be compared to the server's.
+    let positions = (self.pending.iter())
+      .filter(|edit| edit.sequence <= sequence)
+      .map(|edit| (edit.level, edit.pos))
+      .collect::<HashSet<_>>();
+    let affected = |edit: &PredictedEdit| positions.contains(&(edit.level, edit.pos));
+    self.rollback(blocks, affected);
+    let (confirmed, pending) =
+      (self.pending.drain(..)).partition::<Vec<_>, _>(|edit| edit.sequence <= sequence);
+    self.pending = pending;
+    let mispredicted = confirmed
+      .into_iter()
+      .filter(|edit| blocks.get(edit.level, edit.pos) != edit.value)
+      .collect();
+    self.reapply(blocks, affected);
+    mispredicted
+  }
+
+  /// Undoes the affected pending edits, newest first.
+  fn rollback<L, F>(&self, blocks: &mut BlockAccess<'_, u8, L>, affected: F)
+  where
+    L: ChunkLayout,
+    F: Fn(&PredictedEdit) -> bool,
+  {
+    for edit in self.pending.iter().rev().filter(|edit| affected(edit)) {
+      blocks.set(edit.level, edit.pos, edit.previous);
+    }
+  }
+
+  /// Redoes the affected pending edits, oldest first, taking note of what they replace now.
+  fn reapply<L, F>(&mut self, blocks: &mut BlockAccess<'_, u8, L>, affected: F)
+  where
+    L: ChunkLayout,
+    F: Fn(&PredictedEdit) -> bool,
+  {
+    for edit in self.pending.iter_mut().filter(|edit| affected(edit)) {
+      edit.previous = blocks.set(edit.level, edit.pos, edit.value);
+    }
+  }
+}
+
+fn chunk_pos_of<L: ChunkLayout>(pos: BlockPos) -> ChunkPos {
+  let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+  chunk_pos
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{chunk::LinearLayout16, create_level, BlockChange, Level},
+    amethyst::shrev::EventChannel,
+  };
+
+  type Blocks<'a> = BlockAccess<'a, u8, LinearLayout16>;
+
+  fn world() -> (World, Entity) {
+    let mut world = World::new();
+    Blocks::setup(&mut world);
+    world.register::<Level>();
+    let level = create_level(world.create_entity(), Level::new(0));
+    (world, level)
+  }
+
+  #[test]
+  fn confirmed_edits_stay() {
+    let (mut world, level) = world();
+    let pos = BlockPos::new(1, 2, 3);
+    let chunk = chunk_pos_of::<LinearLayout16>(pos);
+    world.exec(|mut blocks: Blocks<'_>| {
+      let mut edits = PredictedEdits::default();
+      let message = edits.predict(&mut blocks, level, pos, 5);
+      assert_eq!(
+        message,
+        Message::EditBlock {
+          sequence: 0,
+          pos,
+          value: 5
+        }
+      );
+      assert_eq!(blocks.get(level, pos), 5);
+
+      // The server applied the edit, and sends the resulting change before confirming it.
+      edits.apply_authoritative(&mut blocks, level, &[chunk], |blocks| {
+        assert_eq!(blocks.get(level, pos), 0);
+        blocks.set(level, pos, 5);
+      });
+      assert!(edits.confirm(&mut blocks, 0).is_empty());
+      assert!(edits.pending().is_empty());
+      assert_eq!(blocks.get(level, pos), 5);
+    });
+  }
+
+  #[test]
+  fn rejected_edits_are_reverted() {
+    let (mut world, level) = world();
+    let (first, second) = (BlockPos::new(0, 0, 0), BlockPos::new(0, 1, 0));
+    world.exec(|mut blocks: Blocks<'_>| {
+      blocks.set(level, first, 2);
+      let mut edits = PredictedEdits::default();
+      edits.predict(&mut blocks, level, first, 0);
+      edits.predict(&mut blocks, level, second, 3);
+
+      // Only the first edit was processed and the server rejected it, so nothing changed.
+      let mispredicted = edits.confirm(&mut blocks, 0);
+      assert_eq!(mispredicted.len(), 1);
+      assert_eq!(mispredicted[0].pos, first);
+      assert_eq!(blocks.get(level, first), 2);
+      // The second edit is still pending.
+      assert_eq!(edits.pending().len(), 1);
+      assert_eq!(blocks.get(level, second), 3);
+    });
+  }
+
+  #[test]
+  fn pending_edits_are_kept_on_top_of_server_changes() {
+    let (mut world, level) = world();
+    let pos = BlockPos::new(-5, 0, 7);
+    let chunk = chunk_pos_of::<LinearLayout16>(pos);
+    world.exec(|mut blocks: Blocks<'_>| {
+      let mut edits = PredictedEdits::default();
+      edits.predict(&mut blocks, level, pos, 4);
+
+      // Someone else changed the block before the server got to our edit.
+      edits.apply_authoritative(&mut blocks, level, &[chunk], |blocks| {
+        blocks.set(level, pos, 9)
+      });
+      assert_eq!(blocks.get(level, pos), 4);
+      assert_eq!(edits.pending()[0].previous, 9);
+
+      // Our edit went through afterwards.
+      edits.apply_authoritative(&mut blocks, level, &[chunk], |blocks| {
+        blocks.set(level, pos, 4)
+      });
+      assert!(edits.confirm(&mut blocks, 0).is_empty());
+      assert_eq!(blocks.get(level, pos), 4);
+    });
+  }
+
+  #[test]
+  fn updates_only_undo_edits_in_their_chunks() {
+    let (mut world, level) = world();
+    let (near, far) = (BlockPos::new(0, 0, 0), BlockPos::new(100, 0, 0));
+    let mut edits = PredictedEdits::default();
+    world.exec(|mut blocks: Blocks<'_>| {
+      edits.predict(&mut blocks, level, near, 1);
+      edits.predict(&mut blocks, level, far, 2);
+    });
+    let mut reader = (world.fetch_mut::<EventChannel<BlockChange>>()).register_reader();
+
+    world.exec(|mut blocks: Blocks<'_>| {
+      let chunk = chunk_pos_of::<LinearLayout16>(near);
+      edits.apply_authoritative(&mut blocks, level, &[chunk], |blocks| {
+        assert_eq!(blocks.get(level, near), 0);
+        assert_eq!(blocks.get(level, far), 2);
+      });
+    });
+    let changes = world.fetch::<EventChannel<BlockChange>>();
+    let changed = changes.read(&mut reader).map(|change| change.pos);
+    assert_eq!(changed.collect::<Vec<_>>(), vec![near, near]);
+  }
+
+  #[test]
+  fn pending_edits_are_reapplied_to_replaced_chunks() {
+    let (mut world, level) = world();
+    let pos = BlockPos::new(3, 3, 3);
+    world.exec(|mut blocks: Blocks<'_>| {
+      let mut edits = PredictedEdits::default();
+      edits.predict(&mut blocks, level, pos, 1);
+
+      // The server sent the whole chunk, which doesn't include our edit yet.
+      blocks.set(level, pos, 6);
+      edits.reapply_chunk(&mut blocks, level, chunk_pos_of::<LinearLayout16>(pos));
+      assert_eq!(blocks.get(level, pos), 1);
+      assert_eq!(edits.pending()[0].previous, 6);
+    });
+  }
+}
@@ -0,0 +1,703 @@
+use {
+  super::{index_bits, ChunkDelta},
+  crate::bloxel::{chunk::LayerData, BlockPos, ChunkPos},
+  std::{convert::TryFrom, error::Error, fmt, io},
+};
+
+/// Version of the protocol, which has to match exactly between client and server.
+/// Bump this whenever the encoding of any message changes.
+pub const PROTOCOL_VERSION: u16 = 6;
+
+/// Bytes every `Message::Hello` starts with, so that connections
+/// from anything other than a gaemstone client are rejected early.
+const MAGIC: [u8; 4] = *b"GAEM";
+
+/// Messages sent between client and server. Each one is encoded as a tag byte
+/// followed by its fields, see `Message::encode`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+  /// Sent by a client as the first message after connecting.
+  Hello { version: u16, name: String },
+  /// Sent by the server in response to an accepted `Hello`.
+  Welcome { player: u32, seed: u32 },
+  /// Sent by either side before closing the connection.
+  Disconnect { reason: String },
+  /// Full contents of a chunk, with every layer registered in `ChunkLayers` it has, by name.
+  ChunkData {
+    pos: ChunkPos,
+    layers: Vec<(String, LayerData)>,
+  },
+  /// Tells the client a chunk is out of range, so it can unload it.
+  /// The server won't send any changes to its blocks until it's sent again.
+  UnloadChunk { pos: ChunkPos },
+  /// Blocks that have changed since they were last sent, along with their new value.
+  BlockChanges { changes: Vec<(BlockPos, u8)> },
+  /// Changes to the blocks of a chunk since its contents were last sent or changed.
+  ChunkDelta { pos: ChunkPos, delta: ChunkDelta },
+  /// Sent by clients to change a block, which they've already changed locally. The sequence
+  /// number increases with every edit, so the server can confirm which ones it's processed.
+  EditBlock {
+    sequence: u32,
+    pos: BlockPos,
+    value: u8,
+  },
+  /// Tells the client that all of its edits up to the specified sequence number have been
+  /// processed, and that any changes they caused have been sent, so they can be confirmed.
+  EditsProcessed { sequence: u32 },
+  /// Position and look direction of a player, sent by clients
+  /// for themselves and by the server for other players.
+  PlayerPosition {
+    player: u32,
+    pos: [f32; 3],
+    yaw: f32,
+    pitch: f32,
+  },
+  /// Source of a script the server runs, which clients load as well, see `Scripts`.
+  /// Sent when a client joins and whenever the script is reloaded.
+  Script { name: String, source: String },
+  /// Tells clients to remove a script the server no longer runs.
+  RemoveScript { name: String },
+  /// Sent by clients which couldn't apply a `ChunkDelta`, asking the server to send the chunk
+  /// again. Further deltas of the chunk are ignored until its `ChunkData` arrives.
+  RequestChunk { pos: ChunkPos },
+}
+
+impl Message {
+  /// Creates the `Hello` message for the protocol version this build speaks.
+  pub fn hello(name: impl Into<String>) -> Self {
+    Message::Hello {
+      version: PROTOCOL_VERSION,
+      name: name.into(),
+    }
+  }
+
+  fn tag(&self) -> u8 {
+    match self {
+      Message::Hello { .. } => 0,
+      Message::Welcome { .. } => 1,
+      Message::Disconnect { .. } => 2,
+      Message::ChunkData { .. } => 3,
+      Message::BlockChanges { .. } => 4,
+      Message::PlayerPosition { .. } => 5,
+      Message::UnloadChunk { .. } => 6,
+      Message::ChunkDelta { .. } => 7,
+      Message::EditBlock { .. } => 8,
+      Message::EditsProcessed { .. } => 9,
+      Message::Script { .. } => 10,
+      Message::RemoveScript { .. } => 11,
+      Message::RequestChunk { .. } => 12,
+    }
+  }
+
+  /// Fails if a list in the message has more entries than the field encoding its length can hold.
+  pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+    let mut out = Encoder(vec![self.tag()]);
+    match self {
+      Message::Hello { version, name } => {
+        out.bytes(&MAGIC);
+        out.u16(*version);
+        out.string(name);
+      }
+      Message::Welcome { player, seed } => {
+        out.u32(*player);
+        out.u32(*seed);
+      }
+      Message::Disconnect { reason } => out.string(reason),
+      Message::ChunkData { pos, layers } => {
+        out.i32(pos.x);
+        out.i32(pos.y);
+        out.i32(pos.z);
+        out.length16(layers.len())?;
+        for (name, data) in layers {
+          out.string(name);
+          out.layer(data)?;
+        }
+      }
+      Message::UnloadChunk { pos } | Message::RequestChunk { pos } => {
+        out.i32(pos.x);
+        out.i32(pos.y);
+        out.i32(pos.z);
+      }
+      Message::ChunkDelta { pos, delta } => {
+        out.i32(pos.x);
+        out.i32(pos.y);
+        out.i32(pos.z);
+        out.delta(delta)?;
+      }
+      Message::BlockChanges { changes } => {
+        out.u32(changes.len() as u32);
+        for (pos, value) in changes {
+          out.i32(pos.x);
+          out.i32(pos.y);
+          out.i32(pos.z);
+          out.bytes(&[*value]);
+        }
+      }
+      Message::EditBlock {
+        sequence,
+        pos,
+        value,
+      } => {
+        out.u32(*sequence);
+        out.i32(pos.x);
+        out.i32(pos.y);
+        out.i32(pos.z);
+        out.bytes(&[*value]);
+      }
+      Message::EditsProcessed { sequence } => out.u32(*sequence),
+      Message::Script { name, source } => {
+        out.string(name);
+        out.string(source);
+      }
+      Message::RemoveScript { name } => out.string(name),
+      Message::PlayerPosition {
+        player,
+        pos,
+        yaw,
+        pitch,
+      } => {
+        out.u32(*player);
+        for value in pos.iter().chain(&[*yaw, *pitch]) {
+          out.f32(*value);
+        }
+      }
+    }
+    Ok(out.0)
+  }
+
+  pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+    let mut input = Decoder(data);
+    let message = match input.u8()? {
+      0 => {
+        if input.bytes(MAGIC.len())? != MAGIC {
+          return Err(ProtocolError::InvalidMagic);
+        }
+        let version = input.u16()?;
+        // Nothing past the version can be relied upon if it doesn't match.
+        if version != PROTOCOL_VERSION {
+          return Err(ProtocolError::VersionMismatch(version));
+        }
+        let name = input.string()?;
+        Message::Hello { version, name }
+      }
+      1 => Message::Welcome {
+        player: input.u32()?,
+        seed: input.u32()?,
+      },
+      2 => Message::Disconnect {
+        reason: input.string()?,
+      },
+      3 => {
+        let pos = ChunkPos::new(input.i32()?, input.i32()?, input.i32()?);
+        let count = input.u16()?;
+        let mut layers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+          layers.push((input.string()?, input.layer()?));
+        }
+        Message::ChunkData { pos, layers }
+      }
+      4 => {
+        let count = input.u32()? as usize;
+        // Don't trust the count for preallocating, as each change takes 13 bytes.
+        let mut changes = Vec::with_capacity(count.min(input.0.len() / 13));
+        for _ in 0..count {
+          let pos = BlockPos::new(input.i32()?, input.i32()?, input.i32()?);
+          changes.push((pos, input.u8()?));
+        }
+        Message::BlockChanges { changes }
+      }
+      5 => Message::PlayerPosition {
+        player: input.u32()?,
+        pos: [input.f32()?, input.f32()?, input.f32()?],
+        yaw: input.f32()?,
+        pitch: input.f32()?,
+      },
+      6 => Message::UnloadChunk {
+        pos: ChunkPos::new(input.i32()?, input.i32()?, input.i32()?),
+      },
+      7 => Message::ChunkDelta {
+        pos: ChunkPos::new(input.i32()?, input.i32()?, input.i32()?),
+        delta: input.delta()?,
+      },
+      8 => Message::EditBlock {
+        sequence: input.u32()?,
+        pos: BlockPos::new(input.i32()?, input.i32()?, input.i32()?),
+        value: input.u8()?,
+      },
+      9 => Message::EditsProcessed {
+        sequence: input.u32()?,
+      },
+      10 => Message::Script {
+        name: input.string()?,
+        source: input.string()?,
+      },
+      11 => Message::RemoveScript {
+        name: input.string()?,
+      },
+      12 => Message::RequestChunk {
+        pos: ChunkPos::new(input.i32()?, input.i32()?, input.i32()?),
+      },
+      tag => return Err(ProtocolError::UnknownMessage(tag)),
+    };
+    if !input.0.is_empty() {
+      return Err(ProtocolError::TrailingBytes(input.0.len()));
+    }
+    Ok(message)
+  }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+  /// The message ended before all of its fields were read.
+  UnexpectedEnd,
+  /// The message was followed by the specified number of unexpected bytes.
+  TrailingBytes(usize),
+  UnknownMessage(u8),
+  UnknownDelta(u8),
+  /// A message was received that isn't valid at this point of the connection.
+  UnexpectedMessage,
+  /// A `Hello` didn't start with the expected magic bytes.
+  InvalidMagic,
+  /// The other side speaks the specified, different protocol version.
+  VersionMismatch(u16),
+  InvalidString,
+  /// The message is larger than `MAX_MESSAGE_SIZE`.
+  TooLarge(usize),
+  /// A list, or a palette along with the entries added to it, has the specified number of
+  /// entries, which is more than the `u16` encoding its length can hold.
+  TooLong(usize),
+  /// The connection was closed by the other side.
+  Closed,
+  Io(io::Error),
+}
+
+impl Error for ProtocolError {}
+
+impl fmt::Display for ProtocolError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ProtocolError::UnexpectedEnd => write!(f, "Message ended unexpectedly"),
+      ProtocolError::TrailingBytes(count) => write!(f, "Message has {} trailing bytes", count),
+      ProtocolError::UnknownMessage(tag) => write!(f, "Unknown message type {}", tag),
+      ProtocolError::UnknownDelta(kind) => write!(f, "Unknown chunk delta type {}", kind),
+      ProtocolError::UnexpectedMessage => write!(f, "Unexpected message"),
+      ProtocolError::InvalidMagic => write!(f, "Not a gaemstone connection"),
+      ProtocolError::VersionMismatch(version) => write!(
+        f,
+        "Protocol version {} doesn't match ours, {}",
+        version, PROTOCOL_VERSION
+      ),
+      ProtocolError::InvalidString => write!(f, "String is not valid UTF-8"),
+      ProtocolError::TooLarge(size) => write!(f, "Message of size {} is too large", size),
+      ProtocolError::TooLong(length) => write!(f, "List of {} entries is too long", length),
+      ProtocolError::Closed => write!(f, "Connection closed"),
+      ProtocolError::Io(err) => write!(f, "Connection error: {}", err),
+    }
+  }
+}
+
+/// Appends values to an encoded message, in little endian.
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+  fn bytes(&mut self, bytes: &[u8]) {
+    self.0.extend_from_slice(bytes);
+  }
+
+  fn u16(&mut self, value: u16) {
+    self.bytes(&value.to_le_bytes());
+  }
+
+  fn u32(&mut self, value: u32) {
+    self.bytes(&value.to_le_bytes());
+  }
+
+  /// Writes the length of a list that's limited to `u16::MAX` entries.
+  fn length16(&mut self, length: usize) -> Result<(), ProtocolError> {
+    let length = u16::try_from(length).map_err(|_| ProtocolError::TooLong(length))?;
+    self.u16(length);
+    Ok(())
+  }
+
+  fn i32(&mut self, value: i32) {
+    self.bytes(&value.to_le_bytes());
+  }
+
+  fn f32(&mut self, value: f32) {
+    self.bytes(&value.to_le_bytes());
+  }
+
+  fn string(&mut self, value: &str) {
+    self.u32(value.len() as u32);
+    self.bytes(value.as_bytes());
+  }
+
+  /// Writes a layer as its palette followed by its indices, packed into as few bits per
+  /// block as the palette requires. Uniform layers don't have any indices to write.
+  fn layer(&mut self, data: &LayerData) -> Result<(), ProtocolError> {
+    self.length16(data.palette.len())?;
+    for value in &data.palette {
+      self.string(value);
+    }
+    self.u32(data.indices.len() as u32);
+    self.indices(&data.indices, index_bits(data.palette.len()));
+    Ok(())
+  }
+
+  /// Writes a chunk delta, see `ChunkDelta::encoded_size` for the resulting size.
+  fn delta(&mut self, delta: &ChunkDelta) -> Result<(), ProtocolError> {
+    match delta {
+      ChunkDelta::Blocks(blocks) => {
+        self.bytes(&[0]);
+        self.u32(blocks.len() as u32);
+        for (index, value) in blocks {
+          self.u32(*index);
+          self.bytes(&[*value]);
+        }
+      }
+      ChunkDelta::Ranges {
+        base_palette,
+        added,
+        ranges,
+      } => {
+        let palette_length = *base_palette as usize + added.len();
+        if palette_length > u16::MAX as usize {
+          return Err(ProtocolError::TooLong(palette_length));
+        }
+        self.bytes(&[1]);
+        self.u16(*base_palette);
+        self.length16(added.len())?;
+        self.bytes(added);
+        self.u32(ranges.len() as u32);
+        let bits = index_bits(palette_length);
+        for (start, indices) in ranges {
+          self.u32(*start);
+          self.u32(indices.len() as u32);
+          self.indices(indices, bits);
+        }
+      }
+      ChunkDelta::Full { palette, indices } => {
+        self.bytes(&[2]);
+        self.length16(palette.len())?;
+        self.bytes(palette);
+        self.u32(indices.len() as u32);
+        self.indices(indices, index_bits(palette.len()));
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes palette indices packed into the specified number of bits each.
+  fn indices(&mut self, indices: &[u16], bits: u32) {
+    let mut buffer = 0u32;
+    let mut buffered = 0;
+    for index in indices {
+      buffer |= (*index as u32) << buffered;
+      buffered += bits;
+      while buffered >= 8 {
+        self.0.push(buffer as u8);
+        buffer >>= 8;
+        buffered -= 8;
+      }
+    }
+    if buffered > 0 {
+      self.0.push(buffer as u8);
+    }
+  }
+}
+
+/// Reads values from the front of an encoded message.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+  fn bytes(&mut self, count: usize) -> Result<&'a [u8], ProtocolError> {
+    if self.0.len() < count {
+      return Err(ProtocolError::UnexpectedEnd);
+    }
+    let (bytes, rest) = self.0.split_at(count);
+    self.0 = rest;
+    Ok(bytes)
+  }
+
+  fn word(&mut self) -> Result<[u8; 4], ProtocolError> {
+    let bytes = self.bytes(4)?;
+    Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+  }
+
+  fn u8(&mut self) -> Result<u8, ProtocolError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, ProtocolError> {
+    let bytes = self.bytes(2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, ProtocolError> {
+    Ok(u32::from_le_bytes(self.word()?))
+  }
+
+  fn i32(&mut self) -> Result<i32, ProtocolError> {
+    Ok(i32::from_le_bytes(self.word()?))
+  }
+
+  fn f32(&mut self) -> Result<f32, ProtocolError> {
+    Ok(f32::from_le_bytes(self.word()?))
+  }
+
+  fn string(&mut self) -> Result<String, ProtocolError> {
+    let length = self.u32()? as usize;
+    let bytes = self.bytes(length)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidString)
+  }
+
+  fn layer(&mut self) -> Result<LayerData, ProtocolError> {
+    let palette_length = self.u16()? as usize;
+    let mut palette = Vec::with_capacity(palette_length.min(self.0.len()));
+    for _ in 0..palette_length {
+      palette.push(self.string()?);
+    }
+    let count = self.u32()? as usize;
+    let indices = self.indices(count, index_bits(palette.len()))?;
+    Ok(LayerData { palette, indices })
+  }
+
+  fn delta(&mut self) -> Result<ChunkDelta, ProtocolError> {
+    Ok(match self.u8()? {
+      0 => {
+        let count = self.u32()? as usize;
+        // Don't trust the count for preallocating, as each block takes 5 bytes.
+        let mut blocks = Vec::with_capacity(count.min(self.0.len() / 5));
+        for _ in 0..count {
+          blocks.push((self.u32()?, self.u8()?));
+        }
+        ChunkDelta::Blocks(blocks)
+      }
+      1 => {
+        let base_palette = self.u16()?;
+        let added_length = self.u16()? as usize;
+        let added = self.bytes(added_length)?.to_vec();
+        // Palette indices are `u16`, so the extended palette can't have any more entries.
+        let palette_length = base_palette as usize + added.len();
+        if palette_length > u16::MAX as usize {
+          return Err(ProtocolError::TooLong(palette_length));
+        }
+        let bits = index_bits(palette_length);
+        let count = self.u32()? as usize;
+        let mut ranges = Vec::with_capacity(count.min(self.0.len() / 8));
+        for _ in 0..count {
+          let start = self.u32()?;
+          let length = self.u32()? as usize;
+          ranges.push((start, self.indices(length, bits)?));
+        }
+        ChunkDelta::Ranges {
+          base_palette,
+          added,
+          ranges,
+        }
+      }
+      2 => {
+        let palette_length = self.u16()? as usize;
+        let palette = self.bytes(palette_length)?.to_vec();
+        let count = self.u32()? as usize;
+        let indices = self.indices(count, index_bits(palette.len()))?;
+        ChunkDelta::Full { palette, indices }
+      }
+      kind => return Err(ProtocolError::UnknownDelta(kind)),
+    })
+  }
+
+  /// Reads the specified number of palette indices, packed into `bits` bits each.
+  fn indices(&mut self, count: usize, bits: u32) -> Result<Vec<u16>, ProtocolError> {
+    let packed = self.bytes((count * bits as usize + 7) / 8)?;
+    let mut indices = Vec::with_capacity(count);
+    let mut bytes = packed.iter();
+    let mut buffer = 0u32;
+    let mut buffered = 0;
+    for _ in 0..count {
+      while buffered < bits {
+        buffer |= (*bytes.next().unwrap() as u32) << buffered;
+        buffered += 8;
+      }
+      indices.push((buffer & ((1 << bits) - 1)) as u16);
+      buffer >>= bits;
+      buffered -= bits;
+    }
+    Ok(indices)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn messages_round_trip() {
+    let messages = vec![
+      Message::hello("player"),
+      Message::Welcome {
+        player: 3,
+        seed: 1234,
+      },
+      Message::Disconnect {
+        reason: "Bye".to_string(),
+      },
+      Message::ChunkData {
+        pos: ChunkPos::new(-1, 2, -3),
+        layers: vec![
+          (
+            "blocks".to_string(),
+            LayerData {
+              palette: vec!["0".to_string(), "1".to_string(), "7".to_string()],
+              indices: (0..4096).map(|i| (i % 3) as u16).collect(),
+            },
+          ),
+          (
+            "fluid_levels".to_string(),
+            LayerData {
+              palette: vec!["(0)".to_string()],
+              indices: Vec::new(),
+            },
+          ),
+        ],
+      },
+      Message::UnloadChunk {
+        pos: ChunkPos::new(4, -5, 6),
+      },
+      Message::BlockChanges {
+        changes: vec![(BlockPos::new(1, -2, 3), 4), (BlockPos::new(0, 0, 0), 0)],
+      },
+      Message::PlayerPosition {
+        player: 1,
+        pos: [1.5, -2.0, 300.25],
+        yaw: 0.5,
+        pitch: -0.25,
+      },
+      Message::EditBlock {
+        sequence: 12,
+        pos: BlockPos::new(-7, 8, 9),
+        value: 3,
+      },
+      Message::EditsProcessed { sequence: 12 },
+      Message::Script {
+        name: "plants".to_string(),
+        source: "fn on_tick(tick) {}".to_string(),
+      },
+      Message::RemoveScript {
+        name: "plants".to_string(),
+      },
+      Message::RequestChunk {
+        pos: ChunkPos::new(-2, 0, 9),
+      },
+      Message::ChunkDelta {
+        pos: ChunkPos::new(0, -1, 0),
+        delta: ChunkDelta::Blocks(vec![(0, 1), (4095, 255), (262_143, 7)]),
+      },
+      Message::ChunkDelta {
+        pos: ChunkPos::new(0, -1, 0),
+        delta: ChunkDelta::Ranges {
+          base_palette: 3,
+          added: vec![9, 10],
+          ranges: vec![
+            (10, vec![4, 0, 3]),
+            (1000, (0..300).map(|i| i % 5).collect()),
+            (200_000, (0..70_000u32).map(|i| (i % 5) as u16).collect()),
+          ],
+        },
+      },
+      Message::ChunkDelta {
+        pos: ChunkPos::new(0, -1, 0),
+        delta: ChunkDelta::Full {
+          palette: vec![0, 1, 2],
+          indices: (0..4096).map(|i| (i % 3) as u16).collect(),
+        },
+      },
+    ];
+    for message in messages {
+      assert_eq!(
+        Message::decode(&message.encode().unwrap()).unwrap(),
+        message
+      );
+    }
+  }
+
+  #[test]
+  fn chunk_indices_are_packed() {
+    let message = Message::ChunkData {
+      pos: ChunkPos::new(0, 0, 0),
+      layers: vec![(
+        "blocks".to_string(),
+        LayerData {
+          palette: vec!["0".to_string(), "1".to_string()],
+          indices: vec![1; 4096],
+        },
+      )],
+    };
+    // A palette of two entries only needs a single bit per block.
+    assert!(message.encode().unwrap().len() < 4096 / 8 + 64);
+  }
+
+  #[test]
+  fn lists_too_long_for_their_length_are_rejected() {
+    let layer = LayerData {
+      palette: (0..70_000).map(|i| i.to_string()).collect(),
+      indices: vec![],
+    };
+    let message = Message::ChunkData {
+      pos: ChunkPos::new(0, 0, 0),
+      layers: vec![("blocks".to_string(), layer)],
+    };
+    assert!(matches!(
+      message.encode(),
+      Err(ProtocolError::TooLong(70_000))
+    ));
+    let message = Message::ChunkDelta {
+      pos: ChunkPos::new(0, 0, 0),
+      delta: ChunkDelta::Ranges {
+        base_palette: u16::MAX,
+        added: vec![1],
+        ranges: vec![],
+      },
+    };
+    assert!(matches!(
+      message.encode(),
+      Err(ProtocolError::TooLong(65_536))
+    ));
+
+    // Ranges delta whose palette, extended by the added value, has more entries than indices.
+    let mut data = vec![7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    data.extend(&u16::MAX.to_le_bytes());
+    data.extend(&1u16.to_le_bytes());
+    data.push(1);
+    data.extend(&0u32.to_le_bytes());
+    assert!(matches!(
+      Message::decode(&data),
+      Err(ProtocolError::TooLong(65_536))
+    ));
+  }
+
+  #[test]
+  fn invalid_messages_are_rejected() {
+    assert!(matches!(
+      Message::decode(&[42]),
+      Err(ProtocolError::UnknownMessage(42))
+    ));
+    let mut welcome = Message::Welcome { player: 0, seed: 0 }.encode().unwrap();
+    welcome.pop();
+    assert!(matches!(
+      Message::decode(&welcome),
+      Err(ProtocolError::UnexpectedEnd)
+    ));
+    let mut hello = Message::hello("player").encode().unwrap();
+    hello[5] = hello[5].wrapping_add(1);
+    assert!(matches!(
+      Message::decode(&hello),
+      Err(ProtocolError::VersionMismatch(_))
+    ));
+    hello[1] = b'X';
+    assert!(matches!(
+      Message::decode(&hello),
+      Err(ProtocolError::InvalidMagic)
+    ));
+  }
+}
@@ -0,0 +1,558 @@
+use {
+  super::{
+    accept_client, chunk_contents, chunk_data, ChunkDelta, Message, ProtocolError, TcpServer,
+    Transport,
+  },
+  crate::{
+    bloxel::{
+      chunk::{ChunkLayout, ChunkPos, ChunkState, DefaultLayout, Index, LayerChange},
+      ActiveLevel, BlockAccess, BlockChange, BlockPos, ChunkLoader, Level, ScriptChange, Scripts,
+      REACH_DISTANCE, VIEW_DISTANCE,
+    },
+    util::{ChunkedOctree, ZOrder},
+  },
+  amethyst::{
+    core::transform::Transform, derive::SystemDesc, ecs::prelude::*, shrev::EventChannel,
+  },
+  log::{info, warn},
+  std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    marker::PhantomData,
+    net::ToSocketAddrs,
+  },
+};
+
+/// Number of chunks `ServerNetworkSystem` sends to each client per run.
+pub const CHUNKS_PER_RUN: usize = 4;
+
+/// Keeps track of which chunks a client has been sent, deciding which to send or unload next
+/// as the client moves around. Chunks are unloaded once they're one chunk farther away than
+/// they're sent at, so moving back and forth along the border doesn't resend them every time.
+#[derive(Debug)]
+pub struct ChunkInterest {
+  /// Distance in chunks around the client in which chunks are sent.
+  radius: f32,
+  sent: HashSet<ChunkPos>,
+}
+
+impl ChunkInterest {
+  pub fn new(radius: f32) -> Self {
+    ChunkInterest {
+      radius,
+      sent: HashSet::new(),
+    }
+  }
+
+  /// Whether the chunk at the specified position has been sent to the client, in which case
+  /// changes to its blocks should be sent as well.
+  pub fn has_chunk(&self, pos: ChunkPos) -> bool {
+    self.sent.contains(&pos)
+  }
+
+  /// Forgets that the chunk at the specified position has been sent, so it's sent again as soon
+  /// as it's within range, such as when the client has asked for it through `RequestChunk`.
+  pub fn forget(&mut self, pos: ChunkPos) {
+    self.sent.remove(&pos);
+  }
+
+  /// Returns up to `limit` generated chunks within range of `center` that haven't been sent yet,
+  /// nearest first, as well as every sent chunk that's now out of range. Both are considered
+  /// sent or unloaded respectively from now on.
+  pub fn update(
+    &mut self,
+    octree: &ChunkedOctree<ChunkState>,
+    center: ChunkPos,
+    limit: usize,
+  ) -> (Vec<ChunkPos>, Vec<ChunkPos>) {
+    let unload_distance = self.radius + 1.0;
+    let unload = self
+      .sent
+      .iter()
+      .filter(|pos| distance_squared(center, **pos) > unload_distance * unload_distance)
+      .copied()
+      .collect::<Vec<_>>();
+    for pos in &unload {
+      self.sent.remove(pos);
+    }
+
+    let z_center = match ZOrder::try_from(center) {
+      Ok(z_center) => z_center,
+      Err(_) => return (Vec::new(), unload),
+    };
+    let max_distance_squared = self.radius * self.radius;
+    let sent = &self.sent;
+    let send = octree
+      .find(
+        |level, pos| {
+          let distance = node_distance_squared(center, level, pos);
+          if distance <= max_distance_squared {
+            Some(distance)
+          } else {
+            None
+          }
+        },
+        |state| state.intersects(ChunkState::GENERATED_SOME | ChunkState::GENERATED_ALL),
+      )
+      .search(z_center)
+      .map(|(pos, _)| ChunkPos::from(pos))
+      .filter(|pos| !sent.contains(pos))
+      .take(limit)
+      .collect::<Vec<_>>();
+    self.sent.extend(&send);
+    (send, unload)
+  }
+}
+
+fn distance_squared(a: ChunkPos, b: ChunkPos) -> f32 {
+  let (x, y, z) = ((a.x - b.x) as f32, (a.y - b.y) as f32, (a.z - b.z) as f32);
+  x * x + y * y + z * z
+}
+
+/// Returns the squared distance from `center` to the nearest chunk of the specified octree node.
+fn node_distance_squared(center: ChunkPos, level: u8, pos: ZOrder) -> f32 {
+  let (x, y, z) = (pos << level as usize).into();
+  let size = (1 << level) - 1;
+  let clamp = |value: i32, min: i32| value.max(min).min(min + size);
+  let nearest = ChunkPos::new(clamp(center.x, x), clamp(center.y, y), clamp(center.z, z));
+  distance_squared(center, nearest)
+}
+
+/// Extra distance beyond `REACH_DISTANCE` at which the server still accepts edits from clients,
+/// since their position may have changed since they made the edit.
+pub const REACH_LEEWAY: f32 = 2.0;
+
+/// Whether a player at the specified position is allowed to edit the block at `pos`.
+pub fn can_reach(player: [f32; 3], pos: BlockPos) -> bool {
+  let center = [pos.x as f32 + 0.5, pos.y as f32 + 0.5, pos.z as f32 + 0.5];
+  let distance_squared = (0..3)
+    .map(|i| (center[i] - player[i]) * (center[i] - player[i]))
+    .sum::<f32>();
+  let reach = REACH_DISTANCE + REACH_LEEWAY;
+  distance_squared <= reach * reach
+}
+
+/// Component for player entities of clients connected to the server. Player entities are
+/// positioned by their `Transform`, and have a `ChunkLoader` so the area around them is simulated.
+pub struct ClientConnection {
+  pub player: u32,
+  pub name: String,
+  /// Level the player is in, which chunks are sent from.
+  pub level: Entity,
+  pub interest: ChunkInterest,
+  transport: Box<dyn Transport>,
+  /// Sequence number of the latest edit received from the client,
+  /// until its processing is reported back to the client.
+  processed: Option<u32>,
+}
+
+impl Component for ClientConnection {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl ClientConnection {
+  pub fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+    self.transport.send(message)
+  }
+}
+
+/// Resource accepting clients on the server, until they've completed the handshake
+/// and `ServerNetworkSystem` creates a player entity for them.
+#[derive(Default)]
+pub struct NetworkServer {
+  listener: Option<TcpServer>,
+  pending: Vec<Box<dyn Transport>>,
+  next_player: u32,
+}
+
+impl NetworkServer {
+  /// Creates a server accepting TCP connections on the specified address.
+  pub fn listen<A: ToSocketAddrs>(addr: A) -> Result<Self, ProtocolError> {
+    Ok(NetworkServer {
+      listener: Some(TcpServer::bind(addr)?),
+      ..Default::default()
+    })
+  }
+
+  /// Adds a client that's already connected, such as through a `LoopbackTransport`.
+  pub fn connect<T: Transport + 'static>(&mut self, transport: T) {
+    self.pending.push(Box::new(transport));
+  }
+}
+
+/// Returns a delta for every chunk with blocks that changed, going from the contents before the
+/// first of the changes to the current ones. Only chunks for which `include` returns `true` are
+/// considered. Changes have to be in the order they happened, with the chunks' storages already
+/// containing all of them.
+pub fn chunk_deltas<'c, L, I, F>(
+  changes: I,
+  blocks: &BlockAccess<'_, u8, L>,
+  mut include: F,
+) -> HashMap<(Entity, ChunkPos), ChunkDelta>
+where
+  L: ChunkLayout,
+  I: IntoIterator<Item = &'c BlockChange>,
+  F: FnMut(Entity, ChunkPos) -> bool,
+{
+  // The value each changed block had before its first change, by chunk.
+  let mut previous = HashMap::<(Entity, ChunkPos), HashMap<u32, u8>>::new();
+  for change in changes {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = change.pos.into();
+    if include(change.level, chunk_pos) {
+      (previous.entry((change.level, chunk_pos)).or_default())
+        .entry(index.raw_index())
+        .or_insert(change.previous);
+    }
+  }
+  previous
+    .into_iter()
+    .filter_map(|((level, pos), previous)| {
+      let new = chunk_contents(blocks.storage(level, pos)?);
+      let mut old = new.clone();
+      for (raw, value) in previous {
+        old[raw as usize] = value;
+      }
+      // Blocks may have been changed back to what they were.
+      if old == new {
+        return None;
+      }
+      Some(((level, pos), ChunkDelta::between(&old, &new)))
+    })
+    .collect()
+}
+
+/// Handles clients connected to the server: Completes handshakes of new clients, applies their
+/// player positions and relays them to others, applies block edits within their reach, streams
+/// chunks around each player and sends deltas of chunks they have whose blocks changed, as well as
+/// the server's `Scripts` whenever they change. Chunks with other layers that changed, as
+/// announced through `LayerChange`, are sent again in full, since deltas only cover the blocks.
+/// Clients join the level in the `ActiveLevel` resource. Block changes made after this system ran
+/// in a frame also end up in chunks sent at the end of it, so this system should run after any
+/// system changing blocks.
+#[derive(SystemDesc)]
+#[system_desc(name(ServerNetworkSystemDesc))]
+pub struct ServerNetworkSystem<L: ChunkLayout = DefaultLayout> {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<BlockChange>,
+  #[system_desc(event_channel_reader)]
+  script_reader: ReaderId<ScriptChange>,
+  #[system_desc(event_channel_reader)]
+  layer_reader: ReaderId<LayerChange>,
+  #[system_desc(skip)]
+  layout: PhantomData<L>,
+}
+
+impl<L: ChunkLayout> ServerNetworkSystem<L> {
+  pub fn new(
+    reader: ReaderId<BlockChange>,
+    script_reader: ReaderId<ScriptChange>,
+    layer_reader: ReaderId<LayerChange>,
+  ) -> Self {
+    Self {
+      reader,
+      script_reader,
+      layer_reader,
+      layout: PhantomData,
+    }
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for ServerNetworkSystem<L> {
+  type SystemData = (
+    Entities<'a>,
+    Read<'a, LazyUpdate>,
+    Write<'a, NetworkServer>,
+    Read<'a, ActiveLevel>,
+    ReadStorage<'a, Level>,
+    WriteStorage<'a, ClientConnection>,
+    WriteStorage<'a, Transform>,
+    BlockAccess<'a, u8, L>,
+    Option<Read<'a, Scripts>>,
+    Read<'a, EventChannel<ScriptChange>>,
+    Read<'a, EventChannel<LayerChange>>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      entities,
+      lazy,
+      mut server,
+      active_level,
+      levels,
+      mut clients,
+      mut transforms,
+      mut blocks,
+      scripts,
+      script_changes,
+      layer_changes,
+    ): Self::SystemData,
+  ) {
+    let server = &mut *server;
+    if let Some(listener) = &server.listener {
+      loop {
+        match listener.accept() {
+          Ok(Some(transport)) => server.pending.push(Box::new(transport)),
+          Ok(None) => break,
+          Err(err) => {
+            warn!("Couldn't accept client: {}", err);
+            break;
+          }
+        }
+      }
+    }
+
+    // Changes to scripts are sent before anything else, as they may affect how the rest is handled.
+    let script_messages = (script_changes.read(&mut self.script_reader))
+      .map(|change| match &change.source {
+        Some(source) => Message::Script {
+          name: change.name.clone(),
+          source: source.clone(),
+        },
+        None => Message::RemoveScript {
+          name: change.name.clone(),
+        },
+      })
+      .collect::<Vec<_>>();
+
+    if let Some((level, level_data)) = active_level.0.and_then(|e| levels.get(e).map(|l| (e, l))) {
+      let mut pending = Vec::new();
+      for mut transport in server.pending.drain(..) {
+        match accept_client(transport.as_mut(), server.next_player, level_data.seed) {
+          Ok(Some(name)) => {
+            info!("Player {} joined as '{}'", server.next_player, name);
+            for (name, source) in scripts.iter().flat_map(|scripts| scripts.iter()) {
+              let message = Message::Script {
+                name: name.to_string(),
+                source: source.to_string(),
+              };
+              if let Err(err) = transport.send(&message) {
+                warn!("Couldn't send script '{}': {}", name, err);
+              }
+            }
+            lazy
+              .create_entity(&entities)
+              .with(ClientConnection {
+                player: server.next_player,
+                name,
+                level,
+                interest: ChunkInterest::new(VIEW_DISTANCE / L::LENGTH as f32),
+                transport,
+                processed: None,
+              })
+              .with(Transform::default())
+              .with(ChunkLoader::default())
+              .build();
+            server.next_player += 1;
+          }
+          Ok(None) => pending.push(transport),
+          Err(err) => warn!("Client failed to connect: {}", err),
+        }
+      }
+      server.pending = pending;
+    }
+
+    // Apply what players sent, taking note of positions to relay to everyone else. Edits are
+    // applied right away, so the changes they cause are sent along with all other changes.
+    let mut positions = Vec::new();
+    for (entity, client, transform) in (&entities, &mut clients, &mut transforms).join() {
+      loop {
+        match client.transport.receive() {
+          Ok(Some(Message::PlayerPosition {
+            pos, yaw, pitch, ..
+          })) => {
+            transform.set_translation_xyz(pos[0], pos[1], pos[2]);
+            let player = client.player;
+            positions.push(Message::PlayerPosition {
+              player,
+              pos,
+              yaw,
+              pitch,
+            });
+          }
+          Ok(Some(Message::EditBlock {
+            sequence,
+            pos,
+            value,
+          })) => {
+            let (chunk_pos, _): (ChunkPos, Index<L>) = pos.into();
+            let translation = transform.translation();
+            let player = [translation.x, translation.y, translation.z];
+            // Edits that aren't allowed are simply not applied, which the client notices
+            // once it's told that they've been processed.
+            if client.interest.has_chunk(chunk_pos) && can_reach(player, pos) {
+              blocks.set(client.level, pos, value);
+            }
+            client.processed = Some(sequence);
+          }
+          // The chunk is sent again once the client's interest is updated below.
+          Ok(Some(Message::RequestChunk { pos })) => client.interest.forget(pos),
+          Ok(Some(Message::Disconnect { reason })) => {
+            info!("Player {} left: {}", client.player, reason);
+            entities.delete(entity).unwrap();
+            break;
+          }
+          Ok(Some(message)) => warn!("Unexpected message from client: {:?}", message),
+          Ok(None) => break,
+          Err(err) => {
+            info!("Player {} disconnected: {}", client.player, err);
+            entities.delete(entity).unwrap();
+            break;
+          }
+        }
+      }
+    }
+
+    let clients_ref = &clients;
+    let changes = blocks
+      .read_changes(&mut self.reader)
+      .copied()
+      .collect::<Vec<_>>();
+    let deltas = chunk_deltas(&changes, &blocks, |level, pos| {
+      clients_ref
+        .join()
+        .any(|client| client.level == level && client.interest.has_chunk(pos))
+    });
+    let resend = (layer_changes.read(&mut self.layer_reader))
+      .map(|change| (change.level, change.pos))
+      .collect::<HashSet<_>>();
+
+    for (entity, client, transform) in (&entities, &mut clients, &transforms).join() {
+      // Sending may fail if the client has disconnected, which is noticed when receiving.
+      for message in &script_messages {
+        let _ = client.send(message);
+      }
+      for message in &positions {
+        match message {
+          Message::PlayerPosition { player, .. } if *player == client.player => {}
+          _ => {
+            let _ = client.send(message);
+          }
+        }
+      }
+
+      // Changes are sent before new chunks, which already include them.
+      for ((level, pos), delta) in &deltas {
+        if *level == client.level && client.interest.has_chunk(*pos) {
+          let _ = client.send(&Message::ChunkDelta {
+            pos: *pos,
+            delta: delta.clone(),
+          });
+        }
+      }
+      for &(level, pos) in &resend {
+        if level == client.level && client.interest.has_chunk(pos) {
+          if let Some(chunk) = blocks.chunk(level, pos) {
+            send_chunk::<L>(&lazy, entity, chunk);
+          }
+        }
+      }
+      // Whatever the client's edits changed has been sent now, so they can be confirmed.
+      if let Some(sequence) = client.processed.take() {
+        let _ = client.send(&Message::EditsProcessed { sequence });
+      }
+
+      let octree = match blocks.octree(client.level) {
+        Some(octree) => octree,
+        None => continue,
+      };
+      let translation = transform.translation();
+      let (center, _): (ChunkPos, Index<L>) = BlockPos::new(
+        translation.x.floor() as i32,
+        translation.y.floor() as i32,
+        translation.z.floor() as i32,
+      )
+      .into();
+      let (send, unload) = client.interest.update(octree, center, CHUNKS_PER_RUN);
+      for pos in unload {
+        let _ = client.send(&Message::UnloadChunk { pos });
+      }
+      // Missing chunks consist of nothing but air, which clients assume anyway.
+      for chunk in send
+        .into_iter()
+        .filter_map(|pos| blocks.chunk(client.level, pos))
+      {
+        send_chunk::<L>(&lazy, entity, chunk);
+      }
+    }
+  }
+}
+
+/// Sends the `ChunkData` of the specified chunk to a client. Chunk layers are saved through the
+/// `World`, so the chunk is encoded once the current frame is done, including any later changes.
+fn send_chunk<L: ChunkLayout>(lazy: &LazyUpdate, client: Entity, chunk: Entity) {
+  lazy.exec(move |world| match chunk_data::<L>(world, chunk) {
+    Ok(message) => {
+      if let Some(client) = world.write_storage::<ClientConnection>().get_mut(client) {
+        let _ = client.send(&message);
+      }
+    }
+    Err(err) => warn!("Couldn't encode chunk: {}", err),
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::bloxel::mark_chunk_generated};
+
+  fn octree_with(chunks: &[(i32, i32, i32)]) -> ChunkedOctree<ChunkState> {
+    let mut octree = ChunkedOctree::new(5);
+    for &(x, y, z) in chunks {
+      mark_chunk_generated(&mut octree, ZOrder::new(x, y, z).unwrap());
+    }
+    octree
+  }
+
+  #[test]
+  fn chunks_are_sent_nearest_first_and_rate_limited() {
+    let octree = octree_with(&[(0, 0, 0), (3, 0, 0), (-1, 0, 0), (0, 2, 0), (10, 0, 0)]);
+    let mut interest = ChunkInterest::new(4.0);
+    let center = ChunkPos::new(0, 0, 0);
+
+    let (send, unload) = interest.update(&octree, center, 2);
+    assert_eq!(send, vec![ChunkPos::new(0, 0, 0), ChunkPos::new(-1, 0, 0)]);
+    assert!(unload.is_empty());
+    let (send, _) = interest.update(&octree, center, 2);
+    assert_eq!(send, vec![ChunkPos::new(0, 2, 0), ChunkPos::new(3, 0, 0)]);
+    // The chunk at x = 10 is out of range.
+    let (send, _) = interest.update(&octree, center, 2);
+    assert!(send.is_empty());
+  }
+
+  #[test]
+  fn chunks_out_of_range_are_unloaded() {
+    let octree = octree_with(&[(0, 0, 0), (-2, 0, 0), (2, 0, 0)]);
+    let mut interest = ChunkInterest::new(2.0);
+    let (send, _) = interest.update(&octree, ChunkPos::new(0, 0, 0), 10);
+    assert_eq!(send.len(), 3);
+
+    // Within the extra chunk of leeway, nothing is unloaded yet.
+    let (send, unload) = interest.update(&octree, ChunkPos::new(1, 0, 0), 10);
+    assert!(send.is_empty() && unload.is_empty());
+    let (send, unload) = interest.update(&octree, ChunkPos::new(2, 0, 0), 10);
+    assert!(send.is_empty());
+    assert_eq!(unload, vec![ChunkPos::new(-2, 0, 0)]);
+    assert!(!interest.has_chunk(ChunkPos::new(-2, 0, 0)));
+  }
+
+  #[test]
+  fn forgotten_chunks_are_sent_again() {
+    let octree = octree_with(&[(0, 0, 0), (1, 0, 0)]);
+    let mut interest = ChunkInterest::new(2.0);
+    let center = ChunkPos::new(0, 0, 0);
+    assert_eq!(interest.update(&octree, center, 10).0.len(), 2);
+    interest.forget(ChunkPos::new(1, 0, 0));
+    assert!(!interest.has_chunk(ChunkPos::new(1, 0, 0)));
+    let (send, _) = interest.update(&octree, center, 10);
+    assert_eq!(send, vec![ChunkPos::new(1, 0, 0)]);
+  }
+
+  #[test]
+  fn edits_are_limited_to_reach() {
+    let player = [0.5, 1.6, 0.5];
+    assert!(can_reach(player, BlockPos::new(0, 0, 0)));
+    assert!(can_reach(player, BlockPos::new(0, 1, -9)));
+    assert!(!can_reach(player, BlockPos::new(0, 1, -12)));
+    assert!(!can_reach(player, BlockPos::new(100, 0, 0)));
+  }
+}
@@ -0,0 +1,201 @@
+use {
+  super::{Message, ProtocolError},
+  std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+  },
+};
+
+/// Largest encoded message a transport accepts, to avoid allocating
+/// arbitrary amounts of memory for a broken or malicious connection.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// A connection between client and server that messages can be sent over.
+/// Neither sending nor receiving blocks, so transports can be polled from systems.
+pub trait Transport: Send + Sync {
+  fn send(&mut self, message: &Message) -> Result<(), ProtocolError>;
+
+  /// Returns the next message that has been received, if any.
+  fn receive(&mut self) -> Result<Option<Message>, ProtocolError>;
+}
+
+/// Encoded messages on their way from one end of a `LoopbackTransport` to the other.
+type LoopbackQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// Transport connecting two ends within the same process, such as a client with an
+/// integrated server, or in tests. Messages are still encoded and decoded, as they
+/// would be for any other transport.
+pub struct LoopbackTransport {
+  outgoing: LoopbackQueue,
+  incoming: LoopbackQueue,
+}
+
+impl LoopbackTransport {
+  /// Creates both ends of a connection, each receiving what the other sends.
+  pub fn pair() -> (Self, Self) {
+    let (a, b) = (LoopbackQueue::default(), LoopbackQueue::default());
+    (
+      LoopbackTransport {
+        outgoing: a.clone(),
+        incoming: b.clone(),
+      },
+      LoopbackTransport {
+        outgoing: b,
+        incoming: a,
+      },
+    )
+  }
+
+  /// Whether the other end has been dropped, as it holds the only other reference to the queues.
+  fn is_closed(&self) -> bool {
+    Arc::strong_count(&self.incoming) == 1
+  }
+}
+
+impl Transport for LoopbackTransport {
+  fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+    if self.is_closed() {
+      return Err(ProtocolError::Closed);
+    }
+    let data = message.encode()?;
+    if data.len() > MAX_MESSAGE_SIZE {
+      return Err(ProtocolError::TooLarge(data.len()));
+    }
+    self.outgoing.lock().unwrap().push_back(data);
+    Ok(())
+  }
+
+  fn receive(&mut self) -> Result<Option<Message>, ProtocolError> {
+    match self.incoming.lock().unwrap().pop_front() {
+      Some(data) => Message::decode(&data).map(Some),
+      None if self.is_closed() => Err(ProtocolError::Closed),
+      None => Ok(None),
+    }
+  }
+}
+
+/// Transport over a TCP connection. Each message is prefixed with its length as a `u32`.
+pub struct TcpTransport {
+  stream: TcpStream,
+  /// Bytes received that don't make up a whole message yet.
+  received: Vec<u8>,
+  /// Encoded messages which couldn't be written without blocking yet.
+  pending: VecDeque<u8>,
+  /// Set once the other side has closed the connection. Messages
+  /// received before that are still returned, until none are left.
+  closed: bool,
+}
+
+impl TcpTransport {
+  /// Connects to the server at the specified address, blocking until the connection is made.
+  pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ProtocolError> {
+    let stream = TcpStream::connect(addr).map_err(ProtocolError::Io)?;
+    Self::from_stream(stream)
+  }
+
+  pub fn from_stream(stream: TcpStream) -> Result<Self, ProtocolError> {
+    stream.set_nonblocking(true).map_err(ProtocolError::Io)?;
+    // Messages such as player positions are small and should go out right away.
+    stream.set_nodelay(true).map_err(ProtocolError::Io)?;
+    Ok(TcpTransport {
+      stream,
+      received: Vec::new(),
+      pending: VecDeque::new(),
+      closed: false,
+    })
+  }
+
+  pub fn peer_addr(&self) -> Option<SocketAddr> {
+    self.stream.peer_addr().ok()
+  }
+
+  /// Writes as much of the pending data as possible without blocking.
+  fn flush(&mut self) -> Result<(), ProtocolError> {
+    while !self.pending.is_empty() {
+      let (front, _) = self.pending.as_slices();
+      match self.stream.write(front) {
+        Ok(0) => return Err(ProtocolError::Closed),
+        Ok(written) => {
+          self.pending.drain(..written);
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+        Err(err) => return Err(ProtocolError::Io(err)),
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Transport for TcpTransport {
+  fn send(&mut self, message: &Message) -> Result<(), ProtocolError> {
+    let data = message.encode()?;
+    if data.len() > MAX_MESSAGE_SIZE {
+      return Err(ProtocolError::TooLarge(data.len()));
+    }
+    self.pending.extend(&(data.len() as u32).to_le_bytes());
+    self.pending.extend(&data);
+    self.flush()
+  }
+
+  fn receive(&mut self) -> Result<Option<Message>, ProtocolError> {
+    if !self.closed {
+      self.flush()?;
+    }
+    let mut buffer = [0; 4096];
+    while !self.closed {
+      match self.stream.read(&mut buffer) {
+        Ok(0) => self.closed = true,
+        Ok(read) => self.received.extend_from_slice(&buffer[..read]),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+        Err(err) => return Err(ProtocolError::Io(err)),
+      }
+    }
+
+    // Nothing more is going to arrive once the connection is closed, so a partial message is lost.
+    let incomplete = if self.closed {
+      Err(ProtocolError::Closed)
+    } else {
+      Ok(None)
+    };
+    if self.received.len() < 4 {
+      return incomplete;
+    }
+    let length = &self.received[..4];
+    let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+    if length > MAX_MESSAGE_SIZE {
+      return Err(ProtocolError::TooLarge(length));
+    }
+    if self.received.len() < 4 + length {
+      return incomplete;
+    }
+    let message = Message::decode(&self.received[4..4 + length]);
+    self.received.drain(..4 + length);
+    message.map(Some)
+  }
+}
+
+/// Accepts incoming TCP connections for a server without blocking.
+pub struct TcpServer(TcpListener);
+
+impl TcpServer {
+  pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, ProtocolError> {
+    let listener = TcpListener::bind(addr).map_err(ProtocolError::Io)?;
+    listener.set_nonblocking(true).map_err(ProtocolError::Io)?;
+    Ok(TcpServer(listener))
+  }
+
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.0.local_addr().ok()
+  }
+
+  /// Returns the next client that has connected, if any.
+  pub fn accept(&self) -> Result<Option<TcpTransport>, ProtocolError> {
+    match self.0.accept() {
+      Ok((stream, _)) => TcpTransport::from_stream(stream).map(Some),
+      Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+      Err(err) => Err(ProtocolError::Io(err)),
+    }
+  }
+}
@@ -0,0 +1,549 @@
+use {
+  super::{
+    chunk::{storage::*, *},
+    mark_chunk_generated, mark_mesh_outdated, BlockAccess, BlockEntityAccess, BlockLight, BlockPos,
+    ChunkBlockEntities, Level, ScheduledTicks, SkyLight,
+  },
+  crate::util::{ChunkedOctree, ZOrder},
+  amethyst::ecs::prelude::*,
+  serde::{de::DeserializeOwned, Deserialize, Serialize},
+  std::{collections::HashSet, convert::TryFrom, error::Error, fmt, fs, io, path::Path},
+};
+
+/// Name of the file `save_level` saves the `Level` component in, next to the level's chunks.
+const LEVEL_FILE_NAME: &str = "level.ron";
+
+/// A chunk along with everything attached to it, in a form that can be written to disk.
+/// Light isn't saved, since `LightingSystem` recalculates it once the chunk is loaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SavedChunk {
+  pub pos: ChunkPos,
+  /// Layers registered in `ChunkLayers` which the chunk has, by name.
+  pub layers: Vec<(String, LayerData)>,
+  pub scheduled_ticks: Option<ScheduledTicks>,
+  pub block_entities: Vec<SavedBlockEntity>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SavedBlockEntity {
+  pub pos: BlockPos,
+  /// Components of the entity, as registered with `PersistedComponents`, by name.
+  pub components: Vec<(String, String)>,
+}
+
+type SaveFn = fn(&World, Entity) -> Option<Result<String, ron::ser::Error>>;
+type LoadFn = fn(&str) -> Result<LoadComponent, ron::de::Error>;
+/// Inserts a component deserialized by a `LoadFn` into an entity.
+type LoadComponent = Box<dyn FnOnce(&World, Entity)>;
+
+/// Resource listing the components of block entities which are saved along with their chunk.
+/// Components that aren't registered here are lost when a chunk is saved and loaded again.
+#[derive(Default)]
+pub struct PersistedComponents(Vec<(&'static str, SaveFn, LoadFn)>);
+
+impl PersistedComponents {
+  pub fn with<C>(mut self, name: &'static str) -> Self
+  where
+    C: Component + Serialize + DeserializeOwned,
+  {
+    self
+      .0
+      .push((name, save_component::<C>, load_component::<C>));
+    self
+  }
+}
+
+fn save_component<C>(world: &World, entity: Entity) -> Option<Result<String, ron::ser::Error>>
+where
+  C: Component + Serialize,
+{
+  world
+    .read_storage::<C>()
+    .get(entity)
+    .map(ron::ser::to_string)
+}
+
+fn load_component<C>(data: &str) -> Result<LoadComponent, ron::de::Error>
+where
+  C: Component + DeserializeOwned,
+{
+  let component = ron::de::from_str::<C>(data)?;
+  Ok(Box::new(move |world, entity| {
+    world
+      .write_storage::<C>()
+      .insert(entity, component)
+      .unwrap();
+  }))
+}
+
+#[derive(Debug)]
+pub enum PersistenceError {
+  /// The entity to save isn't a chunk.
+  NotAChunk(Entity),
+  /// The entity to load a chunk into isn't a level.
+  NotALevel(Entity),
+  /// A layer of the chunk couldn't be saved or loaded.
+  Layer(String, LayerError),
+  Serialize(ron::ser::Error),
+  Deserialize(ron::de::Error),
+  /// A save file couldn't be read or written.
+  Io(io::Error),
+}
+
+impl Error for PersistenceError {}
+
+impl fmt::Display for PersistenceError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PersistenceError::NotAChunk(entity) => write!(f, "Entity {:?} is not a chunk", entity),
+      PersistenceError::NotALevel(entity) => write!(f, "Entity {:?} is not a level", entity),
+      PersistenceError::Layer(name, err) => write!(f, "Invalid layer '{}': {}", name, err),
+      PersistenceError::Serialize(err) => write!(f, "Couldn't serialize component: {}", err),
+      PersistenceError::Deserialize(err) => write!(f, "Couldn't deserialize component: {}", err),
+      PersistenceError::Io(err) => write!(f, "Couldn't access save file: {}", err),
+    }
+  }
+}
+
+fn insert_or_remove<C: Component>(
+  storage: &mut WriteStorage<'_, C>,
+  entity: Entity,
+  value: Option<C>,
+) {
+  match value {
+    Some(value) => {
+      storage.insert(entity, value).unwrap();
+    }
+    None => {
+      storage.remove(entity);
+    }
+  }
+}
+
+/// Saves the specified chunk entity, including every layer registered in the `ChunkLayers`
+/// resource, its scheduled ticks and any block entities with their components registered
+/// in the `PersistedComponents` resource.
+pub fn save_chunk<L: ChunkLayout>(
+  world: &World,
+  chunk: Entity,
+) -> Result<SavedChunk, PersistenceError> {
+  let pos = match world.read_storage::<Chunk>().get(chunk) {
+    Some(chunk) => chunk.pos,
+    None => return Err(PersistenceError::NotAChunk(chunk)),
+  };
+  let mut layers = Vec::new();
+  let (registered, default) = (world.try_fetch::<ChunkLayers<L>>(), ChunkLayers::default());
+  for layer in registered.as_deref().unwrap_or(&default).iter() {
+    let data = layer.save(world, chunk);
+    let data = data.map_err(|err| PersistenceError::Layer(layer.name().to_string(), err))?;
+    if let Some(data) = data {
+      layers.push((layer.name().to_string(), data));
+    }
+  }
+  let scheduled_ticks = world.read_storage::<ScheduledTicks>().get(chunk).cloned();
+
+  let persisted = world.try_fetch::<PersistedComponents>();
+  let mut block_entities = Vec::new();
+  if let Some(chunk_block_entities) = world.read_storage::<ChunkBlockEntities>().get(chunk) {
+    for (pos, entity) in chunk_block_entities.iter() {
+      let mut components = Vec::new();
+      for (name, save, _) in persisted.iter().flat_map(|persisted| persisted.0.iter()) {
+        if let Some(data) = save(world, entity) {
+          let data = data.map_err(PersistenceError::Serialize)?;
+          components.push((name.to_string(), data));
+        }
+      }
+      block_entities.push(SavedBlockEntity { pos, components });
+    }
+  }
+
+  Ok(SavedChunk {
+    pos,
+    layers,
+    scheduled_ticks,
+    block_entities,
+  })
+}
+
+/// Loads a previously saved chunk into the specified level, replacing the chunk at its position
+/// if there is one already. Block entities are recreated with the components that could be
+/// loaded, skipping those that are no longer registered in `PersistedComponents`. Likewise,
+/// saved layers which aren't registered in `ChunkLayers` are ignored. Everything is deserialized
+/// before the world is changed, so a chunk that fails to load leaves the level as it was.
+pub fn load_chunk<L: ChunkLayout>(
+  world: &mut World,
+  level: Entity,
+  saved: SavedChunk,
+) -> Result<Entity, PersistenceError> {
+  let layers = {
+    let (registered, default) = (world.try_fetch::<ChunkLayers<L>>(), ChunkLayers::default());
+    let layers = registered.as_deref().unwrap_or(&default).iter();
+    layers
+      .map(|layer| {
+        let data = (saved.layers.iter())
+          .find(|(name, _)| name == layer.name())
+          .map(|(_, data)| data);
+        (layer.load(data)).map_err(|err| PersistenceError::Layer(layer.name().to_string(), err))
+      })
+      .collect::<Result<Vec<_>, _>>()?
+  };
+  let block_entities = {
+    let persisted = world.try_fetch::<PersistedComponents>();
+    (saved.block_entities.iter())
+      .map(|saved_entity| {
+        let components = (saved_entity.components.iter())
+          .filter_map(|(name, data)| {
+            let load = persisted
+              .iter()
+              .flat_map(|persisted| persisted.0.iter())
+              .find(|(persisted_name, _, _)| persisted_name == name)
+              .map(|(_, _, load)| load)?;
+            Some(load(data).map_err(PersistenceError::Deserialize))
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        Ok((saved_entity.pos, components))
+      })
+      .collect::<Result<Vec<_>, _>>()?
+  };
+
+  let chunk = world
+    .exec(|mut access: BlockAccess<'_, u8, L>| {
+      let chunk = access.chunk(level, saved.pos);
+      chunk.or_else(|| access.create_chunk(level, saved.pos))
+    })
+    .ok_or(PersistenceError::NotALevel(level))?;
+
+  // Block entities of the chunk being replaced are no longer attached to anything.
+  if let Some(previous) = world.write_storage::<ChunkBlockEntities>().remove(chunk) {
+    for (_, entity) in previous.iter() {
+      let _ = world.entities().delete(entity);
+    }
+  }
+
+  for load in layers {
+    load(world, chunk);
+  }
+  insert_or_remove(&mut world.write_storage(), chunk, saved.scheduled_ticks);
+  // Light is recalculated by `LightingSystem` once these are gone.
+  world
+    .write_storage::<ChunkStorage<BlockLight, L>>()
+    .remove(chunk);
+  world
+    .write_storage::<ChunkStorage<SkyLight, L>>()
+    .remove(chunk);
+
+  for (pos, components) in block_entities {
+    let entity = world.entities().create();
+    for load in components {
+      load(world, entity);
+    }
+    world
+      .exec(|mut access: BlockEntityAccess<'_, L>| access.attach_block_entity(level, pos, entity));
+  }
+
+  // The chunk is complete, so `WorldGenerator` mustn't fill in terrain, but it needs a new mesh.
+  if let Some(octree) = world
+    .write_storage::<ChunkedOctree<ChunkState>>()
+    .get_mut(level)
+  {
+    if let Ok(z_pos) = ZOrder::try_from(saved.pos) {
+      mark_chunk_generated(octree, z_pos);
+    }
+    mark_mesh_outdated(octree, saved.pos);
+  }
+
+  Ok(chunk)
+}
+
+/// Saves the specified level into `dir`, with its `Level` component in `level.ron` and one file
+/// per chunk named after its position, and returns how many chunks were saved. Files of chunks
+/// saved previously which no longer exist are deleted, so they don't come back the next time the
+/// level is loaded. Other files in `dir` are left alone.
+pub fn save_level<L: ChunkLayout>(
+  world: &World,
+  level: Entity,
+  dir: &Path,
+) -> Result<usize, PersistenceError> {
+  let level_data = (world.read_storage::<Level>().get(level).cloned())
+    .ok_or(PersistenceError::NotALevel(level))?;
+  fs::create_dir_all(dir).map_err(PersistenceError::Io)?;
+  let data = ron::ser::to_string(&level_data).map_err(PersistenceError::Serialize)?;
+  write_file(&dir.join(LEVEL_FILE_NAME), data)?;
+
+  let chunks = (&world.entities(), &world.read_storage::<Chunk>())
+    .join()
+    .filter(|(_, chunk)| chunk.level == level)
+    .map(|(entity, chunk)| (entity, chunk.pos))
+    .collect::<Vec<_>>();
+  let mut saved_chunks = HashSet::new();
+  for (entity, pos) in &chunks {
+    let saved = save_chunk::<L>(world, *entity)?;
+    let data = ron::ser::to_string(&saved).map_err(PersistenceError::Serialize)?;
+    write_file(&dir.join(chunk_file_name(*pos)), data)?;
+    saved_chunks.insert(*pos);
+  }
+  for entry in fs::read_dir(dir).map_err(PersistenceError::Io)? {
+    let path = entry.map_err(PersistenceError::Io)?.path();
+    if matches!(chunk_file_pos(&path), Some(pos) if !saved_chunks.contains(&pos)) {
+      fs::remove_file(&path).map_err(PersistenceError::Io)?;
+    }
+  }
+  Ok(chunks.len())
+}
+
+/// Loads a level saved by `save_level` in `dir` into the specified level entity, replacing its
+/// `Level` component, and returns how many chunks were loaded. A missing directory is treated as
+/// a level without any saved chunks, which keeps its `Level` component, such as its seed.
+pub fn load_level<L: ChunkLayout>(
+  world: &mut World,
+  level: Entity,
+  dir: &Path,
+) -> Result<usize, PersistenceError> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+    Err(err) => return Err(PersistenceError::Io(err)),
+  };
+  match fs::read_to_string(dir.join(LEVEL_FILE_NAME)) {
+    Ok(data) => {
+      let level_data = ron::de::from_str::<Level>(&data).map_err(PersistenceError::Deserialize)?;
+      let mut levels = world.write_storage::<Level>();
+      match levels.get_mut(level) {
+        Some(existing) => *existing = level_data,
+        None => return Err(PersistenceError::NotALevel(level)),
+      }
+    }
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+    Err(err) => return Err(PersistenceError::Io(err)),
+  }
+
+  let mut count = 0;
+  for entry in entries {
+    let path = entry.map_err(PersistenceError::Io)?.path();
+    if chunk_file_pos(&path).is_none() {
+      continue;
+    }
+    let data = fs::read_to_string(&path).map_err(PersistenceError::Io)?;
+    let saved = ron::de::from_str::<SavedChunk>(&data).map_err(PersistenceError::Deserialize)?;
+    load_chunk::<L>(world, level, saved)?;
+    count += 1;
+  }
+  Ok(count)
+}
+
+/// Writes to a temporary file first, so a crash can't leave a truncated save behind.
+fn write_file(path: &Path, data: String) -> Result<(), PersistenceError> {
+  let temp_path = path.with_extension("ron.tmp");
+  fs::write(&temp_path, data).map_err(PersistenceError::Io)?;
+  fs::rename(&temp_path, path).map_err(PersistenceError::Io)
+}
+
+/// Name of the file `save_level` saves the chunk at the specified position in.
+fn chunk_file_name(pos: ChunkPos) -> String {
+  format!("{}_{}_{}.ron", pos.x, pos.y, pos.z)
+}
+
+/// Gets the position of the chunk saved in the specified file, if it's named like a chunk save.
+fn chunk_file_pos(path: &Path) -> Option<ChunkPos> {
+  let name = path.file_name()?.to_str()?;
+  let mut coords = name.trim_end_matches(".ron").split('_').map(str::parse);
+  let pos = match (coords.next(), coords.next(), coords.next(), coords.next()) {
+    (Some(Ok(x)), Some(Ok(y)), Some(Ok(z)), None) => ChunkPos::new(x, y, z),
+    _ => return None,
+  };
+  // Only accept the exact name the chunk would be saved as, which rules out other extensions.
+  if chunk_file_name(pos) == name {
+    Some(pos)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{create_level, BlockTickScheduler, BlockView, FluidLevel, Level},
+  };
+
+  #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+  struct SignText(String);
+
+  impl Component for SignText {
+    type Storage = DenseVecStorage<Self>;
+  }
+
+  fn world() -> (World, Entity) {
+    let mut world = World::new();
+    BlockAccess::<u8, LinearLayout16>::setup(&mut world);
+    BlockEntityAccess::<LinearLayout16>::setup(&mut world);
+    BlockTickScheduler::<LinearLayout16>::setup(&mut world);
+    let layers = ChunkLayers::<LinearLayout16>::default().with::<FluidLevel>("fluid_levels");
+    layers.register(&mut world);
+    world.insert(layers);
+    world.register::<ChunkStorage<BlockLight, LinearLayout16>>();
+    world.register::<ChunkStorage<SkyLight, LinearLayout16>>();
+    world.register::<SignText>();
+    world.insert(PersistedComponents::default().with::<SignText>("sign_text"));
+    let level = create_level(world.create_entity(), Level::new(0));
+    (world, level)
+  }
+
+  #[test]
+  fn chunk_round_trip() {
+    let (mut world, level) = world();
+    let sign_pos = BlockPos::new(3, 4, 5);
+    let chunk = world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.set(level, BlockPos::new(1, 2, 3), 7);
+      blocks.set(level, sign_pos, 1);
+      blocks.chunk(level, ChunkPos::new(0, 0, 0)).unwrap()
+    });
+    world.exec(|mut levels: BlockAccess<'_, FluidLevel, LinearLayout16>| {
+      levels.set(level, BlockPos::new(1, 2, 3), FluidLevel(5));
+    });
+    let sign = world.exec(|mut access: BlockEntityAccess<'_, LinearLayout16>| {
+      access.create_block_entity(level, sign_pos).unwrap()
+    });
+    world
+      .write_storage()
+      .insert(sign, SignText("Hello".to_string()))
+      .unwrap();
+    world.exec(|mut scheduler: BlockTickScheduler<'_, LinearLayout16>| {
+      scheduler.schedule(level, sign_pos, 10);
+    });
+
+    let saved = save_chunk::<LinearLayout16>(&world, chunk).unwrap();
+    let text = ron::ser::to_string(&saved).unwrap();
+    let saved: SavedChunk = ron::de::from_str(&text).unwrap();
+
+    assert_eq!(saved.layers.len(), 2);
+    let (mut world, level) = self::world();
+    load_chunk::<LinearLayout16>(&mut world, level, saved).unwrap();
+    world.exec(|blocks: BlockView<'_, u8, LinearLayout16>| {
+      assert_eq!(blocks.get(level, BlockPos::new(1, 2, 3)), 7);
+      assert_eq!(blocks.get(level, BlockPos::new(1, 2, 4)), 0);
+    });
+    world.exec(|levels: BlockView<'_, FluidLevel, LinearLayout16>| {
+      assert_eq!(levels.get(level, BlockPos::new(1, 2, 3)), FluidLevel(5));
+      assert_eq!(levels.get(level, sign_pos), FluidLevel(0));
+    });
+    let sign = world.exec(|access: BlockEntityAccess<'_, LinearLayout16>| {
+      let sign = access.block_entity(level, sign_pos).unwrap();
+      assert_eq!(access.block_entity_pos(sign), Some((level, sign_pos)));
+      sign
+    });
+    assert_eq!(
+      world.read_storage::<SignText>().get(sign),
+      Some(&SignText("Hello".to_string()))
+    );
+    world.exec(|scheduler: BlockTickScheduler<'_, LinearLayout16>| {
+      assert!(scheduler.is_scheduled(level, sign_pos));
+    });
+  }
+
+  #[test]
+  fn level_round_trip() {
+    let dir = std::env::temp_dir().join(format!("gaemstone-save-test-{}", std::process::id()));
+    let (mut world, level) = world();
+    {
+      let mut levels = world.write_storage::<Level>();
+      let level = levels.get_mut(level).unwrap();
+      level.seed = 1234;
+      level.tick = 56;
+    }
+    let blocks = [BlockPos::new(1, 2, 3), BlockPos::new(-20, 40, 7)];
+    world.exec(|mut access: BlockAccess<'_, u8, LinearLayout16>| {
+      for pos in &blocks {
+        access.set(level, *pos, 3);
+      }
+    });
+    assert_eq!(
+      save_level::<LinearLayout16>(&world, level, &dir).unwrap(),
+      2
+    );
+
+    let (mut world, level) = self::world();
+    assert_eq!(
+      load_level::<LinearLayout16>(&mut world, level, &dir).unwrap(),
+      2
+    );
+    world.exec(|access: BlockView<'_, u8, LinearLayout16>| {
+      for pos in &blocks {
+        assert_eq!(access.get(level, *pos), 3);
+      }
+    });
+    let loaded = world.read_storage::<Level>().get(level).cloned().unwrap();
+    assert_eq!((loaded.seed, loaded.tick), (1234, 56));
+
+    // Chunks that no longer exist aren't loaded again, but files which aren't chunk saves are
+    // neither deleted nor loaded.
+    let other_files = ["settings.ron", "1_2.ron", "9_9_9.ron.tmp", "0_0_0.txt"];
+    for name in &other_files {
+      fs::write(dir.join(name), "not a chunk").unwrap();
+    }
+    let chunk = world.exec(|access: BlockView<'_, u8, LinearLayout16>| {
+      access.chunk(level, ChunkPos::new(-2, 2, 0)).unwrap()
+    });
+    world.delete_entity(chunk).unwrap();
+    assert_eq!(
+      save_level::<LinearLayout16>(&world, level, &dir).unwrap(),
+      1
+    );
+    let (mut world, level) = self::world();
+    assert_eq!(
+      load_level::<LinearLayout16>(&mut world, level, &dir).unwrap(),
+      1
+    );
+    for name in &other_files {
+      assert!(dir.join(name).exists());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    let missing = dir.join("missing");
+    assert_eq!(
+      load_level::<LinearLayout16>(&mut world, level, &missing).unwrap(),
+      0
+    );
+  }
+
+  #[test]
+  fn unregistered_layers_are_ignored() {
+    let (mut world, level) = world();
+    let saved = SavedChunk {
+      pos: ChunkPos::new(0, 0, 0),
+      layers: vec![("unknown".to_string(), LayerData::default())],
+      scheduled_ticks: None,
+      block_entities: Vec::new(),
+    };
+    let chunk = load_chunk::<LinearLayout16>(&mut world, level, saved).unwrap();
+    assert!(!world
+      .read_storage::<ChunkStorage<u8, LinearLayout16>>()
+      .contains(chunk));
+  }
+
+  #[test]
+  fn failed_load_leaves_chunk_unchanged() {
+    let (mut world, level) = world();
+    let sign_pos = BlockPos::new(3, 4, 5);
+    let chunk = world.exec(|mut blocks: BlockAccess<'_, u8, LinearLayout16>| {
+      blocks.set(level, sign_pos, 1);
+      blocks.chunk(level, ChunkPos::new(0, 0, 0)).unwrap()
+    });
+    let sign = world.exec(|mut access: BlockEntityAccess<'_, LinearLayout16>| {
+      access.create_block_entity(level, sign_pos).unwrap()
+    });
+    let mut saved = save_chunk::<LinearLayout16>(&world, chunk).unwrap();
+    saved.layers[0].1.palette = vec!["0".to_string(), "5".to_string()];
+    saved.block_entities[0].components = vec![("sign_text".to_string(), "(".to_string())];
+    let entity_count = world.entities().join().count();
+
+    assert!(matches!(
+      load_chunk::<LinearLayout16>(&mut world, level, saved),
+      Err(PersistenceError::Deserialize(_))
+    ));
+    assert_eq!(world.entities().join().count(), entity_count);
+    world.exec(|access: BlockEntityAccess<'_, LinearLayout16>| {
+      assert_eq!(access.blocks().get(level, sign_pos), 1);
+      assert_eq!(access.block_entity(level, sign_pos), Some(sign));
+    });
+  }
+}
@@ -0,0 +1,316 @@
+use {
+  super::{
+    chunk::{storage::ChunkStorage, ChunkLookup, ChunkPos, Index},
+    is_solid, move_aabb, sweep_axis, Aabb, ActiveLevel, BlockPos, Console,
+  },
+  amethyst::{
+    assets::PrefabData,
+    core::{
+      math::{Point3, Vector3},
+      transform::Transform,
+      Time,
+    },
+    derive::{PrefabData, SystemDesc},
+    ecs::prelude::*,
+    input::{InputEvent, InputHandler, StringBindings},
+    shrev::EventChannel,
+    Error,
+  },
+  serde::{Deserialize, Serialize},
+};
+
+/// Input action which switches the player between flying and walking.
+pub const ACTION_TOGGLE_FLY: &str = "toggle_fly";
+
+/// Acceleration due to gravity, in blocks per second squared.
+pub const GRAVITY: f32 = 32.0;
+/// Maximum speed at which players fall, in blocks per second.
+const TERMINAL_VELOCITY: f32 = 60.0;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub enum MovementMode {
+  /// Moves freely in the direction the player is looking, passing through terrain.
+  Fly,
+  /// Walks on terrain, affected by gravity and collisions with solid blocks.
+  Walk,
+}
+
+/// Component for the entity controlled by the local player, usually its camera. The entity's
+/// `Transform` is the position of the player's eyes, which its collider is positioned around.
+/// Rotation is left to `FreeRotationSystem`, while `PlayerMovementSystem` handles movement.
+#[derive(Clone, Debug, Deserialize, Serialize, PrefabData)]
+#[prefab(Component)]
+#[serde(default)]
+pub struct PlayerController {
+  pub mode: MovementMode,
+  /// Speed while flying, in blocks per second.
+  pub fly_speed: f32,
+  /// Speed while walking, in blocks per second.
+  pub walk_speed: f32,
+  /// Vertical speed when starting a jump, in blocks per second.
+  pub jump_speed: f32,
+  /// Height of obstacles the player automatically walks up onto, in blocks.
+  pub step_height: f32,
+  /// Width and depth of the player's collider.
+  pub width: f32,
+  /// Height of the player's collider.
+  pub height: f32,
+  /// Height of the player's eyes above the bottom of the collider.
+  pub eye_height: f32,
+
+  #[serde(skip)]
+  pub velocity: Vector3<f32>,
+  #[serde(skip)]
+  pub on_ground: bool,
+}
+
+impl Default for PlayerController {
+  fn default() -> Self {
+    PlayerController {
+      mode: MovementMode::Fly,
+      fly_speed: 20.0,
+      walk_speed: 5.0,
+      jump_speed: 9.0,
+      step_height: 1.0,
+      width: 0.6,
+      height: 1.8,
+      eye_height: 1.6,
+      velocity: Vector3::zeros(),
+      on_ground: false,
+    }
+  }
+}
+
+impl Component for PlayerController {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl PlayerController {
+  /// Returns the player's collider when their eyes are at the specified position.
+  pub fn collider(&self, eye_pos: Point3<f32>) -> Aabb {
+    let half_width = self.width / 2.0;
+    Aabb::new(
+      eye_pos + Vector3::new(-half_width, -self.eye_height, -half_width),
+      eye_pos + Vector3::new(half_width, self.height - self.eye_height, half_width),
+    )
+  }
+}
+
+/// Moves entities with a `PlayerController` according to the "move_x", "move_y" and "move_z"
+/// input axes. While walking, "move_y" makes the player jump. The "toggle_fly" action switches
+/// between `MovementMode`s. Input is ignored while the `Console` is open.
+#[derive(SystemDesc)]
+#[system_desc(name(PlayerMovementSystemDesc))]
+pub struct PlayerMovementSystem {
+  #[system_desc(event_channel_reader)]
+  reader: ReaderId<InputEvent<StringBindings>>,
+}
+
+impl PlayerMovementSystem {
+  pub fn new(reader: ReaderId<InputEvent<StringBindings>>) -> Self {
+    Self { reader }
+  }
+}
+
+impl<'a> System<'a> for PlayerMovementSystem {
+  type SystemData = (
+    Read<'a, EventChannel<InputEvent<StringBindings>>>,
+    Read<'a, InputHandler<StringBindings>>,
+    Read<'a, Time>,
+    Read<'a, ActiveLevel>,
+    Read<'a, Console>,
+    ReadStorage<'a, ChunkLookup>,
+    ReadStorage<'a, ChunkStorage<u8>>,
+    WriteStorage<'a, PlayerController>,
+    WriteStorage<'a, Transform>,
+  );
+
+  fn run(
+    &mut self,
+    (
+      events,
+      input,
+      time,
+      active_level,
+      console,
+      lookups,
+      storages,
+      mut controllers,
+      mut transforms,
+    ): Self::SystemData,
+  ) {
+    // Keys pressed while the console is open are typed into it instead.
+    let ignore_input = console.is_open();
+    let toggle = events
+      .read(&mut self.reader)
+      .filter(|event| match event {
+        InputEvent::ActionPressed(action) => action == ACTION_TOGGLE_FLY,
+        _ => false,
+      })
+      .count()
+      % 2
+      == 1
+      && !ignore_input;
+
+    let lookup = active_level.0.and_then(|level| lookups.get(level));
+    let is_solid_at = |pos: BlockPos| {
+      let (chunk_pos, index): (ChunkPos, Index) = pos.into();
+      lookup
+        .and_then(|lookup| lookup.get(chunk_pos))
+        .and_then(|entity| storages.get(entity))
+        .map_or(false, |storage| is_solid(storage.get(index)))
+    };
+
+    let axis = |name: &str| {
+      if ignore_input {
+        0.0
+      } else {
+        input.axis_value(name).unwrap_or(0.0)
+      }
+    };
+    let local = Vector3::new(axis("move_x"), axis("move_y"), axis("move_z"));
+    let delta = time.delta_seconds();
+
+    for (controller, transform) in (&mut controllers, &mut transforms).join() {
+      if toggle {
+        controller.mode = match controller.mode {
+          MovementMode::Fly => MovementMode::Walk,
+          MovementMode::Walk => MovementMode::Fly,
+        };
+        controller.velocity = Vector3::zeros();
+        controller.on_ground = false;
+      }
+
+      match controller.mode {
+        MovementMode::Fly => {
+          let direction = transform.rotation() * local;
+          if let Some(direction) = direction.try_normalize(std::f32::EPSILON) {
+            *transform.translation_mut() += direction * controller.fly_speed * delta;
+          }
+        }
+        MovementMode::Walk => {
+          // Only the yaw of the camera matters when walking, so flatten its direction.
+          let mut direction = transform.rotation() * Vector3::new(local.x, 0.0, local.z);
+          direction.y = 0.0;
+          let direction = direction
+            .try_normalize(std::f32::EPSILON)
+            .unwrap_or_else(Vector3::zeros);
+
+          let velocity = &mut controller.velocity;
+          velocity.x = direction.x * controller.walk_speed;
+          velocity.z = direction.z * controller.walk_speed;
+          velocity.y = (velocity.y - GRAVITY * delta).max(-TERMINAL_VELOCITY);
+          if controller.on_ground && local.y > 0.0 {
+            velocity.y = controller.jump_speed;
+          }
+
+          let collider = controller.collider(Point3::from(*transform.translation()));
+          let offset = walk(controller, collider, delta, &is_solid_at);
+          *transform.translation_mut() += offset;
+        }
+      }
+    }
+  }
+}
+
+/// Moves a walking player's collider according to its velocity, updating the
+/// controller's state according to any collisions. Returns how far it moved.
+fn walk<F>(
+  controller: &mut PlayerController,
+  collider: Aabb,
+  delta: f32,
+  is_solid: &F,
+) -> Vector3<f32>
+where
+  F: Fn(BlockPos) -> bool,
+{
+  let velocity = controller.velocity * delta;
+  let movement = move_aabb(&collider, velocity, is_solid);
+  let offset = movement.offset;
+
+  let blocked_horizontally = movement.collided[0] || movement.collided[2];
+  if blocked_horizontally && controller.on_ground && velocity.y <= 0.0 {
+    // Attempt to step up onto the obstacle: Move up, then horizontally, then back down.
+    // If that gets the player further than walking into the obstacle, use that instead.
+    let up = sweep_axis(&collider, 1, controller.step_height, is_solid);
+    let raised = collider.translate(Vector3::new(0.0, up, 0.0));
+    let horizontal = move_aabb(&raised, Vector3::new(velocity.x, 0.0, velocity.z), is_solid);
+    let moved = raised.translate(horizontal.offset);
+    let down = sweep_axis(&moved, 1, -up, is_solid);
+
+    let stepped = Vector3::new(horizontal.offset.x, up + down, horizontal.offset.z);
+    let stepped_distance = stepped.x.abs() + stepped.z.abs();
+    if stepped_distance > offset.x.abs() + offset.z.abs() {
+      controller.velocity.y = 0.0;
+      controller.on_ground = true;
+      return stepped;
+    }
+  }
+
+  if movement.collided[1] {
+    controller.on_ground = velocity.y < 0.0;
+    controller.velocity.y = 0.0;
+  } else {
+    controller.on_ground = false;
+  }
+  offset
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Solid ground below `y = 0`, plus a wall `height` blocks tall at `x = 1`.
+  fn wall(height: i32) -> impl Fn(BlockPos) -> bool {
+    move |pos| pos.y < 0 || (pos.x == 1 && pos.y < height)
+  }
+
+  fn walking(velocity: Vector3<f32>, on_ground: bool) -> PlayerController {
+    PlayerController {
+      mode: MovementMode::Walk,
+      velocity,
+      on_ground,
+      ..Default::default()
+    }
+  }
+
+  fn assert_near(a: Vector3<f32>, b: Vector3<f32>) {
+    assert!((a - b).norm() < 1.0e-5, "{} != {}", a, b);
+  }
+
+  #[test]
+  fn steps_up_blocks_at_step_height() {
+    let mut controller = walking(Vector3::new(5.0, -1.0, 0.0), true);
+    let collider = controller.collider(Point3::new(0.5, 1.6, 0.5));
+    let offset = walk(&mut controller, collider, 0.1, &wall(1));
+    assert_near(offset, Vector3::new(0.5, 1.0, 0.0));
+    assert!(controller.on_ground);
+    assert_eq!(controller.velocity.y, 0.0);
+  }
+
+  #[test]
+  fn taller_blocks_stop_the_player() {
+    let mut controller = walking(Vector3::new(5.0, -1.0, 0.0), true);
+    let collider = controller.collider(Point3::new(0.5, 1.6, 0.5));
+    let offset = walk(&mut controller, collider, 0.1, &wall(2));
+    // Only walks up to the wall, rather than onto it.
+    assert_near(offset, Vector3::new(0.2, 0.0, 0.0));
+    assert!(controller.on_ground);
+  }
+
+  #[test]
+  fn lands_on_ground() {
+    let mut controller = walking(Vector3::new(0.0, -1.0, 0.0), false);
+    let collider = controller.collider(Point3::new(-2.5, 2.1, 0.5));
+    let offset = walk(&mut controller, collider, 0.1, &wall(1));
+    assert_near(offset, Vector3::new(0.0, -0.1, 0.0));
+    assert!(!controller.on_ground);
+
+    controller.velocity.y = -10.0;
+    let collider = controller.collider(Point3::new(-2.5, 2.0, 0.5));
+    let offset = walk(&mut controller, collider, 0.1, &wall(1));
+    assert_near(offset, Vector3::new(0.0, -0.4, 0.0));
+    assert!(controller.on_ground);
+    assert_eq!(controller.velocity.y, 0.0);
+  }
+}
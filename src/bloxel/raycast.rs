@@ -0,0 +1,275 @@
+use {
+  super::{
+    chunk::{storage::*, *},
+    BlockPos, Facing,
+  },
+  amethyst::{
+    core::math::{Point3, Vector3},
+    ecs::{prelude::*, storage::GenericReadStorage},
+  },
+  std::convert::TryFrom,
+};
+
+/// Block hit by a `raycast`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RaycastHit {
+  pub pos: BlockPos,
+  /// Face of the hit block the ray entered through, so `pos + facing` is the block in front of
+  /// it. This is `None` if the ray started inside of the block.
+  pub facing: Option<Facing>,
+  /// Distance along the ray from its origin to the point where it entered the block.
+  pub distance: f32,
+}
+
+/// Walks along a ray through the block grid using the voxel traversal algorithm by Amanatides
+/// and Woo, visiting every block the ray passes through in order. Returns the first block for
+/// which `is_hit` returns `true`, or `None` if there is none within `max_distance`.
+///
+/// `direction` doesn't need to be normalized, but must not be zero. Rays with a non-finite
+/// origin, direction or `max_distance` never hit anything.
+pub fn raycast<F>(
+  origin: Point3<f32>,
+  direction: Vector3<f32>,
+  max_distance: f32,
+  mut is_hit: F,
+) -> Option<RaycastHit>
+where
+  F: FnMut(BlockPos) -> bool,
+{
+  let finite = |v: &[f32]| v.iter().all(|value| value.is_finite());
+  if !finite(origin.coords.as_slice()) || !finite(direction.as_slice()) || !max_distance.is_finite()
+  {
+    return None;
+  }
+  let direction = direction.try_normalize(std::f32::EPSILON)?;
+
+  let mut pos = [
+    origin.x.floor() as i32,
+    origin.y.floor() as i32,
+    origin.z.floor() as i32,
+  ];
+  let mut step = [0; 3];
+  // Distance along the ray at which the next block boundary is crossed on each axis.
+  let mut t_max = [std::f32::INFINITY; 3];
+  // Distance along the ray it takes to move the length of one block on each axis.
+  let mut t_delta = [std::f32::INFINITY; 3];
+  for axis in 0..3 {
+    let (o, d) = (origin[axis], direction[axis]);
+    if d > 0.0 {
+      step[axis] = 1;
+      t_max[axis] = (o.floor() + 1.0 - o) / d;
+      t_delta[axis] = 1.0 / d;
+    } else if d < 0.0 {
+      step[axis] = -1;
+      t_max[axis] = (o - o.floor()) / -d;
+      t_delta[axis] = 1.0 / -d;
+    }
+  }
+
+  let to_block_pos = |pos: [i32; 3]| BlockPos::new(pos[0], pos[1], pos[2]);
+  if is_hit(to_block_pos(pos)) {
+    return Some(RaycastHit {
+      pos: to_block_pos(pos),
+      facing: None,
+      distance: 0.0,
+    });
+  }
+
+  // Within `max_distance`, the ray crosses at most `max_distance * |d|` boundaries on each axis,
+  // which adds up to no more than this. Bounding the steps ensures the loop always ends.
+  let max_steps = (max_distance.max(0.0) * 3f32.sqrt()).ceil() as usize + 3;
+  for _ in 0..max_steps {
+    let axis = if t_max[0] < t_max[1] {
+      if t_max[0] < t_max[2] {
+        0
+      } else {
+        2
+      }
+    } else if t_max[1] < t_max[2] {
+      1
+    } else {
+      2
+    };
+
+    let distance = t_max[axis];
+    if distance > max_distance {
+      return None;
+    }
+
+    pos[axis] += step[axis];
+    t_max[axis] += t_delta[axis];
+
+    if is_hit(to_block_pos(pos)) {
+      // The ray enters the block through the face pointing back against the step direction.
+      let mut entered = (0, 0, 0);
+      match axis {
+        0 => entered.0 = -step[0],
+        1 => entered.1 = -step[1],
+        _ => entered.2 = -step[2],
+      }
+      return Some(RaycastHit {
+        pos: to_block_pos(pos),
+        facing: Facing::try_from(entered).ok(),
+        distance,
+      });
+    }
+  }
+  None
+}
+
+/// Casts a ray through the chunks of a level, returning the first block for which `is_hit`
+/// returns `true`. Blocks in chunks that don't exist are never hit.
+pub fn raycast_chunks<T, L, S, F>(
+  lookup: &ChunkLookup,
+  storages: &S,
+  origin: Point3<f32>,
+  direction: Vector3<f32>,
+  max_distance: f32,
+  mut is_hit: F,
+) -> Option<RaycastHit>
+where
+  T: BlockData,
+  L: ChunkLayout,
+  S: GenericReadStorage<Component = ChunkStorage<T, L>>,
+  F: FnMut(T) -> bool,
+{
+  // Consecutive blocks are usually part of the same chunk, so avoid looking it up every time.
+  let mut current: Option<(ChunkPos, Option<&ChunkStorage<T, L>>)> = None;
+  raycast(origin, direction, max_distance, |pos| {
+    let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+    let storage = match current {
+      Some((current_pos, storage)) if current_pos == chunk_pos => storage,
+      _ => {
+        let storage = lookup.get(chunk_pos).and_then(|e| storages.get(e));
+        current = Some((chunk_pos, storage));
+        storage
+      }
+    };
+    storage.map_or(false, |storage| is_hit(storage.get(index)))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Creates a world containing a single level with a solid
+  /// block at each of the specified positions.
+  fn fixture(blocks: &[(i32, i32, i32)]) -> (World, ChunkLookup) {
+    let mut world = World::new();
+    world.register::<ChunkStorage<u8>>();
+    let mut lookup = ChunkLookup::default();
+    for &(x, y, z) in blocks {
+      let (chunk_pos, index): (ChunkPos, Index) = BlockPos::new(x, y, z).into();
+      let entity = match lookup.get(chunk_pos) {
+        Some(entity) => entity,
+        None => {
+          let storage = ChunkStorage::new(PaletteStorageImpl::<u8>::new());
+          let entity = world.create_entity().with(storage).build();
+          lookup.insert(chunk_pos, entity);
+          entity
+        }
+      };
+      let mut storages = world.write_storage::<ChunkStorage<u8>>();
+      storages.get_mut(entity).unwrap().set(index, 1);
+    }
+    (world, lookup)
+  }
+
+  fn cast(
+    (world, lookup): &(World, ChunkLookup),
+    origin: (f32, f32, f32),
+    direction: (f32, f32, f32),
+    max_distance: f32,
+  ) -> Option<RaycastHit> {
+    let storages = world.read_storage::<ChunkStorage<u8>>();
+    raycast_chunks(
+      lookup,
+      &storages,
+      Point3::new(origin.0, origin.1, origin.2),
+      Vector3::new(direction.0, direction.1, direction.2),
+      max_distance,
+      |value| value != 0,
+    )
+  }
+
+  #[test]
+  fn hits_block_along_axis() {
+    let fixture = fixture(&[(5, 0, 0)]);
+    let hit = cast(&fixture, (0.5, 0.5, 0.5), (1.0, 0.0, 0.0), 10.0).unwrap();
+    assert_eq!(hit.pos, BlockPos::new(5, 0, 0));
+    assert_eq!(hit.facing, Some(Facing::West));
+    assert!((hit.distance - 4.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn crosses_chunk_boundaries_in_negative_direction() {
+    let fixture = fixture(&[(-20, 3, 7)]);
+    let hit = cast(&fixture, (2.5, 3.5, 7.5), (-1.0, 0.0, 0.0), 32.0).unwrap();
+    assert_eq!(hit.pos, BlockPos::new(-20, 3, 7));
+    assert_eq!(hit.facing, Some(Facing::East));
+    assert!((hit.distance - 21.5).abs() < 1e-5);
+  }
+
+  #[test]
+  fn reports_entered_face_of_diagonal_ray() {
+    let fixture = fixture(&[(0, 2, 0), (2, 0, 0)]);
+    // Passes through (0, 1, 0) first, then enters (0, 2, 0) from below.
+    let hit = cast(&fixture, (0.5, 0.5, 0.5), (0.2, 1.0, 0.0), 10.0).unwrap();
+    assert_eq!(hit.pos, BlockPos::new(0, 2, 0));
+    assert_eq!(hit.facing, Some(Facing::Down));
+  }
+
+  #[test]
+  fn starts_inside_block() {
+    let fixture = fixture(&[(0, 0, 0)]);
+    let hit = cast(&fixture, (0.5, 0.5, 0.5), (0.0, 0.0, 1.0), 10.0).unwrap();
+    assert_eq!(hit.pos, BlockPos::new(0, 0, 0));
+    assert_eq!(hit.facing, None);
+    assert_eq!(hit.distance, 0.0);
+  }
+
+  #[test]
+  fn misses_beyond_max_distance() {
+    let fixture = fixture(&[(0, 0, -10)]);
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (0.0, 0.0, -1.0), 9.0).is_none());
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (0.0, 0.0, -1.0), 10.0).is_some());
+  }
+
+  #[test]
+  fn ignores_missing_chunks() {
+    let fixture = fixture(&[]);
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (1.0, -1.0, 1.0), 100.0).is_none());
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (0.0, 0.0, 0.0), 100.0).is_none());
+  }
+
+  #[test]
+  fn rejects_non_finite_rays() {
+    let fixture = fixture(&[]);
+    let nan = f32::NAN;
+    let infinity = f32::INFINITY;
+    assert!(cast(&fixture, (nan, 0.5, 0.5), (1.0, 0.0, 0.0), 10.0).is_none());
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (infinity, 0.0, 0.0), 10.0).is_none());
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (1.0, 0.0, 0.0), nan).is_none());
+    assert!(cast(&fixture, (0.5, 0.5, 0.5), (1.0, 0.0, 0.0), infinity).is_none());
+  }
+
+  #[test]
+  fn visits_every_block_along_ray() {
+    let mut visited = vec![];
+    let origin = Point3::new(0.25, 0.5, 0.5);
+    let direction = Vector3::new(1.0, 0.5, 0.0);
+    raycast(origin, direction, 3.0, |pos| {
+      visited.push(pos);
+      false
+    });
+    // Each step only moves along a single axis, so no blocks are skipped.
+    for pair in visited.windows(2) {
+      let (a, b) = (pair[0], pair[1]);
+      let distance = (a.x - b.x).abs() + (a.y - b.y).abs() + (a.z - b.z).abs();
+      assert_eq!(distance, 1);
+    }
+    assert_eq!(visited.first(), Some(&BlockPos::new(0, 0, 0)));
+    assert_eq!(visited.last(), Some(&BlockPos::new(2, 1, 0)));
+  }
+}
@@ -0,0 +1,666 @@
+use {
+  super::{
+    chunk::{ChunkLayout, ChunkPos, DefaultLayout},
+    net::NetworkClient,
+    ActiveLevel, BlockAccess, BlockChange, BlockPos, BlockShape, BlockShapes, BlockTransparency,
+    BlockView, ChunkGenerated, Level,
+  },
+  amethyst::{
+    core::Time,
+    derive::SystemDesc,
+    ecs::prelude::*,
+    shrev::{EventChannel, ReaderId},
+  },
+  log::{info, warn},
+  rhai::{
+    module_resolvers::DummyModuleResolver, Dynamic, Engine, EvalAltResult, FuncArgs, ParseError,
+    Scope, AST, INT,
+  },
+  std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    error::Error,
+    fmt, fs, io, mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+  },
+};
+
+/// Directory scripts are loaded from, relative to the assets directory.
+pub const SCRIPTS_DIR: &str = "scripts";
+
+/// File extension of scripts. Scripts are named after their file, without the extension.
+pub const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Seconds between checking the watched scripts directory for changes.
+pub const SCRIPT_RELOAD_SECONDS: f32 = 1.0;
+
+/// Number of operations a script may perform per hook or when loaded, after which it's
+/// aborted, so a script stuck in a loop can't freeze the game.
+pub const MAX_SCRIPT_OPERATIONS: u64 = 1_000_000;
+
+/// Something that happened in the `ActiveLevel`, which is passed to every script
+/// defining a function of the same name as the event's hook.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptEvent {
+  /// Calls `on_block_changed(x, y, z, previous, value)`.
+  BlockChanged {
+    pos: BlockPos,
+    previous: u8,
+    value: u8,
+  },
+  /// Calls `on_chunk_generated(x, y, z)`, with the position in chunk coordinates.
+  ChunkGenerated(ChunkPos),
+  /// Calls `on_tick(tick)` whenever the level's block tick advances, see `Level::tick`.
+  Tick(u64),
+}
+
+impl ScriptEvent {
+  pub fn hook(&self) -> &'static str {
+    match self {
+      ScriptEvent::BlockChanged { .. } => "on_block_changed",
+      ScriptEvent::ChunkGenerated(_) => "on_chunk_generated",
+      ScriptEvent::Tick(_) => "on_tick",
+    }
+  }
+}
+
+/// Event sent through `EventChannel<ScriptChange>` whenever a script is loaded or removed,
+/// so the server can send it to its clients.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptChange {
+  pub name: String,
+  /// Source of the script, or `None` if it was removed.
+  pub source: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+  Io(io::Error),
+  Parse(ParseError),
+  /// The script failed while running its top level statements or one of its hooks.
+  Runtime(Box<EvalAltResult>),
+  /// There's no `Scripts` resource to load scripts into.
+  MissingResource,
+}
+
+impl Error for ScriptError {}
+
+impl fmt::Display for ScriptError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ScriptError::Io(err) => write!(f, "IO error: {}", err),
+      ScriptError::Parse(err) => write!(f, "Parse error: {}", err),
+      ScriptError::Runtime(err) => write!(f, "Runtime error: {}", err),
+      ScriptError::MissingResource => write!(f, "Scripting is not enabled"),
+    }
+  }
+}
+
+impl From<io::Error> for ScriptError {
+  fn from(err: io::Error) -> Self {
+    ScriptError::Io(err)
+  }
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+struct Script {
+  source: String,
+  ast: AST,
+}
+
+/// Handle through which the functions available to scripts access the `World`. The world is
+/// moved into it while scripts run, see `with_scripts`, as bindings have to be `'static`.
+#[derive(Clone, Default)]
+struct ScriptWorld(Arc<Mutex<Option<World>>>);
+
+impl ScriptWorld {
+  fn with<R>(&self, f: impl FnOnce(&mut World) -> R) -> ScriptResult<R> {
+    let mut world = self.0.lock().unwrap();
+    match world.as_mut() {
+      Some(world) => Ok(f(world)),
+      None => Err("The world can't be accessed right now".into()),
+    }
+  }
+}
+
+/// Resource holding the scripting engine and the scripts loaded into it. Scripts are written in
+/// [Rhai](https://rhai.rs) and sandboxed: They can't access files or other modules, and only
+/// interact with the game through the following functions, which act upon the `ActiveLevel`:
+///
+/// - `get_block(x, y, z)` returns the block at the specified position.
+/// - `set_block(x, y, z, block)` changes a block. Only possible when not connected to a server.
+/// - `is_client()` returns whether the game is connected to a server.
+/// - `block_shape(block)` returns the name of the shape the block is rendered with.
+/// - `set_block_shape(block, shape, transparency)` changes how a block is rendered, from then on.
+///   Shapes are `"empty"`, `"cube"`, `"slab"` or `"cross"`, transparencies are `"opaque"`,
+///   `"cutout"` or `"translucent"`.
+///
+/// Top level statements run whenever a script is loaded. Scripts can react to events by
+/// defining functions named after their hooks, see `ScriptEvent`.
+pub struct Scripts {
+  engine: Engine,
+  world: ScriptWorld,
+  scripts: BTreeMap<String, Script>,
+  /// Directory to load scripts from, along with when each file in it was last modified.
+  watched: Option<(PathBuf, HashMap<String, SystemTime>)>,
+  /// Changes not yet sent through `EventChannel<ScriptChange>`.
+  changes: Vec<ScriptChange>,
+}
+
+impl Default for Scripts {
+  fn default() -> Self {
+    Scripts::new::<DefaultLayout>()
+  }
+}
+
+impl Scripts {
+  pub fn new<L: ChunkLayout>() -> Self {
+    let mut engine = Engine::new();
+    engine
+      .set_module_resolver(DummyModuleResolver::new())
+      .disable_symbol("eval")
+      .set_max_operations(MAX_SCRIPT_OPERATIONS)
+      .set_max_call_levels(32)
+      .set_max_expr_depths(64, 32)
+      .set_max_string_size(1 << 16)
+      .set_max_array_size(1 << 16)
+      .set_max_map_size(1 << 12)
+      .on_print(|text| info!("Script: {}", text));
+
+    let world = ScriptWorld::default();
+    let w = world.clone();
+    engine.register_fn(
+      "get_block",
+      move |x: INT, y: INT, z: INT| -> ScriptResult<INT> {
+        let pos = script_block_pos(x, y, z)?;
+        w.with(|world| -> ScriptResult<INT> {
+          let level = script_level(world)?;
+          Ok(world.exec(|blocks: BlockView<'_, u8, L>| blocks.get(level, pos)) as INT)
+        })?
+      },
+    );
+    let w = world.clone();
+    engine.register_fn(
+      "set_block",
+      move |x: INT, y: INT, z: INT, block: INT| -> ScriptResult<()> {
+        let pos = script_block_pos(x, y, z)?;
+        let block = script_block(block)?;
+        w.with(|world| -> ScriptResult<()> {
+          if world.try_fetch::<NetworkClient>().is_some() {
+            return Err("Blocks can only be changed by the server".into());
+          }
+          let level = script_level(world)?;
+          world.exec(|mut blocks: BlockAccess<'_, u8, L>| blocks.set(level, pos, block));
+          Ok(())
+        })?
+      },
+    );
+    let w = world.clone();
+    engine.register_fn("is_client", move || -> ScriptResult<bool> {
+      w.with(|world| world.try_fetch::<NetworkClient>().is_some())
+    });
+    let w = world.clone();
+    engine.register_fn("block_shape", move |block: INT| -> ScriptResult<String> {
+      let block = script_block(block)?;
+      w.with(|world| {
+        let shapes = world.try_fetch::<BlockShapes>();
+        let name = match shapes.as_ref().map(|shapes| shapes.shape(block)) {
+          Some(BlockShape::Empty) => "empty",
+          Some(BlockShape::Cube) | None => "cube",
+          Some(BlockShape::Slab) => "slab",
+          Some(BlockShape::Stairs(_)) => "stairs",
+          Some(BlockShape::Cross) => "cross",
+          Some(BlockShape::Model(_)) => "model",
+        };
+        name.to_string()
+      })
+    });
+    let w = world.clone();
+    engine.register_fn(
+      "set_block_shape",
+      move |block: INT, shape: &str, transparency: &str| -> ScriptResult<()> {
+        let block = script_block(block)?;
+        let shape = match shape {
+          "empty" => BlockShape::Empty,
+          "cube" => BlockShape::Cube,
+          "slab" => BlockShape::Slab,
+          "cross" => BlockShape::Cross,
+          _ => return Err(format!("Unknown block shape '{}'", shape).into()),
+        };
+        let transparency = match transparency {
+          "opaque" => BlockTransparency::Opaque,
+          "cutout" => BlockTransparency::Cutout,
+          "translucent" => BlockTransparency::Translucent,
+          _ => return Err(format!("Unknown transparency '{}'", transparency).into()),
+        };
+        w.with(|world| {
+          let shapes = world.remove::<BlockShapes>().unwrap_or_default();
+          world.insert(shapes.with_shape(block, shape, transparency));
+        })
+      },
+    );
+
+    Scripts {
+      engine,
+      world,
+      scripts: BTreeMap::new(),
+      watched: None,
+      changes: Vec::new(),
+    }
+  }
+
+  /// Loads scripts from the specified directory, and reloads them whenever they change,
+  /// see `ScriptSystem`. Usually that's `SCRIPTS_DIR` inside the assets directory.
+  pub fn watch<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+    self.watched = Some((dir.into(), HashMap::new()));
+    self
+  }
+
+  /// Returns the names and sources of all loaded scripts.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    (self.scripts.iter()).map(|(name, script)| (name.as_str(), script.source.as_str()))
+  }
+
+  pub fn source(&self, name: &str) -> Option<&str> {
+    self.scripts.get(name).map(|script| script.source.as_str())
+  }
+
+  /// Compiles a script and runs its top level statements, replacing any script of the same name.
+  /// The world has to be accessible through `self.world`, see `with_scripts`.
+  fn load(&mut self, name: &str, source: String) -> Result<(), ScriptError> {
+    let ast = self.engine.compile(&source).map_err(ScriptError::Parse)?;
+    (self.engine.run_ast(&ast)).map_err(ScriptError::Runtime)?;
+    self.changes.push(ScriptChange {
+      name: name.to_string(),
+      source: Some(source.clone()),
+    });
+    self
+      .scripts
+      .insert(name.to_string(), Script { source, ast });
+    Ok(())
+  }
+
+  fn remove(&mut self, name: &str) -> bool {
+    let removed = self.scripts.remove(name).is_some();
+    if removed {
+      self.changes.push(ScriptChange {
+        name: name.to_string(),
+        source: None,
+      });
+    }
+    removed
+  }
+
+  /// Loads scripts in the watched directory which were added or modified since it was last
+  /// checked, and removes the ones whose files were deleted.
+  fn reload(&mut self) {
+    let (dir, mut modified) = match self.watched.take() {
+      Some(watched) => watched,
+      None => return,
+    };
+    match script_files(&dir) {
+      Ok(files) => {
+        for (name, _) in modified
+          .iter()
+          .filter(|(name, _)| !files.contains_key(*name))
+        {
+          info!("Removed script '{}'", name);
+          self.remove(name);
+        }
+        modified.retain(|name, _| files.contains_key(name));
+        for (name, (path, time)) in files {
+          if modified.get(&name) == Some(&time) {
+            continue;
+          }
+          // Scripts that fail to load aren't retried until they're modified again.
+          modified.insert(name.clone(), time);
+          match fs::read_to_string(&path).map_err(ScriptError::from) {
+            Ok(source) => match self.load(&name, source) {
+              Ok(()) => info!("Loaded script '{}'", name),
+              Err(err) => warn!("Couldn't load script '{}': {}", name, err),
+            },
+            Err(err) => warn!("Couldn't read script {:?}: {}", path, err),
+          }
+        }
+      }
+      Err(err) => warn!("Couldn't read scripts from {:?}: {}", dir, err),
+    }
+    self.watched = Some((dir, modified));
+  }
+
+  /// Calls the hook of each event in every script that defines it.
+  fn run_hooks(&self, events: &[ScriptEvent]) {
+    for event in events {
+      let hook = event.hook();
+      for (name, script) in &self.scripts {
+        if !script.ast.iter_functions().any(|f| f.name == hook) {
+          continue;
+        }
+        let result = match *event {
+          ScriptEvent::BlockChanged {
+            pos,
+            previous,
+            value,
+          } => self.call(
+            script,
+            hook,
+            (
+              pos.x as INT,
+              pos.y as INT,
+              pos.z as INT,
+              previous as INT,
+              value as INT,
+            ),
+          ),
+          ScriptEvent::ChunkGenerated(pos) => {
+            self.call(script, hook, (pos.x as INT, pos.y as INT, pos.z as INT))
+          }
+          ScriptEvent::Tick(tick) => self.call(script, hook, (tick as INT,)),
+        };
+        if let Err(err) = result {
+          warn!("Script '{}' failed in {}: {}", name, hook, err);
+        }
+      }
+    }
+  }
+
+  /// Calls a function of the script, ignoring whatever it returns.
+  fn call(&self, script: &Script, hook: &str, args: impl FuncArgs) -> ScriptResult<()> {
+    let mut scope = Scope::new();
+    (self.engine)
+      .call_fn::<Dynamic>(&mut scope, &script.ast, hook, args)
+      .map(|_| ())
+  }
+}
+
+/// Lists the scripts in a directory by name, along with their path and when they were modified.
+fn script_files(dir: &Path) -> io::Result<HashMap<String, (PathBuf, SystemTime)>> {
+  let mut files = HashMap::new();
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let path = entry.path();
+    if path.extension().and_then(|e| e.to_str()) != Some(SCRIPT_EXTENSION) {
+      continue;
+    }
+    if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+      let modified = entry.metadata()?.modified()?;
+      files.insert(name.to_string(), (path.clone(), modified));
+    }
+  }
+  Ok(files)
+}
+
+fn script_block_pos(x: INT, y: INT, z: INT) -> ScriptResult<BlockPos> {
+  match (i32::try_from(x), i32::try_from(y), i32::try_from(z)) {
+    (Ok(x), Ok(y), Ok(z)) => Ok(BlockPos::new(x, y, z)),
+    _ => Err(format!("Position ({}, {}, {}) is out of range", x, y, z).into()),
+  }
+}
+
+fn script_block(block: INT) -> ScriptResult<u8> {
+  u8::try_from(block).map_err(|_| format!("Invalid block {}", block).into())
+}
+
+fn script_level(world: &World) -> ScriptResult<Entity> {
+  (world.try_fetch::<ActiveLevel>())
+    .and_then(|active_level| active_level.0)
+    .ok_or_else(|| "There is no active level".into())
+}
+
+/// Takes the `Scripts` resource out of the world and moves the world into it, so the functions
+/// available to scripts can access it while `f` runs. Returns `None` if there's no `Scripts`.
+fn with_scripts<R>(world: &mut World, f: impl FnOnce(&mut Scripts) -> R) -> Option<R> {
+  let mut scripts = world.remove::<Scripts>()?;
+  *scripts.world.0.lock().unwrap() = Some(mem::replace(world, World::new()));
+  let result = f(&mut scripts);
+  *world = scripts.world.0.lock().unwrap().take().unwrap();
+
+  let changes = mem::take(&mut scripts.changes);
+  world.insert(scripts);
+  (world.entry::<EventChannel<ScriptChange>>())
+    .or_insert_with(Default::default)
+    .iter_write(changes);
+  Some(result)
+}
+
+/// Loads a script, replacing any script of the same name, such as one sent by the server.
+pub fn load_script(world: &mut World, name: &str, source: String) -> Result<(), ScriptError> {
+  with_scripts(world, |scripts| scripts.load(name, source))
+    .unwrap_or(Err(ScriptError::MissingResource))
+}
+
+/// Removes the script with the specified name, returning whether it was loaded.
+pub fn remove_script(world: &mut World, name: &str) -> bool {
+  with_scripts(world, |scripts| scripts.remove(name)).unwrap_or(false)
+}
+
+/// Loads scripts that were added or changed in the directory watched by `Scripts`.
+pub fn reload_scripts(world: &mut World) {
+  with_scripts(world, Scripts::reload);
+}
+
+/// Calls the hooks of the specified events in every script.
+pub fn run_script_hooks(world: &mut World, events: &[ScriptEvent]) {
+  with_scripts(world, |scripts| scripts.run_hooks(events));
+}
+
+/// Passes block changes, generated chunks and block ticks of the `ActiveLevel` to the scripts in
+/// the `Scripts` resource, and reloads scripts every `SCRIPT_RELOAD_SECONDS`. Scripts run at the
+/// end of the frame, so this system should run after any system changing blocks.
+#[derive(SystemDesc)]
+#[system_desc(name(ScriptSystemDesc))]
+pub struct ScriptSystem {
+  #[system_desc(event_channel_reader)]
+  block_reader: ReaderId<BlockChange>,
+  #[system_desc(event_channel_reader)]
+  chunk_reader: ReaderId<ChunkGenerated>,
+  /// Block tick of the `ActiveLevel` when this system last ran.
+  #[system_desc(skip)]
+  tick: Option<u64>,
+  /// Seconds since scripts were last reloaded.
+  #[system_desc(skip)]
+  since_reload: f32,
+}
+
+impl ScriptSystem {
+  pub fn new(block_reader: ReaderId<BlockChange>, chunk_reader: ReaderId<ChunkGenerated>) -> Self {
+    Self {
+      block_reader,
+      chunk_reader,
+      tick: None,
+      since_reload: SCRIPT_RELOAD_SECONDS,
+    }
+  }
+}
+
+impl<'a> System<'a> for ScriptSystem {
+  type SystemData = (
+    Read<'a, Time>,
+    Read<'a, LazyUpdate>,
+    Option<Read<'a, Scripts>>,
+    Read<'a, ActiveLevel>,
+    ReadStorage<'a, Level>,
+    Read<'a, EventChannel<BlockChange>>,
+    Read<'a, EventChannel<ChunkGenerated>>,
+  );
+
+  fn run(
+    &mut self,
+    (time, lazy, scripts, active_level, levels, block_changes, generated): Self::SystemData,
+  ) {
+    let level = active_level.0;
+    let mut events = (block_changes.read(&mut self.block_reader))
+      .filter(|change| Some(change.level) == level)
+      .map(|change| ScriptEvent::BlockChanged {
+        pos: change.pos,
+        previous: change.previous,
+        value: change.value,
+      })
+      .collect::<Vec<_>>();
+    events.extend(
+      (generated.read(&mut self.chunk_reader))
+        .filter(|chunk| Some(chunk.level) == level)
+        .map(|chunk| ScriptEvent::ChunkGenerated(chunk.pos)),
+    );
+    let tick = level
+      .and_then(|level| levels.get(level))
+      .map(|level| level.tick);
+    if let (Some(tick), Some(previous)) = (tick, self.tick) {
+      if tick != previous {
+        events.push(ScriptEvent::Tick(tick));
+      }
+    }
+    self.tick = tick;
+
+    if scripts.is_none() {
+      return;
+    }
+    self.since_reload += time.delta_seconds();
+    let reload = self.since_reload >= SCRIPT_RELOAD_SECONDS;
+    if reload {
+      self.since_reload = 0.0;
+    }
+    if reload || !events.is_empty() {
+      // Scripts may access anything in the `World` through their bindings.
+      lazy.exec_mut(move |world| {
+        if reload {
+          reload_scripts(world);
+        }
+        run_script_hooks(world, &events);
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::{chunk::LinearLayout16, create_level},
+  };
+
+  type Blocks<'a> = BlockAccess<'a, u8, LinearLayout16>;
+
+  fn world() -> (World, Entity) {
+    let mut world = World::new();
+    Blocks::setup(&mut world);
+    world.register::<Level>();
+    let level = create_level(world.create_entity(), Level::new(1234));
+    world.insert(ActiveLevel(Some(level)));
+    world.insert(Scripts::new::<LinearLayout16>());
+    (world, level)
+  }
+
+  #[test]
+  fn hooks_access_blocks() {
+    let (mut world, level) = world();
+    let source = "
+      fn on_block_changed(x, y, z, previous, value) {
+        if value == 1 && get_block(x, y + 1, z) == 0 {
+          set_block(x, y + 1, z, 2);
+        }
+      }
+    ";
+    load_script(&mut world, "grass", source.to_string()).unwrap();
+    let pos = BlockPos::new(3, -4, 5);
+    world.exec(|mut blocks: Blocks<'_>| blocks.set(level, pos, 1));
+    let event = ScriptEvent::BlockChanged {
+      pos,
+      previous: 0,
+      value: 1,
+    };
+    run_script_hooks(&mut world, &[event]);
+    let above = world.exec(|blocks: Blocks<'_>| blocks.get(level, BlockPos::new(3, -3, 5)));
+    assert_eq!(above, 2);
+
+    // Hooks scripts don't define are skipped.
+    run_script_hooks(&mut world, &[ScriptEvent::Tick(1)]);
+  }
+
+  #[test]
+  fn scripts_are_sandboxed() {
+    let (mut world, _) = world();
+    assert!(matches!(
+      load_script(&mut world, "eval", "eval(\"1\")".to_string()),
+      Err(ScriptError::Parse(_))
+    ));
+    assert!(matches!(
+      load_script(
+        &mut world,
+        "import",
+        "import \"other\" as other;".to_string()
+      ),
+      Err(ScriptError::Runtime(_))
+    ));
+    assert!(matches!(
+      load_script(&mut world, "loop", "loop {}".to_string()),
+      Err(ScriptError::Runtime(_))
+    ));
+    assert!(matches!(
+      load_script(&mut world, "block", "set_block(0, 0, 0, 256);".to_string()),
+      Err(ScriptError::Runtime(_))
+    ));
+    assert_eq!(world.read_resource::<Scripts>().iter().count(), 0);
+  }
+
+  #[test]
+  fn scripts_register_block_shapes() {
+    let (mut world, _) = world();
+    let source = "
+      if block_shape(10) == \"cube\" {
+        set_block_shape(10, \"cross\", \"cutout\");
+      }
+    ";
+    load_script(&mut world, "plants", source.to_string()).unwrap();
+    let shapes = world.read_resource::<BlockShapes>();
+    assert_eq!(shapes.shape(10), &BlockShape::Cross);
+    assert_eq!(shapes.transparency(10), BlockTransparency::Cutout);
+  }
+
+  #[test]
+  fn watched_scripts_are_reloaded() {
+    let (mut world, _) = world();
+    let mut reader = (world.entry::<EventChannel<ScriptChange>>())
+      .or_insert_with(Default::default)
+      .register_reader();
+    let dir = std::env::temp_dir().join(format!("gaemstone-scripts-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let scripts = world.remove::<Scripts>().unwrap().watch(&dir);
+    world.insert(scripts);
+
+    fs::write(dir.join("test.rhai"), "fn on_tick(tick) {}").unwrap();
+    fs::write(dir.join("notes.txt"), "Not a script").unwrap();
+    reload_scripts(&mut world);
+    assert_eq!(
+      world.read_resource::<Scripts>().source("test"),
+      Some("fn on_tick(tick) {}")
+    );
+    // Unchanged scripts aren't loaded again.
+    reload_scripts(&mut world);
+
+    fs::remove_file(dir.join("test.rhai")).unwrap();
+    reload_scripts(&mut world);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(world.read_resource::<Scripts>().iter().count(), 0);
+    let changes = (world.read_resource::<EventChannel<ScriptChange>>())
+      .read(&mut reader)
+      .cloned()
+      .collect::<Vec<_>>();
+    assert_eq!(
+      changes,
+      vec![
+        ScriptChange {
+          name: "test".to_string(),
+          source: Some("fn on_tick(tick) {}".to_string()),
+        },
+        ScriptChange {
+          name: "test".to_string(),
+          source: None,
+        },
+      ]
+    );
+  }
+}
@@ -0,0 +1,625 @@
+use {
+  super::{
+    chunk::{
+      storage::ChunkStorage, ChunkLayout, ChunkLookup, ChunkPos, ChunkState, DefaultLayout, Index,
+    },
+    is_solid, BlockPos, GRAVITY, OCTREE_DEPTH,
+  },
+  crate::util::ChunkedOctree,
+  amethyst::{
+    core::{
+      math::{Matrix3, Point3, UnitQuaternion, Vector3},
+      transform::Transform,
+      Time,
+    },
+    ecs::prelude::*,
+  },
+  std::marker::PhantomData,
+};
+
+/// Longest time step simulated at once, so physics doesn't explode when frames take too long.
+const MAX_TIME_STEP: f32 = 1.0 / 20.0;
+/// Fraction of penetration into other blocks which is corrected each step.
+const POSITION_CORRECTION: f32 = 0.8;
+/// Blocks overlapping less than this are considered to merely touch each other.
+const CONTACT_EPSILON: f32 = 1.0e-3;
+
+/// Component for entities made up of voxels which move around freely, such as vehicles and
+/// ships. Like levels, voxel bodies hold the `ChunkLookup` and `ChunkedOctree<ChunkState>` of
+/// their own chunks, whose positions are relative to the body's `Transform`. Chunks of a body
+/// are meshed just like terrain and rendered as children of the body.
+pub struct VoxelBody {
+  /// The level this body is in, whose terrain it collides with.
+  pub level: Entity,
+  pub velocity: Vector3<f32>,
+  /// Angular velocity in world space, as axis scaled by radians per second.
+  pub angular_velocity: Vector3<f32>,
+  /// Multiplier for how strongly this body is affected by gravity.
+  pub gravity_scale: f32,
+  /// How much of its speed a body keeps when bouncing off of something, from `0.0` to `1.0`.
+  pub restitution: f32,
+  pub friction: f32,
+
+  mass: MassProperties,
+  /// Local positions of all solid blocks making up this body.
+  blocks: Vec<BlockPos>,
+  dirty: bool,
+}
+
+impl Component for VoxelBody {
+  type Storage = HashMapStorage<Self>;
+}
+
+impl VoxelBody {
+  pub fn new(level: Entity) -> Self {
+    VoxelBody {
+      level,
+      velocity: Vector3::zeros(),
+      angular_velocity: Vector3::zeros(),
+      gravity_scale: 1.0,
+      restitution: 0.2,
+      friction: 0.5,
+      mass: Default::default(),
+      blocks: Vec::new(),
+      dirty: true,
+    }
+  }
+
+  pub fn mass_properties(&self) -> &MassProperties {
+    &self.mass
+  }
+
+  /// Causes the mass properties and collision shape to be recalculated
+  /// from this body's blocks. Done by `BlockAccess` when blocks change.
+  pub fn mark_dirty(&mut self) {
+    self.dirty = true;
+  }
+}
+
+/// Creates a new, empty voxel body in the specified level. Blocks
+/// can then be added to it using `BlockAccess` like with any level.
+pub fn create_voxel_body<B: Builder>(builder: B, level: Entity, transform: Transform) -> Entity {
+  builder
+    .with(VoxelBody::new(level))
+    .with(ChunkLookup::default())
+    .with(ChunkedOctree::<ChunkState>::new(OCTREE_DEPTH))
+    .with(transform)
+    .build()
+}
+
+/// Mass, center of mass and inertia of a rigid body, in its local space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MassProperties {
+  pub mass: f32,
+  pub center_of_mass: Point3<f32>,
+  /// Inertia tensor relative to the center of mass.
+  pub inertia: Matrix3<f32>,
+  /// Distance from the center of mass to the furthest corner of any block.
+  pub radius: f32,
+}
+
+impl Default for MassProperties {
+  fn default() -> Self {
+    MassProperties {
+      mass: 0.0,
+      center_of_mass: Point3::origin(),
+      inertia: Matrix3::zeros(),
+      radius: 0.0,
+    }
+  }
+}
+
+impl MassProperties {
+  /// Calculates the mass properties of a body made up of unit cubes at the specified
+  /// positions, each with the specified mass.
+  pub fn from_blocks<I>(blocks: I) -> Self
+  where
+    I: IntoIterator<Item = (BlockPos, f32)> + Clone,
+  {
+    let mut mass = 0.0;
+    let mut moment = Vector3::zeros();
+    for (pos, block_mass) in blocks.clone() {
+      mass += block_mass;
+      moment += block_center(pos).coords * block_mass;
+    }
+    if mass <= 0.0 {
+      return Default::default();
+    }
+    let center_of_mass = Point3::from(moment / mass);
+
+    let mut inertia = Matrix3::zeros();
+    let mut radius: f32 = 0.0;
+    for (pos, block_mass) in blocks {
+      let r = block_center(pos) - center_of_mass;
+      // Parallel axis theorem, plus the inertia of a unit cube around its own center.
+      inertia += (Matrix3::identity() * r.norm_squared() - r * r.transpose()) * block_mass;
+      inertia += Matrix3::identity() * (block_mass / 6.0);
+      radius = radius.max(r.abs().add_scalar(0.5).norm());
+    }
+
+    MassProperties {
+      mass,
+      center_of_mass,
+      inertia,
+      radius,
+    }
+  }
+}
+
+fn block_center(pos: BlockPos) -> Point3<f32> {
+  Point3::new(pos.x as f32 + 0.5, pos.y as f32 + 0.5, pos.z as f32 + 0.5)
+}
+
+fn is_solid_at<L: ChunkLayout>(
+  lookup: &ChunkLookup,
+  storages: &ReadStorage<'_, ChunkStorage<u8, L>>,
+  pos: BlockPos,
+) -> bool {
+  let (chunk_pos, index): (ChunkPos, Index<L>) = pos.into();
+  lookup
+    .get(chunk_pos)
+    .and_then(|entity| storages.get(entity))
+    .map_or(false, |storage| is_solid(storage.get(index)))
+}
+
+/// State of a body during a single physics step, in world space.
+struct BodyState {
+  entity: Entity,
+  velocity: Vector3<f32>,
+  angular_velocity: Vector3<f32>,
+  restitution: f32,
+  friction: f32,
+  inverse_mass: f32,
+  inverse_inertia: Matrix3<f32>,
+  center: Point3<f32>,
+  rotation: UnitQuaternion<f32>,
+  /// Offset to move the body by to no longer be stuck inside of anything.
+  correction: Vector3<f32>,
+}
+
+impl BodyState {
+  /// Velocity of the body at the specified point.
+  fn velocity_at(&self, point: Point3<f32>) -> Vector3<f32> {
+    self.velocity + self.angular_velocity.cross(&(point - self.center))
+  }
+
+  /// Resistance of the body against an impulse at the specified point in the specified direction.
+  fn inverse_mass_at(&self, point: Point3<f32>, direction: &Vector3<f32>) -> f32 {
+    let r = point - self.center;
+    self.inverse_mass
+      + (self.inverse_inertia * r.cross(direction))
+        .cross(&r)
+        .dot(direction)
+  }
+
+  fn apply_impulse(&mut self, point: Point3<f32>, impulse: Vector3<f32>) {
+    self.velocity += impulse * self.inverse_mass;
+    self.angular_velocity += self.inverse_inertia * (point - self.center).cross(&impulse);
+  }
+
+  /// Keeps only the deepest correction along each axis, since many blocks overlap at once.
+  fn apply_correction(&mut self, correction: Vector3<f32>) {
+    for axis in 0..3 {
+      if correction[axis].abs() > self.correction[axis].abs() {
+        self.correction[axis] = correction[axis];
+      }
+    }
+  }
+}
+
+/// Point where a block of a body touches a solid block of something else.
+struct Contact {
+  point: Point3<f32>,
+  /// Direction pointing away from the other object, towards the body.
+  normal: Vector3<f32>,
+  depth: f32,
+}
+
+/// Finds the unit cubes of the axis-aligned grid (in the other object's local space) that overlap
+/// a block of a body centered at `point`, along with the direction and depth to push the block
+/// out of each. Both blocks are treated as axis-aligned in that space, which is a decent
+/// approximation at small angles.
+fn grid_overlaps(point: Point3<f32>) -> impl Iterator<Item = (BlockPos, Vector3<f32>, f32)> {
+  let min = (point.coords - Vector3::repeat(0.5)).map(|v| v.floor() as i32);
+  let max = (point.coords + Vector3::repeat(0.5)).map(|v| v.ceil() as i32 - 1);
+  (min.x..=max.x)
+    .flat_map(move |x| (min.y..=max.y).map(move |y| (x, y)))
+    .flat_map(move |(x, y)| (min.z..=max.z).map(move |z| BlockPos::new(x, y, z)))
+    .filter_map(move |block| {
+      let diff = point - block_center(block);
+      let axis = diff.iamax();
+      let depth = 1.0 - diff[axis].abs();
+      if depth > CONTACT_EPSILON {
+        let mut normal = Vector3::zeros();
+        normal[axis] = diff[axis].signum();
+        Some((block, normal, depth))
+      } else {
+        None
+      }
+    })
+}
+
+/// Finds contacts between the blocks of `body` and the solid blocks of `lookup`. The
+/// latter's local space is defined by `center` and `rotation` of its `local_center`.
+fn find_contacts<L: ChunkLayout>(
+  body: &VoxelBody,
+  state: &BodyState,
+  lookup: &ChunkLookup,
+  storages: &ReadStorage<'_, ChunkStorage<u8, L>>,
+  (local_center, center, rotation): (Point3<f32>, Point3<f32>, UnitQuaternion<f32>),
+) -> Vec<Contact> {
+  let inverse = rotation.inverse();
+  let mut contacts = Vec::new();
+  for pos in &body.blocks {
+    let point = state.center + state.rotation * (block_center(*pos) - body.mass.center_of_mass);
+    let local = local_center + inverse * (point - center);
+    for (block, normal, depth) in grid_overlaps(local) {
+      if is_solid_at(lookup, storages, block) {
+        let normal = rotation * normal;
+        contacts.push(Contact {
+          // Contacts are placed on the face of the body's block touching the other block.
+          point: point - normal * 0.5,
+          normal,
+          depth,
+        });
+      }
+    }
+  }
+  contacts
+}
+
+/// Applies an impulse to resolve a contact between a body and either the
+/// static terrain of its level (`other` is `None`) or another body.
+fn resolve(contact: &Contact, state: &mut BodyState, mut other: Option<&mut BodyState>) {
+  let (n, point) = (contact.normal, contact.point);
+  let mut relative = state.velocity_at(point);
+  let mut restitution = state.restitution;
+  let mut friction = state.friction;
+  if let Some(other) = &other {
+    relative -= other.velocity_at(point);
+    restitution = restitution.max(other.restitution);
+    friction = (friction * other.friction).sqrt();
+  }
+  let effective_mass = |state: &BodyState, other: &Option<&mut BodyState>, direction| {
+    let other_mass = other
+      .as_ref()
+      .map_or(0.0, |o| o.inverse_mass_at(point, direction));
+    1.0 / (state.inverse_mass_at(point, direction) + other_mass)
+  };
+
+  let normal_speed = relative.dot(&n);
+  if normal_speed < 0.0 {
+    let normal_impulse = -(1.0 + restitution) * normal_speed * effective_mass(state, &other, &n);
+    let mut impulse = n * normal_impulse;
+    if let Some(tangent) = (relative - n * normal_speed).try_normalize(std::f32::EPSILON) {
+      let tangent_impulse = -relative.dot(&tangent) * effective_mass(state, &other, &tangent);
+      let limit = friction * normal_impulse;
+      impulse += tangent * tangent_impulse.max(-limit).min(limit);
+    }
+
+    state.apply_impulse(point, impulse);
+    if let Some(other) = &mut other {
+      other.apply_impulse(point, -impulse);
+    }
+  }
+
+  // Split the correction between both bodies according to their mass.
+  let share = match &other {
+    Some(other) => state.inverse_mass / (state.inverse_mass + other.inverse_mass),
+    None => 1.0,
+  };
+  let correction = n * contact.depth;
+  state.apply_correction(correction * share);
+  if let Some(other) = other {
+    other.apply_correction(-correction * (1.0 - share));
+  }
+}
+
+/// Simulates `VoxelBody` entities: Applies gravity, integrates their movement and resolves
+/// collisions against the terrain of their level and against each other.
+pub struct VoxelBodySystem<L: ChunkLayout = DefaultLayout>(PhantomData<L>);
+
+impl<L: ChunkLayout> Default for VoxelBodySystem<L> {
+  fn default() -> Self {
+    VoxelBodySystem(PhantomData)
+  }
+}
+
+impl<'a, L: ChunkLayout> System<'a> for VoxelBodySystem<L> {
+  type SystemData = (
+    Entities<'a>,
+    Read<'a, Time>,
+    ReadStorage<'a, ChunkLookup>,
+    ReadStorage<'a, ChunkStorage<u8, L>>,
+    WriteStorage<'a, VoxelBody>,
+    WriteStorage<'a, Transform>,
+  );
+
+  fn run(
+    &mut self,
+    (entities, time, lookups, storages, mut bodies, mut transforms): Self::SystemData,
+  ) {
+    let delta = time.delta_seconds().min(MAX_TIME_STEP);
+
+    // Update mass properties of bodies whose blocks have changed.
+    for (body, lookup) in (&mut bodies, &lookups).join() {
+      if !body.dirty {
+        continue;
+      }
+      body.blocks.clear();
+      for (chunk_pos, entity) in lookup.iter() {
+        if let Some(storage) = storages.get(entity) {
+          for raw in 0..L::SIZE as u32 {
+            let index = Index::<L>::from_raw(raw).unwrap();
+            if is_solid(storage.get(index)) {
+              body.blocks.push((chunk_pos, index).into());
+            }
+          }
+        }
+      }
+      body.mass = MassProperties::from_blocks(body.blocks.iter().map(|pos| (*pos, 1.0)));
+      body.dirty = false;
+    }
+
+    // Integrate velocities and positions, gathering each body's state for handling collisions.
+    let mut states = Vec::new();
+    for (entity, body, transform) in (&entities, &bodies, &mut transforms).join() {
+      if body.mass.mass <= 0.0 {
+        continue;
+      }
+      let mut velocity = body.velocity;
+      velocity.y -= GRAVITY * body.gravity_scale * delta;
+
+      let local_center = body.mass.center_of_mass.coords;
+      let center = transform.translation() + transform.rotation() * local_center;
+      let center = center + velocity * delta;
+      let rotation =
+        UnitQuaternion::from_scaled_axis(body.angular_velocity * delta) * *transform.rotation();
+      *transform.rotation_mut() = rotation;
+      *transform.translation_mut() = center - rotation * local_center;
+
+      let matrix = *rotation.to_rotation_matrix().matrix();
+      let inverse_inertia = body
+        .mass
+        .inertia
+        .try_inverse()
+        .unwrap_or_else(Matrix3::zeros);
+      states.push(BodyState {
+        entity,
+        velocity,
+        angular_velocity: body.angular_velocity,
+        restitution: body.restitution,
+        friction: body.friction,
+        inverse_mass: 1.0 / body.mass.mass,
+        inverse_inertia: matrix * inverse_inertia * matrix.transpose(),
+        center: Point3::from(center),
+        rotation,
+        correction: Vector3::zeros(),
+      });
+    }
+
+    // Collisions against the terrain of the level each body is in, which isn't transformed.
+    for state in &mut states {
+      let body = bodies.get(state.entity).unwrap();
+      if let Some(lookup) = lookups.get(body.level) {
+        let terrain = (
+          Point3::origin(),
+          Point3::origin(),
+          UnitQuaternion::identity(),
+        );
+        for contact in find_contacts(body, state, lookup, &storages, terrain) {
+          resolve(&contact, state, None);
+        }
+      }
+    }
+
+    // Collisions between bodies, skipping pairs whose bounding spheres don't overlap. Blocks of
+    // both bodies are unit cubes, so overlaps are found the same from either side. Only the
+    // blocks of the body with fewer of them are checked against the grid of the other one.
+    for j in 1..states.len() {
+      let (head, tail) = states.split_at_mut(j);
+      let b = &mut tail[0];
+      for a in head {
+        let (body_a, body_b) = (bodies.get(a.entity).unwrap(), bodies.get(b.entity).unwrap());
+        if (a.center - b.center).norm() > body_a.mass.radius + body_b.mass.radius {
+          continue;
+        }
+        let a_is_smaller = body_a.blocks.len() <= body_b.blocks.len();
+        let (small, large, body_small, body_large) = if a_is_smaller {
+          (&mut *a, &mut *b, body_a, body_b)
+        } else {
+          (&mut *b, &mut *a, body_b, body_a)
+        };
+        // Without chunks of its own, there's nothing of the larger body to collide with.
+        let lookup = match lookups.get(large.entity) {
+          Some(lookup) => lookup,
+          None => continue,
+        };
+        let space = (body_large.mass.center_of_mass, large.center, large.rotation);
+        for contact in find_contacts(body_small, small, lookup, &storages, space) {
+          resolve(&contact, small, Some(large));
+        }
+      }
+    }
+
+    // Write back the results and push bodies out of whatever they ended up inside of.
+    for state in states {
+      let body = bodies.get_mut(state.entity).unwrap();
+      body.velocity = state.velocity;
+      body.angular_velocity = state.angular_velocity;
+      let transform = transforms.get_mut(state.entity).unwrap();
+      *transform.translation_mut() += state.correction * POSITION_CORRECTION;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::bloxel::chunk::{storage::PaletteStorageImpl, LinearLayout16},
+  };
+
+  fn assert_near(a: f32, b: f32) {
+    assert!((a - b).abs() < 1.0e-5, "{} != {}", a, b);
+  }
+
+  #[test]
+  fn single_block_mass_properties() {
+    let mass = MassProperties::from_blocks(vec![(BlockPos::new(2, 0, -1), 3.0)]);
+    assert_near(mass.mass, 3.0);
+    assert_eq!(mass.center_of_mass, Point3::new(2.5, 0.5, -0.5));
+    // A solid cube with side length `1` has an inertia of `m / 6` around each axis.
+    assert_eq!(mass.inertia, Matrix3::identity() * 0.5);
+    assert_near(mass.radius, 0.75f32.sqrt());
+  }
+
+  #[test]
+  fn row_of_blocks_mass_properties() {
+    let blocks = (0..4)
+      .map(|x| (BlockPos::new(x, 0, 0), 1.0))
+      .collect::<Vec<_>>();
+    let mass = MassProperties::from_blocks(blocks);
+    assert_near(mass.mass, 4.0);
+    assert_eq!(mass.center_of_mass, Point3::new(2.0, 0.5, 0.5));
+    // Same as a solid 4×1×1 box: `m / 12 * (a² + b²)` around each axis.
+    assert_near(mass.inertia[(0, 0)], 4.0 / 12.0 * 2.0);
+    assert_near(mass.inertia[(1, 1)], 4.0 / 12.0 * 17.0);
+    assert_near(mass.inertia[(2, 2)], 4.0 / 12.0 * 17.0);
+    assert_near(mass.inertia[(0, 1)], 0.0);
+  }
+
+  #[test]
+  fn empty_body_has_no_mass() {
+    let mass = MassProperties::from_blocks(Vec::new());
+    assert_eq!(mass, MassProperties::default());
+  }
+
+  #[test]
+  fn grid_overlaps_push_out_along_shallowest_axis() {
+    let overlaps = grid_overlaps(Point3::new(3.5, 1.4, -1.5)).collect::<Vec<_>>();
+    assert_eq!(overlaps.len(), 2);
+    let (block, normal, depth) = overlaps[0];
+    assert_eq!(block, BlockPos::new(3, 0, -2));
+    assert_eq!(normal, Vector3::new(0.0, 1.0, 0.0));
+    assert_near(depth, 0.1);
+    let (block, normal, depth) = overlaps[1];
+    assert_eq!(block, BlockPos::new(3, 1, -2));
+    assert_eq!(normal, Vector3::new(0.0, -1.0, 0.0));
+    assert_near(depth, 0.9);
+  }
+
+  #[test]
+  fn grid_overlaps_ignore_touching_blocks() {
+    assert_eq!(grid_overlaps(Point3::new(0.5, 1.5, 0.5)).count(), 1);
+  }
+
+  /// Creates a body out of a single chunk containing the specified blocks.
+  fn create_body(
+    world: &mut World,
+    level: Entity,
+    blocks: &[BlockPos],
+    at: Vector3<f32>,
+  ) -> Entity {
+    let mut storage = ChunkStorage::<u8, LinearLayout16>::new(PaletteStorageImpl::new());
+    for pos in blocks {
+      let (_, index): (ChunkPos, Index<LinearLayout16>) = (*pos).into();
+      storage.set(index, 1);
+    }
+    let chunk = world.create_entity().with(storage).build();
+    let mut lookup = ChunkLookup::default();
+    lookup.insert(ChunkPos::new(0, 0, 0), chunk);
+    let mut body = VoxelBody::new(level);
+    body.gravity_scale = 0.0;
+    world
+      .create_entity()
+      .with(body)
+      .with(lookup)
+      .with(Transform::from(at))
+      .build()
+  }
+
+  #[test]
+  fn bodies_without_chunks_are_skipped() {
+    let mut world = World::new();
+    let mut system = VoxelBodySystem::<LinearLayout16>::default();
+    System::setup(&mut system, &mut world);
+    let level = world.create_entity().build();
+    let mut body = VoxelBody::new(level);
+    body.mass = MassProperties::from_blocks(vec![(BlockPos::new(0, 0, 0), 1.0)]);
+    body.blocks = vec![BlockPos::new(0, 0, 0)];
+    body.dirty = false;
+    body.velocity = Vector3::new(1.0, 0.0, 0.0);
+    let mut other = VoxelBody::new(level);
+    other.mass = body.mass.clone();
+    other.blocks = body.blocks.clone();
+    other.dirty = false;
+    other.velocity = Vector3::new(-1.0, 0.0, 0.0);
+    let body = world
+      .create_entity()
+      .with(body)
+      .with(ChunkLookup::default())
+      .with(Transform::default())
+      .build();
+    let other = world
+      .create_entity()
+      .with(other)
+      .with(Transform::default())
+      .build();
+    system.run_now(&world);
+
+    // Both bodies overlap, but neither has any chunks to collide with.
+    let bodies = world.read_storage::<VoxelBody>();
+    let transforms = world.read_storage::<Transform>();
+    for &(entity, velocity) in &[(body, 1.0), (other, -1.0)] {
+      assert_eq!(
+        bodies.get(entity).unwrap().velocity,
+        Vector3::new(velocity, 0.0, 0.0)
+      );
+      assert_eq!(
+        bodies.get(entity).unwrap().angular_velocity,
+        Vector3::zeros()
+      );
+      assert_eq!(
+        *transforms.get(entity).unwrap().translation(),
+        Vector3::zeros()
+      );
+    }
+  }
+
+  #[test]
+  fn small_body_bounces_off_large_body() {
+    let mut world = World::new();
+    let mut system = VoxelBodySystem::<LinearLayout16>::default();
+    System::setup(&mut system, &mut world);
+    let level = world.create_entity().build();
+    // A single block falling onto the middle of a 4×4×4 cube, slightly sunk into its top.
+    let small = create_body(
+      &mut world,
+      level,
+      &[BlockPos::new(0, 0, 0)],
+      Vector3::new(1.5, 3.9, 1.5),
+    );
+    let cube = (0..64)
+      .map(|i| BlockPos::new(i % 4, i / 4 % 4, i / 16))
+      .collect::<Vec<_>>();
+    let large = create_body(&mut world, level, &cube, Vector3::zeros());
+    world
+      .write_storage::<VoxelBody>()
+      .get_mut(small)
+      .unwrap()
+      .velocity = Vector3::new(0.0, -2.0, 0.0);
+    system.run_now(&world);
+
+    let bodies = world.read_storage::<VoxelBody>();
+    let small_velocity = bodies.get(small).unwrap().velocity;
+    let large_velocity = bodies.get(large).unwrap().velocity;
+    assert!(small_velocity.y > 0.0, "{:?}", small_velocity);
+    assert!(large_velocity.y < 0.0, "{:?}", large_velocity);
+    // The impulse is shared between both bodies, so their momentum is unchanged.
+    assert_near(small_velocity.y + large_velocity.y * 64.0, -2.0);
+    let transforms = world.read_storage::<Transform>();
+    assert!(transforms.get(small).unwrap().translation().y > 3.9);
+    assert!(transforms.get(large).unwrap().translation().y < 0.0);
+  }
+}
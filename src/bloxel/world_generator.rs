@@ -1,115 +1,248 @@
 use {
-  super::chunk::{storage::*, *},
+  super::{
+    chunk::{storage::*, *},
+    chunk_loader_chunks, nearest_full_detail,
+    net::NetworkClient,
+    BlockLight, BlockPos, ChunkLoader, Level, SkyLight,
+  },
   crate::util::{ChunkedOctree, ZOrder},
   amethyst::{
     core::{math::Vector3, transform::Transform},
     ecs::prelude::*,
     renderer::visibility::BoundingSphere,
+    shrev::EventChannel,
   },
-  noise::{NoiseFn, OpenSimplex},
+  noise::{NoiseFn, OpenSimplex, Seedable},
+  serde::{Deserialize, Serialize},
+  std::marker::PhantomData,
 };
 
-#[derive(Default)]
-pub struct WorldGenerator;
+/// Distance in blocks around the origin in which chunks are generated and shown at full detail.
+/// Beyond that, terrain is shown at a lower level of detail, see `LodMeshGenerator`.
+pub const VIEW_DISTANCE: f32 = 8.5 * 16.0;
+
+/// Per-level settings for how `WorldGenerator` shapes terrain.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GeneratorConfig {
+  /// Size of terrain features, in blocks.
+  pub scale: f64,
+  /// Height (in units of `scale`) over which terrain fades into air. Below `y = 0`, the
+  /// terrain is as dense as it gets, and at `fade_height * 2` it's completely gone.
+  pub fade_height: f64,
+}
+
+impl Default for GeneratorConfig {
+  fn default() -> Self {
+    GeneratorConfig {
+      scale: 16.0,
+      fade_height: 4.0,
+    }
+  }
+}
+
+impl GeneratorConfig {
+  /// Generates the terrain of the chunk at the specified position.
+  pub fn generate<L: ChunkLayout>(&self, seed: u32, pos: ChunkPos) -> PaletteStorageImpl<u8, L> {
+    let origin = BlockPos::from((pos, Index::<L>::new(0, 0, 0).unwrap()));
+    self.generate_lod::<L>(seed, 0, origin)
+  }
+
+  /// Generates a coarse version of the terrain of the `2^lod` by `2^lod` by `2^lod` chunks
+  /// starting at `origin`, where each entry of the storage stands for a cube of `2^lod` blocks
+  /// on each side. The terrain is sampled at the center of each of these cubes, so at `lod`
+  /// level `0` this is the same as `generate`.
+  pub fn generate_lod<L: ChunkLayout>(
+    &self,
+    seed: u32,
+    lod: u8,
+    origin: BlockPos,
+  ) -> PaletteStorageImpl<u8, L> {
+    let noise = OpenSimplex::new().set_seed(seed);
+    let cell_size = (1 << lod) as f64;
+    let mut storage = PaletteStorageImpl::<u8, L>::new();
+    for x in 0..L::LENGTH as i32 {
+      for y in 0..L::LENGTH as i32 {
+        for z in 0..L::LENGTH as i32 {
+          let fx = (origin.x as f64 + (x as f64 + 0.5) * cell_size) / self.scale;
+          let fy = (origin.y as f64 + (y as f64 + 0.5) * cell_size) / self.scale;
+          let fz = (origin.z as f64 + (z as f64 + 0.5) * cell_size) / self.scale;
+          let bias = (fy / self.fade_height).max(0.0).min(2.0);
+          if noise.get([fx, fy, fz]) > bias {
+            // SAFETY: Bounds should be safe due to loop only going over valid values.
+            let index = unsafe { Index::new_unchecked(x, y, z) };
+            storage.set(index, 1u8);
+          }
+        }
+      }
+    }
+    storage
+  }
+}
+
+/// Event sent through `EventChannel<ChunkGenerated>` whenever `WorldGenerator` has generated the
+/// terrain of a chunk. Its storage is only inserted at the end of the frame it was generated in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkGenerated {
+  pub level: Entity,
+  pub pos: ChunkPos,
+}
+
+/// Generates the terrain of the chunks around each `ChunkLoader`, in every level. Does nothing
+/// while a `NetworkClient` exists, since the server sends the chunks it generated instead.
+pub struct WorldGenerator<L: ChunkLayout = DefaultLayout>(PhantomData<L>);
+
+impl<L: ChunkLayout> Default for WorldGenerator<L> {
+  fn default() -> Self {
+    WorldGenerator(PhantomData)
+  }
+}
 
-impl<'a> System<'a> for WorldGenerator {
+impl<'a, L: ChunkLayout> System<'a> for WorldGenerator<L> {
   type SystemData = (
     Entities<'a>,
     ReadExpect<'a, LazyUpdate>,
-    WriteExpect<'a, ChunkedOctree<ChunkState>>,
+    ReadStorage<'a, Level>,
+    Read<'a, ChunkLayers<L>>,
+    ReadStorage<'a, ChunkLookup>,
+    WriteStorage<'a, ChunkedOctree<ChunkState>>,
+    WriteStorage<'a, ChunkStorage<u8, L>>,
+    WriteStorage<'a, UngeneratedEdits<u8, L>>,
+    Write<'a, EventChannel<ChunkGenerated>>,
+    ReadStorage<'a, ChunkLoader>,
+    ReadStorage<'a, Transform>,
+    Option<Read<'a, NetworkClient>>,
   );
 
-  fn run(&mut self, (entities, lazy, mut octree): Self::SystemData) {
-    const MAX_DISTANCE_SQUARED: f32 = 8.5 * 8.5;
-    let nearest = octree
-      .find(
-        |level, pos| {
-          let (mut x, mut y, mut z) = (pos << level as usize).into();
-          if x < 0 {
-            x += 1 << level;
-          }
-          if y < 0 {
-            y += 1 << level;
-          }
-          if z < 0 {
-            z += 1 << level;
-          }
+  fn run(
+    &mut self,
+    (
+      entities,
+      lazy,
+      levels,
+      layers,
+      lookups,
+      mut octrees,
+      mut storages,
+      mut edits,
+      mut generated,
+      loaders,
+      transforms,
+      client,
+    ): Self::SystemData,
+  ) {
+    if client.is_some() {
+      return;
+    }
+    let centers = chunk_loader_chunks::<L>(&loaders, &transforms);
+    for (level_entity, level, lookup, octree) in (&entities, &levels, &lookups, &mut octrees).join()
+    {
+      let max_distance = VIEW_DISTANCE / L::LENGTH as f32;
+      let nearest = nearest_full_detail(octree, &centers, max_distance, 4, |state| {
+        (*state & ChunkState::GENERATED_ALL) != ChunkState::GENERATED_ALL
+      });
 
-          let (x, y, z) = (x as f32, y as f32, z as f32);
-          let distance = x * x + y * y + z * z;
-          if distance <= MAX_DISTANCE_SQUARED {
-            Some(distance)
-          } else {
-            None
-          }
-        },
-        |state| (*state & ChunkState::GENERATED_ALL) != ChunkState::GENERATED_ALL,
-      )
-      .search(ZOrder::new(0, 0, 0).unwrap())
-      .take(4)
-      .collect::<Vec<_>>();
-
-    for (pos, _) in nearest {
-      let state = octree.get(0, pos);
-      // TODO: This should handle chunk entities which already exist, rather than creating them manually.
-
-      let (x, y, z) = pos.into();
-      let chunk_pos = ChunkPos::new(x as i32, y as i32, z as i32);
-      let position = Vector3::new(
-        (x << CHUNK_LENGTH_BITS as i64) as f32,
-        (y << CHUNK_LENGTH_BITS as i64) as f32,
-        (z << CHUNK_LENGTH_BITS as i64) as f32,
-      );
-
-      let noise = OpenSimplex::new();
-      let mut storage = PaletteStorageImpl::<u8>::new();
-      for x in 0..CHUNK_LENGTH as i32 {
-        for y in 0..CHUNK_LENGTH as i32 {
-          for z in 0..CHUNK_LENGTH as i32 {
-            let fx = (position.x as f64 + x as f64 + 0.5) / 16.0;
-            let fy = (position.y as f64 + y as f64 + 0.5) / 16.0;
-            let fz = (position.z as f64 + z as f64 + 0.5) / 16.0;
-            let bias = (fy / 4.0).max(0.0).min(2.0);
-            if noise.get([fx, fy, fz]) > bias {
-              // SAFETY: Bounds should be safe due to loop only going over valid values.
-              let index = unsafe { Index::new_unchecked(x, y, z) };
-              storage.set(index, 1u8);
+      for pos in nearest {
+        let chunk_pos = ChunkPos::from(pos);
+        let storage = level.generator.generate::<L>(level.seed, chunk_pos);
+
+        // Chunk entities may already exist, for example when blocks were placed into them
+        // through `BlockAccess`. Blocks that were set that way take precedence over terrain.
+        let entity = match lookup.get(chunk_pos) {
+          Some(entity) => {
+            let edits = edits.remove(entity).unwrap_or_default();
+            if let Some(existing) = storages.get_mut(entity) {
+              for x in 0..L::LENGTH as i32 {
+                for y in 0..L::LENGTH as i32 {
+                  for z in 0..L::LENGTH as i32 {
+                    // SAFETY: Bounds should be safe due to loop only going over valid values.
+                    let index = unsafe { Index::new_unchecked(x, y, z) };
+                    if !edits.contains(index) {
+                      existing.set(index, storage.get(index));
+                    }
+                  }
+                }
+              }
             }
+            // Light was calculated without the generated terrain, so have the chunk relit.
+            lazy.remove::<ChunkStorage<BlockLight, L>>(entity);
+            lazy.remove::<ChunkStorage<SkyLight, L>>(entity);
+            entity
+          }
+          None => {
+            let (transform, bounds) = chunk_render_components::<L>(chunk_pos);
+            lazy
+              .create_entity(&entities)
+              .with(Chunk {
+                level: level_entity,
+                pos: chunk_pos,
+              })
+              .with(transform)
+              .with(bounds)
+              .build()
           }
+        };
+
+        // Other layers are generated from the terrain before it's moved into the chunk.
+        for layer in layers.iter() {
+          layer.generate(&lazy, entity, level.seed, chunk_pos, &storage);
+        }
+        if !storages.contains(entity) {
+          lazy.insert(entity, ChunkStorage::new(storage));
         }
-      }
 
-      const HALF_CHUNK_LENGTH: i64 = 1 << (CHUNK_LENGTH_BITS - 1);
-      const CENTER: [f32; 3] = [HALF_CHUNK_LENGTH as f32; 3];
-      const RADIUS: f32 = (HALF_CHUNK_LENGTH * HALF_CHUNK_LENGTH * 3) as f32;
-
-      lazy
-        .create_entity(&entities)
-        .with(Chunk { pos: chunk_pos })
-        .with(ChunkStorage::new(storage))
-        .with(Transform::from(position))
-        .with(BoundingSphere::new(CENTER.into(), RADIUS.sqrt()))
-        .build();
-
-      let mask_some = ChunkState::EXISTS_SOME | ChunkState::GENERATED_SOME;
-      let mask_all = ChunkState::EXISTS_ALL | ChunkState::GENERATED_ALL;
-      octree.update(
-        pos,
-        |state| *state = *state | mask_all,
-        |_level, children, parent| {
-          let mask = if children.iter().all(|s| *s & mask_all == mask_all) {
-            mask_all
-          } else {
-            mask_some
-          };
-          if *parent & mask == mask {
-            false
-          } else {
-            *parent = *parent | mask;
-            true
-          }
-        },
-      );
+        mark_chunk_generated(octree, pos);
+        generated.single_write(ChunkGenerated {
+          level: level_entity,
+          pos: chunk_pos,
+        });
+      }
     }
   }
 }
+
+/// Marks the chunk at the specified position as existing and generated in the level's octree.
+pub(crate) fn mark_chunk_generated(octree: &mut ChunkedOctree<ChunkState>, pos: ZOrder) {
+  let mask_some = ChunkState::EXISTS_SOME | ChunkState::GENERATED_SOME;
+  let mask_all = ChunkState::EXISTS_ALL | ChunkState::GENERATED_ALL;
+  octree.update(
+    pos,
+    |state| *state = *state | mask_all,
+    |_level, children, parent| {
+      let mask = if children.iter().all(|s| *s & mask_all == mask_all) {
+        mask_all
+      } else {
+        mask_some
+      };
+      if *parent & mask == mask {
+        false
+      } else {
+        *parent = *parent | mask;
+        true
+      }
+    },
+  );
+}
+
+/// Returns the world-space position of the block with the lowest
+/// coordinates in the specified chunk.
+fn chunk_origin<L: ChunkLayout>(pos: ChunkPos) -> Vector3<f32> {
+  Vector3::new(
+    (pos.x << L::LENGTH_BITS) as f32,
+    (pos.y << L::LENGTH_BITS) as f32,
+    (pos.z << L::LENGTH_BITS) as f32,
+  )
+}
+
+/// Creates the components required to position and render the chunk at the specified position.
+pub(crate) fn chunk_render_components<L: ChunkLayout>(
+  pos: ChunkPos,
+) -> (Transform, BoundingSphere) {
+  let half_length = (L::LENGTH / 2) as f32;
+  let center = [half_length; 3];
+  let radius = (half_length * half_length * 3.0).sqrt();
+  (
+    Transform::from(chunk_origin::<L>(pos)),
+    BoundingSphere::new(center.into(), radius),
+  )
+}
@@ -0,0 +1,6 @@
+#[macro_use]
+extern crate bitflags;
+
+pub mod bloxel;
+pub mod render;
+pub mod util;
@@ -1,14 +1,4 @@
-#[macro_use]
-extern crate bitflags;
-
 use {
-  crate::{
-    bloxel::{
-      chunk::{ChunkLookupSystemDesc, ChunkState},
-      ChunkMeshGenerator, WorldGenerator,
-    },
-    util::ChunkedOctree,
-  },
   amethyst::{
     assets::*,
     controls::{ControlTagPrefab, FlyControlBundle, HideCursor},
@@ -22,11 +12,12 @@ use {
       camera::CameraPrefab,
       formats::GraphicsPrefab,
       light::LightPrefab,
-      plugins::{RenderShaded3D, RenderToWindow},
+      plugins::{RenderDebugLines, RenderShaded3D, RenderToWindow},
       rendy::mesh::{Normal, Position, TexCoord},
       types::DefaultBackend,
       RenderingBundle,
     },
+    ui::{RenderUi, UiBundle},
     utils::{
       application_root_dir,
       auto_fov::{AutoFov, AutoFovSystem},
@@ -34,14 +25,33 @@ use {
     winit::{MouseButton, VirtualKeyCode},
     Error,
   },
+  gaemstone::bloxel::{
+    chunk::{Chunk, ChunkLayers, ChunkLookupSystemDesc, DefaultLayout},
+    chunk_borders_command, create_console_ui, create_debug_overlay, create_inspector_panel,
+    create_level, edit_command, inspect_command,
+    net::{connect_command, ClientNetworkSystem},
+    ActiveLevel, BlockEntitySystemDesc, BlockInteractionSystemDesc, BlockLighting, BlockShape,
+    BlockShapes, BlockTickSystem, BlockTransparency, ChunkBordersSystemDesc, ChunkLoader,
+    ChunkMeshGenerator, Console, ConsoleCommands, ConsoleSystemDesc, DebugOverlaySystemDesc,
+    DebugStatsSystem, Facing, FluidLevel, FluidSystemDesc, InspectableComponents,
+    InspectorSystemDesc, Level, LightingSystemDesc, LodMeshGeneratorDesc, MeshVertexCount,
+    PlayerController, PlayerMovementSystemDesc, ScriptSystemDesc, Scripts, Timed, VoxelBodySystem,
+    WorldGenerator, FLUID_LEVEL_LAYER, SCRIPTS_DIR,
+  },
+  gaemstone::render::RenderVertexLit3D,
   serde::{Deserialize, Serialize},
 };
 
-mod bloxel;
-mod util;
-
 const CLEAR_COLOR: [f32; 4] = [0.1, 0.0, 0.3, 1.0];
 
+// Example blocks showing off the different block shapes and transparencies.
+const GLASS: u8 = 4;
+const LEAVES: u8 = 5;
+const SLAB: u8 = 6;
+const STAIRS: u8 = 7;
+const PLANT: u8 = 8;
+const HEART: u8 = 9;
+
 fn main() -> Result<(), Error> {
   amethyst::start_logger(Default::default());
 
@@ -72,37 +82,144 @@ fn main() -> Result<(), Error> {
     .with_bundle(
       InputBundle::<StringBindings>::new().with_bindings_from_file(&config_path_bindings)?,
     )?
+    // Movement is handled by `PlayerMovementSystem`, so only use this bundle for mouse look.
     .with_bundle(
-      FlyControlBundle::<StringBindings>::new(
-        Some(String::from("move_x")),
-        Some(String::from("move_y")),
-        Some(String::from("move_z")),
-      )
-      .with_sensitivity(0.1, 0.1)
-      .with_speed(20.0),
+      FlyControlBundle::<StringBindings>::new(None, None, None).with_sensitivity(0.1, 0.1),
     )?
+    .with_system_desc(ConsoleSystemDesc::default(), "console", &["input_system"])
     // ===========================
     // == World / Chunk related ==
     // ===========================
     .with_system_desc(ChunkLookupSystemDesc::default(), "chunk_lookup", &[])
-    .with(WorldGenerator::default(), "world_gen", &["chunk_lookup"])
     .with(
-      ChunkMeshGenerator::default(),
-      "chunk_mesh_gen",
+      Timed::new("world_gen", WorldGenerator::<DefaultLayout>::default()),
+      "world_gen",
       &["chunk_lookup"],
     )
+    .with(
+      VoxelBodySystem::<DefaultLayout>::default(),
+      "voxel_bodies",
+      &["chunk_lookup"],
+    )
+    .with_system_desc(
+      PlayerMovementSystemDesc::default(),
+      "player_movement",
+      &["input_system", "console", "chunk_lookup"],
+    )
+    .with_system_desc(
+      BlockInteractionSystemDesc::default(),
+      "block_interaction",
+      &["input_system", "console", "chunk_lookup"],
+    )
+    .with(
+      ClientNetworkSystem::<DefaultLayout>::default(),
+      "client_network",
+      &["chunk_lookup", "block_interaction"],
+    )
+    .with(
+      BlockTickSystem::<DefaultLayout>::default(),
+      "block_ticks",
+      &["chunk_lookup", "block_interaction"],
+    )
+    .with_system_desc(
+      FluidSystemDesc::<DefaultLayout>::default(),
+      "fluids",
+      &["chunk_lookup", "block_interaction"],
+    )
+    .with_system_desc(
+      BlockEntitySystemDesc::default(),
+      "block_entities",
+      &["chunk_lookup", "block_interaction", "block_ticks", "fluids"],
+    )
+    .with_system_desc(
+      ScriptSystemDesc::default(),
+      "scripts",
+      &[
+        "world_gen",
+        "block_interaction",
+        "client_network",
+        "block_ticks",
+        "fluids",
+      ],
+    )
+    .with_system_desc(
+      LightingSystemDesc::<DefaultLayout>::default(),
+      "lighting",
+      &[
+        "chunk_lookup",
+        "world_gen",
+        "block_interaction",
+        "block_ticks",
+        "fluids",
+      ],
+    )
+    .with(
+      Timed::new(
+        "chunk_mesh_gen",
+        ChunkMeshGenerator::<DefaultLayout>::default(),
+      ),
+      "chunk_mesh_gen",
+      &[
+        "chunk_lookup",
+        "block_interaction",
+        "client_network",
+        "block_ticks",
+        "fluids",
+        "lighting",
+      ],
+    )
+    .with_system_desc(
+      LodMeshGeneratorDesc::<DefaultLayout>::default(),
+      "lod_mesh_gen",
+      &["chunk_lookup", "world_gen", "chunk_mesh_gen"],
+    )
+    // ===========
+    // == Debug ==
+    // ===========
+    .with(
+      DebugStatsSystem::<DefaultLayout>::default(),
+      "debug_stats",
+      &["world_gen", "chunk_mesh_gen", "lod_mesh_gen"],
+    )
+    .with_system_desc(
+      DebugOverlaySystemDesc::default(),
+      "debug_overlay",
+      &["input_system", "debug_stats"],
+    )
+    .with_system_desc(
+      ChunkBordersSystemDesc::<DefaultLayout>::default(),
+      "chunk_borders",
+      &["input_system", "world_gen", "chunk_mesh_gen"],
+    )
+    .with_system_desc(
+      InspectorSystemDesc::default(),
+      "inspector",
+      &["input_system", "block_interaction"],
+    )
     // =======================
     // == Rendering related ==
     // =======================
     .with(AutoFovSystem::new(), "auto_fov", &[])
-    .with_bundle(TransformBundle::new().with_dep(&["fly_movement", "free_rotation"]))?
+    .with_bundle(TransformBundle::new().with_dep(&[
+      "fly_movement",
+      "free_rotation",
+      "player_movement",
+      "voxel_bodies",
+    ]))?
+    .with_bundle(UiBundle::<StringBindings>::new())?
     .with_bundle(
       RenderingBundle::<DefaultBackend>::new()
         .with_plugin(RenderToWindow::from_config_path(config_path_display)?.with_clear(CLEAR_COLOR))
-        .with_plugin(RenderShaded3D::default()),
+        .with_plugin(RenderShaded3D::default())
+        .with_plugin(RenderVertexLit3D::default())
+        .with_plugin(RenderDebugLines::default())
+        .with_plugin(RenderUi::default()),
     )?;
 
-  let mut game = Application::build(assets_dir, MainState::default())?.build(game_data)?;
+  let scripts = Scripts::new::<DefaultLayout>().watch(assets_dir.join(SCRIPTS_DIR));
+  let mut game = Application::build(assets_dir, MainState::default())?
+    .with_resource(scripts)
+    .build(game_data)?;
   game.run();
   Ok(())
 }
@@ -116,6 +233,8 @@ struct ScenePrefab {
   light: Option<LightPrefab>,
   camera: Option<CameraPrefab>,
   control_tag: Option<ControlTagPrefab>,
+  player: Option<PlayerController>,
+  loader: Option<ChunkLoader>,
   auto_fov: Option<AutoFov>,
 }
 
@@ -124,7 +243,76 @@ struct MainState;
 
 impl SimpleState for MainState {
   fn on_start(&mut self, data: StateData<'_, GameData<'_, '_>>) {
-    data.world.insert(ChunkedOctree::<ChunkState>::new(5));
+    let layers = ChunkLayers::<DefaultLayout>::default().with::<FluidLevel>(FLUID_LEVEL_LAYER);
+    layers.register(data.world);
+    data.world.insert(layers);
+
+    data.world.insert(
+      BlockShapes::default()
+        .with_shape(GLASS, BlockShape::Cube, BlockTransparency::Translucent)
+        .with_shape(LEAVES, BlockShape::Cube, BlockTransparency::Cutout)
+        .with_shape(SLAB, BlockShape::Slab, BlockTransparency::Opaque)
+        .with_shape(
+          STAIRS,
+          BlockShape::Stairs(Facing::North),
+          BlockTransparency::Opaque,
+        )
+        .with_shape(PLANT, BlockShape::Cross, BlockTransparency::Cutout)
+        .with_shape(
+          HEART,
+          BlockShape::Model("mesh/heart.glb".to_string()),
+          BlockTransparency::Opaque,
+        ),
+    );
+    // Light passes through any block that isn't a full, opaque cube.
+    let lighting = [GLASS, LEAVES, SLAB, STAIRS, PLANT, HEART]
+      .iter()
+      .fold(BlockLighting::default(), |lighting, &block| {
+        lighting.with_opaque(block, false)
+      });
+    data.world.insert(lighting);
+
+    let level = create_level(data.world.create_entity(), Level::new(0));
+    data.world.insert(ActiveLevel(Some(level)));
+    data.world.insert(
+      ConsoleCommands::builtin::<DefaultLayout>()
+        .with(
+          "borders",
+          "borders [<octree level>]",
+          "Toggles chunk borders, or shows octree nodes at the specified level",
+          chunk_borders_command,
+        )
+        .with(
+          "connect",
+          "connect <address> [<name>]",
+          "Connects to a server, replacing the local level with the server's",
+          connect_command,
+        )
+        .with(
+          "inspect",
+          "inspect [<entity>]",
+          "Shows the components of an entity, or information about the targeted block",
+          inspect_command,
+        )
+        .with(
+          "edit",
+          "edit <entity> <component> <field> <value>",
+          "Sets a field of a component of an entity",
+          edit_command,
+        ),
+    );
+    data.world.insert(
+      InspectableComponents::default()
+        .with::<Transform>("transform")
+        .with::<PlayerController>("player")
+        .with::<ChunkLoader>("chunk_loader")
+        .with::<Level>("level")
+        .with::<Chunk>("chunk")
+        .with::<MeshVertexCount>("mesh"),
+    );
+    create_console_ui(data.world);
+    create_debug_overlay(data.world);
+    create_inspector_panel(data.world);
     let handle = data.world.exec(|loader: PrefabLoader<ScenePrefab>| {
       loader.load("prefab/basic_scene.ron", RonFormat, ())
     });
@@ -140,7 +328,9 @@ impl SimpleState for MainState {
     if let StateEvent::Window(event) = &event {
       if is_key_down(&event, VirtualKeyCode::Escape) {
         world.write_resource::<HideCursor>().hide = false;
-      } else if is_mouse_button_down(&event, MouseButton::Left) {
+      } else if is_mouse_button_down(&event, MouseButton::Left)
+        && !world.read_resource::<Console>().is_open()
+      {
         world.write_resource::<HideCursor>().hide = true;
       }
     }
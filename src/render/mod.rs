@@ -0,0 +1,334 @@
+//! Render pass drawing meshes lit through their vertex colors.
+//!
+//! Amethyst's own passes ignore vertex colors, so light baked into chunk meshes by
+//! `ChunkMeshGenerator` would never show up. `RenderVertexLit3D` draws entities with a
+//! `VertexLitMaterial` instead, multiplying the material's albedo by the mesh's vertex colors.
+
+use {
+  amethyst::{
+    assets::{AssetStorage, Handle},
+    core::transform::Transform,
+    ecs::prelude::*,
+    renderer::{
+      batch::{GroupIterator, OrderedTwoLevelBatch},
+      bundle::{RenderOrder, RenderPlan, RenderPlugin, Target},
+      mtl::TexAlbedo,
+      pipeline::{PipelineDescBuilder, PipelinesBuilder},
+      pod::VertexArgs,
+      rendy::{
+        command::{QueueId, RenderPassEncoder},
+        factory::Factory,
+        graph::{
+          render::{PrepareResult, RenderGroup, RenderGroupDesc},
+          GraphContext, NodeBuffer, NodeImage,
+        },
+        hal::{self, device::Device, pso},
+        mesh::{AsVertex, Color, Normal, Position, TexCoord, VertexFormat},
+        shader::{Shader, ShaderKind, SourceLanguage, SourceShaderInfo, SpirvShader},
+      },
+      resources::Tint,
+      submodules::{DynamicVertexBuffer, FlatEnvironmentSub, MaterialId, MaterialSub},
+      types::{Backend, Mesh},
+      util,
+      visibility::Visibility,
+      Material,
+    },
+    Error,
+  },
+  log::warn,
+  std::marker::PhantomData,
+};
+
+const VERTEX_SHADER: &str = include_str!("vertex_lit.vert");
+const FRAGMENT_SHADER: &str = include_str!("vertex_lit.frag");
+
+/// Component for entities whose mesh is drawn by `RenderVertexLit3D`. The mesh needs position,
+/// normal, texture coordinate and color vertices. Entities with a `VertexLitMaterial` shouldn't
+/// also have a `Handle<Material>`, as they'd be drawn by amethyst's passes as well.
+#[derive(Clone)]
+pub struct VertexLitMaterial(pub Handle<Material>);
+
+impl Component for VertexLitMaterial {
+  type Storage = DenseVecStorage<Self>;
+}
+
+/// Render plugin drawing entities with a `VertexLitMaterial`, unaffected by scene lights.
+///
+/// Entities marked `Transparent` are drawn after opaque geometry in the order sorted by the
+/// visibility system of `RenderShaded3D`, so this plugin is meant to be used alongside it.
+#[derive(Default, Debug)]
+pub struct RenderVertexLit3D;
+
+impl<B: Backend> RenderPlugin<B> for RenderVertexLit3D {
+  fn on_build<'a, 'b>(
+    &mut self,
+    world: &mut World,
+    _builder: &mut DispatcherBuilder<'a, 'b>,
+  ) -> Result<(), Error> {
+    world.register::<VertexLitMaterial>();
+    Ok(())
+  }
+
+  fn on_plan(
+    &mut self,
+    plan: &mut RenderPlan<B>,
+    _factory: &mut Factory<B>,
+    _world: &World,
+  ) -> Result<(), Error> {
+    plan.extend_target(Target::Main, |ctx| {
+      ctx.add(RenderOrder::Opaque, DrawVertexLitDesc::new(false).builder())?;
+      ctx.add(
+        RenderOrder::Transparent,
+        DrawVertexLitDesc::new(true).builder(),
+      )?;
+      Ok(())
+    });
+    Ok(())
+  }
+}
+
+/// Describes a `DrawVertexLit` render group for either opaque or transparent entities.
+#[derive(Debug)]
+pub struct DrawVertexLitDesc<B: Backend> {
+  transparent: bool,
+  marker: PhantomData<B>,
+}
+
+impl<B: Backend> DrawVertexLitDesc<B> {
+  pub fn new(transparent: bool) -> Self {
+    DrawVertexLitDesc {
+      transparent,
+      marker: PhantomData,
+    }
+  }
+}
+
+impl<B: Backend> RenderGroupDesc<B, World> for DrawVertexLitDesc<B> {
+  fn build(
+    self,
+    _ctx: &GraphContext<B>,
+    factory: &mut Factory<B>,
+    _queue: QueueId,
+    _world: &World,
+    framebuffer_width: u32,
+    framebuffer_height: u32,
+    subpass: hal::pass::Subpass<'_, B>,
+    _buffers: Vec<NodeBuffer>,
+    _images: Vec<NodeImage>,
+  ) -> Result<Box<dyn RenderGroup<B, World>>, failure::Error> {
+    let env = FlatEnvironmentSub::new(factory)?;
+    let materials = MaterialSub::new(factory)?;
+
+    let mut vertex_format = vec![
+      Position::vertex(),
+      Normal::vertex(),
+      TexCoord::vertex(),
+      Color::vertex(),
+    ];
+    let (pipeline, pipeline_layout) = build_pipeline(
+      factory,
+      subpass,
+      (framebuffer_width, framebuffer_height),
+      &vertex_format,
+      self.transparent,
+      vec![env.raw_layout(), materials.raw_layout()],
+    )?;
+    // Meshes look up their vertex buffers by the sorted format when they're drawn.
+    vertex_format.sort();
+
+    Ok(Box::new(DrawVertexLit {
+      transparent: self.transparent,
+      pipeline,
+      pipeline_layout,
+      vertex_format,
+      env,
+      materials,
+      models: DynamicVertexBuffer::new(),
+      batches: Default::default(),
+    }))
+  }
+}
+
+/// Draws the visible opaque or transparent entities with a `VertexLitMaterial`.
+#[derive(Debug)]
+pub struct DrawVertexLit<B: Backend> {
+  transparent: bool,
+  pipeline: B::GraphicsPipeline,
+  pipeline_layout: B::PipelineLayout,
+  vertex_format: Vec<VertexFormat>,
+  env: FlatEnvironmentSub<B>,
+  materials: MaterialSub<B, (TexAlbedo,)>,
+  models: DynamicVertexBuffer<B, VertexArgs>,
+  batches: OrderedTwoLevelBatch<MaterialId, u32, VertexArgs>,
+}
+
+impl<B: Backend> RenderGroup<B, World> for DrawVertexLit<B> {
+  fn prepare(
+    &mut self,
+    factory: &Factory<B>,
+    _queue: QueueId,
+    index: usize,
+    _subpass: hal::pass::Subpass<'_, B>,
+    world: &World,
+  ) -> PrepareResult {
+    let (mesh_storage, visibility, meshes, materials, transforms, tints) = <(
+      Read<'_, AssetStorage<Mesh>>,
+      ReadExpect<'_, Visibility>,
+      ReadStorage<'_, Handle<Mesh>>,
+      ReadStorage<'_, VertexLitMaterial>,
+      ReadStorage<'_, Transform>,
+      ReadStorage<'_, Tint>,
+    )>::fetch(world);
+
+    self.env.process(factory, index, world);
+    self.materials.maintain();
+    self.batches.swap_clear();
+
+    // Transparent entities have to be drawn back to front, in the order they were sorted in.
+    let visible: Box<dyn Iterator<Item = u32>> = if self.transparent {
+      Box::new(visibility.visible_ordered.iter().map(|entity| entity.id()))
+    } else {
+      Box::new((&visibility.visible_unordered).join())
+    };
+    let materials_ref = &mut self.materials;
+    let batches_ref = &mut self.batches;
+    let mut joined = (&materials, &meshes, &transforms, tints.maybe()).join();
+    visible
+      .filter_map(|id| joined.get_unchecked(id))
+      .map(|(material, mesh, transform, tint)| {
+        let args = VertexArgs::from_object_data(transform, tint);
+        ((&material.0, mesh.id()), args)
+      })
+      .for_each_group(|(material, mesh_id), data| {
+        if mesh_storage.contains_id(mesh_id) {
+          if let Some((material, _)) = materials_ref.insert(factory, world, material) {
+            batches_ref.insert(material, mesh_id, data.drain(..));
+          }
+        }
+      });
+
+    self.models.write(
+      factory,
+      index,
+      self.batches.count() as u64,
+      Some(self.batches.data()),
+    );
+    PrepareResult::DrawRecord
+  }
+
+  fn draw_inline(
+    &mut self,
+    mut encoder: RenderPassEncoder<'_, B>,
+    index: usize,
+    _subpass: hal::pass::Subpass<'_, B>,
+    world: &World,
+  ) {
+    let mesh_storage = <Read<'_, AssetStorage<Mesh>>>::fetch(world);
+    let models_location = self.vertex_format.len() as u32;
+
+    encoder.bind_graphics_pipeline(&self.pipeline);
+    self.env.bind(index, &self.pipeline_layout, 0, &mut encoder);
+    if !self.models.bind(index, models_location, 0, &mut encoder) {
+      return;
+    }
+    for (&material, batches) in self.batches.iter() {
+      if !self.materials.loaded(material) {
+        continue;
+      }
+      (self.materials).bind(&self.pipeline_layout, 1, material, &mut encoder);
+      for (mesh_id, range) in batches {
+        // Only meshes which are loaded make it into a batch, see `prepare`.
+        let mesh = unsafe { mesh_storage.get_by_id_unchecked(*mesh_id) };
+        if let Some(mesh) = B::unwrap_mesh(mesh) {
+          let result = mesh.bind_and_draw(0, &self.vertex_format, range.clone(), &mut encoder);
+          if let Err(error) = result {
+            warn!("Mesh can't be drawn with vertex colors: {:?}", error);
+          }
+        }
+      }
+    }
+  }
+
+  fn dispose(self: Box<Self>, factory: &mut Factory<B>, _world: &World) {
+    unsafe {
+      factory.device().destroy_graphics_pipeline(self.pipeline);
+      factory
+        .device()
+        .destroy_pipeline_layout(self.pipeline_layout);
+    }
+  }
+}
+
+/// Compiles the shaders and builds the graphics pipeline drawing meshes with the
+/// specified vertex format, blending transparent geometry with what's behind it.
+fn build_pipeline<B: Backend>(
+  factory: &Factory<B>,
+  subpass: hal::pass::Subpass<'_, B>,
+  (width, height): (u32, u32),
+  vertex_format: &[VertexFormat],
+  transparent: bool,
+  layouts: Vec<&B::DescriptorSetLayout>,
+) -> Result<(B::GraphicsPipeline, B::PipelineLayout), failure::Error> {
+  let vertex_shader = compile_shader("vertex_lit.vert", VERTEX_SHADER, ShaderKind::Vertex)?;
+  let fragment_shader = compile_shader("vertex_lit.frag", FRAGMENT_SHADER, ShaderKind::Fragment)?;
+
+  let pipeline_layout = unsafe {
+    factory
+      .device()
+      .create_pipeline_layout(layouts, None as Option<(_, _)>)
+  }?;
+
+  let vertex_desc = (vertex_format.iter())
+    .map(|format| (format.clone(), pso::VertexInputRate::Vertex))
+    .chain(Some((
+      VertexArgs::vertex(),
+      pso::VertexInputRate::Instance(1),
+    )))
+    .collect::<Vec<_>>();
+  let vertex_module = unsafe { vertex_shader.module(factory).unwrap() };
+  let fragment_module = unsafe { fragment_shader.module(factory).unwrap() };
+  let blend = if transparent {
+    pso::BlendState::PREMULTIPLIED_ALPHA
+  } else {
+    pso::BlendState::Off
+  };
+  let desc = PipelineDescBuilder::new()
+    .with_vertex_desc(&vertex_desc)
+    .with_shaders(util::simple_shader_set(
+      &vertex_module,
+      Some(&fragment_module),
+    ))
+    .with_layout(&pipeline_layout)
+    .with_subpass(subpass)
+    .with_framebuffer_size(width, height)
+    .with_face_culling(pso::Face::BACK)
+    .with_depth_test(pso::DepthTest::On {
+      fun: pso::Comparison::Less,
+      write: !transparent,
+    })
+    .with_blend_targets(vec![pso::ColorBlendDesc(pso::ColorMask::ALL, blend)]);
+  let pipelines = PipelinesBuilder::new()
+    .with_pipeline(desc)
+    .build(factory, None);
+
+  unsafe {
+    factory.destroy_shader_module(vertex_module);
+    factory.destroy_shader_module(fragment_module);
+  }
+
+  match pipelines {
+    Ok(mut pipelines) => Ok((pipelines.remove(0), pipeline_layout)),
+    Err(error) => {
+      unsafe { factory.device().destroy_pipeline_layout(pipeline_layout) };
+      Err(error)
+    }
+  }
+}
+
+fn compile_shader(
+  name: &str,
+  source: &str,
+  kind: ShaderKind,
+) -> Result<SpirvShader, failure::Error> {
+  SourceShaderInfo::new(source, name, kind, SourceLanguage::GLSL, "main").precompile()
+}
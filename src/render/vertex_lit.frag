@@ -0,0 +1,34 @@
+#version 450
+
+struct UvOffset {
+    vec2 u_offset;
+    vec2 v_offset;
+};
+
+layout(std140, set = 1, binding = 0) uniform Material {
+    UvOffset uv_offset;
+    float alpha_cutoff;
+};
+
+layout(set = 1, binding = 1) uniform sampler2D albedo;
+
+layout(location = 0) in VertexData {
+    vec2 tex_coord;
+    vec4 color;
+} vertex;
+
+layout(location = 0) out vec4 out_color;
+
+vec2 tex_coords(vec2 coords, UvOffset offset) {
+    return vec2(
+        mix(offset.u_offset.x, offset.u_offset.y, coords.x),
+        mix(offset.v_offset.x, offset.v_offset.y, coords.y)
+    );
+}
+
+void main() {
+    vec4 color = texture(albedo, tex_coords(vertex.tex_coord, uv_offset)) * vertex.color;
+    if (color.a < alpha_cutoff) discard;
+    // Transparent geometry is blended with premultiplied alpha.
+    out_color = vec4(color.rgb * color.a, color.a);
+}
@@ -0,0 +1,31 @@
+#version 450
+
+layout(std140, set = 0, binding = 0) uniform ViewArgs {
+    uniform mat4 proj;
+    uniform mat4 view;
+    uniform mat4 proj_view;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 tex_coord;
+layout(location = 3) in vec4 color;
+layout(location = 4) in mat4 model;
+layout(location = 8) in vec4 tint;
+
+layout(location = 0) out VertexData {
+    vec2 tex_coord;
+    vec4 color;
+} vertex;
+
+// Faces are shaded slightly by their direction so the shape of
+// evenly lit terrain remains visible without any scene lights.
+const vec3 SHADE_DIRECTION = normalize(vec3(0.3, 1.0, 0.5));
+
+void main() {
+    vec3 world_normal = normalize(mat3(model) * normal);
+    float shade = 0.75 + 0.25 * dot(world_normal, SHADE_DIRECTION);
+    vertex.tex_coord = tex_coord;
+    vertex.color = vec4(color.rgb * shade, color.a) * tint;
+    gl_Position = proj_view * model * vec4(position, 1.0);
+}
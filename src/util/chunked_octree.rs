@@ -50,18 +50,35 @@ where
     }
   }
 
+  /// Returns the value of the node at the specified position, in units of
+  /// the node size at `level`, where level `0` refers to single chunks.
   pub fn get(&self, level: u8, node_pos: ZOrder) -> T {
+    let levels_to_region = self.depth - level;
     self
       .chunks
-      .get(&(node_pos >> self.depth as usize))
+      .get(&(node_pos >> levels_to_region as usize))
       .map(|region| {
-        let base_index = START_INDEX_LOOKUP[(self.depth - level) as usize];
-        let local_index = (node_pos.raw() as usize) & !(!0 << (self.depth * 3));
+        let base_index = START_INDEX_LOOKUP[levels_to_region as usize];
+        let local_index = (node_pos.raw() as usize) & !(!0 << (levels_to_region * 3));
         region.0[base_index + local_index]
       })
       .unwrap_or_default()
   }
 
+  /// Iterates over the positions and values of all nodes at level `0`, in
+  /// no particular order. Only regions which have been updated are included.
+  pub fn leaves(&self) -> impl Iterator<Item = (ZOrder, T)> + '_ {
+    let depth = self.depth as usize;
+    let start = START_INDEX_LOOKUP[depth];
+    self.chunks.iter().flat_map(move |(&region_pos, region)| {
+      let leaves = region.0[start..start + (1 << (depth * 3))].iter();
+      (leaves.enumerate()).map(move |(i, &value)| {
+        let node_pos = (region_pos << depth) | ZOrder::from_raw(i as _);
+        (node_pos, value)
+      })
+    })
+  }
+
   pub fn update<U, B>(&mut self, node_pos: ZOrder, update_fn: U, bubble_fn: B)
   where
     U: FnOnce(&mut T),
@@ -204,3 +221,48 @@ impl PartialEq for ProcessingNode {
     self.weight == rhs.weight
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn get_returns_nodes_at_every_level() {
+    let mut octree = ChunkedOctree::<u8>::new(3);
+    let pos = ZOrder::new(-3, 5, 9).unwrap();
+    octree.update(
+      pos,
+      |value| *value = 1,
+      |level, _children, parent| {
+        *parent = level + 1;
+        true
+      },
+    );
+    for level in 0..=3 {
+      assert_eq!(octree.get(level, pos >> level as usize), level + 1);
+    }
+    assert_eq!(octree.get(1, (pos >> 1) + ZOrder::new(1, 0, 0).unwrap()), 0);
+  }
+
+  #[test]
+  fn leaves_includes_every_chunk_of_updated_regions() {
+    let mut octree = ChunkedOctree::<u8>::new(2);
+    let positions = [
+      ZOrder::new(-3, 5, 9).unwrap(),
+      ZOrder::new(1, 2, 3).unwrap(),
+    ];
+    for pos in &positions {
+      octree.update(*pos, |value| *value = 1, |_, _, _| false);
+    }
+    let leaves = octree.leaves().collect::<Vec<_>>();
+    assert_eq!(leaves.len(), 2 * 4 * 4 * 4);
+    let mut set = (leaves.iter())
+      .filter(|(_, value)| *value == 1)
+      .map(|(pos, _)| *pos)
+      .collect::<Vec<_>>();
+    set.sort();
+    let mut expected = positions.to_vec();
+    expected.sort();
+    assert_eq!(set, expected);
+  }
+}
@@ -17,6 +17,7 @@ const DEFAULT_CAPACITY: usize = 32;
 /// # Examples
 ///
 /// ```
+/// # use gaemstone::util::PaletteStore;
 /// let mut store = PaletteStore::<u8>::new(16);
 /// assert_eq!(store.get(8).unwrap(), Default::default());
 ///
@@ -67,7 +68,7 @@ impl<T: Default + Copy + Eq> PaletteStore<T> {
   /// of virtual elements and capacity of palette entries.
   ///
   /// This is equivalent to calling:
-  /// ```
+  /// ```ignore
   /// let mut storage = Self::new(size);
   /// storage.reserve(capacity);
   /// ```
@@ -95,6 +96,13 @@ impl<T: Default + Copy + Eq> PaletteStore<T> {
     self.entries.len() - self.used_entries()
   }
 
+  /// Gets the number of bytes allocated on the heap by the
+  /// underlying bit vector and palette entries.
+  pub fn heap_size(&self) -> usize {
+    self.bits.as_slice().len() * std::mem::size_of::<usize>()
+      + self.entries.capacity() * std::mem::size_of::<PaletteEntry<T>>()
+  }
+
   /// Reserves a number of additional palette entries on top of the current number of
   /// `used_entries()`. No effect if `additional` is smaller or equals to `free_entries()`.
   pub fn reserve(&mut self, additional: usize) {
@@ -123,6 +131,16 @@ impl<T: Default + Copy + Eq> PaletteStore<T> {
     }
   }
 
+  /// Gets the index of the palette entry the element at the specified index refers to,
+  /// or `None` if the index is out of bounds or no palette entries are in use yet.
+  pub fn palette_index(&self, index: usize) -> Option<usize> {
+    if index >= self.size || self.used == 0 {
+      None
+    } else {
+      Some(self.get_palette_index(index))
+    }
+  }
+
   pub unsafe fn get_unchecked(&self, index: usize) -> T {
     if self.used == 0 {
       // If no palette entries are currently being used (such as when the
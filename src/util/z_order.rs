@@ -34,6 +34,7 @@ use {
 /// # Examples
 ///
 /// ```
+/// # use gaemstone::util::ZOrder;
 /// let o = ZOrder::<i32>::new(13, -8, 1).unwrap();
 /// // Can be destructed into a `(i32, i32, i32)` tuple:
 /// let (x, y, z) = o.into();
@@ -289,6 +290,16 @@ mod tests {
     assert_eq!(ZOrder::<i64>::new(0, 0, 2i32.pow(20)), None);
   }
 
+  #[test]
+  fn negative_elements_using_all_bits() {
+    // Signed elements are sign-extended when converted to the index type, so
+    // any bits beyond the usable range have to be masked before interleaving.
+    let o = ZOrder::<i64>::new(-65537, -1048576, 1048575).unwrap();
+    assert_eq!(o.into(), (-65537, -1048576, 1048575));
+    let o = ZOrder::<i32>::new(-300, -512, 511).unwrap();
+    assert_eq!(o.into(), (-300, -512, 511));
+  }
+
   #[test]
   fn bitwise_shifting() {
     let zero = ZOrder::<i32>::new(0, 0, 0).unwrap();
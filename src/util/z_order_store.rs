@@ -131,7 +131,7 @@ impl_store!(
   MASKS_8BIT[MASKS_8BIT.len() - 1],
   x,
   {
-    // x = x & MASKS_8BIT[0];
+    x = x & MASKS_8BIT[0];
     x = (x | x << 2) & MASKS_8BIT[1];
   },
   {
@@ -148,7 +148,7 @@ impl_store!(
   MASKS_16BIT[MASKS_16BIT.len() - 1],
   x,
   {
-    // x = x & MASKS_16BIT[0];
+    x = x & MASKS_16BIT[0];
     x = (x | x << 8) & MASKS_16BIT[1];
     x = (x | x << 4) & MASKS_16BIT[2];
     x = (x | x << 2) & MASKS_16BIT[3];
@@ -169,7 +169,7 @@ impl_store!(
   MASKS_32BIT[MASKS_32BIT.len() - 1],
   x,
   {
-    // x = x & MASKS_32BIT[0];
+    x = x & MASKS_32BIT[0];
     x = (x | x << 16) & MASKS_32BIT[1];
     x = (x | x << 8) & MASKS_32BIT[2];
     x = (x | x << 4) & MASKS_32BIT[3];
@@ -192,7 +192,7 @@ impl_store!(
   MASKS_64BIT[MASKS_64BIT.len() - 1],
   x,
   {
-    // x = x & MASKS_64BIT[0];
+    x = x & MASKS_64BIT[0];
     x = (x | x << 32) & MASKS_64BIT[1];
     x = (x | x << 16) & MASKS_64BIT[2];
     x = (x | x << 8) & MASKS_64BIT[3];